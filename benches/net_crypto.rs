@@ -0,0 +1,158 @@
+/*! Benchmarks of `NetCrypto` connections lookup.
+
+Run with `cargo bench --bench net_crypto`. It's a plain program without
+external benchmark harness so that it builds with the minimum supported Rust
+version.
+*/
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use tokio::runtime::{Builder, Runtime};
+
+use tox::toxcore::crypto_core::*;
+use tox::toxcore::dht::packet::*;
+use tox::toxcore::dht::precomputed_cache::*;
+use tox::toxcore::net_crypto::*;
+
+/// Numbers of connections to benchmark with.
+const CONNECTIONS: [usize; 4] = [10, 100, 1000, 10000];
+
+/// Number of threads that handle packets in parallel.
+const THREADS: usize = 4;
+
+/// Number of packets handled by every benchmark iteration.
+const ITERATIONS: usize = 100_000;
+
+struct Node {
+    net_crypto: NetCrypto,
+    /// DHT `PublicKey` and UDP address of every peer
+    peers: Vec<(PublicKey, SocketAddr)>,
+    // receivers must be alive so that sending doesn't fail
+    _udp_rx: mpsc::Receiver<(Packet, SocketAddr)>,
+    _lossless_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+    _lossy_rx: mpsc::UnboundedReceiver<(PublicKey, Vec<u8>)>,
+}
+
+fn node(connections: usize) -> Node {
+    let (udp_tx, udp_rx) = mpsc::channel(1);
+    let (lossless_tx, lossless_rx) = mpsc::unbounded();
+    let (lossy_tx, lossy_rx) = mpsc::unbounded();
+    let (dht_pk, dht_sk) = gen_keypair();
+    let (real_pk, real_sk) = gen_keypair();
+    let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+    let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+        udp_tx,
+        lossless_tx,
+        lossy_tx,
+        dht_pk,
+        dht_sk,
+        real_pk,
+        real_sk,
+        precomputed_keys,
+    });
+
+    let peers = (0 .. connections).map(|i| {
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let addr = SocketAddr::from(([127, 0, (i >> 8) as u8, i as u8], 33445));
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        (peer_dht_pk, addr)
+    }).collect();
+
+    Node {
+        net_crypto,
+        peers,
+        _udp_rx: udp_rx,
+        _lossless_rx: lossless_rx,
+        _lossy_rx: lossy_rx,
+    }
+}
+
+fn runtime() -> Runtime {
+    Builder::new().basic_scheduler().build().unwrap()
+}
+
+fn crypto_data() -> CryptoData {
+    CryptoData {
+        nonce_last_bytes: 42,
+        payload: vec![42; 123],
+    }
+}
+
+/// Print average time spent on one packet.
+fn report(group: &str, connections: usize, elapsed: Duration) {
+    let nanos = elapsed.as_nanos() / ITERATIONS as u128;
+    println!("{:<28} {:>6} connections: {:>8} ns/packet", group, connections, nanos);
+}
+
+/// Packets received via TCP relays are matched with connections by DHT
+/// `PublicKey` of the sender.
+fn by_dht_pk() {
+    let mut runtime = runtime();
+    let packet = crypto_data();
+
+    for &connections in &CONNECTIONS {
+        let node = node(connections);
+        let start = Instant::now();
+        for (dht_pk, _) in node.peers.iter().cycle().take(ITERATIONS) {
+            // connections are not established so the packet is dropped
+            // right after lookup
+            runtime.block_on(node.net_crypto.handle_tcp_crypto_data(&packet, *dht_pk)).unwrap_err();
+        }
+        report("connection by dht pk", connections, start.elapsed());
+    }
+}
+
+/// Packets received via UDP are matched with connections by the address of
+/// the sender.
+fn by_addr() {
+    let mut runtime = runtime();
+    let packet = crypto_data();
+
+    for &connections in &CONNECTIONS {
+        let node = node(connections);
+        let start = Instant::now();
+        for (_, addr) in node.peers.iter().cycle().take(ITERATIONS) {
+            // connections are not established so the packet is dropped
+            // right after lookup
+            runtime.block_on(node.net_crypto.handle_udp_crypto_data(&packet, *addr)).unwrap_err();
+        }
+        report("connection by addr", connections, start.elapsed());
+    }
+}
+
+/// Packets from different peers are handled by several threads in parallel.
+/// The reported time is the wall time divided by the number of packets handled
+/// by every thread.
+fn parallel() {
+    for &connections in &CONNECTIONS {
+        let node = node(connections);
+        let start = Instant::now();
+        let handles = (0 .. THREADS).map(|i| {
+            let net_crypto = node.net_crypto.clone();
+            let peers = node.peers.clone();
+            thread::spawn(move || {
+                let mut runtime = runtime();
+                let packet = crypto_data();
+                for (_, addr) in peers.iter().skip(i).step_by(THREADS).cycle().take(ITERATIONS) {
+                    runtime.block_on(net_crypto.handle_udp_crypto_data(&packet, *addr)).unwrap_err();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        report("parallel udp packets", connections, start.elapsed());
+    }
+}
+
+fn main() {
+    crypto_init().unwrap();
+    by_dht_pk();
+    by_addr();
+    parallel();
+}
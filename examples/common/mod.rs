@@ -24,6 +24,7 @@ pub const BOOTSTRAP_NODES: [(&str, &str); 9] = [
 ];
 
 /// Bind a UDP listener to the socket address.
+#[allow(dead_code)] // not every example binds to a fixed address
 pub async fn bind_socket(addr: SocketAddr) -> UdpSocket {
     let socket = UdpSocket::bind(&addr)
         .await
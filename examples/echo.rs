@@ -8,20 +8,19 @@ use futures::channel::mpsc;
 use hex::FromHex;
 use failure::{err_msg, Error};
 
-use std::net::SocketAddr;
-
 use tox::toxcore::binary_io::*;
 use tox::toxcore::dht::server::Server;
 use tox::toxcore::dht::server_ext::ServerExt;
 use tox::toxcore::dht::packed_node::PackedNode;
 use tox::toxcore::dht::lan_discovery::LanDiscoverySender;
+use tox::toxcore::dht::udp_socket::{bind_udp, IpMode, PortRange};
 use tox::toxcore::crypto_core::*;
 use tox::toxcore::friend_connection::FriendConnections;
 use tox::toxcore::friend_connection::packet::*;
 use tox::toxcore::net_crypto::{NetCrypto, NetCryptoNewArgs};
 use tox::toxcore::onion::client::OnionClient;
 use tox::toxcore::onion::packet::InnerOnionResponse;
-use tox::toxcore::tcp::client::{Connections, IncomingPacket};
+use tox::toxcore::tcp::client::{Connections, IncomingPacket, ProxyConfig};
 use tox::toxcore::tcp::packet::DataPayload;
 use tox::toxcore::stats::Stats;
 use tox::toxcore::toxid::ToxId;
@@ -54,23 +53,49 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create a channel for server to communicate with network
     let (tx, rx) = mpsc::channel(32);
 
-    let local_addr: SocketAddr = "0.0.0.0:33447".parse()?; // 0.0.0.0 for IPv4
-    // let local_addr: SocketAddr = "[::]:33445".parse().unwrap(); // [::] for IPv6
-    info!("Running echo server on {}", local_addr);
+    let ip_mode = IpMode::V4; // IpMode::DualStack for IPv6
+
+    // in TCP-only mode UDP socket is not bound and we are connected to the
+    // network only via TCP relays
+    let tcp_only = std::env::args().any(|arg| arg == "--tcp-only");
+    // connections to TCP relays can be made through SOCKS5 proxy, e.g. Tor
+    let socks5_proxy = std::env::args()
+        .skip_while(|arg| arg != "--socks5")
+        .nth(1)
+        .map(|addr| ProxyConfig::Socks5 {
+            addr: addr.parse().expect("Invalid SOCKS5 proxy address"),
+            credentials: None,
+        });
 
     let future = async {
-        let socket = common::bind_socket(local_addr).await;
-        let stats = Stats::new();
-
-        let lan_discovery_sender = LanDiscoverySender::new(tx.clone(), dht_pk, local_addr.is_ipv6());
-
         let (tcp_incoming_tx, mut tcp_incoming_rx) = mpsc::unbounded();
 
         let mut dht_server = Server::new(tx.clone(), dht_pk, dht_sk.clone());
-        dht_server.enable_lan_discovery(true);
-        dht_server.enable_ipv6_mode(local_addr.is_ipv6());
+
+        let udp = if tcp_only {
+            info!("Running echo server in TCP-only mode");
+            dht_server.enable_tcp_only(true);
+            None
+        } else {
+            // bind to the first free port from 33445 .. 33545 range
+            let socket = bind_udp(ip_mode, PortRange::default())
+                .await
+                .expect("Failed to bind UDP socket");
+            let local_addr = socket.local_addr().expect("Failed to get local address");
+            info!("Running echo server on {}", local_addr);
+            let stats = Stats::new();
+
+            let mut lan_discovery_sender = LanDiscoverySender::new(tx.clone(), dht_pk, local_addr.is_ipv6());
+            lan_discovery_sender.set_local_port(local_addr.port());
+
+            dht_server.enable_lan_discovery(true);
+            dht_server.enable_ipv6_mode(local_addr.is_ipv6());
+
+            Some((socket, stats, lan_discovery_sender))
+        };
 
         let tcp_connections = Connections::new(dht_pk, dht_sk.clone(), tcp_incoming_tx);
+        tcp_connections.set_proxy(socks5_proxy);
         let onion_client = OnionClient::new(dht_server.clone(), tcp_connections.clone(), real_sk.clone(), real_pk);
 
         let (lossless_tx, mut lossless_rx) = mpsc::unbounded();
@@ -92,6 +117,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
         let (net_crypto_tcp_tx, mut net_crypto_tcp_rx) = mpsc::channel(32);
         net_crypto.set_tcp_sink(net_crypto_tcp_tx);
+        net_crypto.set_tcp_only(tcp_only);
 
         dht_server.set_net_crypto(net_crypto.clone());
         dht_server.set_onion_client(onion_client.clone());
@@ -114,7 +140,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             let node = PackedNode::new(saddr.parse().unwrap(), &bootstrap_pk);
 
-            dht_server.add_initial_bootstrap(node);
+            if !tcp_only {
+                dht_server.add_initial_bootstrap(node);
+            }
+            // bootstrap nodes are used as onion path nodes in both modes
             onion_client.add_path_node(node);
         }
 
@@ -220,8 +249,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             .collect();
 
         let mut futures = vec![
-            dht_server.run_socket(socket, rx, stats).map_err(Error::from).boxed(),
-            lan_discovery_sender.run().map_err(Error::from).boxed(),
             tcp_connections.run().map_err(Error::from).boxed(),
             onion_client.run().map_err(Error::from).boxed(),
             net_crypto.run().map_err(Error::from).boxed(),
@@ -233,6 +260,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             friend_future.boxed(),
         ];
 
+        if let Some((socket, stats, lan_discovery_sender)) = udp {
+            futures.push(dht_server.run_socket(socket, rx, stats).map_err(Error::from).boxed());
+            futures.push(lan_discovery_sender.run().map_err(Error::from).boxed());
+        }
+
         futures.append(&mut relays_futures);
 
         future::try_join_all(futures)
@@ -2,7 +2,7 @@
 extern crate log;
 
 use tox::toxcore::crypto_core::*;
-use tox::toxcore::tcp::server::{Server, ServerExt};
+use tox::toxcore::tcp::server::{Server, ServerExt, ServerLimits};
 use tox::toxcore::stats::Stats;
 
 use tokio::net::TcpListener;
@@ -35,7 +35,7 @@ fn main() {
     let stats = Stats::new();
     let future = async move {
         let listener = TcpListener::bind(&addr).await.unwrap();
-        drop(server.run(listener, server_sk, stats, TCP_CONNECTIONS_LIMIT).await);
+        drop(server.run(listener, server_sk, stats, ServerLimits::new(TCP_CONNECTIONS_LIMIT)).await);
     };
 
     let mut runtime = tokio::runtime::Runtime::new().unwrap();
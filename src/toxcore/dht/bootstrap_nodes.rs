@@ -0,0 +1,353 @@
+/*!
+Loader for the list of bootstrap nodes in the `nodes.json` format.
+
+This is the format of the list published at https://nodes.tox.chat/json:
+
+```json
+{
+  "last_scan": 1588000000,
+  "last_refresh": 1588000000,
+  "nodes": [
+    {
+      "ipv4": "85.172.30.117",
+      "ipv6": "-",
+      "port": 33445,
+      "tcp_ports": [33445],
+      "public_key": "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832",
+      "maintainer": "ray65536",
+      "location": "RU",
+      "status_udp": true,
+      "status_tcp": true,
+      "version": "1000002018",
+      "motd": "Welcome!",
+      "last_ping": 1588000000
+    }
+  ]
+}
+```
+
+Nodes can be passed to `Server::add_initial_bootstrap` and relays to
+`tcp::client::Connections::add_relay_global`. Only IP addresses are supported,
+nodes with domain names instead of addresses are skipped since resolving them
+is up to the caller.
+*/
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use failure::Fail;
+use serde::Deserialize;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::*;
+
+error_kind! {
+    #[doc = "Error that can happen when loading bootstrap nodes list."]
+    #[derive(Debug)]
+    LoadNodesError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    LoadNodesErrorKind {
+        #[doc = "Failed to read the file."]
+        #[fail(display = "Failed to read bootstrap nodes file")]
+        Read,
+        #[doc = "The file is not a valid nodes list."]
+        #[fail(display = "Failed to parse bootstrap nodes list")]
+        Parse,
+    }
+}
+
+/// Node from the bootstrap nodes list as it's stored in JSON.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct BootstrapNode {
+    /// IPv4 address or domain name of the node. `-` if it's unknown.
+    pub ipv4: String,
+    /// IPv6 address or domain name of the node. `-` if it's unknown.
+    pub ipv6: String,
+    /// UDP port of the node.
+    pub port: u16,
+    /// TCP ports of the node if it works as a TCP relay.
+    #[serde(default)]
+    pub tcp_ports: Vec<u16>,
+    /// DHT `PublicKey` of the node in hex format.
+    pub public_key: String,
+    /// Name of the node maintainer.
+    #[serde(default)]
+    pub maintainer: String,
+    /// Country code of the node location.
+    #[serde(default)]
+    pub location: String,
+    /// Whether the node was reachable via UDP during the last scan.
+    #[serde(default)]
+    pub status_udp: bool,
+    /// Whether the node was reachable via TCP during the last scan.
+    #[serde(default)]
+    pub status_tcp: bool,
+    /// Unix time of the last successful ping of the node.
+    #[serde(default)]
+    pub last_ping: Option<u64>,
+}
+
+impl BootstrapNode {
+    /// Parse `PublicKey` of the node.
+    pub fn pk(&self) -> Option<PublicKey> {
+        hex::decode(&self.public_key).ok()
+            .and_then(|bytes| PublicKey::from_slice(&bytes))
+    }
+
+    /// Parse IPv4 address of the node. Domain names are not resolved.
+    pub fn ipv4_addr(&self) -> Option<IpAddr> {
+        self.ipv4.parse().ok().filter(IpAddr::is_ipv4)
+    }
+
+    /// Parse IPv6 address of the node. Domain names are not resolved.
+    pub fn ipv6_addr(&self) -> Option<IpAddr> {
+        self.ipv6.parse().ok().filter(IpAddr::is_ipv6)
+    }
+
+    /// Get IP addresses of the node that can be used. IPv6 address goes first
+    /// if it's allowed.
+    fn ip_addrs(&self, ipv6_enabled: bool) -> Vec<IpAddr> {
+        let ipv6 = if ipv6_enabled { self.ipv6_addr() } else { None };
+        ipv6.into_iter().chain(self.ipv4_addr()).collect()
+    }
+}
+
+/// Options to choose nodes from the bootstrap nodes list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NodesFilter {
+    /// Whether IPv6 addresses should be used.
+    pub ipv6_enabled: bool,
+    /// Whether only nodes that were reachable during the last scan should be
+    /// used.
+    pub only_online: bool,
+}
+
+impl Default for NodesFilter {
+    fn default() -> Self {
+        NodesFilter {
+            ipv6_enabled: false,
+            only_online: true,
+        }
+    }
+}
+
+/// List of bootstrap nodes in the `nodes.json` format.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct BootstrapNodes {
+    /// Unix time of the last scan of the nodes.
+    #[serde(default)]
+    pub last_scan: Option<u64>,
+    /// Unix time of the last refresh of the list.
+    #[serde(default)]
+    pub last_refresh: Option<u64>,
+    /// Nodes of the list.
+    pub nodes: Vec<BootstrapNode>,
+}
+
+impl BootstrapNodes {
+    /// Parse the list from JSON.
+    pub fn from_json(json: &[u8]) -> Result<BootstrapNodes, LoadNodesError> {
+        serde_json::from_slice(json)
+            .map_err(|e| e.context(LoadNodesErrorKind::Parse).into())
+    }
+
+    /// Load the list from a local file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<BootstrapNodes, LoadNodesError> {
+        let json = fs::read(path)
+            .map_err(|e| e.context(LoadNodesErrorKind::Read))?;
+        BootstrapNodes::from_json(&json)
+    }
+
+    /// Get nodes to bootstrap DHT from. A node with both IPv4 and IPv6
+    /// addresses results in two `PackedNode`s.
+    pub fn udp_nodes(&self, filter: NodesFilter) -> Vec<PackedNode> {
+        self.nodes.iter()
+            .filter(|node| !filter.only_online || node.status_udp)
+            .flat_map(|node| {
+                let pk = node.pk();
+                if pk.is_none() {
+                    warn!("Invalid public key of bootstrap node: {}", node.public_key);
+                }
+                let port = node.port;
+                pk.into_iter().flat_map(move |pk|
+                    node.ip_addrs(filter.ipv6_enabled)
+                        .into_iter()
+                        .map(move |ip| PackedNode::new(SocketAddr::new(ip, port), &pk))
+                )
+            })
+            .collect()
+    }
+
+    /// Get TCP relays as pairs of address and `PublicKey`. A relay with
+    /// several TCP ports or both IPv4 and IPv6 addresses results in several
+    /// pairs.
+    pub fn tcp_relays(&self, filter: NodesFilter) -> Vec<(SocketAddr, PublicKey)> {
+        self.nodes.iter()
+            .filter(|node| !filter.only_online || node.status_tcp)
+            .flat_map(|node| {
+                let pk = node.pk();
+                if pk.is_none() {
+                    warn!("Invalid public key of bootstrap node: {}", node.public_key);
+                }
+                pk.into_iter().flat_map(move |pk|
+                    node.ip_addrs(filter.ipv6_enabled)
+                        .into_iter()
+                        .flat_map(move |ip| node.tcp_ports.iter()
+                            .map(move |&port| (SocketAddr::new(ip, port), pk))
+                        )
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES_JSON: &str = r#"{
+        "last_scan": 1588000000,
+        "last_refresh": 1588000000,
+        "nodes": [
+            {
+                "ipv4": "85.172.30.117",
+                "ipv6": "-",
+                "port": 33445,
+                "tcp_ports": [33445, 443],
+                "public_key": "8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832",
+                "maintainer": "ray65536",
+                "location": "RU",
+                "status_udp": true,
+                "status_tcp": true,
+                "version": "1000002018",
+                "motd": "Welcome!",
+                "last_ping": 1588000000
+            },
+            {
+                "ipv4": "46.101.197.175",
+                "ipv6": "2a03:b0c0:3:d0::ac:5001",
+                "port": 443,
+                "tcp_ports": [],
+                "public_key": "CD133B521159541FB1D326DE9850F5E56A6C724B5B8E5EB5CD8D950408E95707",
+                "maintainer": "clearmartin",
+                "location": "DE",
+                "status_udp": true,
+                "status_tcp": false,
+                "version": "1000002018",
+                "motd": "",
+                "last_ping": 1588000000
+            },
+            {
+                "ipv4": "node.tox.example.org",
+                "ipv6": "-",
+                "port": 33445,
+                "tcp_ports": [33445],
+                "public_key": "1D5A5F2F5D6233058BF0259B09622FB40B482E4FA0931EB8FD3AB8E7BF7DAF6F",
+                "maintainer": "example",
+                "location": "US",
+                "status_udp": true,
+                "status_tcp": true,
+                "version": "1000002018",
+                "motd": "",
+                "last_ping": 1588000000
+            },
+            {
+                "ipv4": "5.189.176.217",
+                "ipv6": "-",
+                "port": 5190,
+                "tcp_ports": [5190],
+                "public_key": "2B2137E094F743AC8BD44652C55F41DFACC502F125E99E4FE24D40537489E32F",
+                "maintainer": "tastytea",
+                "location": "DE",
+                "status_udp": false,
+                "status_tcp": false,
+                "version": "",
+                "motd": "",
+                "last_ping": null
+            },
+            {
+                "ipv4": "1.2.3.4",
+                "ipv6": "-",
+                "port": 33445,
+                "tcp_ports": [],
+                "public_key": "invalid",
+                "status_udp": true,
+                "status_tcp": true
+            }
+        ]
+    }"#;
+
+    fn pk(hex: &str) -> PublicKey {
+        PublicKey::from_slice(&hex::decode(hex).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn from_json() {
+        let nodes = BootstrapNodes::from_json(NODES_JSON.as_bytes()).unwrap();
+
+        assert_eq!(nodes.last_scan, Some(1_588_000_000));
+        assert_eq!(nodes.nodes.len(), 5);
+        assert_eq!(nodes.nodes[0].tcp_ports, vec![33445, 443]);
+        assert_eq!(nodes.nodes[3].last_ping, None);
+    }
+
+    #[test]
+    fn from_json_invalid() {
+        let error = BootstrapNodes::from_json(b"{\"nodes\": 42}").err().unwrap();
+        assert_eq!(*error.kind(), LoadNodesErrorKind::Parse);
+    }
+
+    #[test]
+    fn load_nonexistent_file() {
+        let error = BootstrapNodes::load("nonexistent/nodes.json").err().unwrap();
+        assert_eq!(*error.kind(), LoadNodesErrorKind::Read);
+    }
+
+    #[test]
+    fn udp_nodes() {
+        crypto_init().unwrap();
+        let nodes = BootstrapNodes::from_json(NODES_JSON.as_bytes()).unwrap();
+
+        let filter = NodesFilter { ipv6_enabled: false, only_online: true };
+        assert_eq!(nodes.udp_nodes(filter), vec![
+            PackedNode::new(
+                "85.172.30.117:33445".parse().unwrap(),
+                &pk("8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832")
+            ),
+            PackedNode::new(
+                "46.101.197.175:443".parse().unwrap(),
+                &pk("CD133B521159541FB1D326DE9850F5E56A6C724B5B8E5EB5CD8D950408E95707")
+            ),
+        ]);
+    }
+
+    #[test]
+    fn udp_nodes_ipv6_and_offline() {
+        crypto_init().unwrap();
+        let nodes = BootstrapNodes::from_json(NODES_JSON.as_bytes()).unwrap();
+
+        let filter = NodesFilter { ipv6_enabled: true, only_online: false };
+        let udp_nodes = nodes.udp_nodes(filter);
+        let addrs = udp_nodes.iter().map(|node| node.saddr).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![
+            "85.172.30.117:33445".parse().unwrap(),
+            "[2a03:b0c0:3:d0::ac:5001]:443".parse().unwrap(),
+            "46.101.197.175:443".parse().unwrap(),
+            "5.189.176.217:5190".parse().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn tcp_relays() {
+        crypto_init().unwrap();
+        let nodes = BootstrapNodes::from_json(NODES_JSON.as_bytes()).unwrap();
+
+        let relay_pk = pk("8E7D0B859922EF569298B4D261A8CCB5FEA14FB91ED412A7603A585A25698832");
+        assert_eq!(nodes.tcp_relays(NodesFilter::default()), vec![
+            ("85.172.30.117:33445".parse().unwrap(), relay_pk),
+            ("85.172.30.117:443".parse().unwrap(), relay_pk),
+        ]);
+    }
+}
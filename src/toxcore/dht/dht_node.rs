@@ -101,6 +101,8 @@ pub struct DhtNode {
     pub assoc6: SockAndTime<SocketAddrV6>,
     /// Public Key of the node.
     pub pk: PublicKey,
+    /// Time when the node was added to the list.
+    pub added_time: Instant,
 }
 
 impl DhtNode {
@@ -115,9 +117,15 @@ impl DhtNode {
             pk: pn.pk,
             assoc4: SockAndTime::new(saddr_v4),
             assoc6: SockAndTime::new(saddr_v6),
+            added_time: clock_now(),
         }
     }
 
+    /// How long the node is kept in the list.
+    pub fn uptime(&self) -> Duration {
+        clock_elapsed(self.added_time)
+    }
+
     /// Check if the node is considered bad i.e. it does not answer both on IPv4
     /// and IPv6 addresses for `BAD_NODE_TIMEOUT` seconds.
     pub fn is_bad(&self) -> bool {
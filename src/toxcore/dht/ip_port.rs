@@ -2,7 +2,7 @@
 Module for utils of IP and Port.
 */
 
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 
 /// TODO: replace with https://doc.rust-lang.org/std/net/struct.Ipv4Addr.html#method.is_global when it is stabilized
 pub trait IsGlobal {
@@ -68,6 +68,34 @@ impl IsGlobal for IpAddr {
     }
 }
 
+/** Convert IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4 address. Other
+addresses are returned unchanged.
+
+Unlike `Ipv6Addr::to_ipv4` it doesn't convert deprecated IPv4-compatible
+addresses so that e.g. `::1` remains IPv6 loopback address.
+*/
+pub fn ipv4_mapped_to_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => match ipv6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => ipv6.to_ipv4().map_or(ip, IpAddr::V4),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/** Normalize socket address so that the same host always has the same address
+regardless of the socket it was received from.
+
+When we are listening on a dual-stack `[::]` socket IPv4 peers have
+IPv4-mapped addresses. Such addresses are converted to IPv4 so that they can be
+compared with addresses received in `NodesResponse` packets. Outgoing packets
+are mapped back to IPv6 when sending through IPv6 socket.
+*/
+pub fn normalize_saddr(saddr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(ipv4_mapped_to_ipv4(saddr.ip()), saddr.port())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,4 +135,22 @@ mod tests {
         let ipv4 = "128.0.0.1".parse().unwrap();
         assert!(IsGlobal::is_global(&IpAddr::V4(ipv4)));
     }
+
+    #[test]
+    fn normalize_saddr_test() {
+        let saddr: SocketAddr = "[::ffff:1.2.3.4]:12345".parse().unwrap();
+        assert_eq!(normalize_saddr(saddr), "1.2.3.4:12345".parse().unwrap());
+
+        let saddr: SocketAddr = "[::1]:12345".parse().unwrap();
+        assert_eq!(normalize_saddr(saddr), saddr);
+
+        let saddr: SocketAddr = "[::1234:4321]:12345".parse().unwrap();
+        assert_eq!(normalize_saddr(saddr), saddr);
+
+        let saddr: SocketAddr = "[2001:db8::1]:12345".parse().unwrap();
+        assert_eq!(normalize_saddr(saddr), saddr);
+
+        let saddr: SocketAddr = "1.2.3.4:12345".parse().unwrap();
+        assert_eq!(normalize_saddr(saddr), saddr);
+    }
 }
@@ -117,6 +117,29 @@ impl KbucketNode for PackedNode {
     }
 }
 
+/// The result of an attempt to add a node to a `Kbucket`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AddOutcome<Node> {
+    /// The node was inserted. Contains the node that was evicted to make
+    /// space for the new one if the `Kbucket` was full.
+    Added(Option<Node>),
+    /// The node was already in the `Kbucket` and was updated.
+    Updated,
+    /// The node can't be added to the `Kbucket`.
+    Rejected,
+}
+
+impl<Node> AddOutcome<Node> {
+    /// Check if the node is in the `Kbucket` after the attempt to add it, i.e.
+    /// it was either inserted or updated.
+    pub fn is_added(&self) -> bool {
+        match *self {
+            AddOutcome::Added(_) | AddOutcome::Updated => true,
+            AddOutcome::Rejected => false,
+        }
+    }
+}
+
 /**
 Structure for holding nodes.
 
@@ -211,6 +234,17 @@ where
     [`PackedNode`]: ../packed_node/struct.PackedNode.html
     */
     pub fn try_add(&mut self, base_pk: &PublicKey, new_node: NewNode, evict: bool) -> bool {
+        self.try_add_detailed(base_pk, new_node, evict).is_added()
+    }
+
+    /**
+    Same as [`try_add`](#method.try_add) but returns [`AddOutcome`] which says
+    whether the node was inserted, updated or rejected and which node was
+    evicted to make space for the new one.
+
+    [`AddOutcome`]: ./enum.AddOutcome.html
+    */
+    pub fn try_add_detailed(&mut self, base_pk: &PublicKey, new_node: NewNode, evict: bool) -> AddOutcome<Node> {
         trace!(target: "Kbucket", "Trying to add PackedNode: {:?}.", new_node.pk());
 
         match self.nodes.binary_search_by(|n| base_pk.distance(&n.pk(), &new_node.pk())) {
@@ -218,7 +252,7 @@ where
                 debug!(target: "Kbucket",
                     "Updated: the node was already in the kbucket.");
                 self.nodes[index].update(&new_node);
-                true
+                AddOutcome::Updated
             },
             Err(index) if !evict || index == self.nodes.len() => {
                 // index is pointing past the end
@@ -230,14 +264,14 @@ where
                             debug!(target: "Kbucket",
                                 "No free space left in the kbucket, the last bad node removed.");
                             // replace the farthest bad node
-                            self.nodes.remove(index);
+                            let evicted = self.nodes.remove(index);
                             self.nodes.push(new_node.into());
-                            true
+                            AddOutcome::Added(Some(evicted))
                         },
                         None => {
                             debug!(target: "Kbucket",
                                 "Node can't be added to the kbucket.");
-                            false
+                            AddOutcome::Rejected
                         },
                     }
                 } else {
@@ -246,20 +280,22 @@ where
                     debug!(target: "Kbucket",
                         "Node inserted inside the kbucket.");
                     self.nodes.insert(index, new_node.into());
-                    true
+                    AddOutcome::Added(None)
                 }
             },
             Err(index) => {
                 // index is pointing inside the list
                 // we are going to evict the farthest node if the kbucket is full
-                if self.is_full() {
+                let evicted = if self.is_full() {
                     debug!(target: "Kbucket",
                         "No free space left in the kbucket, the last node removed.");
-                    self.nodes.pop();
-                }
+                    self.nodes.pop()
+                } else {
+                    None
+                };
                 debug!(target: "Kbucket", "Node inserted inside the kbucket.");
                 self.nodes.insert(index, new_node.into());
-                true
+                AddOutcome::Added(evicted)
             },
         }
     }
@@ -492,6 +528,29 @@ mod tests {
         assert!(kbucket.try_add(&pk, node_2, /* evict */ true));
     }
 
+    #[test]
+    fn kbucket_try_add_detailed() {
+        let pk = PublicKey([0; PUBLICKEYBYTES]);
+        let mut kbucket = Kbucket::<DhtNode>::new(1);
+
+        let node_1 = PackedNode::new(
+            "1.2.3.4:12345".parse().unwrap(),
+            &PublicKey([1; PUBLICKEYBYTES])
+        );
+        let node_2 = PackedNode::new(
+            "1.2.3.4:12346".parse().unwrap(),
+            &PublicKey([2; PUBLICKEYBYTES])
+        );
+
+        assert_eq!(kbucket.try_add_detailed(&pk, node_2, /* evict */ true), AddOutcome::Added(None));
+        assert_eq!(kbucket.try_add_detailed(&pk, node_2, /* evict */ true), AddOutcome::Updated);
+        assert_eq!(kbucket.try_add_detailed(&pk, node_1, /* evict */ false), AddOutcome::Rejected);
+        match kbucket.try_add_detailed(&pk, node_1, /* evict */ true) {
+            AddOutcome::Added(Some(evicted)) => assert_eq!(evicted.pk, node_2.pk),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
     #[test]
     fn kbucket_remove() {
         let pk = PublicKey([0; PUBLICKEYBYTES]);
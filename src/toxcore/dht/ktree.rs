@@ -87,14 +87,24 @@ impl Ktree {
     Returns `true` if node was added successfully, `false` otherwise.
     */
     pub fn try_add(&mut self, node: PackedNode) -> bool {
+        self.try_add_detailed(node).is_added()
+    }
+
+    /** Same as [`try_add`](#method.try_add) but returns [`AddOutcome`]
+    which also contains the node that was evicted from the `Ktree` to make
+    space for the new one.
+
+    [`AddOutcome`]: ../kbucket/enum.AddOutcome.html
+    */
+    pub fn try_add_detailed(&mut self, node: PackedNode) -> AddOutcome<DhtNode> {
         debug!(target: "Ktree", "Trying to add PackedNode.");
         trace!(target: "Ktree", "With PN: {:?}; and self: {:?}", node, self);
 
         match self.kbucket_index(&node.pk) {
-            Some(index) => self.kbuckets[index].try_add(&self.pk, node, /* evict */ false),
+            Some(index) => self.kbuckets[index].try_add_detailed(&self.pk, node, /* evict */ false),
             None => {
                 trace!("Failed to add node: {:?}", node);
-                false
+                AddOutcome::Rejected
             }
         }
     }
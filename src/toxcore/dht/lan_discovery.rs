@@ -1,13 +1,13 @@
 //! Module for LAN discovery.
 
 use std::iter;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::time::{Duration};
 
 use failure::Fail;
 use futures::{stream, StreamExt, SinkExt};
 use futures::channel::mpsc;
-use get_if_addrs::IfAddr;
+use get_if_addrs::{IfAddr, Interface};
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packet::*;
@@ -44,9 +44,79 @@ pub const END_PORT: u16 = 33546;
 /// Interval of time between `LanDiscovery` packet sending.
 pub const LAN_DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
 
+/// IPv6 link-local all-nodes multicast address.
+const IPV6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::Sender<(Packet, SocketAddr)>;
 
+/// Allow and deny lists of network interfaces names used to select interfaces
+/// to send `LanDiscovery` packets to.
+///
+/// An interface is used if it's not in the deny list and either the allow list
+/// is empty or the interface is in the allow list.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterfaceFilter {
+    /// Names of interfaces that are allowed to be used. Empty list means that
+    /// all interfaces are allowed.
+    allow: Vec<String>,
+    /// Names of interfaces that are not allowed to be used.
+    deny: Vec<String>,
+}
+
+impl InterfaceFilter {
+    /// Create new `InterfaceFilter` that allows all interfaces.
+    pub fn new() -> InterfaceFilter {
+        InterfaceFilter::default()
+    }
+
+    /// Add interface to the allow list.
+    pub fn allow<S: Into<String>>(mut self, name: S) -> InterfaceFilter {
+        self.allow.push(name.into());
+        self
+    }
+
+    /// Add interface to the deny list.
+    pub fn deny<S: Into<String>>(mut self, name: S) -> InterfaceFilter {
+        self.deny.push(name.into());
+        self
+    }
+
+    /// Check if the allow list restricts interfaces to use.
+    pub fn is_restricted(&self) -> bool {
+        !self.allow.is_empty()
+    }
+
+    /// Check if interface with this name can be used.
+    pub fn is_allowed(&self, name: &str) -> bool {
+        !self.deny.iter().any(|n| n == name) &&
+            (self.allow.is_empty() || self.allow.iter().any(|n| n == name))
+    }
+}
+
+/// Get index of network interface by its name. It's used as scope id for
+/// link-local IPv6 addresses.
+#[cfg(target_os = "linux")]
+fn interface_index(name: &str) -> Option<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/ifindex", name))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+}
+
+/// Get index of network interface by its name. It's used as scope id for
+/// link-local IPv6 addresses. There is no safe way to get it on this
+/// platform so IPv6 multicast will be sent through the default interface.
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Check if IPv6 address is link-local unicast address, i.e. it's in `fe80::/10`
+/// network.
+fn is_ipv6_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
 /// LAN discovery struct
 pub struct LanDiscoverySender {
     /// Sink to send packet to UDP socket
@@ -57,6 +127,10 @@ pub struct LanDiscoverySender {
     ipv6: bool,
     /// Start port for the next iteration of `LanDiscovery` packets sending
     next_port: u16,
+    /// Port our UDP socket is bound to
+    local_port: Option<u16>,
+    /// Network interfaces to send `LanDiscovery` packets to
+    interface_filter: InterfaceFilter,
 }
 
 impl LanDiscoverySender {
@@ -67,13 +141,44 @@ impl LanDiscoverySender {
             dht_pk,
             ipv6,
             next_port: START_PORT,
+            local_port: None,
+            interface_filter: InterfaceFilter::new(),
         }
     }
 
-    /// Get broadcast addresses for host's network interfaces.
-    fn get_ipv4_broadcast_addrs() -> Vec<IpAddr> {
-        let ifs = get_if_addrs::get_if_addrs().expect("no network interface");
-        ifs
+    /// Set network interfaces to send `LanDiscovery` packets to. If the allow
+    /// list is not empty global broadcast addresses won't be used since they
+    /// are not bound to a particular interface.
+    pub fn set_interface_filter(&mut self, interface_filter: InterfaceFilter) {
+        self.interface_filter = interface_filter;
+    }
+
+    /// Set the port our UDP socket is bound to. Nodes with the same ports
+    /// configuration are likely to be bound to the same port on other hosts
+    /// so `LanDiscovery` packets will be sent to this port every iteration
+    /// like to `DEFAULT_PORT`.
+    pub fn set_local_port(&mut self, port: u16) {
+        self.local_port = Some(port);
+    }
+
+    /// Get host's network interfaces allowed by the filter. Hosts without
+    /// network interfaces are not considered as an error.
+    fn get_interfaces(&self) -> Vec<Interface> {
+        let ifs = match get_if_addrs::get_if_addrs() {
+            Ok(ifs) => ifs,
+            Err(e) => {
+                warn!("Failed to get network interfaces: {}", e);
+                return Vec::new();
+            },
+        };
+        ifs.into_iter()
+            .filter(|interface| self.interface_filter.is_allowed(&interface.name))
+            .collect()
+    }
+
+    /// Get IPv4 broadcast addresses for host's network interfaces.
+    fn get_ipv4_broadcast_addrs(interfaces: &[Interface]) -> Vec<SocketAddr> {
+        interfaces
             .iter()
             .filter_map(|interface|
                 match interface.addr {
@@ -81,23 +186,59 @@ impl LanDiscoverySender {
                     _ => None,
                 }
             )
-            .map(Into::into)
+            .map(|ip| SocketAddr::new(IpAddr::V4(ip), 0))
+            .collect()
+    }
+
+    /// Get IPv6 link-local multicast addresses for host's network interfaces
+    /// that have link-local IPv6 address. Interface index is used as scope id
+    /// so that the packet is sent through this interface. If indices are
+    /// unknown a single address with zero scope id is returned.
+    fn get_ipv6_multicast_addrs(interfaces: &[Interface]) -> Vec<SocketAddr> {
+        let ipv6_interfaces = interfaces
+            .iter()
+            .filter(|interface|
+                match interface.addr {
+                    IfAddr::V6(ref addr) => is_ipv6_link_local(&addr.ip),
+                    _ => false,
+                }
+            )
+            .collect::<Vec<_>>();
+        let mut scope_ids = ipv6_interfaces
+            .iter()
+            .filter_map(|interface| interface_index(&interface.name))
+            .collect::<Vec<_>>();
+        scope_ids.sort();
+        scope_ids.dedup();
+        if scope_ids.is_empty() && !ipv6_interfaces.is_empty() {
+            // we failed to get interfaces indices so let the OS choose the
+            // interface
+            scope_ids.push(0);
+        }
+        scope_ids
+            .into_iter()
+            .map(|scope_id| SocketAddr::V6(SocketAddrV6::new(IPV6_ALL_NODES, 0, 0, scope_id)))
             .collect()
     }
 
-    /// Get broadcast addresses depending on IP version.
-    fn get_broadcast_addrs(&self) -> Vec<IpAddr> {
-        let mut ip_addrs = LanDiscoverySender::get_ipv4_broadcast_addrs();
+    /// Get broadcast addresses depending on IP version. Returned addresses
+    /// have zero port.
+    fn get_broadcast_addrs(&self) -> Vec<SocketAddr> {
+        let interfaces = self.get_interfaces();
+        let mut addrs = LanDiscoverySender::get_ipv4_broadcast_addrs(&interfaces);
         if self.ipv6 {
-            // IPv6 broadcast address
-            ip_addrs.push("FF02::1".parse().unwrap());
-            // IPv4 global broadcast address
-            ip_addrs.push("::ffff:255.255.255.255".parse().unwrap());
-        } else {
+            addrs.extend(LanDiscoverySender::get_ipv6_multicast_addrs(&interfaces));
+        }
+        if !self.interface_filter.is_restricted() {
             // IPv4 global broadcast address
-            ip_addrs.push("255.255.255.255".parse().unwrap());
+            let ip = if self.ipv6 {
+                IpAddr::V6(Ipv4Addr::BROADCAST.to_ipv6_mapped())
+            } else {
+                IpAddr::V4(Ipv4Addr::BROADCAST)
+            };
+            addrs.push(SocketAddr::new(ip, 0));
         }
-        ip_addrs
+        addrs
     }
 
     /// Get broadcast addresses to send `LanDiscovery` packet.
@@ -108,14 +249,21 @@ impl LanDiscoverySender {
         fn cycle(port: u16) -> u16 {
             (port - START_PORT) % (END_PORT - START_PORT) + START_PORT
         }
-        let ip_addrs = self.get_broadcast_addrs();
+        let addrs = self.get_broadcast_addrs();
         // range of ports to send discovery packet to
         let ports_range = (self.next_port .. self.next_port + PORTS_PER_DISCOVERY).map(cycle);
-        // always send discovery packet to default port
-        let ports_range = iter::once(DEFAULT_PORT).chain(ports_range);
-        // add ports to ip addrs
-        let socket_addrs = ip_addrs.into_iter().flat_map(move |ip_addr| {
-            ports_range.clone().map(move |port| SocketAddr::new(ip_addr, port))
+        // always send discovery packet to default port and to our own port
+        // unless it's already in the range
+        let local_port = self.local_port
+            .filter(|&port| port != DEFAULT_PORT && !ports_range.clone().any(|p| p == port));
+        let ports_range = iter::once(DEFAULT_PORT).chain(local_port).chain(ports_range);
+        // add ports to addrs
+        let socket_addrs = addrs.into_iter().flat_map(move |addr| {
+            ports_range.clone().map(move |port| {
+                let mut addr = addr;
+                addr.set_port(port);
+                addr
+            })
         }).collect();
         // update port for next iteration
         self.next_port = cycle(self.next_port + PORTS_PER_DISCOVERY);
@@ -171,6 +319,50 @@ mod tests {
             .count()
     }
 
+    fn multicast_addrs_count() -> usize {
+        let interfaces = get_if_addrs::get_if_addrs().expect("no network interface");
+        LanDiscoverySender::get_ipv6_multicast_addrs(&interfaces).len()
+    }
+
+    #[test]
+    fn interface_filter() {
+        let filter = InterfaceFilter::new();
+        assert!(!filter.is_restricted());
+        assert!(filter.is_allowed("eth0"));
+
+        let filter = InterfaceFilter::new().deny("tun0");
+        assert!(!filter.is_restricted());
+        assert!(filter.is_allowed("eth0"));
+        assert!(!filter.is_allowed("tun0"));
+
+        let filter = InterfaceFilter::new().allow("eth0").allow("tun0").deny("tun0");
+        assert!(filter.is_restricted());
+        assert!(filter.is_allowed("eth0"));
+        assert!(!filter.is_allowed("eth1"));
+        assert!(!filter.is_allowed("tun0"));
+    }
+
+    #[test]
+    fn ipv6_link_local() {
+        assert!(is_ipv6_link_local(&"fe80::1".parse().unwrap()));
+        assert!(is_ipv6_link_local(&"febf::1".parse().unwrap()));
+        assert!(!is_ipv6_link_local(&"fec0::1".parse().unwrap()));
+        assert!(!is_ipv6_link_local(&"::1".parse().unwrap()));
+        assert!(!is_ipv6_link_local(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_multicast_addrs() {
+        let interfaces = get_if_addrs::get_if_addrs().expect("no network interface");
+
+        for addr in LanDiscoverySender::get_ipv6_multicast_addrs(&interfaces) {
+            match addr {
+                SocketAddr::V6(addr) => assert_eq!(*addr.ip(), IPV6_ALL_NODES),
+                SocketAddr::V4(_) => panic!("IPv4 multicast address"),
+            }
+        }
+    }
+
     #[tokio::test]
     async fn send_ipv4() {
         crypto_init().unwrap();
@@ -200,8 +392,8 @@ mod tests {
     #[tokio::test]
     async fn send_ipv6() {
         crypto_init().unwrap();
-        // `+2` for ::1 and ::ffff:255.255.255.255
-        let packets_count = (broadcast_addrs_count() + 2) * (PORTS_PER_DISCOVERY + 1) as usize;
+        // `+1` for ::ffff:255.255.255.255
+        let packets_count = (broadcast_addrs_count() + multicast_addrs_count() + 1) * (PORTS_PER_DISCOVERY + 1) as usize;
 
         let (tx, mut rx) = mpsc::channel(packets_count);
         let (dht_pk, _dht_sk) = gen_keypair();
@@ -223,6 +415,67 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn send_to_local_port() {
+        crypto_init().unwrap();
+        // `+1` for 255.255.255.255
+        let addrs_count = broadcast_addrs_count() + 1;
+        // `+2` for default and local ports
+        let packets_count = addrs_count * (PORTS_PER_DISCOVERY + 2) as usize;
+
+        let (tx, rx) = mpsc::channel(packets_count);
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ false);
+        lan_discovery.set_local_port(END_PORT + 1);
+
+        assert!(lan_discovery.send().await.is_ok());
+
+        drop(lan_discovery);
+
+        let addrs = rx.map(|(_packet, addr)| addr).collect::<Vec<_>>().await;
+        assert_eq!(addrs.len(), packets_count);
+        assert_eq!(addrs.iter().filter(|addr| addr.port() == END_PORT + 1).count(), addrs_count);
+    }
+
+    #[tokio::test]
+    async fn send_no_allowed_interfaces() {
+        crypto_init().unwrap();
+        let (tx, rx) = mpsc::channel(1);
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ true);
+        lan_discovery.set_interface_filter(InterfaceFilter::new().allow("nonexistent interface"));
+
+        assert!(lan_discovery.send().await.is_ok());
+
+        drop(lan_discovery);
+
+        // global broadcast is not sent when interfaces are restricted
+        assert!(rx.collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_denied_interfaces() {
+        crypto_init().unwrap();
+        let packets_count = (PORTS_PER_DISCOVERY + 1) as usize;
+
+        let (tx, rx) = mpsc::channel(packets_count);
+        let (dht_pk, _dht_sk) = gen_keypair();
+        let mut lan_discovery = LanDiscoverySender::new(tx, dht_pk, /* ipv6 */ true);
+        let filter = get_if_addrs::get_if_addrs().expect("no network interface")
+            .into_iter()
+            .fold(InterfaceFilter::new(), |filter, interface| filter.deny(interface.name));
+        lan_discovery.set_interface_filter(filter);
+
+        assert!(lan_discovery.send().await.is_ok());
+
+        drop(lan_discovery);
+
+        let addrs = rx.map(|(_packet, addr)| addr).collect::<Vec<_>>().await;
+        assert_eq!(addrs.len(), packets_count);
+        let global_broadcast = IpAddr::V6(Ipv4Addr::BROADCAST.to_ipv6_mapped());
+        assert!(addrs.iter().all(|addr| addr.ip() == global_broadcast));
+    }
+
     #[tokio::test]
     async fn cycle_around_ports() {
         crypto_init().unwrap();
@@ -15,3 +15,5 @@ pub mod ip_port;
 pub mod request_queue;
 pub mod precomputed_cache;
 pub mod server_ext;
+pub mod udp_socket;
+pub mod bootstrap_nodes;
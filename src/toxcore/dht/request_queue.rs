@@ -74,6 +74,19 @@ impl<T> RequestQueue<T> {
         );
     }
 
+    /// Remove timed out request IDs and return their data.
+    pub fn take_timed_out(&mut self) -> Vec<T> {
+        let timeout = self.timeout;
+        let timed_out_ids = self.ping_map.iter()
+            .filter(|&(_, &(time, _))| clock_elapsed(time) > timeout)
+            .map(|(&ping_id, _)| ping_id)
+            .collect::<Vec<_>>();
+        timed_out_ids.into_iter()
+            .flat_map(|ping_id| self.ping_map.remove(&ping_id))
+            .map(|(_time, data)| data)
+            .collect()
+    }
+
     /// Get not timed out requests stored in this `RequestQueue`.
     pub fn get_values(&self) -> impl Iterator<Item = (Instant, &T)> {
         self.ping_map
@@ -169,6 +182,25 @@ mod tests {
         assert!(queue.ping_map.contains_key(&ping_id_2));
     }
 
+    #[tokio::test]
+    async fn take_timed_out() {
+        crypto_init().unwrap();
+
+        let mut queue = RequestQueue::new(Duration::from_secs(42));
+
+        tokio::time::pause();
+
+        let ping_id_1 = queue.new_ping_id(1);
+        tokio::time::advance(Duration::from_secs(21)).await;
+        let ping_id_2 = queue.new_ping_id(2);
+        tokio::time::advance(Duration::from_secs(43 - 21)).await;
+
+        assert_eq!(queue.take_timed_out(), vec![1]);
+        assert!(queue.take_timed_out().is_empty());
+        assert!(!queue.ping_map.contains_key(&ping_id_1));
+        assert!(queue.ping_map.contains_key(&ping_id_2));
+    }
+
     #[test]
     fn get_values() {
         crypto_init().unwrap();
@@ -0,0 +1,186 @@
+/*!
+Read-only snapshots of the DHT routing table and events about its changes.
+
+Snapshots are detached copies of the server state so they can be inspected
+without holding any locks of the DHT `Server`.
+*/
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::dht_friend::*;
+use crate::toxcore::dht::dht_node::*;
+use crate::toxcore::dht::ktree::*;
+use crate::toxcore::dht::packed_node::*;
+
+/// Snapshot of one of the addresses of a DHT node.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AddrInfo {
+    /// Socket address of the node.
+    pub saddr: SocketAddr,
+    /// Last time when the node responded on this address.
+    pub last_resp_time: Option<Instant>,
+    /// Whether the node doesn't respond on this address for
+    /// `BAD_NODE_TIMEOUT`.
+    pub is_bad: bool,
+    /// Whether the node doesn't respond on this address for
+    /// `KILL_NODE_TIMEOUT`.
+    pub is_discarded: bool,
+    /// Our or friend's address returned by this node.
+    pub ret_saddr: Option<SocketAddr>,
+    /// Last time when the node returned `ret_saddr`.
+    pub ret_last_resp_time: Option<Instant>,
+}
+
+impl AddrInfo {
+    fn new<T: Into<SocketAddr> + Copy>(assoc: &SockAndTime<T>) -> Option<AddrInfo> {
+        assoc.saddr.map(|saddr| AddrInfo {
+            saddr: saddr.into(),
+            last_resp_time: assoc.last_resp_time,
+            is_bad: assoc.is_bad(),
+            is_discarded: assoc.is_discarded(),
+            ret_saddr: assoc.ret_saddr.map(Into::into),
+            ret_last_resp_time: assoc.ret_last_resp_time,
+        })
+    }
+}
+
+/// Snapshot of a `DhtNode`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NodeInfo {
+    /// `PublicKey` of the node.
+    pub pk: PublicKey,
+    /// IPv4 address of the node if it's known.
+    pub addr_v4: Option<AddrInfo>,
+    /// IPv6 address of the node if it's known.
+    pub addr_v6: Option<AddrInfo>,
+    /// Whether the node doesn't respond on both addresses for
+    /// `BAD_NODE_TIMEOUT`.
+    pub is_bad: bool,
+    /// Whether the node doesn't respond on both addresses for
+    /// `KILL_NODE_TIMEOUT`.
+    pub is_discarded: bool,
+    /// Time when the node was added to the list.
+    pub added_time: Instant,
+}
+
+impl From<&DhtNode> for NodeInfo {
+    fn from(node: &DhtNode) -> Self {
+        NodeInfo {
+            pk: node.pk,
+            addr_v4: AddrInfo::new(&node.assoc4),
+            addr_v6: AddrInfo::new(&node.assoc6),
+            is_bad: node.is_bad(),
+            is_discarded: node.is_discarded(),
+            added_time: node.added_time,
+        }
+    }
+}
+
+/// Snapshot of a non-empty `Kbucket` of the close nodes list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KbucketInfo {
+    /// Index of the `Kbucket` in the `Ktree`.
+    pub index: u8,
+    /// Amount of nodes the `Kbucket` can hold.
+    pub capacity: u8,
+    /// Nodes of the `Kbucket` sorted by distance to our DHT `PublicKey`.
+    pub nodes: Vec<NodeInfo>,
+}
+
+/// Snapshot of a `DhtFriend`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FriendInfo {
+    /// Friend's DHT `PublicKey`.
+    pub pk: PublicKey,
+    /// Friend's close nodes sorted by distance to his `PublicKey`.
+    pub close_nodes: Vec<NodeInfo>,
+    /// Whether we know friend's IP address.
+    pub is_addr_known: bool,
+    /// Friend's addresses returned by his close nodes.
+    pub returned_addrs: Vec<SocketAddr>,
+    /// Number of hole punching attempts since the last reset.
+    pub num_punch_tries: u32,
+    /// Time when the last attempt to punch holes was made.
+    pub last_punching_time: Option<Instant>,
+}
+
+impl From<&DhtFriend> for FriendInfo {
+    fn from(friend: &DhtFriend) -> Self {
+        FriendInfo {
+            pk: friend.pk,
+            close_nodes: friend.close_nodes.iter().map(NodeInfo::from).collect(),
+            is_addr_known: friend.is_addr_known(),
+            returned_addrs: friend.get_returned_addrs(),
+            num_punch_tries: friend.hole_punch.num_punch_tries,
+            last_punching_time: friend.hole_punch.last_punching_time,
+        }
+    }
+}
+
+/// Snapshot of the DHT routing table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RoutingInfo {
+    /// Our DHT `PublicKey`.
+    pub pk: PublicKey,
+    /// Non-empty kbuckets of the close nodes list sorted by index.
+    pub kbuckets: Vec<KbucketInfo>,
+    /// Friends we are looking for. Fake friends used for bootstrapping are
+    /// not included.
+    pub friends: Vec<FriendInfo>,
+}
+
+impl RoutingInfo {
+    /// Make snapshot of close nodes list and friends.
+    pub fn new<'a, I>(pk: PublicKey, close_nodes: &Ktree, friends: I) -> Self
+        where I: Iterator<Item = &'a DhtFriend>
+    {
+        let kbuckets = close_nodes.kbuckets.iter()
+            .enumerate()
+            .filter(|(_, kbucket)| !kbucket.is_empty())
+            .map(|(index, kbucket)| KbucketInfo {
+                index: index as u8,
+                capacity: kbucket.capacity,
+                nodes: kbucket.iter().map(NodeInfo::from).collect(),
+            })
+            .collect();
+
+        RoutingInfo {
+            pk,
+            kbuckets,
+            friends: friends.map(FriendInfo::from).collect(),
+        }
+    }
+
+    /// Total number of nodes in the close nodes list.
+    pub fn nodes_count(&self) -> usize {
+        self.kbuckets.iter().map(|kbucket| kbucket.nodes.len()).sum()
+    }
+
+    /// Number of nodes in the close nodes list that are not bad.
+    pub fn good_nodes_count(&self) -> usize {
+        self.kbuckets.iter()
+            .flat_map(|kbucket| kbucket.nodes.iter())
+            .filter(|node| !node.is_bad)
+            .count()
+    }
+}
+
+/// Event that happened with the DHT routing table.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DhtEvent {
+    /// A new node was added to the close nodes list.
+    NodeAdded(PackedNode),
+    /// A node was evicted from the close nodes list to make space for a new
+    /// one or it was discarded because it didn't respond for
+    /// `KILL_NODE_TIMEOUT`.
+    NodeEvicted(PublicKey),
+    /// A node from the close nodes list didn't respond for
+    /// `BAD_NODE_TIMEOUT`.
+    NodeWentBad(PublicKey),
+    /// Friend's IP address was found or changed.
+    FriendAddrFound(PackedNode),
+    /// Friend's IP address was found after a hole punching round.
+    HolePunched(PackedNode),
+}
@@ -4,6 +4,7 @@ This module works on top of other modules.
 */
 
 pub mod hole_punching;
+pub mod info;
 mod errors;
 
 use failure::Fail;
@@ -12,7 +13,7 @@ use futures::future::{Either};
 use futures::channel::mpsc;
 use parking_lot::RwLock;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -33,9 +34,10 @@ use crate::toxcore::ip_port::*;
 use crate::toxcore::dht::dht_friend::*;
 use crate::toxcore::dht::dht_node::*;
 use crate::toxcore::dht::server::hole_punching::*;
+use crate::toxcore::dht::server::info::*;
 use crate::toxcore::tcp::packet::OnionRequest;
 use crate::toxcore::net_crypto::*;
-use crate::toxcore::dht::ip_port::IsGlobal;
+use crate::toxcore::dht::ip_port::{IsGlobal, normalize_saddr};
 use crate::toxcore::utils::*;
 use crate::toxcore::dht::server::errors::*;
 use crate::toxcore::io_tokio::*;
@@ -46,12 +48,17 @@ type Tx = mpsc::Sender<(Packet, SocketAddr)>;
 /// Shorthand for the transmit half of the TCP onion channel.
 type TcpOnionTx = mpsc::Sender<(InnerOnionResponse, SocketAddr)>;
 
+/// Shorthand for the transmit half of the DHT events channel.
+type DhtEventTx = mpsc::UnboundedSender<DhtEvent>;
+
 /// Number of random `NodesRequest` packet to send every second one per second.
 /// After random requests count exceeds this number `NODES_REQ_INTERVAL` will be
 /// used.
 pub const MAX_BOOTSTRAP_TIMES: u32 = 5;
 /// How often onion key should be refreshed.
 pub const ONION_REFRESH_KEY_INTERVAL: Duration = Duration::from_secs(7200);
+/// How often timed out entries should be removed from onion announce list.
+pub const ONION_ANNOUNCE_PRUNE_INTERVAL: Duration = Duration::from_secs(10);
 /// Interval for random `NodesRequest`.
 pub const NODES_REQ_INTERVAL: Duration = Duration::from_secs(20);
 /// Ping timeout in seconds.
@@ -117,6 +124,16 @@ pub struct Server {
     pub tx: Tx,
     /// Sink to send friend's `SocketAddr` when it gets known.
     friend_saddr_sink: Arc<RwLock<Option<mpsc::UnboundedSender<PackedNode>>>>,
+    /// Sink to send events about changes of the routing table.
+    dht_event_sink: Arc<RwLock<Option<DhtEventTx>>>,
+    /// Public keys of nodes from close nodes list that were bad during the
+    /// last main loop iteration. Used to emit `DhtEvent::NodeWentBad` only
+    /// once per node.
+    bad_nodes: Arc<RwLock<HashSet<PublicKey>>>,
+    /// Public keys of nodes from close nodes list that were discarded during
+    /// the last main loop iteration. Used to emit `DhtEvent::NodeEvicted`
+    /// only once per node.
+    discarded_nodes: Arc<RwLock<HashSet<PublicKey>>>,
     /// Struct that stores and manages requests IDs and timeouts.
     request_queue: Arc<RwLock<RequestQueue<PublicKey>>>,
     /// Close nodes list which contains nodes close to own DHT `PublicKey`.
@@ -172,6 +189,10 @@ pub struct Server {
     /// If IPv6 mode is enabled `Server` will send packets to IPv6 addresses. If
     /// it's disabled such packets will be dropped.
     is_ipv6_enabled: bool,
+    /// If TCP-only mode is enabled `Server` never sends UDP packets. It's
+    /// used when UDP socket is not bound at all and we are connected to the
+    /// network only via TCP relays.
+    tcp_only: bool,
     /// Initial bootstrap nodes list. We send `NodesRequest` packet to each node
     /// from this list if Ktree doesn't have good (or bad but not discarded)
     /// nodes.
@@ -210,6 +231,9 @@ impl Server {
             pk,
             tx,
             friend_saddr_sink: Default::default(),
+            dht_event_sink: Default::default(),
+            bad_nodes: Default::default(),
+            discarded_nodes: Default::default(),
             request_queue: Arc::new(RwLock::new(RequestQueue::new(PING_TIMEOUT))),
             close_nodes: Arc::new(RwLock::new(Ktree::new(&pk))),
             onion_symmetric_key: Arc::new(RwLock::new(secretbox::gen_key())),
@@ -226,12 +250,15 @@ impl Server {
             onion_client: None,
             lan_discovery_enabled: true,
             is_ipv6_enabled: false,
+            tcp_only: false,
             initial_bootstrap: Vec::new(),
             precomputed_keys,
         }
     }
 
-    /// Enable/disable IPv6 mode of DHT server.
+    /// Enable/disable IPv6 mode of DHT server. In IPv6 mode the server is
+    /// expected to run on a dual-stack `[::]` socket and talks to both IPv4
+    /// and IPv6 nodes.
     pub fn enable_ipv6_mode(&mut self, enable: bool) {
         self.is_ipv6_enabled = enable;
     }
@@ -246,6 +273,20 @@ impl Server {
         self.lan_discovery_enabled = enable;
     }
 
+    /// Enable/disable TCP-only mode of DHT server. In this mode no UDP
+    /// packets are sent so both bootstrapping and LAN discovery are disabled.
+    pub fn enable_tcp_only(&mut self, enable: bool) {
+        self.tcp_only = enable;
+        if enable {
+            self.lan_discovery_enabled = false;
+        }
+    }
+
+    /// Check if TCP-only mode is enabled.
+    pub fn is_tcp_only(&self) -> bool {
+        self.tcp_only
+    }
+
     /// Check if we have at least one node in good state.
     pub fn is_connected(&self) -> bool {
         self.close_nodes.read()
@@ -333,6 +374,9 @@ impl Server {
 
         request_queue.clear_timed_out();
 
+        self.check_bad_nodes(&close_nodes);
+        self.check_discarded_nodes(&close_nodes);
+
         // Send NodesRequest packets to nodes from the Server
         let ping_nodes_to_bootstrap = self.ping_nodes_to_bootstrap(&mut request_queue, &mut nodes_to_bootstrap, self.pk);
         let ping_close_nodes = self.ping_close_nodes(&mut request_queue, close_nodes.iter_mut(), self.pk);
@@ -377,18 +421,64 @@ impl Server {
         }
     }
 
+    /// Send `DhtEvent::NodeWentBad` for every node from close nodes list that
+    /// became bad since the last check.
+    fn check_bad_nodes(&self, close_nodes: &Ktree) {
+        let mut bad_nodes = self.bad_nodes.write();
+
+        let new_bad_nodes = close_nodes.iter()
+            .filter(|node| node.is_bad())
+            .map(|node| node.pk)
+            .collect::<HashSet<_>>();
+
+        for &pk in new_bad_nodes.difference(&bad_nodes) {
+            self.send_event(DhtEvent::NodeWentBad(pk));
+        }
+
+        *bad_nodes = new_bad_nodes;
+    }
+
+    /// Send `DhtEvent::NodeEvicted` for every node from close nodes list that
+    /// was discarded since the last check. Discarded nodes are not used
+    /// anymore but they are kept in the list to bootstrap from them when we
+    /// lose all other nodes.
+    fn check_discarded_nodes(&self, close_nodes: &Ktree) {
+        let mut discarded_nodes = self.discarded_nodes.write();
+
+        let new_discarded_nodes = close_nodes.iter()
+            .filter(|node| node.is_discarded())
+            .map(|node| node.pk)
+            .collect::<HashSet<_>>();
+
+        for &pk in new_discarded_nodes.difference(&discarded_nodes) {
+            self.send_event(DhtEvent::NodeEvicted(pk));
+        }
+
+        *discarded_nodes = new_discarded_nodes;
+    }
+
+    /// Send event to `dht_event_sink` if it's set.
+    fn send_event(&self, event: DhtEvent) {
+        if let Some(ref tx) = *self.dht_event_sink.read() {
+            if tx.unbounded_send(event).is_err() {
+                trace!("DHT events receiver is dropped");
+            }
+        }
+    }
+
     /// Run DHT periodical tasks. Result future will never be completed
     /// successfully.
     pub fn run(self) -> impl Future<Output = Result<(), RunError>> + Send {
         async {
-            let (r1, r2, r3, r4) = futures::join!(
+            let (r1, r2, r3, r4, r5) = futures::join!(
                 self.clone().run_pings_sending(),
                 self.clone().run_onion_key_refreshing(),
+                self.clone().run_onion_announce_pruning(),
                 self.clone().run_main_loop(),
                 self.run_bootstrap_requests_sending(),
             );
 
-            r1?; r2?; r3?; r4?;
+            r1?; r2?; r3?; r4?; r5?;
 
             Ok(())
         }
@@ -439,6 +529,10 @@ impl Server {
     /// it's empty) and if so then send `NodesRequest` packet to nodes from
     /// initial bootstrap list and from Ktree.
     fn send_bootstrap_requests(&self) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
+        if self.tcp_only {
+            return Either::Left(future::ok(()));
+        }
+
         let mut request_queue = self.request_queue.write();
         let close_nodes = self.close_nodes.read();
 
@@ -503,6 +597,21 @@ impl Server {
         }
     }
 
+    /// Remove timed out entries from onion announce list periodically. Result
+    /// future will never be completed successfully.
+    fn run_onion_announce_pruning(self) -> impl Future<Output = Result<(), RunError>> + Send {
+        let interval = ONION_ANNOUNCE_PRUNE_INTERVAL;
+        let mut wakeups = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+        async move {
+            loop {
+                wakeups.tick().await;
+                let removed = self.onion_announce.write().remove_timed_out();
+                trace!("Removed {} timed out onion announce entries", removed);
+            }
+        }
+    }
+
     /// Run ping sending periodically. Result future will never be completed
     /// successfully.
     fn run_pings_sending(self) -> impl Future<Output = Result<(), RunError>> + Send {
@@ -726,8 +835,12 @@ impl Server {
             (packet, addr)
         }).collect::<Vec<_>>();
 
+        let tcp_only = self.tcp_only;
         let mut tx = self.tx.clone();
         async move {
+            if tcp_only {
+                return Ok(())
+            }
             let mut stream = futures::stream::iter(packets).map(Ok);
             tx.send_all(&mut stream).await
         }
@@ -748,7 +861,10 @@ impl Server {
     }
 
     /// Function to handle incoming packets and send responses if necessary.
+    /// IPv4-mapped addresses received from dual-stack socket are handled as
+    /// IPv4 addresses.
     pub fn handle_packet(&self, packet: Packet, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let addr = normalize_saddr(addr);
         match packet {
             Packet::PingRequest(packet) =>
                 self.handle_ping_req(&packet, addr).boxed(),
@@ -799,9 +915,14 @@ impl Server {
     fn send_to(&self, addr: SocketAddr, packet: Packet)
         -> impl Future<Output = Result<(), mpsc::SendError>> + Send
     {
+        let tcp_only = self.tcp_only;
         let mut tx = self.tx.clone();
 
         async move {
+            if tcp_only {
+                trace!("Dropping UDP packet to {} in TCP-only mode", addr);
+                return Ok(())
+            }
             tx.send((packet, addr)).await
         }
     }
@@ -841,9 +962,38 @@ impl Server {
     /// Add node to close list after we received a response from it. If it's a
     /// friend then send it's IP address to appropriate sink.
     fn try_add_to_close(&self, close_nodes: &mut Ktree, friends: &mut HashMap<PublicKey, DhtFriend>, node: PackedNode) -> impl Future<Output = Result<(), HandlePacketError>> {
-        close_nodes.try_add(node);
+        if let AddOutcome::Added(evicted) = close_nodes.try_add_detailed(node) {
+            if let Some(evicted) = evicted {
+                // discarded nodes were already reported by the main loop
+                if !self.discarded_nodes.write().remove(&evicted.pk) {
+                    self.send_event(DhtEvent::NodeEvicted(evicted.pk));
+                }
+            }
+            self.send_event(DhtEvent::NodeAdded(node));
+        }
         for friend in friends.values_mut() {
+            if friend.pk != node.pk {
+                friend.try_add_to_close(node);
+                continue;
+            }
+
+            let is_new_addr = match friend.close_nodes.get_node(&friend.pk, &node.pk) {
+                Some(friend_node) => friend_node.is_outdated(&node),
+                None => true,
+            };
             friend.try_add_to_close(node);
+            if is_new_addr && friend.is_addr_known() {
+                self.send_event(DhtEvent::FriendAddrFound(node));
+                // We consider the hole punched if the address was found
+                // shortly after the last hole punching round
+                let is_punching = match friend.hole_punch.last_punching_time {
+                    Some(time) => clock_elapsed(time) <= PUNCH_INTERVAL * 2,
+                    None => false,
+                };
+                if is_punching {
+                    self.send_event(DhtEvent::HolePunched(node));
+                }
+            }
         }
         if friends.contains_key(&node.pk) {
             let sink = self.friend_saddr_sink.read().clone();
@@ -1532,6 +1682,18 @@ impl Server {
         nodes
     }
 
+    /// Get addresses of a friend returned by its close nodes. They may differ
+    /// from each other if the friend is behind NAT. These addresses are also
+    /// used for hole punching.
+    pub fn get_friend_returned_addrs(&self, friend_pk: &PublicKey) -> Vec<SocketAddr> {
+        let mut addrs = self.friends.read()
+            .get(friend_pk)
+            .map_or_else(Vec::new, DhtFriend::get_returned_addrs);
+        addrs.sort();
+        addrs.dedup();
+        addrs
+    }
+
     /// Set toxcore version and message of the day callback.
     pub fn set_bootstrap_info(&mut self, version: u32, motd_cb: Box<dyn Fn(&Server) -> Vec<u8> + Send + Sync>) {
         self.bootstrap_info = Some(ServerBootstrapInfo {
@@ -1560,6 +1722,45 @@ impl Server {
         *self.friend_saddr_sink.write() = Some(friend_saddr_sink);
     }
 
+    /// Set sink to send events about changes of the routing table.
+    pub fn set_dht_event_sink(&self, dht_event_sink: DhtEventTx) {
+        *self.dht_event_sink.write() = Some(dht_event_sink);
+    }
+
+    /// Get how long a node is kept in the close nodes list. Returns `None` if
+    /// there is no such node.
+    pub fn node_uptime(&self, pk: &PublicKey) -> Option<Duration> {
+        self.close_nodes.read().get_node(pk).map(DhtNode::uptime)
+    }
+
+    /// Get a snapshot of close nodes list and friends' close nodes lists.
+    pub fn routing_info(&self) -> RoutingInfo {
+        let close_nodes = self.close_nodes.read();
+        let friends = self.friends.read();
+
+        let friends = friends.values()
+            .filter(|friend| !self.fake_friends_keys.contains(&friend.pk));
+
+        RoutingInfo::new(self.pk, &close_nodes, friends)
+    }
+
+    /// Change settings of onion announce list.
+    pub fn set_onion_announce_config(&self, config: OnionAnnounceConfig) {
+        self.onion_announce.write().set_config(config);
+    }
+
+    /// Get a persistable copy of onion announce list that can be restored
+    /// after restart with `restore_onion_announce`.
+    pub fn onion_announce_snapshot(&self) -> OnionAnnounceSnapshot {
+        self.onion_announce.read().snapshot()
+    }
+
+    /// Restore onion announce list from a snapshot made with
+    /// `onion_announce_snapshot`.
+    pub fn restore_onion_announce(&self, snapshot: OnionAnnounceSnapshot) {
+        self.onion_announce.write().restore(snapshot);
+    }
+
     /// Get `PrecomputedKey`s cache.
     pub fn get_precomputed_keys(&self) -> PrecomputedCache {
         self.precomputed_keys.clone()
@@ -3224,6 +3425,28 @@ mod tests {
         assert!(rx.collect::<Vec<_>>().await.is_empty());
     }
 
+    #[tokio::test]
+    async fn tcp_only() {
+        let (mut alice, _precomp, bob_pk, _bob_sk, rx, _addr) = create_node();
+
+        alice.enable_tcp_only(true);
+        assert!(alice.is_tcp_only());
+        assert!(!alice.lan_discovery_enabled);
+
+        let pn = PackedNode::new("127.1.1.1:12345".parse().unwrap(), &bob_pk);
+        alice.add_initial_bootstrap(pn);
+        alice.send_bootstrap_requests().await.unwrap();
+
+        let pn = PackedNode::new("127.1.1.2:12345".parse().unwrap(), &gen_keypair().0);
+        assert!(alice.close_nodes.write().try_add(pn));
+        alice.dht_main_loop().await.unwrap();
+
+        // Necessary to drop tx so that rx.collect::<Vec<_>>() can be finished
+        drop(alice);
+
+        assert!(rx.collect::<Vec<_>>().await.is_empty());
+    }
+
     #[tokio::test]
     async fn handle_lan_discovery_when_disabled() {
         let (mut alice, _precomp, _bob_pk, _bob_sk, rx, addr) = create_node();
@@ -3787,6 +4010,35 @@ mod tests {
         assert!(!nodes.contains(&node));
     }
 
+    #[test]
+    fn get_friend_returned_addrs() {
+        let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
+
+        let friend_pk = gen_keypair().0;
+        alice.add_friend(friend_pk);
+
+        let nat_addr = "192.168.2.1:12345".parse().unwrap();
+        let another_nat_addr = "192.168.2.1:23456".parse().unwrap();
+        let returned_addrs = [nat_addr, another_nat_addr, nat_addr];
+
+        let mut friends = alice.friends.write();
+        let friend = friends.get_mut(&friend_pk).unwrap();
+        for (i, &addr) in returned_addrs.iter().enumerate() {
+            let node = PackedNode::new(SocketAddr::new("192.168.1.1".parse().unwrap(), 12345 + i as u16), &gen_keypair().0);
+            assert!(friend.try_add_to_close(node));
+            friend.close_nodes.get_node_mut(&friend_pk, &node.pk).unwrap().update_returned_addr(addr);
+        }
+        drop(friends);
+
+        // addresses are deduplicated
+        let mut expected = vec![nat_addr, another_nat_addr];
+        expected.sort();
+        assert_eq!(alice.get_friend_returned_addrs(&friend_pk), expected);
+
+        // unknown friend has no addresses
+        assert!(alice.get_friend_returned_addrs(&gen_keypair().0).is_empty());
+    }
+
     #[test]
     fn is_connected() {
         let (alice, _precomp, _bob_pk, _bob_sk, _rx, _addr) = create_node();
@@ -3795,4 +4047,96 @@ mod tests {
         alice.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
         assert!(alice.is_connected());
     }
+
+    #[test]
+    fn routing_info() {
+        let (alice, _precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        alice.add_friend(bob_pk);
+        alice.add_node(PackedNode::new(addr, &bob_pk));
+        assert!(alice.friends.write().get_mut(&bob_pk).unwrap().try_add_to_close(PackedNode::new(addr, &bob_pk)));
+
+        let info = alice.routing_info();
+
+        assert_eq!(info.pk, alice.pk);
+        assert_eq!(info.nodes_count(), 1);
+        assert_eq!(info.good_nodes_count(), 1);
+        let node = &info.kbuckets[0].nodes[0];
+        assert_eq!(node.pk, bob_pk);
+        assert_eq!(node.addr_v4.as_ref().unwrap().saddr, addr);
+        assert!(node.addr_v6.is_none());
+        // fake friends should not be in the snapshot
+        assert_eq!(info.friends.len(), 1);
+        assert_eq!(info.friends[0].pk, bob_pk);
+        assert!(info.friends[0].is_addr_known);
+    }
+
+    #[tokio::test]
+    async fn dht_events() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let (dht_event_tx, dht_event_rx) = mpsc::unbounded();
+        alice.set_dht_event_sink(dht_event_tx);
+
+        alice.add_friend(bob_pk);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).await.unwrap();
+
+        tokio::time::pause();
+        tokio::time::advance(BAD_NODE_TIMEOUT + Duration::from_secs(1)).await;
+
+        alice.check_bad_nodes(&alice.close_nodes.read());
+        // should be sent only once
+        alice.check_bad_nodes(&alice.close_nodes.read());
+
+        drop(alice);
+
+        let node = PackedNode::new(addr, &bob_pk);
+        let events = dht_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            DhtEvent::NodeAdded(node),
+            DhtEvent::FriendAddrFound(node),
+            DhtEvent::NodeWentBad(bob_pk),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn dht_main_loop_node_discarded() {
+        let (alice, precomp, bob_pk, _bob_sk, _rx, addr) = create_node();
+
+        let (dht_event_tx, dht_event_rx) = mpsc::unbounded();
+        alice.set_dht_event_sink(dht_event_tx);
+
+        let ping_id = alice.request_queue.write().new_ping_id(bob_pk);
+
+        let resp_payload = PingResponsePayload { id: ping_id };
+        let ping_resp = Packet::PingResponse(PingResponse::new(&precomp, &bob_pk, &resp_payload));
+
+        alice.handle_packet(ping_resp, addr).await.unwrap();
+
+        tokio::time::pause();
+        tokio::time::advance(KILL_NODE_TIMEOUT + Duration::from_secs(1)).await;
+
+        alice.dht_main_loop().await.unwrap();
+        // should be sent only once
+        alice.dht_main_loop().await.unwrap();
+
+        // discarded node is kept to bootstrap from it
+        assert!(alice.close_nodes.read().contains(&bob_pk));
+
+        drop(alice);
+
+        let node = PackedNode::new(addr, &bob_pk);
+        let events = dht_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            DhtEvent::NodeAdded(node),
+            DhtEvent::NodeWentBad(bob_pk),
+            DhtEvent::NodeEvicted(bob_pk),
+        ]);
+    }
 }
@@ -176,4 +176,79 @@ mod tests {
             res = server_future.fuse() => ()
         };
     }
+
+    /// Send `PingRequest` from a new socket bound to `client_addr` and check
+    /// that `PingResponse` is received.
+    async fn ping_server(server_addr: SocketAddr, server_pk: PublicKey, client_addr: SocketAddr) {
+        let (client_pk, client_sk) = gen_keypair();
+        let shared_secret = precompute(&server_pk, &client_sk);
+
+        let client_socket = UdpSocket::bind(&client_addr).await.unwrap();
+
+        let stats = Stats::new();
+        let codec = DhtCodec::new(stats);
+        let (mut sink, stream) = tokio_util::udp::UdpFramed::new(client_socket, codec).split();
+
+        let ping_id = 42;
+        let ping_request_payload = PingRequestPayload {
+            id: ping_id,
+        };
+        let ping_request = PingRequest::new(&shared_secret, &client_pk, &ping_request_payload);
+
+        sink.send((Packet::PingRequest(ping_request), server_addr)).await.unwrap();
+
+        let (ping_response, addr) = stream
+            .try_filter_map(|(packet, addr)| futures::future::ok(
+                match packet {
+                    Packet::PingResponse(ping_response) => Some((ping_response, addr)),
+                    _ => None,
+                }
+            ))
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        let ping_response_payload = ping_response.get_payload(&shared_secret).unwrap();
+
+        assert_eq!(addr, server_addr);
+        assert_eq!(ping_response_payload.id, ping_id);
+    }
+
+    #[tokio::test]
+    async fn run_dual_stack_socket() {
+        crypto_init().unwrap();
+        let (server_pk, server_sk) = gen_keypair();
+
+        let (tx, rx) = mpsc::channel(32);
+
+        let mut server = Server::new(tx, server_pk, server_sk);
+        server.enable_ipv6_mode(true);
+
+        let server_addr: SocketAddr = "[::]:0".parse().unwrap();
+        let server_socket = UdpSocket::bind(&server_addr).await.unwrap();
+        let server_port = server_socket.local_addr().unwrap().port();
+
+        let stats = Stats::new();
+        let server_future = server.run_socket(server_socket, rx, stats);
+
+        let client_future = async {
+            // IPv4-only peer
+            ping_server(
+                SocketAddr::new("127.0.0.1".parse().unwrap(), server_port),
+                server_pk,
+                "127.0.0.1:0".parse().unwrap(),
+            ).await;
+            // IPv6-only peer
+            ping_server(
+                SocketAddr::new("::1".parse().unwrap(), server_port),
+                server_pk,
+                "[::1]:0".parse().unwrap(),
+            ).await;
+        };
+
+        futures::select! {
+            () = client_future.fuse() => (),
+            res = server_future.fuse() => panic!("Server stopped: {:?}", res),
+        };
+    }
 }
@@ -0,0 +1,207 @@
+/*!
+Binding of the DHT UDP socket.
+
+Like c-toxcore we try ports from a range one by one until we find a free one.
+This allows to run several nodes on the same host without any configuration.
+Other nodes on the same host or in the same LAN will find them with
+`LanDiscovery` packets since [`LanDiscoverySender`] sends them to the same
+range of ports.
+
+[`LanDiscoverySender`]: ../lan_discovery/struct.LanDiscoverySender.html
+*/
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use failure::Fail;
+use tokio::net::UdpSocket;
+
+use crate::toxcore::dht::lan_discovery::{DEFAULT_PORT, END_PORT};
+
+error_kind! {
+    #[doc = "Error that can happen when binding UDP socket."]
+    #[derive(Debug)]
+    BindError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, Fail)]
+    BindErrorKind {
+        #[doc = "All ports from the range are in use."]
+        #[fail(display = "All ports from the range are in use")]
+        NoFreePort,
+        #[doc = "Binding failed with an error not related to busy port."]
+        #[fail(display = "Failed to bind UDP socket")]
+        Bind,
+        #[doc = "Failed to set socket options."]
+        #[fail(display = "Failed to set socket options")]
+        SetOption,
+    }
+}
+
+/// First port of the default range that c-toxcore uses.
+pub const DEFAULT_PORT_RANGE_START: u16 = DEFAULT_PORT;
+
+/// Last port of the default range that c-toxcore uses.
+pub const DEFAULT_PORT_RANGE_END: u16 = END_PORT - 1;
+
+/// IP version of the DHT UDP socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpMode {
+    /// Socket bound to `0.0.0.0` which handles only IPv4 traffic.
+    V4,
+    /// Socket bound to `[::]` which handles IPv6 traffic and IPv4 traffic as
+    /// IPv4-mapped addresses. It relies on the OS default for `IPV6_V6ONLY`
+    /// option which is disabled on most systems.
+    DualStack,
+}
+
+impl IpMode {
+    /// Unspecified IP address to bind to.
+    fn unspecified_ip(self) -> IpAddr {
+        match self {
+            IpMode::V4 => Ipv4Addr::UNSPECIFIED.into(),
+            IpMode::DualStack => Ipv6Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    /// Whether socket bound in this mode is IPv6.
+    pub fn is_ipv6(self) -> bool {
+        self == IpMode::DualStack
+    }
+}
+
+/// Inclusive range of ports to try when binding UDP socket.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortRange {
+    /// First port to try.
+    pub start: u16,
+    /// Last port to try.
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Create new `PortRange`. Ports are swapped if `start` is greater than
+    /// `end`.
+    pub fn new(start: u16, end: u16) -> PortRange {
+        if start <= end {
+            PortRange { start, end }
+        } else {
+            PortRange { start: end, end: start }
+        }
+    }
+
+    /// Create `PortRange` that consists of a single port.
+    pub fn single(port: u16) -> PortRange {
+        PortRange::new(port, port)
+    }
+
+    /// Iterate over ports of the range.
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start ..= self.end
+    }
+}
+
+impl Default for PortRange {
+    fn default() -> Self {
+        PortRange::new(DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)
+    }
+}
+
+/// Check if the error means that the port is already taken by other socket.
+fn is_addr_in_use(e: &IoError) -> bool {
+    match e.kind() {
+        IoErrorKind::AddrInUse => true,
+        // Windows returns `PermissionDenied` when port is used by other
+        // socket with `SO_EXCLUSIVEADDRUSE` option
+        IoErrorKind::PermissionDenied => cfg!(windows),
+        _ => false,
+    }
+}
+
+/// Bind UDP socket to the first free port from the range. The socket is set
+/// up to send `LanDiscovery` packets, i.e. broadcasting is enabled and
+/// multicast packets are looped back in dual-stack mode.
+///
+/// Use `socket.local_addr()` to get the chosen port and pass it to
+/// `LanDiscoverySender::set_local_port`.
+pub async fn bind_udp(ip_mode: IpMode, port_range: PortRange) -> Result<UdpSocket, BindError> {
+    let ip = ip_mode.unspecified_ip();
+
+    for port in port_range.iter() {
+        let addr = SocketAddr::new(ip, port);
+        let socket = match UdpSocket::bind(&addr).await {
+            Ok(socket) => socket,
+            Err(ref e) if is_addr_in_use(e) => {
+                trace!("Port {} is already in use", port);
+                continue;
+            },
+            Err(e) => return Err(e.context(BindErrorKind::Bind).into()),
+        };
+
+        socket.set_broadcast(true)
+            .map_err(|e| e.context(BindErrorKind::SetOption))?;
+        if ip_mode.is_ipv6() {
+            socket.set_multicast_loop_v6(true)
+                .map_err(|e| e.context(BindErrorKind::SetOption))?;
+        }
+
+        debug!("Bound UDP socket to {}", addr);
+
+        return Ok(socket);
+    }
+
+    Err(BindErrorKind::NoFreePort.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_range_new() {
+        assert_eq!(PortRange::new(2, 1), PortRange { start: 1, end: 2 });
+        assert_eq!(PortRange::single(1).iter().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn port_range_default() {
+        let range = PortRange::default();
+        assert_eq!(range.start, 33445);
+        assert_eq!(range.end, 33545);
+    }
+
+    /// Bind a socket to a port chosen by OS such that the next port is free.
+    async fn bind_taken_port() -> (UdpSocket, u16) {
+        loop {
+            let taken = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+            let taken_port = taken.local_addr().unwrap().port();
+            if taken_port == std::u16::MAX {
+                continue;
+            }
+            let next_addr = SocketAddr::new("0.0.0.0".parse().unwrap(), taken_port + 1);
+            // the probe socket is dropped right away to free the port
+            if UdpSocket::bind(&next_addr).await.is_ok() {
+                return (taken, taken_port);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_udp_fallback() {
+        let (_taken, taken_port) = bind_taken_port().await;
+        let range = PortRange::new(taken_port, taken_port + 1);
+
+        let socket = bind_udp(IpMode::V4, range).await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        assert_eq!(port, taken_port + 1);
+    }
+
+    #[tokio::test]
+    async fn bind_udp_no_free_port() {
+        let taken = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+
+        let error = bind_udp(IpMode::V4, PortRange::single(taken_port)).await.err().unwrap();
+        assert_eq!(*error.kind(), BindErrorKind::NoFreePort);
+    }
+}
@@ -40,9 +40,9 @@ error_kind! {
         #[doc = "Failed to add TCP connection."]
         #[fail(display = "Failed to TCP connection")]
         AddTcpConnection,
-        #[doc = "Failed to send connection status."]
-        #[fail(display = "Failed to send connection status")]
-        SendToConnectionStatus
+        #[doc = "Failed to send connection event."]
+        #[fail(display = "Failed to send connection event")]
+        SendToConnectionEvent
     }
 }
 
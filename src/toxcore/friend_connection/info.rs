@@ -0,0 +1,74 @@
+/*!
+Read-only snapshots of friends and events about their lifecycle.
+
+Snapshots are detached copies of the friend connections state so they can be
+inspected without holding any locks of `FriendConnections`.
+*/
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::net_crypto::ConnectionEvent;
+use super::Friend;
+
+/// Snapshot of a friend we want to be connected to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FriendInfo {
+    /// Friend's long term `PublicKey`.
+    pub real_pk: PublicKey,
+    /// Friend's DHT `PublicKey` when it's known.
+    pub dht_pk: Option<PublicKey>,
+    /// Friend's IP address when it's known.
+    pub saddr: Option<SocketAddr>,
+    /// Whether we connected to this friend.
+    pub connected: bool,
+    /// Time when we received the last ping packet from the friend.
+    pub last_seen: Option<Instant>,
+    /// Time when we sent the last `ShareRelays` packet to the friend.
+    pub relays_shared_time: Option<Instant>,
+    /// Not expired relays received from the friend.
+    pub shared_relays: Vec<PackedNode>,
+}
+
+impl FriendInfo {
+    /// Create snapshot of the friend.
+    pub(super) fn new(friend: &Friend) -> Self {
+        FriendInfo {
+            real_pk: friend.real_pk,
+            dht_pk: friend.dht_pk,
+            saddr: friend.saddr,
+            connected: friend.connected,
+            last_seen: friend.ping_received_time,
+            relays_shared_time: friend.share_relays_time,
+            shared_relays: friend.shared_relays.iter()
+                .filter(|relay| !relay.is_expired())
+                .map(|relay| relay.node)
+                .collect(),
+        }
+    }
+}
+
+/// Event that happened with a friend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FriendEvent {
+    /// The friend was added to the list of friends we want to be connected
+    /// to.
+    Added,
+    /// The friend was removed and all connections with the friend were
+    /// dropped.
+    Removed,
+    /// Friend's DHT `PublicKey` was found or changed.
+    DhtPkFound(PublicKey),
+    /// Friend's IP address was found or changed.
+    AddrFound(SocketAddr),
+    /// We sent `ShareRelays` packet with these relays to the friend.
+    RelaysShared(Vec<PackedNode>),
+    /// We didn't receive ping packets from the friend for
+    /// `FRIEND_CONNECTION_TIMEOUT` so the connection is being killed.
+    PingTimeout,
+    /// Connection with the friend was established, lost or changed its
+    /// transport.
+    Connection(ConnectionEvent),
+}
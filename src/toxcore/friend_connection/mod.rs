@@ -2,6 +2,7 @@
 */
 
 pub mod errors;
+pub mod info;
 pub mod packet;
 
 use std::collections::HashMap;
@@ -22,17 +23,21 @@ use crate::toxcore::dht::dht_node::BAD_NODE_TIMEOUT;
 use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::friend_connection::errors::*;
+use crate::toxcore::friend_connection::info::*;
 use crate::toxcore::friend_connection::packet::*;
-use crate::toxcore::net_crypto::NetCrypto;
+use crate::toxcore::net_crypto::{NetCrypto, ConnectionEvent, DisconnectReason};
 use crate::toxcore::net_crypto::errors::KillConnectionErrorKind;
 use crate::toxcore::onion::client::OnionClient;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
 use crate::toxcore::time::*;
 
-/// Shorthand for the transmit half of the message channel for sending a
-/// connection status when it becomes connected or disconnected. The key is a
-/// long term key of the connection.
-type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
+/// Shorthand for the transmit half of the message channel for sending
+/// connection events. The key is a long term key of the connection.
+type ConnectionEventTx = mpsc::UnboundedSender<(PublicKey, ConnectionEvent)>;
+
+/// Shorthand for the transmit half of the message channel for sending friend
+/// events. The key is a long term key of the friend.
+type FriendEventTx = mpsc::UnboundedSender<(PublicKey, FriendEvent)>;
 
 /// How often we should send ping packets to a friend.
 const FRIEND_PING_INTERVAL: Duration = Duration::from_secs(8);
@@ -44,6 +49,21 @@ const FRIEND_CONNECTION_TIMEOUT: Duration = Duration::from_secs(FRIEND_PING_INTE
 /// How often we should send `ShareRelays` packet to a friend.
 const SHARE_RELAYS_INTERVAL: Duration = Duration::from_secs(300);
 
+/// How often we should try to send `ShareRelays` packet to a friend again if
+/// the previous attempt failed.
+const SHARE_RELAYS_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Relays received from a friend are considered expired if the friend didn't
+/// share them again for this amount of time.
+const SHARED_RELAYS_TIMEOUT: Duration = Duration::from_secs(SHARE_RELAYS_INTERVAL.as_secs() * 2);
+
+/// Maximum number of relays received from a friend we remember.
+const MAX_SHARED_RELAYS_PER_FRIEND: usize = MAX_SHARED_RELAYS * 2;
+
+/// How often we should try to upgrade connection to a friend established via
+/// TCP relays to direct UDP.
+const UDP_UPGRADE_INTERVAL: Duration = Duration::from_secs(10);
+
 /// How often the main loop should be called.
 const MAIN_LOOP_INTERVAL: Duration = Duration::from_secs(1);
 
@@ -51,6 +71,23 @@ const MAIN_LOOP_INTERVAL: Duration = Duration::from_secs(1);
 /// address will be considered timed out.
 const FRIEND_DHT_TIMEOUT: Duration = BAD_NODE_TIMEOUT;
 
+/// TCP relay received from a friend in `ShareRelays` packet.
+#[derive(Clone, Debug)]
+struct SharedRelay {
+    /// Relay's address and `PublicKey`.
+    node: PackedNode,
+    /// Time when we received this relay last time.
+    received_time: Instant,
+}
+
+impl SharedRelay {
+    /// Check if the friend didn't share this relay for
+    /// `SHARED_RELAYS_TIMEOUT`.
+    fn is_expired(&self) -> bool {
+        clock_elapsed(self.received_time) >= SHARED_RELAYS_TIMEOUT
+    }
+}
+
 /// Friend related data stored in the friend connections module.
 #[derive(Clone, Debug)]
 struct Friend {
@@ -72,6 +109,13 @@ struct Friend {
     ping_received_time: Option<Instant>,
     /// Time when we sent the last `ShareRelays` packet.
     share_relays_time: Option<Instant>,
+    /// Time when we tried to send `ShareRelays` packet last time.
+    share_relays_attempt_time: Option<Instant>,
+    /// Relays received from the friend. They are used to connect to the
+    /// friend again when its DHT `PublicKey` changes.
+    shared_relays: Vec<SharedRelay>,
+    /// Time when we tried to upgrade the connection to direct UDP.
+    udp_upgrade_time: Option<Instant>,
 }
 
 impl Friend {
@@ -86,7 +130,26 @@ impl Friend {
             ping_sent_time: None,
             ping_received_time: None,
             share_relays_time: None,
+            share_relays_attempt_time: None,
+            shared_relays: Vec::new(),
+            udp_upgrade_time: None,
+        }
+    }
+
+    /// Remember relays received from the friend replacing the oldest ones if
+    /// there are too many of them.
+    fn add_shared_relays(&mut self, relays: &[PackedNode]) {
+        let now = clock_now();
+        for &node in relays {
+            self.shared_relays.retain(|relay| relay.node.pk != node.pk);
+            self.shared_relays.push(SharedRelay {
+                node,
+                received_time: now,
+            });
         }
+        // relays are sorted by received time so the oldest ones go first
+        let surplus = self.shared_relays.len().saturating_sub(MAX_SHARED_RELAYS_PER_FRIEND);
+        self.shared_relays.drain(.. surplus);
     }
 }
 
@@ -99,9 +162,11 @@ pub struct FriendConnections {
     real_pk: PublicKey,
     /// List of friends we want to be connected to.
     friends: Arc<RwLock<HashMap<PublicKey, Friend>>>,
-    /// Sink to send a connection status when it becomes connected or
-    /// disconnected. The key is a long term key of the connection.
-    connection_status_tx: Arc<RwLock<Option<ConnectionStatusTx>>>,
+    /// Sink to send connection events. The key is a long term key of the
+    /// connection.
+    connection_event_tx: Arc<RwLock<Option<ConnectionEventTx>>>,
+    /// Sink to send friend events. The key is a long term key of the friend.
+    friend_event_tx: Arc<RwLock<Option<FriendEventTx>>>,
     /// DHT server.
     dht: DhtServer,
     /// TCP connections.
@@ -126,7 +191,8 @@ impl FriendConnections {
             real_sk,
             real_pk,
             friends: Arc::new(RwLock::new(HashMap::new())),
-            connection_status_tx: Arc::new(RwLock::new(None)),
+            connection_event_tx: Arc::new(RwLock::new(None)),
+            friend_event_tx: Arc::new(RwLock::new(None)),
             dht,
             tcp_connections,
             onion_client,
@@ -141,6 +207,7 @@ impl FriendConnections {
             entry.insert(Friend::new(friend_pk));
             self.onion_client.add_friend(friend_pk);
             self.net_crypto.add_friend(friend_pk);
+            send_friend_event(&self.friend_event_tx, friend_pk, FriendEvent::Added);
         }
     }
 
@@ -160,6 +227,7 @@ impl FriendConnections {
             };
             self.net_crypto.remove_friend(friend_pk);
             self.onion_client.remove_friend(friend_pk);
+            send_friend_event(&self.friend_event_tx, friend_pk, FriendEvent::Removed);
             let kill_connection_future = self.net_crypto.kill_connection(friend_pk)
                 .then(|res| future::ready(match res {
                     Err(ref e)
@@ -177,9 +245,11 @@ impl FriendConnections {
         }
     }
 
-    /// Handle received `ShareRelays` packet.
+    /// Handle received `ShareRelays` packet. Relays are remembered so that
+    /// they can be used again when the friend's DHT `PublicKey` changes.
     pub fn handle_share_relays(&self, friend_pk: PublicKey, share_relays: ShareRelays) -> impl Future<Output = Result<(), HandleShareRelaysError>> + Send {
-        if let Some(friend) = self.friends.read().get(&friend_pk) {
+        if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+            friend.add_shared_relays(&share_relays.relays);
             if let Some(dht_pk) = friend.dht_pk {
                 let futures = share_relays.relays
                     .iter()
@@ -207,6 +277,17 @@ impl FriendConnections {
         }
     }
 
+    /// Get a snapshot of the friend with specified long term `PublicKey`.
+    /// Returns `None` if there is no such friend.
+    pub fn friend_info(&self, friend_pk: &PublicKey) -> Option<FriendInfo> {
+        self.friends.read().get(friend_pk).map(FriendInfo::new)
+    }
+
+    /// Get snapshots of all friends we want to be connected to.
+    pub fn friends_info(&self) -> Vec<FriendInfo> {
+        self.friends.read().values().map(FriendInfo::new).collect()
+    }
+
     /// Handle the stream of found DHT `PublicKey`s.
     fn handle_dht_pk(&self, dht_pk_rx: mpsc::UnboundedReceiver<(PublicKey, PublicKey)>) -> impl Future<Output = Result<(), RunError>> + Send {
         let dht = self.dht.clone();
@@ -214,6 +295,7 @@ impl FriendConnections {
         let onion_client = self.onion_client.clone();
         let friends = self.friends.clone();
         let tcp_connections = self.tcp_connections.clone();
+        let friend_event_tx = self.friend_event_tx.clone();
         dht_pk_rx
             .map(Ok)
             .try_for_each(move |(real_pk, dht_pk)| {
@@ -253,8 +335,22 @@ impl FriendConnections {
                         dht.add_friend(dht_pk);
                         net_crypto.add_connection(real_pk, dht_pk);
                         onion_client.set_friend_dht_pk(real_pk, dht_pk);
-
-                        kill_future
+                        send_friend_event(&friend_event_tx, real_pk, FriendEvent::DhtPkFound(dht_pk));
+
+                        // the friend is likely still reachable via relays it
+                        // shared with us before
+                        let relay_futures = friend.shared_relays.iter()
+                            .filter(|relay| !relay.is_expired())
+                            .map(|relay| tcp_connections.add_relay_connection(relay.node.saddr, relay.node.pk, dht_pk)
+                                .map_err(|e| warn!("Failed to add a relay shared by a friend: {}", e)))
+                            .collect::<Vec<_>>();
+                        let relays_future = future::join_all(relay_futures)
+                            .map(|_| Ok(()));
+
+                        Either::Left(
+                            future::try_join(kill_future, relays_future)
+                                .map_ok(drop)
+                        )
                     } else {
                         Either::Right(future::ok(()))
                     }
@@ -268,6 +364,7 @@ impl FriendConnections {
     fn handle_friend_saddr(&self, friend_saddr_rx: mpsc::UnboundedReceiver<PackedNode>) -> impl Future<Output = Result<(), RunError>> + Send {
         let net_crypto = self.net_crypto.clone();
         let friends = self.friends.clone();
+        let friend_event_tx = self.friend_event_tx.clone();
         friend_saddr_rx
             .map(Ok)
             .try_for_each(move |node| {
@@ -284,6 +381,7 @@ impl FriendConnections {
 
                         net_crypto.add_connection(friend.real_pk, node.pk);
                         net_crypto.set_friend_udp_addr(friend.real_pk, node.saddr);
+                        send_friend_event(&friend_event_tx, friend.real_pk, FriendEvent::AddrFound(node.saddr));
                     }
                 }
 
@@ -291,35 +389,48 @@ impl FriendConnections {
             })
     }
 
-    /// Handle the stream of connection statuses.
-    fn handle_connection_status(&self, connnection_status_rx: mpsc::UnboundedReceiver<(PublicKey, bool)>) -> impl Future<Output = Result<(), RunError>> + Send {
+    /// Handle the stream of connection events.
+    fn handle_connection_events(&self, connection_event_rx: mpsc::UnboundedReceiver<(PublicKey, ConnectionEvent)>) -> impl Future<Output = Result<(), RunError>> + Send {
         let onion_client = self.onion_client.clone();
         let friends = self.friends.clone();
-        let connection_status_tx = self.connection_status_tx.clone();
-        connnection_status_rx
+        let connection_event_tx = self.connection_event_tx.clone();
+        let friend_event_tx = self.friend_event_tx.clone();
+        connection_event_rx
             .map(Ok)
-            .try_for_each(move |(real_pk, status)| {
+            .try_for_each(move |(real_pk, event)| {
                 if let Some(friend) = friends.write().get_mut(&real_pk) {
-                    if status && !friend.connected {
-                        info!("Connection with a friend is established");
-
-                        friend.ping_received_time = Some(clock_now());
-                        friend.ping_sent_time = None;
-                        friend.share_relays_time = None;
-                    } else if !status && friend.connected {
-                        info!("Connection with a friend is lost");
-
-                        // update dht_pk_time right after it went offline to enforce attemts to reconnect
-                        friend.dht_pk_time = Some(clock_now());
-                    }
-
-                    if status != friend.connected {
-                        friend.connected = status;
-                        onion_client.set_friend_connected(real_pk, status);
-                        if let Some(mut connection_status_tx) = connection_status_tx.read().clone() {
+                    let should_forward = match event {
+                        ConnectionEvent::Connected(_) if !friend.connected => {
+                            info!("Connection with a friend is established");
+
+                            friend.ping_received_time = Some(clock_now());
+                            friend.ping_sent_time = None;
+                            friend.share_relays_time = None;
+                            friend.share_relays_attempt_time = None;
+                            friend.connected = true;
+                            onion_client.set_friend_connected(real_pk, true);
+                            true
+                        },
+                        ConnectionEvent::Disconnected(reason) if friend.connected => {
+                            info!("Connection with a friend is lost: {:?}", reason);
+
+                            // update dht_pk_time right after it went offline to enforce attemts to reconnect
+                            friend.dht_pk_time = Some(clock_now());
+                            friend.connected = false;
+                            onion_client.set_friend_connected(real_pk, false);
+                            true
+                        },
+                        ConnectionEvent::TransportChanged(_) => friend.connected,
+                        _ => false,
+                    };
+
+                    if should_forward {
+                        send_friend_event(&friend_event_tx, real_pk, FriendEvent::Connection(event));
+
+                        if let Some(mut connection_event_tx) = connection_event_tx.read().clone() {
                             let res = async move {
-                                connection_status_tx.send((real_pk, status)).await
-                                    .map_err(|e| e.context(RunErrorKind::SendToConnectionStatus).into())
+                                connection_event_tx.send((real_pk, event)).await
+                                    .map_err(|e| e.context(RunErrorKind::SendToConnectionEvent).into())
                             };
 
                             return Either::Left(res);
@@ -331,18 +442,25 @@ impl FriendConnections {
             })
     }
 
-    /// Send some of our relays to a friend and start using these relays to
-    /// connect to this friend.
-    fn share_relays(&self, friend_pk: PublicKey) -> impl Future<Output = Result<(), RunError>> + Send {
+    /// Send some of our connected relays to a friend and start using these
+    /// relays to connect to this friend. `share_relays_time` is updated only
+    /// when relays are sent so that the next packet is sent after
+    /// `SHARE_RELAYS_INTERVAL`. Failed attempts are repeated after
+    /// `SHARE_RELAYS_RETRY_INTERVAL`. When we are not connected to any relay
+    /// yet nothing is updated to share relays as soon as we connect.
+    fn share_relays(&self, friend: &mut Friend, dht_pk: PublicKey) -> impl Future<Output = Result<(), RunError>> + Send {
+        let friend_pk = friend.real_pk;
         let relays = self.tcp_connections.get_random_relays(MAX_SHARED_RELAYS as u8);
         if !relays.is_empty() {
+            friend.share_relays_attempt_time = Some(clock_now());
+
             let relay_futures = relays.iter().map(|relay|
-                self.tcp_connections.add_connection(relay.pk, friend_pk)
+                self.tcp_connections.add_connection(relay.pk, dht_pk)
                     .map_err(|e| e.context(RunErrorKind::AddTcpConnection).into())
             ).collect::<Vec<_>>();
 
             let share_relays = ShareRelays {
-                relays,
+                relays: relays.clone(),
             };
             let mut buf = vec![0; 154];
             let (_, size) = share_relays.to_bytes((&mut buf, 0)).unwrap();
@@ -350,14 +468,28 @@ impl FriendConnections {
             let send_future = self.net_crypto.send_lossless(friend_pk, buf)
                 .map_err(|e| e.context(RunErrorKind::SendTo).into());
 
+            let friends = self.friends.clone();
+            let friend_event_tx = self.friend_event_tx.clone();
             Either::Left(
                 future::try_join(
                     future::try_join_all(relay_futures),
                     send_future
                 )
-                .map_ok(drop)
+                .map(move |res: Result<_, RunError>| {
+                    match res {
+                        Ok(_) => {
+                            if let Some(friend) = friends.write().get_mut(&friend_pk) {
+                                friend.share_relays_time = Some(clock_now());
+                            }
+                            send_friend_event(&friend_event_tx, friend_pk, FriendEvent::RelaysShared(relays))
+                        },
+                        Err(e) => warn!("Failed to share relays with a friend: {}", e),
+                    }
+                    Ok(())
+                })
             )
         } else {
+            // we are not connected to any relay yet so try again later
             Either::Right(future::ok(()))
         }
     }
@@ -368,9 +500,12 @@ impl FriendConnections {
         let mut futures: Vec<Pin<Box<dyn Future<Output = Result<_, _>> + Send>>> = Vec::new();
 
         for friend in self.friends.write().values_mut() {
+            friend.shared_relays.retain(|relay| !relay.is_expired());
+
             if friend.connected {
                 if friend.ping_received_time.map_or(true, |time| clock_elapsed(time) >= FRIEND_CONNECTION_TIMEOUT) {
-                    let future = self.net_crypto.kill_connection(friend.real_pk)
+                    send_friend_event(&self.friend_event_tx, friend.real_pk, FriendEvent::PingTimeout);
+                    let future = self.net_crypto.kill_connection_with_reason(friend.real_pk, DisconnectReason::Timeout)
                         .then(|res| future::ready(match res {
                             Err(ref e)
                             if *e.kind() == KillConnectionErrorKind::NoConnection =>
@@ -389,9 +524,22 @@ impl FriendConnections {
                     friend.ping_sent_time = Some(clock_now());
                 }
 
-                if friend.share_relays_time.map_or(true, |time| clock_elapsed(time) >= SHARE_RELAYS_INTERVAL) {
-                    futures.push(Box::pin(self.share_relays(friend.real_pk)));
-                    friend.share_relays_time = Some(clock_now());
+                if friend.share_relays_time.map_or(true, |time| clock_elapsed(time) >= SHARE_RELAYS_INTERVAL) &&
+                    friend.share_relays_attempt_time.map_or(true, |time| clock_elapsed(time) >= SHARE_RELAYS_RETRY_INTERVAL) {
+                    if let Some(dht_pk) = friend.dht_pk {
+                        futures.push(Box::pin(self.share_relays(friend, dht_pk)));
+                    }
+                }
+
+                if friend.udp_upgrade_time.map_or(true, |time| clock_elapsed(time) >= UDP_UPGRADE_INTERVAL) {
+                    if let Some(dht_pk) = friend.dht_pk {
+                        // addresses of the friend returned by its close nodes
+                        // include addresses found by hole punching
+                        let mut addrs = self.dht.get_friend_returned_addrs(&dht_pk);
+                        addrs.extend(friend.saddr);
+                        self.net_crypto.probe_friend_udp_addrs(friend.real_pk, &addrs);
+                    }
+                    friend.udp_upgrade_time = Some(clock_now());
                 }
             } else {
                 if friend.dht_pk_time.map_or(false, |time| clock_elapsed(time) >= FRIEND_DHT_TIMEOUT) {
@@ -442,7 +590,7 @@ impl FriendConnections {
     }
 
     /// Run friends connection module. This will add handlers for DHT
-    /// `PublicKey`, IP address and connection status updates to appropriate
+    /// `PublicKey`, IP address and connection event updates to appropriate
     /// modules.
     pub fn run(self) -> impl Future<Output = Result<(), RunError>> + Send {
         let (dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
@@ -452,19 +600,19 @@ impl FriendConnections {
         let (friend_saddr_tx, friend_saddr_rx) = mpsc::unbounded();
         self.dht.set_friend_saddr_sink(friend_saddr_tx);
 
-        let (connection_status_tx, connection_status_rx) = mpsc::unbounded();
-        self.net_crypto.set_connection_status_sink(connection_status_tx);
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        self.net_crypto.set_connection_event_sink(connection_event_tx);
 
         let dht_pk_future = self.handle_dht_pk(dht_pk_rx);
         let friend_saddr_future = self.handle_friend_saddr(friend_saddr_rx);
-        let connection_status_future = self.handle_connection_status(connection_status_rx);
+        let connection_event_future = self.handle_connection_events(connection_event_rx);
         let main_loop_future = self.run_main_loop();
 
         async {
             let res = futures::select! {
                 res = dht_pk_future.fuse() => res,
                 res = friend_saddr_future.fuse() => res,
-                res = connection_status_future.fuse() => res,
+                res = connection_event_future.fuse() => res,
                 res = main_loop_future.fuse() => res,
             };
 
@@ -472,10 +620,26 @@ impl FriendConnections {
         }
     }
 
-    /// Set sink to send a connection status when it becomes connected or
-    /// disconnected.
-    pub fn set_connection_status_sink(&self, connection_status_tx: mpsc::UnboundedSender<(PublicKey, bool)>) {
-        *self.connection_status_tx.write() = Some(connection_status_tx);
+    /// Set sink to send connection events. `Connected`, `Disconnected` and
+    /// `TransportChanged` events are sent only when the friend becomes
+    /// connected, disconnected or changes transport being connected. Failed
+    /// connection attempts of a not connected friend are not reported.
+    pub fn set_connection_event_sink(&self, connection_event_tx: ConnectionEventTx) {
+        *self.connection_event_tx.write() = Some(connection_event_tx);
+    }
+
+    /// Set sink to send friend events.
+    pub fn set_friend_event_sink(&self, friend_event_tx: FriendEventTx) {
+        *self.friend_event_tx.write() = Some(friend_event_tx);
+    }
+}
+
+/// Send friend event to the sink if it's set.
+fn send_friend_event(friend_event_tx: &RwLock<Option<FriendEventTx>>, real_pk: PublicKey, event: FriendEvent) {
+    if let Some(ref tx) = *friend_event_tx.read() {
+        if tx.unbounded_send((real_pk, event)).is_err() {
+            trace!("Friend events receiver is dropped");
+        }
     }
 }
 
@@ -677,7 +841,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn handle_connection_status_connected() {
+    async fn handle_connection_events_connected() {
         tokio::time::pause();
         let now = clock_now();
 
@@ -692,11 +856,17 @@ mod tests {
         friend_connections.friends.write().insert(friend_pk, friend);
         friend_connections.onion_client.add_friend(friend_pk);
 
-        let (mut connnection_status_tx, connnection_status_rx) = mpsc::unbounded();
-        connnection_status_tx.send((friend_pk, true)).await.unwrap();
-        drop(connnection_status_tx);
+        let (event_tx, mut event_rx) = mpsc::unbounded();
+        friend_connections.set_connection_event_sink(event_tx);
+
+        let (mut connnection_event_tx, connnection_event_rx) = mpsc::unbounded();
+        let event = ConnectionEvent::Connected(ConnectionTransport::Tcp);
+        connnection_event_tx.send((friend_pk, event)).await.unwrap();
+        drop(connnection_event_tx);
 
-        friend_connections.handle_connection_status(connnection_status_rx).await.unwrap();
+        friend_connections.handle_connection_events(connnection_event_rx).await.unwrap();
+
+        assert_eq!(event_rx.next().await, Some((friend_pk, event)));
 
         let friend = &friend_connections.friends.read()[&friend_pk];
         assert!(friend.connected);
@@ -708,7 +878,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn handle_connection_status_disconnected() {
+    async fn handle_connection_events_disconnected() {
         tokio::time::pause();
         let now = clock_now();
 
@@ -723,11 +893,17 @@ mod tests {
         friend_connections.onion_client.add_friend(friend_pk);
         friend_connections.onion_client.set_friend_connected(friend_pk, true);
 
-        let (mut connnection_status_tx, connnection_status_rx) = mpsc::unbounded();
-        connnection_status_tx.send((friend_pk, false)).await.unwrap();
-        drop(connnection_status_tx);
+        let (event_tx, mut event_rx) = mpsc::unbounded();
+        friend_connections.set_connection_event_sink(event_tx);
+
+        let (mut connnection_event_tx, connnection_event_rx) = mpsc::unbounded();
+        let event = ConnectionEvent::Disconnected(DisconnectReason::Killed);
+        connnection_event_tx.send((friend_pk, event)).await.unwrap();
+        drop(connnection_event_tx);
 
-        friend_connections.handle_connection_status(connnection_status_rx).await.unwrap();
+        friend_connections.handle_connection_events(connnection_event_rx).await.unwrap();
+
+        assert_eq!(event_rx.next().await, Some((friend_pk, event)));
 
         let friend = &friend_connections.friends.read()[&friend_pk];
         assert!(!friend.connected);
@@ -736,6 +912,46 @@ mod tests {
         assert!(!friend_connections.onion_client.is_friend_connected(&friend_pk));
     }
 
+    #[tokio::test]
+    async fn handle_connection_events_forward() {
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        friend_connections.friends.write().insert(friend_pk, Friend::new(friend_pk));
+        friend_connections.onion_client.add_friend(friend_pk);
+
+        let (event_tx, event_rx) = mpsc::unbounded();
+        friend_connections.set_connection_event_sink(event_tx);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let events = vec![
+            // not connected friend can't be disconnected or change transport
+            ConnectionEvent::Disconnected(DisconnectReason::Local),
+            ConnectionEvent::TransportChanged(ConnectionTransport::Tcp),
+            ConnectionEvent::Disconnected(DisconnectReason::HandshakeFailed),
+            ConnectionEvent::Connected(ConnectionTransport::Tcp),
+            // friend is already connected
+            ConnectionEvent::Connected(ConnectionTransport::Tcp),
+            ConnectionEvent::TransportChanged(ConnectionTransport::UdpV4(addr)),
+            ConnectionEvent::Disconnected(DisconnectReason::Timeout),
+        ];
+
+        let (mut connnection_event_tx, connnection_event_rx) = mpsc::unbounded();
+        for &event in &events {
+            connnection_event_tx.send((friend_pk, event)).await.unwrap();
+        }
+        drop(connnection_event_tx);
+
+        friend_connections.handle_connection_events(connnection_event_rx).await.unwrap();
+        drop(friend_connections);
+
+        let forwarded = event_rx.map(|(_pk, event)| event).collect::<Vec<_>>().await;
+        assert_eq!(forwarded, vec![
+            ConnectionEvent::Connected(ConnectionTransport::Tcp),
+            ConnectionEvent::TransportChanged(ConnectionTransport::UdpV4(addr)),
+            ConnectionEvent::Disconnected(DisconnectReason::Timeout),
+        ]);
+    }
+
     #[tokio::test]
     async fn main_loop_remove_timed_out() {
         let (friend_connections, udp_rx, _lossless_rx) = create_friend_connections();
@@ -766,11 +982,22 @@ mod tests {
         );
         friend_connections.net_crypto.set_friend_udp_addr(friend_pk, saddr);
 
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        friend_connections.net_crypto.set_connection_event_sink(connection_event_tx);
+
+        let (friend_event_tx, mut friend_event_rx) = mpsc::unbounded();
+        friend_connections.set_friend_event_sink(friend_event_tx);
+
         tokio::time::pause();
         tokio::time::advance(FRIEND_CONNECTION_TIMEOUT + Duration::from_secs(1)).await;
 
         friend_connections.main_loop().await.unwrap();
 
+        assert_eq!(friend_event_rx.next().await, Some((friend_pk, FriendEvent::PingTimeout)));
+
+        let (event, _connection_event_rx) = connection_event_rx.into_future().await;
+        assert_eq!(event, Some((friend_pk, ConnectionEvent::Disconnected(DisconnectReason::Timeout))));
+
         let (received, _udp_rx) = udp_rx.into_future().await;
         let (received, addr_to_send) = received.unwrap();
 
@@ -863,6 +1090,9 @@ mod tests {
 
         let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = friend_connections.tcp_connections.add_client();
 
+        let (friend_event_tx, mut friend_event_rx) = mpsc::unbounded();
+        friend_connections.set_friend_event_sink(friend_event_tx);
+
         let delay = Duration::from_secs(1);
         tokio::time::advance(delay).await;
 
@@ -870,6 +1100,16 @@ mod tests {
 
         let friend = &friend_connections.friends.read()[&friend_pk];
         assert_eq!(friend.share_relays_time, Some(now + delay));
+        assert_eq!(friend.share_relays_attempt_time, Some(now + delay));
+
+        let (event_pk, event) = friend_event_rx.next().await.unwrap();
+        assert_eq!(event_pk, friend_pk);
+        let relays = unpack!(event, FriendEvent::RelaysShared);
+        assert_eq!(relays.len(), 1);
+        assert_eq!(relays[0].pk, relay_pk);
+
+        // connection to the friend via shared relays is keyed by DHT key
+        assert!(friend_connections.tcp_connections.has_connection(&friend_dht_pk));
 
         let (received, _udp_rx) = udp_rx.into_future().await;
         let (received, addr_to_send) = received.unwrap();
@@ -883,6 +1123,124 @@ mod tests {
         assert_eq!(packet.relays[0].pk, relay_pk);
     }
 
+    #[tokio::test]
+    async fn main_loop_share_relays_send_failed() {
+        tokio::time::pause();
+        let now = clock_now();
+
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+        friend.dht_pk_time = Some(now);
+        friend.ping_received_time = Some(now);
+        friend.ping_sent_time = Some(now);
+        friend.udp_upgrade_time = Some(now);
+        friend.connected = true;
+        friend_connections.friends.write().insert(friend_pk, friend);
+
+        let (_relay_incoming_rx, _relay_outgoing_rx, _relay_pk) = friend_connections.tcp_connections.add_client();
+
+        let (friend_event_tx, mut friend_event_rx) = mpsc::unbounded();
+        friend_connections.set_friend_event_sink(friend_event_tx);
+
+        // there is no crypto connection so sending relays fails
+        friend_connections.main_loop().await.unwrap();
+
+        {
+            let friend = &friend_connections.friends.read()[&friend_pk];
+            assert!(friend.share_relays_time.is_none());
+            assert_eq!(friend.share_relays_attempt_time, Some(now));
+        }
+        assert!(friend_event_rx.try_recv().is_err());
+
+        // failed attempt is not repeated until `SHARE_RELAYS_RETRY_INTERVAL` passes
+        tokio::time::advance(SHARE_RELAYS_RETRY_INTERVAL - Duration::from_secs(1)).await;
+        {
+            // don't send ping packets
+            let mut friends = friend_connections.friends.write();
+            let friend = friends.get_mut(&friend_pk).unwrap();
+            friend.ping_received_time = Some(clock_now());
+            friend.ping_sent_time = Some(clock_now());
+        }
+        friend_connections.main_loop().await.unwrap();
+        assert_eq!(friend_connections.friends.read()[&friend_pk].share_relays_attempt_time, Some(now));
+
+        // and it's repeated once the connection is established
+        let session_precomputed_key = precompute(&gen_keypair().0, &gen_keypair().1);
+        friend_connections.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            gen_nonce(),
+            gen_nonce(),
+            session_precomputed_key
+        );
+        tokio::time::advance(Duration::from_secs(1)).await;
+        {
+            let mut friends = friend_connections.friends.write();
+            let friend = friends.get_mut(&friend_pk).unwrap();
+            friend.ping_received_time = Some(clock_now());
+            friend.ping_sent_time = Some(clock_now());
+        }
+        friend_connections.main_loop().await.unwrap();
+
+        let (_event_pk, event) = friend_event_rx.next().await.unwrap();
+        unpack!(event, FriendEvent::RelaysShared);
+
+        let friend = &friend_connections.friends.read()[&friend_pk];
+        assert_eq!(friend.share_relays_time, Some(now + SHARE_RELAYS_RETRY_INTERVAL));
+        assert_eq!(friend.share_relays_attempt_time, Some(now + SHARE_RELAYS_RETRY_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn main_loop_share_relays_no_relays() {
+        tokio::time::pause();
+        let now = clock_now();
+
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+        friend.dht_pk_time = Some(now);
+        friend.ping_received_time = Some(now);
+        friend.ping_sent_time = Some(now);
+        friend.udp_upgrade_time = Some(now);
+        friend.connected = true;
+        friend_connections.friends.write().insert(friend_pk, friend);
+
+        friend_connections.main_loop().await.unwrap();
+
+        // relays should be sent as soon as we connect to any of them
+        let friend = &friend_connections.friends.read()[&friend_pk];
+        assert!(friend.share_relays_time.is_none());
+        assert!(friend.share_relays_attempt_time.is_none());
+    }
+
+    #[tokio::test]
+    async fn main_loop_remove_expired_shared_relays() {
+        tokio::time::pause();
+
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+        let relay = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+        friend.add_shared_relays(&[relay]);
+        friend_connections.friends.write().insert(friend_pk, friend);
+
+        tokio::time::advance(SHARED_RELAYS_TIMEOUT - Duration::from_secs(1)).await;
+        friend_connections.main_loop().await.unwrap();
+        assert_eq!(friend_connections.friends.read()[&friend_pk].shared_relays.len(), 1);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        friend_connections.main_loop().await.unwrap();
+        assert!(friend_connections.friends.read()[&friend_pk].shared_relays.is_empty());
+    }
+
     #[tokio::test]
     async fn main_loop_clear_dht_pk() {
         tokio::time::pause();
@@ -943,6 +1301,103 @@ mod tests {
         assert!(friend.saddr_time.is_none());
     }
 
+    #[tokio::test]
+    async fn friend_info() {
+        tokio::time::pause();
+        let now = clock_now();
+
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let saddr = "127.0.0.1:12345".parse().unwrap();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+        friend.dht_pk = Some(friend_dht_pk);
+        friend.saddr = Some(saddr);
+        friend.ping_received_time = Some(now);
+        friend.share_relays_time = Some(now);
+        friend.connected = true;
+        friend_connections.friends.write().insert(friend_pk, friend);
+
+        let (another_friend_pk, _another_friend_sk) = gen_keypair();
+        friend_connections.add_friend(another_friend_pk);
+
+        let info = friend_connections.friend_info(&friend_pk).unwrap();
+        assert_eq!(info, FriendInfo {
+            real_pk: friend_pk,
+            dht_pk: Some(friend_dht_pk),
+            saddr: Some(saddr),
+            connected: true,
+            last_seen: Some(now),
+            relays_shared_time: Some(now),
+            shared_relays: Vec::new(),
+        });
+
+        let another_info = friend_connections.friend_info(&another_friend_pk).unwrap();
+        assert_eq!(another_info, FriendInfo {
+            real_pk: another_friend_pk,
+            dht_pk: None,
+            saddr: None,
+            connected: false,
+            last_seen: None,
+            relays_shared_time: None,
+            shared_relays: Vec::new(),
+        });
+
+        assert!(friend_connections.friend_info(&gen_keypair().0).is_none());
+
+        let mut friends_info = friend_connections.friends_info();
+        friends_info.sort_by_key(|info| info.real_pk);
+        let mut expected = vec![info, another_info];
+        expected.sort_by_key(|info| info.real_pk);
+        assert_eq!(friends_info, expected);
+    }
+
+    #[tokio::test]
+    async fn friend_events() {
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_event_tx, friend_event_rx) = mpsc::unbounded();
+        friend_connections.set_friend_event_sink(friend_event_tx);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        friend_connections.add_friend(friend_pk);
+        // adding the same friend twice doesn't do anything
+        friend_connections.add_friend(friend_pk);
+
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        let (mut dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        dht_pk_tx.send((friend_pk, friend_dht_pk)).await.unwrap();
+        // the same key is not reported twice
+        dht_pk_tx.send((friend_pk, friend_dht_pk)).await.unwrap();
+        drop(dht_pk_tx);
+        friend_connections.handle_dht_pk(dht_pk_rx).await.unwrap();
+
+        let saddr = "127.0.0.1:12345".parse().unwrap();
+        let (mut friend_saddr_tx, friend_saddr_rx) = mpsc::unbounded();
+        friend_saddr_tx.send(PackedNode::new(saddr, &friend_dht_pk)).await.unwrap();
+        drop(friend_saddr_tx);
+        friend_connections.handle_friend_saddr(friend_saddr_rx).await.unwrap();
+
+        let (mut connnection_event_tx, connnection_event_rx) = mpsc::unbounded();
+        let event = ConnectionEvent::Connected(ConnectionTransport::Tcp);
+        connnection_event_tx.send((friend_pk, event)).await.unwrap();
+        drop(connnection_event_tx);
+        friend_connections.handle_connection_events(connnection_event_rx).await.unwrap();
+
+        friend_connections.remove_friend(friend_pk).await.unwrap();
+        drop(friend_connections);
+
+        let events = friend_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            (friend_pk, FriendEvent::Added),
+            (friend_pk, FriendEvent::DhtPkFound(friend_dht_pk)),
+            (friend_pk, FriendEvent::AddrFound(saddr)),
+            (friend_pk, FriendEvent::Connection(event)),
+            (friend_pk, FriendEvent::Removed),
+        ]);
+    }
+
     #[tokio::test]
     async fn handle_share_relays() {
         let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
@@ -966,6 +1421,62 @@ mod tests {
 
         assert!(friend_connections.tcp_connections.has_relay(&relay_pk));
         assert!(friend_connections.tcp_connections.has_connection(&friend_dht_pk));
+
+        let info = friend_connections.friend_info(&friend_pk).unwrap();
+        assert_eq!(info.shared_relays, vec![PackedNode::new(relay_saddr, &relay_pk)]);
+    }
+
+    #[tokio::test]
+    async fn handle_dht_pk_reuse_shared_relays() {
+        let (friend_connections, _udp_rx, _lossless_rx) = create_friend_connections();
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        friend_connections.friends.write().insert(friend_pk, Friend::new(friend_pk));
+
+        let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = friend_connections.tcp_connections.add_client();
+        let relay_saddr = "127.0.0.1:12345".parse().unwrap();
+        let share_relays = ShareRelays {
+            relays: vec![PackedNode::new(relay_saddr, &relay_pk)],
+        };
+
+        // DHT `PublicKey` is not known yet so relays are only remembered
+        friend_connections.handle_share_relays(friend_pk, share_relays).await.unwrap();
+
+        let (friend_dht_pk, _friend_dht_sk) = gen_keypair();
+        assert!(!friend_connections.tcp_connections.has_connection(&friend_dht_pk));
+
+        let (mut dht_pk_tx, dht_pk_rx) = mpsc::unbounded();
+        dht_pk_tx.send((friend_pk, friend_dht_pk)).await.unwrap();
+        drop(dht_pk_tx);
+
+        friend_connections.handle_dht_pk(dht_pk_rx).await.unwrap();
+
+        assert!(friend_connections.tcp_connections.has_connection(&friend_dht_pk));
+    }
+
+    #[test]
+    fn add_shared_relays() {
+        crypto_init().unwrap();
+        let (friend_pk, _friend_sk) = gen_keypair();
+        let mut friend = Friend::new(friend_pk);
+
+        let relays = (0 .. MAX_SHARED_RELAYS_PER_FRIEND + 1)
+            .map(|i| PackedNode::new(SocketAddr::new("127.0.0.1".parse().unwrap(), 12345 + i as u16), &gen_keypair().0))
+            .collect::<Vec<_>>();
+
+        friend.add_shared_relays(&relays[.. 2]);
+        // the same relay is not stored twice
+        friend.add_shared_relays(&relays[.. 1]);
+        assert_eq!(friend.shared_relays.len(), 2);
+        assert_eq!(friend.shared_relays[0].node, relays[1]);
+        assert_eq!(friend.shared_relays[1].node, relays[0]);
+
+        // the oldest relays are replaced
+        friend.add_shared_relays(&relays[2 ..]);
+        let stored = friend.shared_relays.iter().map(|relay| relay.node).collect::<Vec<_>>();
+        let mut expected = vec![relays[0]];
+        expected.extend_from_slice(&relays[2 ..]);
+        assert_eq!(stored, expected);
     }
 
     #[tokio::test]
@@ -1000,8 +1511,8 @@ mod tests {
         let friend_saddr = "127.0.0.1:12345".parse().unwrap();
         friend_connections.add_friend(friend_pk);
 
-        let (connection_status_tx, mut connection_status_rx) = mpsc::unbounded();
-        friend_connections.set_connection_status_sink(connection_status_tx);
+        let (connection_event_tx, mut connection_event_rx) = mpsc::unbounded();
+        friend_connections.set_connection_event_sink(connection_event_tx);
 
         // the ordering is essential since `run` adds its handlers that should
         // be done before packets handling
@@ -1047,11 +1558,11 @@ mod tests {
             assert_eq!(received_data, vec![PACKET_ID_ALIVE]);
         };
 
-        let connection_status_future = async move {
-            let packet = connection_status_rx.next().await;
-            let (pk, status) = packet.unwrap();
+        let connection_event_future = async move {
+            let packet = connection_event_rx.next().await;
+            let (pk, event) = packet.unwrap();
             assert_eq!(pk, friend_pk);
-            assert!(status);
+            assert_eq!(event, ConnectionEvent::Connected(ConnectionTransport::UdpV4("127.0.0.1:12345".parse().unwrap())));
         };
 
         let future = async move {
@@ -1059,7 +1570,7 @@ mod tests {
 
             futures::select! {
                 _ = join.fuse() => (),
-                _ = connection_status_future.fuse() => (),
+                _ = connection_event_future.fuse() => (),
             }
         };
 
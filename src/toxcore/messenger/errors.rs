@@ -0,0 +1,75 @@
+/*! Errors enum for messenger.
+*/
+
+use failure::Fail;
+
+error_kind! {
+    #[doc = "Error that can happen while sending a message to a friend."]
+    #[derive(Debug)]
+    SendMessageError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    SendMessageErrorKind {
+        #[doc = "Message is empty."]
+        #[fail(display = "Message is empty")]
+        Empty,
+        #[doc = "Message is too long to fit into one packet."]
+        #[fail(display = "Message is too long")]
+        TooLong,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen while sending queued messages to a friend."]
+    #[derive(Debug)]
+    FlushError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    FlushErrorKind {
+        #[doc = "Failed to send a message via net_crypto connection."]
+        #[fail(display = "Failed to send a message via net_crypto connection")]
+        SendTo,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen while changing presence."]
+    #[derive(Debug)]
+    SetPresenceError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    SetPresenceErrorKind {
+        #[doc = "Name or status message is too long to fit into one packet."]
+        #[fail(display = "Name or status message is too long")]
+        TooLong,
+        #[doc = "Friend with such PublicKey does not exist."]
+        #[fail(display = "Friend with such PublicKey does not exist")]
+        NoFriend,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen while removing a friend from presence."]
+    #[derive(Debug)]
+    RemoveFriendError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    RemoveFriendErrorKind {
+        #[doc = "Failed to send Offline packet."]
+        #[fail(display = "Failed to send Offline packet")]
+        SendTo,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen when calling `run` of presence."]
+    #[derive(Debug)]
+    RunError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Debug, Eq, PartialEq, Fail)]
+    RunErrorKind {
+        #[doc = "Timeout error."]
+        #[fail(display = "Timeout error")]
+        Timeout,
+    }
+}
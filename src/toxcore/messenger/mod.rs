@@ -1,6 +1,9 @@
 /*! The implementation of Messenger
 */
 
+pub mod errors;
 pub mod packet;
+pub mod presence;
 pub mod conference;
 pub mod file_transfer;
+pub mod outbox;
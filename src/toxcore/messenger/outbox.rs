@@ -0,0 +1,816 @@
+/*! Per-friend queue of outgoing messages.
+
+Messages and actions sent to a friend that is offline are queued and sent in
+the same order when the friend becomes online. Every message gets a receipt ID
+when it's queued. A sent message stays in the outbox until net_crypto reports
+that the friend received the packet with it, then its receipt ID is returned
+by `Outbox::check_receipts` so that the application can match delivery
+receipts with its messages. Messages that were sent but not received are sent
+again when the friend becomes online next time. Undelivered messages can be
+saved with `Outbox::state` and restored with `Outbox::load_state` so that they
+survive restart.
+*/
+
+use std::collections::{HashMap, VecDeque};
+use std::str;
+use std::sync::Arc;
+
+use failure::Fail;
+use nom::number::complete::{be_u8, be_u16, be_u32};
+use parking_lot::RwLock;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::messenger::errors::*;
+use crate::toxcore::messenger::packet::{Action, Message, Packet};
+use crate::toxcore::net_crypto::{NetCrypto, PacketPriority};
+use crate::toxcore::net_crypto::errors::SendLosslessPacketErrorKind;
+
+/// Maximum size in bytes of a message packet.
+const MAX_MESSAGE_PACKET_SIZE: usize = 1373;
+
+/// Type of a queued message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageType {
+    /// Normal text message.
+    Normal = 0,
+    /// Message describing an action, like `/me` in IRC.
+    Action = 1,
+}
+
+impl FromBytes for MessageType {
+    named!(from_bytes<MessageType>, switch!(be_u8,
+        0 => value!(MessageType::Normal) |
+        1 => value!(MessageType::Action)
+    ));
+}
+
+/** Message that is waiting to be sent to a friend.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`4`       | Receipt ID in BigEndian
+`1`       | Message type (`0` - normal, `1` - action)
+`2`       | Length of the message in BigEndian
+variable  | UTF8 byte string
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedMessage {
+    /// Receipt ID that was returned when the message was queued.
+    pub receipt_id: u32,
+    /// Type of the message.
+    pub message_type: MessageType,
+    /// Text of the message.
+    pub text: String,
+}
+
+impl QueuedMessage {
+    /// Serialize the message to messenger `Message` or `Action` packet.
+    fn to_packet_bytes(&self) -> Result<Vec<u8>, GenError> {
+        let packet = match self.message_type {
+            MessageType::Normal => Packet::Message(Message::new(self.text.clone())),
+            MessageType::Action => Packet::Action(Action::new(self.text.clone())),
+        };
+        let mut buf = vec![0; MAX_MESSAGE_PACKET_SIZE];
+        let (_, size) = packet.to_bytes((&mut buf, 0))?;
+        buf.truncate(size);
+        Ok(buf)
+    }
+}
+
+impl FromBytes for QueuedMessage {
+    named!(from_bytes<QueuedMessage>, do_parse!(
+        receipt_id: be_u32 >>
+        message_type: call!(MessageType::from_bytes) >>
+        text: map_res!(length_data!(be_u16), str::from_utf8) >>
+        (QueuedMessage {
+            receipt_id,
+            message_type,
+            text: text.to_string(),
+        })
+    ));
+}
+
+impl ToBytes for QueuedMessage {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u32!(self.receipt_id) >>
+            gen_be_u8!(self.message_type as u8) >>
+            gen_cond!(self.text.len() > std::u16::MAX as usize, |buf| gen_error(buf, 0)) >>
+            gen_be_u16!(self.text.len() as u16) >>
+            gen_slice!(self.text.as_bytes())
+        )
+    }
+}
+
+/** Queued messages of one friend.
+
+Serialized form:
+
+Length    | Content
+--------- | ------
+`32`      | Friend's long term `PublicKey`
+`4`       | Receipt ID of the next message in BigEndian
+`4`       | Number of queued messages in BigEndian
+variable  | Queued messages
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FriendOutboxState {
+    /// Friend's long term `PublicKey`.
+    pub pk: PublicKey,
+    /// Receipt ID that will be assigned to the next message.
+    pub next_receipt_id: u32,
+    /// Queued messages in the order they should be sent.
+    pub messages: Vec<QueuedMessage>,
+}
+
+impl FromBytes for FriendOutboxState {
+    named!(from_bytes<FriendOutboxState>, do_parse!(
+        pk: call!(PublicKey::from_bytes) >>
+        next_receipt_id: be_u32 >>
+        messages_count: be_u32 >>
+        messages: count!(QueuedMessage::from_bytes, messages_count as usize) >>
+        (FriendOutboxState {
+            pk,
+            next_receipt_id,
+            messages,
+        })
+    ));
+}
+
+impl ToBytes for FriendOutboxState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.pk.as_ref()) >>
+            gen_be_u32!(self.next_receipt_id) >>
+            gen_be_u32!(self.messages.len() as u32) >>
+            gen_many_ref!(&self.messages, |buf, message| QueuedMessage::to_bytes(message, buf))
+        )
+    }
+}
+
+/// Snapshot of queued messages of all friends that can be persisted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct OutboxState {
+    /// Queued messages by friends.
+    pub friends: Vec<FriendOutboxState>,
+}
+
+impl FromBytes for OutboxState {
+    named!(from_bytes<OutboxState>, do_parse!(
+        friends: many0!(complete!(FriendOutboxState::from_bytes)) >>
+        eof!() >>
+        (OutboxState {
+            friends,
+        })
+    ));
+}
+
+impl ToBytes for OutboxState {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_many_ref!(&self.friends, |buf, friend| FriendOutboxState::to_bytes(friend, buf))
+        )
+    }
+}
+
+/// Message that was sent to a friend but is not received yet.
+#[derive(Clone, Debug)]
+struct SentMessage {
+    /// The sent message.
+    message: QueuedMessage,
+    /// Number of net_crypto packet the message was sent with.
+    packet_number: u32,
+}
+
+/// Outgoing messages of a friend.
+#[derive(Clone, Debug, Default)]
+struct FriendOutbox {
+    /// Whether the friend is online so that messages can be sent right away.
+    online: bool,
+    /// Whether queued messages are being sent right now.
+    flushing: bool,
+    /// Receipt ID that will be assigned to the next message.
+    next_receipt_id: u32,
+    /// Messages sent via the current connection in the order they were sent.
+    sent: VecDeque<SentMessage>,
+    /// Queued messages in the order they should be sent.
+    messages: VecDeque<QueuedMessage>,
+}
+
+impl FriendOutbox {
+    /// Move sent but not received messages back to the queue since their
+    /// packet numbers are not valid for another connection.
+    fn requeue_sent(&mut self) {
+        while let Some(sent) = self.sent.pop_back() {
+            self.messages.push_front(sent.message);
+        }
+    }
+
+    /// Undelivered messages in the order they should be received.
+    fn undelivered(&self) -> impl Iterator<Item = &QueuedMessage> {
+        self.sent.iter().map(|sent| &sent.message).chain(self.messages.iter())
+    }
+}
+
+/// Guard that resets `flushing` flag of a friend when dropped so that the
+/// flag is reset even if the flushing future is dropped before completion.
+struct FlushGuard {
+    /// Outgoing messages by friend's long term `PublicKey`.
+    friends: Arc<RwLock<HashMap<PublicKey, FriendOutbox>>>,
+    /// Friend's long term `PublicKey`.
+    friend_pk: PublicKey,
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        if let Some(friend) = self.friends.write().get_mut(&self.friend_pk) {
+            friend.flushing = false;
+        }
+    }
+}
+
+/// Outbox that queues messages while friends are offline and sends them when
+/// friends become online.
+#[derive(Clone)]
+pub struct Outbox {
+    /// Outgoing messages by friend's long term `PublicKey`.
+    friends: Arc<RwLock<HashMap<PublicKey, FriendOutbox>>>,
+    /// Net crypto module to send messages.
+    net_crypto: NetCrypto,
+}
+
+impl Outbox {
+    /// Create new `Outbox`.
+    pub fn new(net_crypto: NetCrypto) -> Self {
+        Outbox {
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            net_crypto,
+        }
+    }
+
+    /// Send a message to a friend. The message is queued if the friend is
+    /// offline or if earlier messages are not sent yet. Returns receipt ID of
+    /// the message.
+    pub async fn send_message(&self, friend_pk: PublicKey, message_type: MessageType, text: String) -> Result<u32, SendMessageError> {
+        if text.is_empty() {
+            return Err(SendMessageErrorKind::Empty.into());
+        }
+
+        let (receipt_id, online) = {
+            let mut friends = self.friends.write();
+            let friend = friends.entry(friend_pk).or_default();
+            let message = QueuedMessage {
+                receipt_id: friend.next_receipt_id,
+                message_type,
+                text,
+            };
+            if message.to_packet_bytes().is_err() {
+                return Err(SendMessageErrorKind::TooLong.into());
+            }
+            friend.next_receipt_id = friend.next_receipt_id.wrapping_add(1);
+            friend.messages.push_back(message);
+            (friend.next_receipt_id.wrapping_sub(1), friend.online)
+        };
+
+        if online {
+            // the message stays queued if it can't be sent now
+            if let Err(e) = self.flush(friend_pk).await {
+                warn!("Failed to send queued messages: {}", e);
+            }
+        }
+
+        Ok(receipt_id)
+    }
+
+    /// Mark the friend as online and send all queued messages in order.
+    /// Messages that were sent via the previous connection but not received
+    /// are sent again.
+    pub async fn handle_online(&self, friend_pk: PublicKey) -> Result<(), FlushError> {
+        {
+            let mut friends = self.friends.write();
+            let friend = friends.entry(friend_pk).or_default();
+            if !friend.online {
+                friend.requeue_sent();
+                friend.online = true;
+            }
+        }
+        self.flush(friend_pk).await
+    }
+
+    /// Mark the friend as offline so that new messages are queued. Messages
+    /// that were sent but not received are queued again.
+    pub fn handle_offline(&self, friend_pk: PublicKey) {
+        if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+            friend.online = false;
+            friend.requeue_sent();
+        }
+    }
+
+    /// Send queued messages to the friend in order until the queue is empty
+    /// or the friend goes offline. When the send queue of the connection is
+    /// full it waits until there is free space so messages are never dropped.
+    /// Sent messages are kept until the friend receives them.
+    pub async fn flush(&self, friend_pk: PublicKey) -> Result<(), FlushError> {
+        {
+            let mut friends = self.friends.write();
+            let friend = match friends.get_mut(&friend_pk) {
+                Some(friend) if friend.online && !friend.flushing => friend,
+                // messages queued while flushing will be sent by the running
+                // flush
+                _ => return Ok(()),
+            };
+            friend.flushing = true;
+        }
+
+        let _guard = FlushGuard {
+            friends: self.friends.clone(),
+            friend_pk,
+        };
+
+        self.flush_inner(friend_pk).await
+    }
+
+    /// Send queued messages one by one while `flushing` flag is set.
+    async fn flush_inner(&self, friend_pk: PublicKey) -> Result<(), FlushError> {
+        loop {
+            let message = {
+                let friends = self.friends.read();
+                match friends.get(&friend_pk) {
+                    Some(friend) if friend.online => friend.messages.front().cloned(),
+                    _ => None,
+                }
+            };
+            let message = match message {
+                Some(message) => message,
+                None => return Ok(()),
+            };
+
+            // messages are checked when they are queued
+            let packet = message.to_packet_bytes().unwrap_or_default();
+            let packet_number = match self.net_crypto.send_queued(friend_pk, packet, PacketPriority::Lossless).await {
+                Ok(packet_number) => packet_number,
+                Err(ref e) if *e.kind() == SendLosslessPacketErrorKind::NoConnection => {
+                    self.handle_offline(friend_pk);
+                    return Ok(());
+                },
+                Err(e) => return Err(e.context(FlushErrorKind::SendTo).into()),
+            };
+
+            if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+                // the message is sent again if the friend went offline while
+                // it was being sent
+                if friend.online && friend.messages.front().map(|queued| queued.receipt_id) == Some(message.receipt_id) {
+                    friend.messages.pop_front();
+                    friend.sent.push_back(SentMessage {
+                        message,
+                        packet_number,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Remove messages received by the friend from the outbox. Returns their
+    /// receipt IDs in the order they were sent.
+    pub fn check_receipts(&self, friend_pk: PublicKey) -> Vec<u32> {
+        let mut friends = self.friends.write();
+        let friend = match friends.get_mut(&friend_pk) {
+            Some(friend) if friend.online => friend,
+            _ => return Vec::new(),
+        };
+
+        let mut receipts = Vec::new();
+        while let Some(sent) = friend.sent.front() {
+            // packets are received in order so later messages can't be
+            // received before this one
+            if !self.net_crypto.is_packet_delivered(friend_pk, sent.packet_number).unwrap_or(false) {
+                break;
+            }
+            receipts.push(sent.message.receipt_id);
+            friend.sent.pop_front();
+        }
+        receipts
+    }
+
+    /// Get messages queued for the friend including sent ones that are not
+    /// received yet.
+    pub fn queued_messages(&self, friend_pk: &PublicKey) -> Vec<QueuedMessage> {
+        self.friends.read().get(friend_pk)
+            .map(|friend| friend.undelivered().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Remove the friend and drop all its queued messages.
+    pub fn remove_friend(&self, friend_pk: &PublicKey) {
+        self.friends.write().remove(friend_pk);
+    }
+
+    /// Get a snapshot of undelivered messages that can be persisted. Friends
+    /// without such messages are included as well to keep their receipt ID
+    /// counters.
+    pub fn state(&self) -> OutboxState {
+        let friends = self.friends.read().iter()
+            .map(|(&pk, friend)| FriendOutboxState {
+                pk,
+                next_receipt_id: friend.next_receipt_id,
+                messages: friend.undelivered().cloned().collect(),
+            })
+            .collect();
+        OutboxState {
+            friends,
+        }
+    }
+
+    /// Restore queued messages from a snapshot. Messages are added after
+    /// already queued ones. Receipt ID counter continues after both the
+    /// current and the restored counters and restored messages so that new
+    /// messages never get receipt IDs that are already used. Friends are
+    /// considered offline until `handle_online` is called.
+    pub fn load_state(&self, state: OutboxState) {
+        let mut friends = self.friends.write();
+        for friend_state in state.friends {
+            let friend = friends.entry(friend_state.pk).or_default();
+            let next_restored_id = friend_state.messages.iter()
+                .map(|message| message.receipt_id.wrapping_add(1))
+                .max()
+                .unwrap_or_default();
+            friend.next_receipt_id = friend.next_receipt_id
+                .max(friend_state.next_receipt_id)
+                .max(next_restored_id);
+            friend.messages.extend(friend_state.messages);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::channel::mpsc;
+    use futures::{FutureExt, StreamExt};
+
+    use crate::toxcore::dht::packet::Packet as DhtPacket;
+    use crate::toxcore::dht::precomputed_cache::*;
+    use crate::toxcore::net_crypto::*;
+
+    type UdpRx = mpsc::Receiver<(DhtPacket, std::net::SocketAddr)>;
+
+    encode_decode_test!(
+        queued_message_encode_decode,
+        QueuedMessage {
+            receipt_id: 42,
+            message_type: MessageType::Action,
+            text: "waves".to_string(),
+        }
+    );
+
+    encode_decode_test!(
+        friend_outbox_state_encode_decode,
+        FriendOutboxState {
+            pk: gen_keypair().0,
+            next_receipt_id: 3,
+            messages: vec![
+                QueuedMessage {
+                    receipt_id: 1,
+                    message_type: MessageType::Normal,
+                    text: "hello".to_string(),
+                },
+                QueuedMessage {
+                    receipt_id: 2,
+                    message_type: MessageType::Action,
+                    text: "waves".to_string(),
+                },
+            ],
+        }
+    );
+
+    encode_decode_test!(
+        outbox_state_encode_decode,
+        OutboxState {
+            friends: vec![
+                FriendOutboxState {
+                    pk: gen_keypair().0,
+                    next_receipt_id: 1,
+                    messages: vec![
+                        QueuedMessage {
+                            receipt_id: 0,
+                            message_type: MessageType::Normal,
+                            text: "hello".to_string(),
+                        },
+                    ],
+                },
+                FriendOutboxState {
+                    pk: gen_keypair().0,
+                    next_receipt_id: 0,
+                    messages: Vec::new(),
+                },
+            ],
+        }
+    );
+
+    #[test]
+    fn message_type_from_bytes_invalid() {
+        assert!(MessageType::from_bytes(&[2]).is_err());
+    }
+
+    fn create_outbox() -> (Outbox, UdpRx) {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(4);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+        (Outbox::new(net_crypto), udp_rx)
+    }
+
+    #[tokio::test]
+    async fn send_message_empty() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        let error = outbox.send_message(friend_pk, MessageType::Normal, String::new()).await.err().unwrap();
+        assert_eq!(*error.kind(), SendMessageErrorKind::Empty);
+        assert!(outbox.queued_messages(&friend_pk).is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_message_too_long() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        let text = "1".repeat(MAX_MESSAGE_PACKET_SIZE);
+        let error = outbox.send_message(friend_pk, MessageType::Normal, text).await.err().unwrap();
+        assert_eq!(*error.kind(), SendMessageErrorKind::TooLong);
+        assert!(outbox.queued_messages(&friend_pk).is_empty());
+    }
+
+    #[tokio::test]
+    async fn send_message_offline() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        assert_eq!(outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap(), 0);
+        assert_eq!(outbox.send_message(friend_pk, MessageType::Action, "waves".to_string()).await.unwrap(), 1);
+
+        assert_eq!(outbox.queued_messages(&friend_pk), vec![
+            QueuedMessage {
+                receipt_id: 0,
+                message_type: MessageType::Normal,
+                text: "hello".to_string(),
+            },
+            QueuedMessage {
+                receipt_id: 1,
+                message_type: MessageType::Action,
+                text: "waves".to_string(),
+            },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn handle_online_flush_in_order() {
+        let (outbox, udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+        outbox.send_message(friend_pk, MessageType::Action, "waves".to_string()).await.unwrap();
+
+        let mut sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        outbox.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            sent_nonce,
+            gen_nonce(),
+            session_precomputed_key.clone()
+        );
+        outbox.net_crypto.set_friend_udp_addr(friend_pk, "127.0.0.1:12345".parse().unwrap());
+
+        outbox.handle_online(friend_pk).await.unwrap();
+
+        // messages are kept until the friend receives them
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 2);
+        assert!(outbox.check_receipts(friend_pk).is_empty());
+
+        let received = udp_rx.take(2).map(|(packet, _addr)| {
+            let packet = unpack!(packet, DhtPacket::CryptoData);
+            let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+            increment_nonce(&mut sent_nonce);
+            payload.data
+        }).collect::<Vec<_>>().await;
+
+        let (_, message) = Packet::from_bytes(&received[0]).unwrap();
+        assert_eq!(message, Packet::Message(Message::new("hello".to_string())));
+        let (_, action) = Packet::from_bytes(&received[1]).unwrap();
+        assert_eq!(action, Packet::Action(Action::new("waves".to_string())));
+
+        outbox.net_crypto.ack_sent_packets(&friend_pk, 1);
+        assert_eq!(outbox.check_receipts(friend_pk), vec![0]);
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 1);
+
+        outbox.net_crypto.ack_sent_packets(&friend_pk, 2);
+        assert_eq!(outbox.check_receipts(friend_pk), vec![1]);
+        assert!(outbox.queued_messages(&friend_pk).is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_offline_requeue_sent() {
+        let (outbox, mut udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        outbox.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            sent_nonce,
+            gen_nonce(),
+            session_precomputed_key.clone()
+        );
+        outbox.net_crypto.set_friend_udp_addr(friend_pk, "127.0.0.1:12345".parse().unwrap());
+
+        outbox.handle_online(friend_pk).await.unwrap();
+        udp_rx.next().await.unwrap();
+
+        // the friend went offline before it received the message
+        outbox.handle_offline(friend_pk);
+        assert!(outbox.check_receipts(friend_pk).is_empty());
+        assert_eq!(outbox.state().friends[0].messages.len(), 1);
+
+        // so the message is sent again via the new connection
+        outbox.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            sent_nonce,
+            gen_nonce(),
+            session_precomputed_key.clone()
+        );
+        outbox.net_crypto.set_friend_udp_addr(friend_pk, "127.0.0.1:12345".parse().unwrap());
+        outbox.handle_online(friend_pk).await.unwrap();
+
+        let (packet, _addr) = udp_rx.next().await.unwrap();
+        let packet = unpack!(packet, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.packet_number, 0);
+        let (_, message) = Packet::from_bytes(&payload.data).unwrap();
+        assert_eq!(message, Packet::Message(Message::new("hello".to_string())));
+
+        outbox.net_crypto.ack_sent_packets(&friend_pk, 1);
+        assert_eq!(outbox.check_receipts(friend_pk), vec![0]);
+        assert!(outbox.queued_messages(&friend_pk).is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_online_no_connection() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+
+        outbox.handle_online(friend_pk).await.unwrap();
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 1);
+
+        // the friend is considered offline again so new messages are queued
+        assert_eq!(outbox.send_message(friend_pk, MessageType::Normal, "world".to_string()).await.unwrap(), 1);
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn remove_friend() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+        outbox.remove_friend(&friend_pk);
+
+        assert!(outbox.queued_messages(&friend_pk).is_empty());
+        assert!(outbox.state().friends.is_empty());
+    }
+
+    #[tokio::test]
+    async fn state_load_state() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+        outbox.send_message(friend_pk, MessageType::Action, "waves".to_string()).await.unwrap();
+
+        let state = outbox.state();
+        let mut buf = [0; 256];
+        let (_, size) = state.to_bytes((&mut buf, 0)).unwrap();
+        let (_, restored_state) = OutboxState::from_bytes(&buf[..size]).unwrap();
+
+        let (restored_outbox, _udp_rx) = create_outbox();
+        restored_outbox.load_state(restored_state);
+
+        assert_eq!(restored_outbox.queued_messages(&friend_pk), outbox.queued_messages(&friend_pk));
+        // receipt IDs continue after restored messages
+        assert_eq!(restored_outbox.send_message(friend_pk, MessageType::Normal, "world".to_string()).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn state_without_messages() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+        outbox.friends.write().get_mut(&friend_pk).unwrap().messages.clear();
+
+        // the counter is kept even when all messages were sent
+        assert_eq!(outbox.state(), OutboxState {
+            friends: vec![
+                FriendOutboxState {
+                    pk: friend_pk,
+                    next_receipt_id: 1,
+                    messages: Vec::new(),
+                },
+            ],
+        });
+    }
+
+    #[tokio::test]
+    async fn load_state_no_duplicate_receipt_ids() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+
+        outbox.load_state(OutboxState {
+            friends: vec![
+                FriendOutboxState {
+                    pk: friend_pk,
+                    next_receipt_id: 0,
+                    messages: vec![
+                        QueuedMessage {
+                            receipt_id: 5,
+                            message_type: MessageType::Normal,
+                            text: "world".to_string(),
+                        },
+                    ],
+                },
+            ],
+        });
+
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 2);
+        // receipt IDs continue after the highest restored receipt ID
+        assert_eq!(outbox.send_message(friend_pk, MessageType::Normal, "!".to_string()).await.unwrap(), 6);
+
+        outbox.load_state(OutboxState {
+            friends: vec![
+                FriendOutboxState {
+                    pk: friend_pk,
+                    next_receipt_id: 10,
+                    messages: Vec::new(),
+                },
+            ],
+        });
+
+        assert_eq!(outbox.send_message(friend_pk, MessageType::Normal, "!".to_string()).await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn flush_send_queue_full() {
+        let (outbox, _udp_rx) = create_outbox();
+        let friend_pk = gen_keypair().0;
+
+        outbox.send_message(friend_pk, MessageType::Normal, "hello".to_string()).await.unwrap();
+
+        outbox.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            gen_nonce(),
+            gen_nonce(),
+            precompute(&gen_keypair().0, &gen_keypair().1)
+        );
+        outbox.net_crypto.fill_send_queue(&friend_pk, PacketPriority::Lossless);
+
+        // flushing waits for free space in the send queue
+        assert!(outbox.handle_online(friend_pk).now_or_never().is_none());
+
+        // the message stays queued and the dropped flush doesn't prevent
+        // next flushes
+        assert_eq!(outbox.queued_messages(&friend_pk).len(), 1);
+        assert!(!outbox.friends.read()[&friend_pk].flushing);
+    }
+}
@@ -52,6 +52,11 @@ impl Nickname {
     pub fn new(nickname: String) -> Self {
         Nickname { nickname }
     }
+
+    /// Get the nickname.
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
 }
 
 #[cfg(test)]
@@ -52,6 +52,11 @@ impl StatusMessage {
     pub fn new(message: String) -> Self {
         StatusMessage(message)
     }
+
+    /// Get the status message.
+    pub fn message(&self) -> &str {
+        &self.0
+    }
 }
 
 #[cfg(test)]
@@ -60,6 +60,11 @@ impl Typing {
     pub fn new(status: TypingStatus) -> Self {
         Typing(status)
     }
+
+    /// Get the typing status.
+    pub fn status(&self) -> TypingStatus {
+        self.0
+    }
 }
 
 #[cfg(test)]
@@ -65,6 +65,11 @@ impl UserStatus {
     pub fn new(status: PeerStatus) -> Self {
         UserStatus(status)
     }
+
+    /// Get the status.
+    pub fn status(&self) -> PeerStatus {
+        self.0
+    }
 }
 
 #[cfg(test)]
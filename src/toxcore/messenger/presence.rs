@@ -0,0 +1,863 @@
+/*! Presence of us and our friends.
+
+Our name, status, status message and typing flags are sent to every friend
+that becomes connected so that friends always see our current state. Changes
+are sent to connected friends by the main loop. An update is marked as sent
+only when its packet is sent and is still up to date, otherwise the main loop
+sends it again. Typing flags are debounced so that fast typing changes don't
+flood friends with packets.
+
+Presence packets received from friends are tracked and reported as
+`PresenceEvent`s when they change.
+*/
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::{FutureExt, StreamExt, future};
+use futures::channel::mpsc;
+use parking_lot::RwLock;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::messenger::errors::*;
+use crate::toxcore::messenger::packet::*;
+use crate::toxcore::net_crypto::{NetCrypto, ConnectionEvent};
+use crate::toxcore::net_crypto::errors::SendLosslessPacketErrorKind;
+use crate::toxcore::time::*;
+
+/// Shorthand for the transmit half of the message channel for sending
+/// presence events. The key is a long term key of the friend.
+type PresenceEventTx = mpsc::UnboundedSender<(PublicKey, PresenceEvent)>;
+
+/// Maximum size in bytes of a presence packet.
+const MAX_PRESENCE_PACKET_SIZE: usize = 1008;
+
+/// Typing flag is sent to a friend only when it wasn't changed for this
+/// amount of time.
+const TYPING_DEBOUNCE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Interval in which presence updates are sent to friends.
+const MAIN_LOOP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Presence of a friend as it's known from received packets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PresenceInfo {
+    /// Whether the friend sent us `Online` packet and is not offline since.
+    pub online: bool,
+    /// Friend's name.
+    pub name: String,
+    /// Friend's status.
+    pub status: PeerStatus,
+    /// Friend's status message.
+    pub status_message: String,
+    /// Whether the friend is typing a message to us.
+    pub typing: bool,
+}
+
+impl Default for PresenceInfo {
+    fn default() -> Self {
+        PresenceInfo {
+            online: false,
+            name: String::new(),
+            status: PeerStatus::Online,
+            status_message: String::new(),
+            typing: false,
+        }
+    }
+}
+
+/// Change of a friend's presence.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PresenceEvent {
+    /// The friend became online.
+    Online,
+    /// The friend became offline either explicitly or because the connection
+    /// was lost.
+    Offline,
+    /// The friend changed its name.
+    NameChanged(String),
+    /// The friend changed its status.
+    StatusChanged(PeerStatus),
+    /// The friend changed its status message.
+    StatusMessageChanged(String),
+    /// The friend started or stopped typing.
+    TypingChanged(bool),
+}
+
+/// Our own presence that is sent to all friends.
+#[derive(Clone, Debug)]
+struct OwnPresence {
+    /// Our name.
+    name: String,
+    /// Our status.
+    status: PeerStatus,
+    /// Our status message.
+    status_message: String,
+}
+
+/// Presence packet that is going to be sent to a friend.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum PresenceUpdate {
+    Online,
+    Name,
+    StatusMessage,
+    Status,
+    Typing,
+}
+
+/// Presence state of a friend.
+#[derive(Clone, Debug)]
+struct FriendPresence {
+    /// Whether we have established connection with the friend.
+    connected: bool,
+    /// Whether `Online` packet was sent since the connection was established.
+    online_sent: bool,
+    /// Whether our current name was sent to the friend.
+    name_sent: bool,
+    /// Whether our current status message was sent to the friend.
+    status_message_sent: bool,
+    /// Whether our current status was sent to the friend.
+    status_sent: bool,
+    /// Whether we are typing a message to the friend.
+    typing: bool,
+    /// Typing flag that was sent to the friend last time.
+    sent_typing: bool,
+    /// Time when our typing flag was changed last time.
+    typing_changed_time: Instant,
+    /// Friend's presence received from the friend.
+    info: PresenceInfo,
+}
+
+impl FriendPresence {
+    /// Create new `FriendPresence`.
+    fn new() -> Self {
+        FriendPresence {
+            connected: false,
+            online_sent: false,
+            name_sent: false,
+            status_message_sent: false,
+            status_sent: false,
+            typing: false,
+            sent_typing: false,
+            typing_changed_time: clock_now(),
+            info: PresenceInfo::default(),
+        }
+    }
+
+    /// Mark everything as not sent so that our presence will be sent to the
+    /// friend again.
+    fn resync(&mut self) {
+        self.online_sent = false;
+        self.name_sent = false;
+        self.status_message_sent = false;
+        self.status_sent = false;
+        // not typing is the default state so it's not worth sending
+        self.sent_typing = false;
+    }
+
+    /// Get current packet for the update.
+    fn update_packet(&self, update: PresenceUpdate, own: &OwnPresence) -> Packet {
+        match update {
+            PresenceUpdate::Online => Packet::Online(Online),
+            PresenceUpdate::Name => Packet::Nickname(Nickname::new(own.name.clone())),
+            PresenceUpdate::StatusMessage => Packet::StatusMessage(StatusMessage::new(own.status_message.clone())),
+            PresenceUpdate::Status => Packet::UserStatus(UserStatus::new(own.status)),
+            PresenceUpdate::Typing => {
+                let status = if self.typing { TypingStatus::Typing } else { TypingStatus::NotTyping };
+                Packet::Typing(Typing::new(status))
+            },
+        }
+    }
+
+    /// Get presence updates that should be sent to the friend. They are not
+    /// marked as sent until their packets are sent.
+    fn pending_updates(&self, own: &OwnPresence) -> Vec<(PresenceUpdate, Packet)> {
+        let mut updates = Vec::new();
+        if !self.online_sent {
+            updates.push(PresenceUpdate::Online);
+        }
+        if !self.name_sent {
+            updates.push(PresenceUpdate::Name);
+        }
+        if !self.status_message_sent {
+            updates.push(PresenceUpdate::StatusMessage);
+        }
+        if !self.status_sent {
+            updates.push(PresenceUpdate::Status);
+        }
+        if self.sent_typing != self.typing && clock_elapsed(self.typing_changed_time) >= TYPING_DEBOUNCE_INTERVAL {
+            updates.push(PresenceUpdate::Typing);
+        }
+        updates.into_iter()
+            .map(|update| (update, self.update_packet(update, own)))
+            .collect()
+    }
+
+    /// Mark the update as sent if the sent packet is still up to date. Packet
+    /// that became outdated while it was being sent will be sent again.
+    fn mark_sent(&mut self, update: PresenceUpdate, packet: &Packet, own: &OwnPresence) {
+        if self.update_packet(update, own) != *packet {
+            return;
+        }
+
+        match update {
+            PresenceUpdate::Online => self.online_sent = true,
+            PresenceUpdate::Name => self.name_sent = true,
+            PresenceUpdate::StatusMessage => self.status_message_sent = true,
+            PresenceUpdate::Status => self.status_sent = true,
+            PresenceUpdate::Typing => self.sent_typing = self.typing,
+        }
+    }
+}
+
+/// Serialize presence packet.
+fn packet_to_bytes(packet: &Packet) -> Result<Vec<u8>, GenError> {
+    let mut buf = vec![0; MAX_PRESENCE_PACKET_SIZE];
+    let (_, size) = packet.to_bytes((&mut buf, 0))?;
+    buf.truncate(size);
+    Ok(buf)
+}
+
+/// Presence module that keeps our presence in sync with friends and tracks
+/// presence of friends.
+#[derive(Clone)]
+pub struct Presence {
+    /// Our own presence.
+    own: Arc<RwLock<OwnPresence>>,
+    /// Presence state by friend's long term `PublicKey`.
+    friends: Arc<RwLock<HashMap<PublicKey, FriendPresence>>>,
+    /// Net crypto module to send presence packets.
+    net_crypto: NetCrypto,
+    /// Sink to send presence events.
+    presence_event_tx: Arc<RwLock<Option<PresenceEventTx>>>,
+}
+
+impl Presence {
+    /// Create new `Presence`.
+    pub fn new(net_crypto: NetCrypto) -> Self {
+        Presence {
+            own: Arc::new(RwLock::new(OwnPresence {
+                name: String::new(),
+                status: PeerStatus::Online,
+                status_message: String::new(),
+            })),
+            friends: Arc::new(RwLock::new(HashMap::new())),
+            net_crypto,
+            presence_event_tx: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Set sink to send presence events.
+    pub fn set_presence_event_sink(&self, presence_event_tx: PresenceEventTx) {
+        *self.presence_event_tx.write() = Some(presence_event_tx);
+    }
+
+    /// Send presence event to the sink if it's set.
+    fn send_presence_event(&self, friend_pk: PublicKey, event: PresenceEvent) {
+        if let Some(ref tx) = *self.presence_event_tx.read() {
+            if tx.unbounded_send((friend_pk, event)).is_err() {
+                trace!("Presence events receiver is dropped");
+            }
+        }
+    }
+
+    /// Add a friend to track its presence.
+    pub fn add_friend(&self, friend_pk: PublicKey) {
+        self.friends.write().entry(friend_pk).or_insert_with(FriendPresence::new);
+    }
+
+    /// Remove a friend. `Offline` packet is sent to the friend if we are
+    /// connected so that the friend sees us offline immediately.
+    pub async fn remove_friend(&self, friend_pk: PublicKey) -> Result<(), RemoveFriendError> {
+        let connected = self.friends.write().remove(&friend_pk)
+            .map_or(false, |friend| friend.connected);
+        if !connected {
+            return Ok(());
+        }
+
+        // packet without fields can't fail to serialize
+        let packet = packet_to_bytes(&Packet::Offline(Offline)).unwrap_or_default();
+        self.net_crypto.send_lossless(friend_pk, packet).await
+            .map_err(|e| e.context(RemoveFriendErrorKind::SendTo).into())
+    }
+
+    /// Send `Offline` packet to all connected friends so that they see us
+    /// offline immediately, e.g. when we are going offline or shutting down.
+    /// Packets that fail to send are not retried since the friends will see
+    /// us offline anyway when the connection is lost.
+    pub async fn send_offline(&self) {
+        let friends = self.friends.read().iter()
+            .filter(|(_, friend)| friend.connected)
+            .map(|(&friend_pk, _)| friend_pk)
+            .collect::<Vec<_>>();
+
+        // packet without fields can't fail to serialize
+        let packet = packet_to_bytes(&Packet::Offline(Offline)).unwrap_or_default();
+        let futures = friends.into_iter().map(|friend_pk|
+            self.net_crypto.send_lossless(friend_pk, packet.clone()).map(move |res|
+                if let Err(e) = res {
+                    debug!("Failed to send offline packet to {:?}: {}", friend_pk, e);
+                }
+            )
+        );
+        future::join_all(futures).await;
+    }
+
+    /// Get presence of a friend.
+    pub fn friend_presence(&self, friend_pk: &PublicKey) -> Option<PresenceInfo> {
+        self.friends.read().get(friend_pk).map(|friend| friend.info.clone())
+    }
+
+    /// Set our name. It will be sent to all connected friends.
+    pub fn set_name(&self, name: String) -> Result<(), SetPresenceError> {
+        if packet_to_bytes(&Packet::Nickname(Nickname::new(name.clone()))).is_err() {
+            return Err(SetPresenceErrorKind::TooLong.into());
+        }
+
+        self.own.write().name = name;
+        for friend in self.friends.write().values_mut() {
+            friend.name_sent = false;
+        }
+        Ok(())
+    }
+
+    /// Set our status. It will be sent to all connected friends.
+    pub fn set_status(&self, status: PeerStatus) {
+        self.own.write().status = status;
+        for friend in self.friends.write().values_mut() {
+            friend.status_sent = false;
+        }
+    }
+
+    /// Set our status message. It will be sent to all connected friends.
+    pub fn set_status_message(&self, status_message: String) -> Result<(), SetPresenceError> {
+        if packet_to_bytes(&Packet::StatusMessage(StatusMessage::new(status_message.clone()))).is_err() {
+            return Err(SetPresenceErrorKind::TooLong.into());
+        }
+
+        self.own.write().status_message = status_message;
+        for friend in self.friends.write().values_mut() {
+            friend.status_message_sent = false;
+        }
+        Ok(())
+    }
+
+    /// Set whether we are typing a message to a friend. The flag is sent to
+    /// the friend when it stays unchanged for `TYPING_DEBOUNCE_INTERVAL`.
+    pub fn set_typing(&self, friend_pk: PublicKey, typing: bool) -> Result<(), SetPresenceError> {
+        let mut friends = self.friends.write();
+        let friend = friends.get_mut(&friend_pk)
+            .ok_or(SetPresenceErrorKind::NoFriend)?;
+        // flag that was changed back before it was sent doesn't need to be
+        // sent at all since it equals to the sent one
+        if friend.typing != typing {
+            friend.typing = typing;
+            friend.typing_changed_time = clock_now();
+        }
+        Ok(())
+    }
+
+    /// Handle connection event of a friend. When connection is established
+    /// our whole presence is sent to the friend. When it's lost the friend is
+    /// considered offline.
+    pub fn handle_connection_event(&self, friend_pk: PublicKey, event: &ConnectionEvent) {
+        let mut friends = self.friends.write();
+        let friend = if let Some(friend) = friends.get_mut(&friend_pk) {
+            friend
+        } else {
+            return;
+        };
+
+        match *event {
+            ConnectionEvent::Connected(_) => {
+                friend.connected = true;
+                friend.resync();
+            },
+            ConnectionEvent::Disconnected(_) => {
+                friend.connected = false;
+                let was_online = friend.info.online;
+                friend.info.online = false;
+                friend.info.typing = false;
+                drop(friends);
+                if was_online {
+                    self.send_presence_event(friend_pk, PresenceEvent::Offline);
+                }
+            },
+            ConnectionEvent::TransportChanged(_) => { },
+        }
+    }
+
+    /// Handle presence packet received from a friend. Other packets are
+    /// ignored.
+    pub fn handle_packet(&self, friend_pk: PublicKey, packet: &Packet) {
+        let mut friends = self.friends.write();
+        let info = if let Some(friend) = friends.get_mut(&friend_pk) {
+            &mut friend.info
+        } else {
+            trace!("Received presence packet from unknown friend {:?}", friend_pk);
+            return;
+        };
+
+        let event = match *packet {
+            Packet::Online(_) if !info.online => {
+                info.online = true;
+                PresenceEvent::Online
+            },
+            Packet::Offline(_) if info.online => {
+                info.online = false;
+                info.typing = false;
+                PresenceEvent::Offline
+            },
+            Packet::Nickname(ref nickname) if info.name != nickname.nickname() => {
+                info.name = nickname.nickname().to_string();
+                PresenceEvent::NameChanged(info.name.clone())
+            },
+            Packet::StatusMessage(ref message) if info.status_message != message.message() => {
+                info.status_message = message.message().to_string();
+                PresenceEvent::StatusMessageChanged(info.status_message.clone())
+            },
+            Packet::UserStatus(ref status) if info.status != status.status() => {
+                info.status = status.status();
+                PresenceEvent::StatusChanged(info.status)
+            },
+            Packet::Typing(ref typing) if info.typing != (typing.status() == TypingStatus::Typing) => {
+                info.typing = typing.status() == TypingStatus::Typing;
+                PresenceEvent::TypingChanged(info.typing)
+            },
+            _ => return,
+        };
+
+        drop(friends);
+        self.send_presence_event(friend_pk, event);
+    }
+
+    /// Send presence updates to a friend in order marking every update as
+    /// sent when its packet is sent. When a packet fails to send it and all
+    /// following packets are left not sent to be retried on the next
+    /// iteration.
+    async fn send_updates(&self, friend_pk: PublicKey, updates: Vec<(PresenceUpdate, Packet)>) {
+        for (update, packet) in updates {
+            // presence is checked when it's set
+            let bytes = packet_to_bytes(&packet).unwrap_or_default();
+            match self.net_crypto.send_lossless(friend_pk, bytes).await {
+                // when sending fails after the packet was added to the send
+                // array it will be resent by net_crypto
+                Ok(()) => (),
+                Err(ref e) if *e.kind() == SendLosslessPacketErrorKind::SendTo => (),
+                Err(e) => {
+                    debug!("Failed to send presence to {:?}: {}", friend_pk, e);
+                    return;
+                },
+            }
+
+            let own = self.own.read();
+            if let Some(friend) = self.friends.write().get_mut(&friend_pk) {
+                friend.mark_sent(update, &packet, &own);
+            }
+        }
+    }
+
+    /// Send presence updates to all connected friends.
+    async fn main_loop(&self) {
+        let updates = {
+            let own = self.own.read();
+            let friends = self.friends.read();
+            friends.iter()
+                .filter(|(_, friend)| friend.connected)
+                .map(|(&friend_pk, friend)| (friend_pk, friend.pending_updates(&own)))
+                .filter(|(_, updates)| !updates.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let futures = updates.into_iter()
+            .map(|(friend_pk, updates)| self.send_updates(friend_pk, updates));
+        future::join_all(futures).await;
+    }
+
+    /// Run presence module. This will send presence updates to friends
+    /// periodically. An iteration that doesn't finish in time is dropped and
+    /// its unsent updates are sent on the next one.
+    pub async fn run(self) -> Result<(), RunError> {
+        let mut wakeups = tokio::time::interval(MAIN_LOOP_INTERVAL);
+
+        while let Some(_) = wakeups.next().await {
+            let fut = tokio::time::timeout(MAIN_LOOP_INTERVAL, self.main_loop());
+            if let Err(e) = fut.await {
+                warn!("Failed to send presence updates: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::dht::packet::Packet as DhtPacket;
+    use crate::toxcore::dht::precomputed_cache::*;
+    use crate::toxcore::net_crypto::*;
+
+    type UdpRx = mpsc::Receiver<(DhtPacket, std::net::SocketAddr)>;
+
+    fn create_presence() -> (Presence, UdpRx) {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(8);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+        (Presence::new(net_crypto), udp_rx)
+    }
+
+    /// Add established connection to the friend and return the key and the
+    /// nonce to decrypt sent packets.
+    fn add_connection(presence: &Presence, friend_pk: PublicKey) -> (PrecomputedKey, Nonce) {
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        presence.net_crypto.add_established_connection(
+            gen_keypair().0,
+            friend_pk,
+            sent_nonce,
+            gen_nonce(),
+            session_precomputed_key.clone()
+        );
+        presence.net_crypto.set_friend_udp_addr(friend_pk, "127.0.0.1:12345".parse().unwrap());
+        presence.handle_connection_event(friend_pk, &ConnectionEvent::Connected(ConnectionTransport::Tcp));
+        (session_precomputed_key, sent_nonce)
+    }
+
+    /// Receive and decrypt sent messenger packets.
+    async fn receive_packets(udp_rx: &mut UdpRx, count: usize, key: &PrecomputedKey, nonce: &mut Nonce) -> Vec<Packet> {
+        udp_rx.take(count).map(|(packet, _addr)| {
+            let packet = unpack!(packet, DhtPacket::CryptoData);
+            let payload = packet.get_payload(key, nonce).unwrap();
+            increment_nonce(nonce);
+            Packet::from_bytes(&payload.data).unwrap().1
+        }).collect().await
+    }
+
+    #[test]
+    fn set_name_too_long() {
+        let (presence, _udp_rx) = create_presence();
+
+        let error = presence.set_name("1".repeat(MAX_PRESENCE_PACKET_SIZE)).err().unwrap();
+        assert_eq!(*error.kind(), SetPresenceErrorKind::TooLong);
+    }
+
+    #[test]
+    fn set_status_message_too_long() {
+        let (presence, _udp_rx) = create_presence();
+
+        let error = presence.set_status_message("1".repeat(MAX_PRESENCE_PACKET_SIZE)).err().unwrap();
+        assert_eq!(*error.kind(), SetPresenceErrorKind::TooLong);
+    }
+
+    #[test]
+    fn set_typing_no_friend() {
+        let (presence, _udp_rx) = create_presence();
+
+        let error = presence.set_typing(gen_keypair().0, true).err().unwrap();
+        assert_eq!(*error.kind(), SetPresenceErrorKind::NoFriend);
+    }
+
+    #[test]
+    fn handle_packet() {
+        let (presence, _udp_rx) = create_presence();
+        let (event_tx, mut event_rx) = mpsc::unbounded();
+        presence.set_presence_event_sink(event_tx);
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+
+        presence.handle_packet(friend_pk, &Packet::Online(Online));
+        presence.handle_packet(friend_pk, &Packet::Nickname(Nickname::new("Alice".to_string())));
+        presence.handle_packet(friend_pk, &Packet::StatusMessage(StatusMessage::new("Happy!".to_string())));
+        presence.handle_packet(friend_pk, &Packet::UserStatus(UserStatus::new(PeerStatus::Busy)));
+        presence.handle_packet(friend_pk, &Packet::Typing(Typing::new(TypingStatus::Typing)));
+        // unchanged presence doesn't produce events
+        presence.handle_packet(friend_pk, &Packet::Nickname(Nickname::new("Alice".to_string())));
+        presence.handle_packet(friend_pk, &Packet::Offline(Offline));
+
+        assert_eq!(presence.friend_presence(&friend_pk), Some(PresenceInfo {
+            online: false,
+            name: "Alice".to_string(),
+            status: PeerStatus::Busy,
+            status_message: "Happy!".to_string(),
+            typing: false,
+        }));
+
+        let events = (0 .. 6).map(|_| event_rx.try_recv().unwrap()).collect::<Vec<_>>();
+        assert_eq!(events, vec![
+            (friend_pk, PresenceEvent::Online),
+            (friend_pk, PresenceEvent::NameChanged("Alice".to_string())),
+            (friend_pk, PresenceEvent::StatusMessageChanged("Happy!".to_string())),
+            (friend_pk, PresenceEvent::StatusChanged(PeerStatus::Busy)),
+            (friend_pk, PresenceEvent::TypingChanged(true)),
+            (friend_pk, PresenceEvent::Offline),
+        ]);
+        assert!(event_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn handle_connection_event_disconnected() {
+        let (presence, _udp_rx) = create_presence();
+        let (event_tx, mut event_rx) = mpsc::unbounded();
+        presence.set_presence_event_sink(event_tx);
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        presence.handle_connection_event(friend_pk, &ConnectionEvent::Connected(ConnectionTransport::Tcp));
+        presence.handle_packet(friend_pk, &Packet::Online(Online));
+        presence.handle_connection_event(friend_pk, &ConnectionEvent::Disconnected(DisconnectReason::Timeout));
+
+        assert!(!presence.friend_presence(&friend_pk).unwrap().online);
+        assert_eq!(event_rx.try_recv().unwrap(), (friend_pk, PresenceEvent::Online));
+        assert_eq!(event_rx.try_recv().unwrap(), (friend_pk, PresenceEvent::Offline));
+    }
+
+    #[tokio::test]
+    async fn main_loop_resync_on_connected() {
+        let (presence, mut udp_rx) = create_presence();
+        presence.set_name("Bob".to_string()).unwrap();
+        presence.set_status_message("Busy!".to_string()).unwrap();
+        presence.set_status(PeerStatus::Busy);
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        presence.main_loop().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 4, &key, &mut nonce).await, vec![
+            Packet::Online(Online),
+            Packet::Nickname(Nickname::new("Bob".to_string())),
+            Packet::StatusMessage(StatusMessage::new("Busy!".to_string())),
+            Packet::UserStatus(UserStatus::new(PeerStatus::Busy)),
+        ]);
+
+        // nothing is sent when presence is unchanged
+        presence.main_loop().await;
+        assert!(udp_rx.try_recv().is_err());
+
+        presence.set_name("Robert".to_string()).unwrap();
+        presence.main_loop().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Nickname(Nickname::new("Robert".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn main_loop_not_connected() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        presence.set_name("Bob".to_string()).unwrap();
+
+        presence.main_loop().await;
+
+        assert!(udp_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn main_loop_retry_failed() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        // connection is established but net_crypto doesn't know about it
+        presence.handle_connection_event(friend_pk, &ConnectionEvent::Connected(ConnectionTransport::Tcp));
+
+        presence.main_loop().await;
+
+        let friends = presence.friends.read();
+        let friend = &friends[&friend_pk];
+        assert!(!friend.online_sent);
+        assert!(!friend.name_sent);
+        assert!(!friend.status_message_sent);
+        assert!(!friend.status_sent);
+        assert!(udp_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn send_updates_outdated() {
+        let (presence, mut udp_rx) = create_presence();
+        presence.set_name("Bob".to_string()).unwrap();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        let updates = {
+            let own = presence.own.read();
+            presence.friends.read()[&friend_pk].pending_updates(&own)
+        };
+        // name is changed while updates are being sent
+        presence.set_name("Robert".to_string()).unwrap();
+        presence.send_updates(friend_pk, updates).await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 4, &key, &mut nonce).await, vec![
+            Packet::Online(Online),
+            Packet::Nickname(Nickname::new("Bob".to_string())),
+            Packet::StatusMessage(StatusMessage::new(String::new())),
+            Packet::UserStatus(UserStatus::new(PeerStatus::Online)),
+        ]);
+
+        {
+            let friends = presence.friends.read();
+            let friend = &friends[&friend_pk];
+            assert!(friend.online_sent);
+            assert!(!friend.name_sent);
+            assert!(friend.status_message_sent);
+            assert!(friend.status_sent);
+        }
+
+        // the new name is sent on the next iteration
+        presence.main_loop().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Nickname(Nickname::new("Robert".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn main_loop_typing_debounce() {
+        tokio::time::pause();
+
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        presence.main_loop().await;
+        receive_packets(&mut udp_rx, 4, &key, &mut nonce).await;
+
+        presence.set_typing(friend_pk, true).unwrap();
+        presence.main_loop().await;
+        assert!(udp_rx.try_recv().is_err());
+
+        // changing the flag back cancels sending
+        presence.set_typing(friend_pk, false).unwrap();
+        tokio::time::advance(TYPING_DEBOUNCE_INTERVAL).await;
+        presence.main_loop().await;
+        assert!(udp_rx.try_recv().is_err());
+
+        presence.set_typing(friend_pk, true).unwrap();
+        tokio::time::advance(TYPING_DEBOUNCE_INTERVAL).await;
+        presence.main_loop().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Typing(Typing::new(TypingStatus::Typing)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn remove_friend_send_offline() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        presence.remove_friend(friend_pk).await.unwrap();
+
+        assert!(presence.friend_presence(&friend_pk).is_none());
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Offline(Offline),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let (presence, mut udp_rx) = create_presence();
+        presence.set_name("Bob".to_string()).unwrap();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        tokio::spawn(presence.clone().run());
+
+        assert_eq!(receive_packets(&mut udp_rx, 4, &key, &mut nonce).await, vec![
+            Packet::Online(Online),
+            Packet::Nickname(Nickname::new("Bob".to_string())),
+            Packet::StatusMessage(StatusMessage::new(String::new())),
+            Packet::UserStatus(UserStatus::new(PeerStatus::Online)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn send_offline() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        let (key, mut nonce) = add_connection(&presence, friend_pk);
+
+        // not connected friends are skipped
+        let another_friend_pk = gen_keypair().0;
+        presence.add_friend(another_friend_pk);
+
+        presence.send_offline().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Offline(Offline),
+        ]);
+        assert!(udp_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn send_offline_send_failed() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+        // connection is established but net_crypto doesn't know about it
+        presence.handle_connection_event(friend_pk, &ConnectionEvent::Connected(ConnectionTransport::Tcp));
+
+        let another_friend_pk = gen_keypair().0;
+        presence.add_friend(another_friend_pk);
+        let (key, mut nonce) = add_connection(&presence, another_friend_pk);
+
+        // failure for one friend doesn't prevent sending to others
+        presence.send_offline().await;
+
+        assert_eq!(receive_packets(&mut udp_rx, 1, &key, &mut nonce).await, vec![
+            Packet::Offline(Offline),
+        ]);
+        assert!(udp_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_friend_not_connected() {
+        let (presence, mut udp_rx) = create_presence();
+
+        let friend_pk = gen_keypair().0;
+        presence.add_friend(friend_pk);
+
+        presence.remove_friend(friend_pk).await.unwrap();
+
+        assert!(presence.friend_presence(&friend_pk).is_none());
+        assert!(udp_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+/*! The storage of crypto connections with secondary indices by DHT `PublicKey`
+and by UDP address
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Index;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+
+use crate::toxcore::crypto_core::*;
+use super::crypto_connection::CryptoConnection;
+
+/// Crypto connections by long term `PublicKey` of the peer. Also stores indices
+/// by DHT `PublicKey` and by UDP addresses of the peer that are updated on
+/// every insertion and removal so that connections can be found without
+/// iterating over them.
+#[derive(Default)]
+pub struct Connections {
+    /// Connections by long term `PublicKey`
+    connections: HashMap<PublicKey, Arc<RwLock<CryptoConnection>>>,
+    /// Long term `PublicKey` by DHT `PublicKey`
+    keys_by_dht_pk: HashMap<PublicKey, PublicKey>,
+    /// DHT `PublicKey` by long term `PublicKey`. It's stored separately so
+    /// that connections don't need to be locked on removal.
+    dht_pks_by_key: HashMap<PublicKey, PublicKey>,
+    /// Long term `PublicKey` by UDP address of the peer. `SocketAddr` can't be
+    /// used as a key since it contains additional info for `IPv6` address.
+    keys_by_addr: HashMap<(IpAddr, /*port*/ u16), PublicKey>,
+    /// UDP addresses by long term `PublicKey` of the peer. It's stored
+    /// separately so that only addresses of the removed connection are
+    /// looked up on removal.
+    addrs_by_key: HashMap<PublicKey, HashSet<(IpAddr, /*port*/ u16)>>,
+}
+
+impl Connections {
+    /// Create new empty `Connections` object.
+    pub fn new() -> Connections {
+        Connections::default()
+    }
+
+    /// Check if there is a connection with specified long term `PublicKey`.
+    pub fn contains_key(&self, real_pk: &PublicKey) -> bool {
+        self.connections.contains_key(real_pk)
+    }
+
+    /// Get connection by long term `PublicKey`.
+    pub fn get(&self, real_pk: &PublicKey) -> Option<&Arc<RwLock<CryptoConnection>>> {
+        self.connections.get(real_pk)
+    }
+
+    /// Get connection by DHT `PublicKey`.
+    pub fn get_by_dht_pk(&self, dht_pk: &PublicKey) -> Option<&Arc<RwLock<CryptoConnection>>> {
+        self.keys_by_dht_pk.get(dht_pk).and_then(|real_pk| self.connections.get(real_pk))
+    }
+
+    /// Get long term `PublicKey` of the peer by its UDP address.
+    pub fn key_by_addr(&self, addr: SocketAddr) -> Option<PublicKey> {
+        self.keys_by_addr.get(&(addr.ip(), addr.port())).cloned()
+    }
+
+    /// Iterate over all stored connections.
+    pub fn values(&self) -> impl Iterator<Item = &Arc<RwLock<CryptoConnection>>> {
+        self.connections.values()
+    }
+
+    /// Insert connection with specified long term `PublicKey` replacing the
+    /// old one. Returns the replaced connection. The inserted connection must
+    /// not be locked for writing.
+    pub fn insert(&mut self, real_pk: PublicKey, connection: Arc<RwLock<CryptoConnection>>) -> Option<Arc<RwLock<CryptoConnection>>> {
+        let dht_pk = connection.read().peer_dht_pk;
+        let old_connection = self.remove(&real_pk);
+        if let Some(old_real_pk) = self.keys_by_dht_pk.insert(dht_pk, real_pk) {
+            warn!("DHT PublicKey {:?} of {:?} is already used by {:?}", dht_pk, real_pk, old_real_pk);
+        }
+        self.dht_pks_by_key.insert(real_pk, dht_pk);
+        self.connections.insert(real_pk, connection);
+        old_connection
+    }
+
+    /// Remove connection with specified long term `PublicKey` together with
+    /// its UDP addresses. The connection is not locked so it can be removed
+    /// while its lock is held.
+    pub fn remove(&mut self, real_pk: &PublicKey) -> Option<Arc<RwLock<CryptoConnection>>> {
+        let connection = self.connections.remove(real_pk)?;
+        if let Some(dht_pk) = self.dht_pks_by_key.remove(real_pk) {
+            if self.keys_by_dht_pk.get(&dht_pk) == Some(real_pk) {
+                self.keys_by_dht_pk.remove(&dht_pk);
+            }
+        }
+        for key in self.addrs_by_key.remove(real_pk).unwrap_or_default() {
+            if self.keys_by_addr.get(&key) == Some(real_pk) {
+                self.keys_by_addr.remove(&key);
+            }
+        }
+        Some(connection)
+    }
+
+    /// Remove connection with specified long term `PublicKey` only if it's
+    /// the same connection that is passed, i.e. it wasn't replaced by a new
+    /// one. Returns `true` if the connection was removed.
+    pub fn remove_exact(&mut self, real_pk: &PublicKey, connection: &Arc<RwLock<CryptoConnection>>) -> bool {
+        if self.connections.get(real_pk).map_or(false, |stored| Arc::ptr_eq(stored, connection)) {
+            self.remove(real_pk);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Associate UDP address with the peer so that packets from this address
+    /// are handled by its connection.
+    pub fn insert_addr(&mut self, addr: SocketAddr, real_pk: PublicKey) {
+        let key = (addr.ip(), addr.port());
+        if let Some(old_real_pk) = self.keys_by_addr.insert(key, real_pk) {
+            if old_real_pk != real_pk {
+                self.remove_key_addr(&old_real_pk, key);
+            }
+        }
+        self.addrs_by_key.entry(real_pk).or_default().insert(key);
+    }
+
+    /// Remove UDP address of the peer. The address is not removed if it's
+    /// associated with another peer.
+    pub fn remove_addr(&mut self, addr: SocketAddr, real_pk: &PublicKey) {
+        let key = (addr.ip(), addr.port());
+        if self.keys_by_addr.get(&key) == Some(real_pk) {
+            self.keys_by_addr.remove(&key);
+            self.remove_key_addr(real_pk, key);
+        }
+    }
+
+    /// Remove UDP address from addresses of the peer.
+    fn remove_key_addr(&mut self, real_pk: &PublicKey, key: (IpAddr, u16)) {
+        if let Some(addrs) = self.addrs_by_key.get_mut(real_pk) {
+            addrs.remove(&key);
+            if addrs.is_empty() {
+                self.addrs_by_key.remove(real_pk);
+            }
+        }
+    }
+}
+
+impl Index<&PublicKey> for Connections {
+    type Output = Arc<RwLock<CryptoConnection>>;
+
+    fn index(&self, real_pk: &PublicKey) -> &Self::Output {
+        &self.connections[real_pk]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_connection(peer_real_pk: PublicKey, peer_dht_pk: PublicKey) -> Arc<RwLock<CryptoConnection>> {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        Arc::new(RwLock::new(connection))
+    }
+
+    #[test]
+    fn insert_remove() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let connection = new_connection(peer_real_pk, peer_dht_pk);
+
+        assert!(connections.insert(peer_real_pk, connection.clone()).is_none());
+        assert_eq!(connections.connections.len(), 1);
+        assert!(connections.contains_key(&peer_real_pk));
+        assert!(Arc::ptr_eq(connections.get(&peer_real_pk).unwrap(), &connection));
+        assert!(Arc::ptr_eq(connections.get_by_dht_pk(&peer_dht_pk).unwrap(), &connection));
+
+        assert!(Arc::ptr_eq(&connections.remove(&peer_real_pk).unwrap(), &connection));
+        assert!(connections.connections.is_empty());
+        assert!(connections.get_by_dht_pk(&peer_dht_pk).is_none());
+        assert!(connections.remove(&peer_real_pk).is_none());
+    }
+
+    #[test]
+    fn insert_new_dht_pk() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (old_peer_dht_pk, _old_peer_dht_sk) = gen_keypair();
+        let (new_peer_dht_pk, _new_peer_dht_sk) = gen_keypair();
+        let old_connection = new_connection(peer_real_pk, old_peer_dht_pk);
+        let new_connection = new_connection(peer_real_pk, new_peer_dht_pk);
+
+        connections.insert(peer_real_pk, old_connection.clone());
+        let replaced = connections.insert(peer_real_pk, new_connection.clone()).unwrap();
+
+        assert!(Arc::ptr_eq(&replaced, &old_connection));
+        assert_eq!(connections.connections.len(), 1);
+        assert!(connections.get_by_dht_pk(&old_peer_dht_pk).is_none());
+        assert!(Arc::ptr_eq(connections.get_by_dht_pk(&new_peer_dht_pk).unwrap(), &new_connection));
+    }
+
+    #[test]
+    fn remove_exact() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let old_connection = new_connection(peer_real_pk, peer_dht_pk);
+        let new_connection = new_connection(peer_real_pk, peer_dht_pk);
+
+        connections.insert(peer_real_pk, old_connection.clone());
+        connections.insert(peer_real_pk, new_connection.clone());
+
+        // the old connection was replaced so it shouldn't remove the new one
+        assert!(!connections.remove_exact(&peer_real_pk, &old_connection));
+        assert!(Arc::ptr_eq(connections.get_by_dht_pk(&peer_dht_pk).unwrap(), &new_connection));
+
+        assert!(connections.remove_exact(&peer_real_pk, &new_connection));
+        assert!(connections.connections.is_empty());
+        assert!(connections.get_by_dht_pk(&peer_dht_pk).is_none());
+    }
+
+    #[test]
+    fn remove_clears_addrs() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk_1, _peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let connection_1 = new_connection(peer_real_pk_1, gen_keypair().0);
+        let connection_2 = new_connection(peer_real_pk_2, gen_keypair().0);
+        let addr_1 = "127.0.0.1:12345".parse().unwrap();
+        let addr_2 = "127.0.0.1:12346".parse().unwrap();
+
+        connections.insert(peer_real_pk_1, connection_1);
+        connections.insert(peer_real_pk_2, connection_2);
+        connections.insert_addr(addr_1, peer_real_pk_1);
+        connections.insert_addr(addr_2, peer_real_pk_2);
+
+        connections.remove(&peer_real_pk_1);
+
+        assert!(connections.key_by_addr(addr_1).is_none());
+        assert_eq!(connections.key_by_addr(addr_2), Some(peer_real_pk_2));
+        assert!(!connections.addrs_by_key.contains_key(&peer_real_pk_1));
+    }
+
+    #[test]
+    fn insert_addr_of_another_peer() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk_1, _peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let connection_1 = new_connection(peer_real_pk_1, gen_keypair().0);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        connections.insert(peer_real_pk_1, connection_1);
+        connections.insert_addr(addr, peer_real_pk_1);
+        connections.insert_addr(addr, peer_real_pk_2);
+
+        assert!(!connections.addrs_by_key.contains_key(&peer_real_pk_1));
+
+        // the address now belongs to another peer so it's kept
+        connections.remove(&peer_real_pk_1);
+        assert_eq!(connections.key_by_addr(addr), Some(peer_real_pk_2));
+    }
+
+    #[test]
+    fn remove_addr_of_another_peer() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk_1, _peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+
+        connections.insert_addr(addr, peer_real_pk_2);
+        connections.remove_addr(addr, &peer_real_pk_1);
+        assert_eq!(connections.key_by_addr(addr), Some(peer_real_pk_2));
+
+        connections.remove_addr(addr, &peer_real_pk_2);
+        assert!(connections.key_by_addr(addr).is_none());
+        assert!(connections.addrs_by_key.is_empty());
+    }
+
+    #[test]
+    fn insert_same_dht_pk() {
+        crypto_init().unwrap();
+        let mut connections = Connections::new();
+
+        let (peer_real_pk_1, _peer_real_sk_1) = gen_keypair();
+        let (peer_real_pk_2, _peer_real_sk_2) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let connection_1 = new_connection(peer_real_pk_1, peer_dht_pk);
+        let connection_2 = new_connection(peer_real_pk_2, peer_dht_pk);
+
+        connections.insert(peer_real_pk_1, connection_1.clone());
+        connections.insert(peer_real_pk_2, connection_2.clone());
+
+        // the latest connection wins
+        assert!(Arc::ptr_eq(connections.get_by_dht_pk(&peer_dht_pk).unwrap(), &connection_2));
+
+        // removing the first connection doesn't affect the second one
+        connections.remove(&peer_real_pk_1);
+        assert!(Arc::ptr_eq(connections.get_by_dht_pk(&peer_dht_pk).unwrap(), &connection_2));
+    }
+}
@@ -5,6 +5,8 @@ use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::time::{Duration, Instant};
 
 use super::packets_array::*;
+use super::send_queue::*;
+use super::rekey::*;
 
 use crate::toxcore::dht::ip_port::IsGlobal;
 use crate::toxcore::crypto_core::*;
@@ -62,6 +64,32 @@ pub const CRYPTO_MIN_QUEUE_LENGTH: u32 = 64;
 /// the number of ms between request packets to send at that ratio.
 pub const REQUEST_PACKETS_COMPARE_CONSTANT: f64 = 0.125 * 100.0;
 
+/// How often UDP probes are sent to a not confirmed address of the peer.
+pub const UDP_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many UDP probes are sent to an address before it's considered
+/// unreachable.
+pub const MAX_UDP_PROBES: u8 = 8;
+
+/// Maximum number of not confirmed UDP addresses of the peer that are probed
+/// simultaneously.
+pub const MAX_UDP_CANDIDATES: usize = 8;
+
+/// How long an address that didn't answer to UDP probes isn't probed again.
+/// The interval is doubled after every next failure.
+pub const UDP_UNREACHABLE_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Maximum interval an unreachable UDP address isn't probed again.
+pub const MAX_UDP_UNREACHABLE_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Maximum number of unreachable UDP addresses of the peer that are
+/// remembered to not probe them too often.
+pub const MAX_UDP_UNREACHABLE_ADDRS: usize = 32;
+
+/// How often a packet is sent via TCP relays when direct UDP connection is
+/// alive so that TCP path stays usable as a fallback.
+pub const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
 /// Packet that should be sent every second. Depending on `ConnectionStatus` it
 /// can be `CookieRequest` or `CryptoHandshake`
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -185,6 +213,67 @@ pub enum ConnectionStatus {
     },
 }
 
+/// Transport that is used to send packets to the peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionTransport {
+    /// Packets are sent directly via UDP to IPv4 address.
+    UdpV4(SocketAddrV4),
+    /// Packets are sent directly via UDP to IPv6 address.
+    UdpV6(SocketAddrV6),
+    /// Packets are sent via TCP relays.
+    Tcp,
+}
+
+/// Reason why a crypto connection was closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The peer sent us `PACKET_ID_KILL` packet.
+    Killed,
+    /// The peer stopped responding.
+    Timeout,
+    /// The peer didn't complete the handshake in time.
+    HandshakeFailed,
+    /// The peer started a new session with a different DHT `PublicKey` so the
+    /// old session was replaced.
+    Replaced,
+    /// The connection was killed by us.
+    Local,
+}
+
+/// Event that happens to a crypto connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection became established and uses the specified transport.
+    Connected(ConnectionTransport),
+    /// The connection was closed. `Disconnected` events are sent only for
+    /// established connections except `HandshakeFailed` which is sent when a
+    /// connection failed to become established.
+    Disconnected(DisconnectReason),
+    /// The established connection switched to another transport, e.g. from
+    /// TCP relay to direct UDP or to another UDP address.
+    TransportChanged(ConnectionTransport),
+}
+
+/// Statistics of a crypto connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    /// Transport that is used to send packets to the peer.
+    pub transport: ConnectionTransport,
+    /// Round trip time of the connection.
+    pub rtt: Duration,
+    /// Estimated packets send rate.
+    pub send_rate: f64,
+    /// Number of sent lossless packets that are not confirmed yet.
+    pub packets_in_flight: u64,
+    /// Number of packets waiting in the send queue.
+    pub packets_queued: u64,
+    /// Total number of resent lossless packets.
+    pub packets_resent: u64,
+    /// Time when the connection was established. `None` if it's not
+    /// established yet.
+    pub established_time: Option<Instant>,
+}
+
 /// Sent but not confirmed data packet that is stored in `PacketsArray`
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SentPacket {
@@ -253,6 +342,66 @@ impl<T: Into<SocketAddr> + Copy> ConnectionAddr<T> {
     }
 }
 
+/// UDP address of the peer that is not confirmed yet. Traffic is switched to
+/// this address only after a packet is received from it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UdpCandidate {
+    /// Address to send UDP probes to
+    pub addr: SocketAddr,
+    /// Number of probes sent to this address
+    pub probes_sent: u8,
+    /// Time when the last probe was sent
+    pub probe_sent_time: Option<Instant>,
+}
+
+impl UdpCandidate {
+    /// Create new `UdpCandidate` that wasn't probed yet.
+    pub fn new(addr: SocketAddr) -> Self {
+        UdpCandidate {
+            addr,
+            probes_sent: 0,
+            probe_sent_time: None,
+        }
+    }
+
+    /// Check if the next probe should be sent to this address.
+    pub fn probe_should_be_sent(&self) -> bool {
+        self.probes_sent < MAX_UDP_PROBES &&
+            self.probe_sent_time.map_or(true, |time| clock_elapsed(time) >= UDP_PROBE_INTERVAL)
+    }
+
+    /// Check if all probes were sent to this address and none was answered.
+    pub fn is_unreachable(&self) -> bool {
+        self.probes_sent >= MAX_UDP_PROBES &&
+            self.probe_sent_time.map_or(true, |time| clock_elapsed(time) >= UDP_PROBE_INTERVAL)
+    }
+}
+
+/// UDP address of the peer that didn't answer to probes. It isn't probed again
+/// until the backoff interval passes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnreachableUdpAddr {
+    /// Address that didn't answer to probes
+    pub addr: SocketAddr,
+    /// How many times in a row all probes to this address were not answered
+    pub failures: u32,
+    /// Time when the address was found unreachable last time
+    pub time: Instant,
+}
+
+impl UnreachableUdpAddr {
+    /// Interval during which the address shouldn't be probed.
+    pub fn backoff(&self) -> Duration {
+        let shift = self.failures.saturating_sub(1).min(16);
+        (UDP_UNREACHABLE_BACKOFF * (1 << shift)).min(MAX_UDP_UNREACHABLE_BACKOFF)
+    }
+
+    /// Check if the address can be probed again.
+    pub fn can_be_probed(&self) -> bool {
+        clock_elapsed(self.time) >= self.backoff()
+    }
+}
+
 /** Secure connection to send data between two friends that provides encryption,
 ordered delivery, and perfect forward secrecy.
 
@@ -278,6 +427,14 @@ pub struct CryptoConnection {
     pub udp_addr_v6: Option<ConnectionAddr<SocketAddrV6>>,
     /// Time when we made an attempt to send UDP packet
     pub udp_send_attempt_time: Option<Instant>,
+    /// Not confirmed UDP addresses of the peer that are probed to upgrade the
+    /// connection to direct UDP
+    pub udp_candidates: Vec<UdpCandidate>,
+    /// UDP addresses of the peer that didn't answer to probes
+    pub udp_unreachable: Vec<UnreachableUdpAddr>,
+    /// Time when we sent the last packet via TCP relays while direct UDP
+    /// connection was alive
+    pub tcp_keepalive_time: Option<Instant>,
     /// Buffer of sent packets
     pub send_array: PacketsArray<SentPacket>,
     /// Buffer of received packets
@@ -298,6 +455,9 @@ pub struct CryptoConnection {
     pub packets_sent: u32,
     /// Number of resent lossless packets.
     pub packets_resent: u32,
+    /// Total number of resent lossless packets since the connection was
+    /// created. Unlike `packets_resent` it's never reset.
+    pub total_packets_resent: u64,
     /// Current position in `last_send_array_sizes` and `last_num_packets` arrays.
     pub last_sendqueue_counter: u32,
     /// Last sizes of `send_array`.
@@ -315,6 +475,33 @@ pub struct CryptoConnection {
     pub packet_send_rate: f64,
     /// Estimated requested packets send rate.
     pub packet_send_rate_requested: f64,
+    /// Number of packets from `send_queue` that can be sent right now. It's
+    /// replenished with `packet_send_rate`.
+    pub send_budget: f64,
+    /// Packets waiting to be sent when congestion control allows it.
+    pub send_queue: SendQueue,
+    /// Time when the connection became established.
+    pub established_time: Option<Instant>,
+    /// Transport that was reported with the last `ConnectionEvent`.
+    pub reported_transport: Option<ConnectionTransport>,
+    /// Capabilities announced by the peer. `None` if the peer didn't announce
+    /// them, e.g. if it's c-toxcore client.
+    pub peer_capabilities: Option<u8>,
+    /// Number of times we sent our capabilities to the peer.
+    pub capabilities_sent: u8,
+    /// Time when we sent our capabilities last time.
+    pub capabilities_sent_time: Option<Instant>,
+    /// State of session keys rotation if it's in progress.
+    pub rekey_status: Option<RekeyStatus>,
+    /// Keys of the previous session that are still accepted after rotation.
+    pub previous_session: Option<PreviousSession>,
+    /// Time when the current session keys started to be used.
+    pub session_start_time: Instant,
+    /// Number of data packets sent with the current session keys.
+    pub session_packets_sent: u64,
+    /// The peer sent us `PACKET_ID_KILL` packet. Such connection should be
+    /// removed from the connections list after its lock is released.
+    pub killed: bool,
 }
 
 impl CryptoConnection {
@@ -343,6 +530,9 @@ impl CryptoConnection {
             udp_addr_v4: None,
             udp_addr_v6: None,
             udp_send_attempt_time: None,
+            udp_candidates: Vec::new(),
+            udp_unreachable: Vec::new(),
+            tcp_keepalive_time: None,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: DEFAULT_RTT,
@@ -351,6 +541,7 @@ impl CryptoConnection {
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
+            total_packets_resent: 0,
             last_sendqueue_counter: 0,
             last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
             last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
@@ -359,6 +550,18 @@ impl CryptoConnection {
             packet_recv_rate: 0.0,
             packet_send_rate: CRYPTO_PACKET_MIN_RATE,
             packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            send_budget: 0.0,
+            send_queue: SendQueue::new(),
+            established_time: None,
+            reported_transport: None,
+            peer_capabilities: None,
+            capabilities_sent: 0,
+            capabilities_sent_time: None,
+            rekey_status: None,
+            previous_session: None,
+            session_start_time: clock_now(),
+            session_packets_sent: 0,
+            killed: false,
         }
     }
 
@@ -402,6 +605,9 @@ impl CryptoConnection {
             udp_addr_v4: None,
             udp_addr_v6: None,
             udp_send_attempt_time: None,
+            udp_candidates: Vec::new(),
+            udp_unreachable: Vec::new(),
+            tcp_keepalive_time: None,
             send_array: PacketsArray::new(),
             recv_array: PacketsArray::new(),
             rtt: DEFAULT_RTT,
@@ -410,6 +616,7 @@ impl CryptoConnection {
             packets_received: 0,
             packets_sent: 0,
             packets_resent: 0,
+            total_packets_resent: 0,
             last_sendqueue_counter: 0,
             last_send_array_sizes: [0; CONGESTION_QUEUE_ARRAY_SIZE],
             last_num_packets_sent: [0; CONGESTION_LAST_SENT_ARRAY_SIZE],
@@ -418,6 +625,18 @@ impl CryptoConnection {
             packet_recv_rate: 0.0,
             packet_send_rate: CRYPTO_PACKET_MIN_RATE,
             packet_send_rate_requested: CRYPTO_PACKET_MIN_RATE,
+            send_budget: 0.0,
+            send_queue: SendQueue::new(),
+            established_time: None,
+            reported_transport: None,
+            peer_capabilities: None,
+            capabilities_sent: 0,
+            capabilities_sent_time: None,
+            rekey_status: None,
+            previous_session: None,
+            session_start_time: clock_now(),
+            session_packets_sent: 0,
+            killed: false,
         }
     }
 
@@ -453,6 +672,8 @@ impl CryptoConnection {
 
     /// Set UPD address for this connection
     pub fn set_udp_addr(&mut self, addr: SocketAddr) {
+        self.udp_candidates.retain(|candidate| candidate.addr != addr);
+        self.udp_unreachable.retain(|unreachable| unreachable.addr != addr);
         match addr {
             SocketAddr::V4(addr) => self.udp_addr_v4 = Some(ConnectionAddr::new(addr)),
             SocketAddr::V6(addr) => self.udp_addr_v6 = Some(ConnectionAddr::new(addr)),
@@ -525,6 +746,154 @@ impl CryptoConnection {
             .map_or(true, |time| clock_elapsed(time) >= UDP_DIRECT_TIMEOUT / 2)
     }
 
+    /// Get transport that is used to send packets to the peer. UDP is used
+    /// only when it's alive and TCP-only mode is disabled.
+    pub fn transport(&self, tcp_only: bool) -> ConnectionTransport {
+        match self.get_udp_addr() {
+            Some(SocketAddr::V4(addr)) if !tcp_only && self.is_udp_alive() => ConnectionTransport::UdpV4(addr),
+            Some(SocketAddr::V6(addr)) if !tcp_only && self.is_udp_alive() => ConnectionTransport::UdpV6(addr),
+            _ => ConnectionTransport::Tcp,
+        }
+    }
+
+    /// Get statistics of this connection.
+    pub fn info(&self, tcp_only: bool) -> ConnectionInfo {
+        ConnectionInfo {
+            transport: self.transport(tcp_only),
+            rtt: self.rtt,
+            send_rate: self.packet_send_rate,
+            packets_in_flight: u64::from(self.send_array.len()),
+            packets_queued: self.send_queue.len() as u64,
+            packets_resent: self.total_packets_resent,
+            established_time: self.established_time,
+        }
+    }
+
+    /// Check if we should send our capabilities to the peer. They are sent
+    /// every second until the peer announces its own ones but not more than
+    /// `MAX_NUM_CAPABILITIES_TRIES` times.
+    pub fn capabilities_should_be_sent(&self) -> bool {
+        self.is_established() &&
+            self.peer_capabilities.is_none() &&
+            self.capabilities_sent < MAX_NUM_CAPABILITIES_TRIES &&
+            self.capabilities_sent_time.map_or(true, |time| clock_elapsed(time) >= CRYPTO_SEND_PACKET_INTERVAL)
+    }
+
+    /// Add UDP address of the peer that should be probed before switching to
+    /// it. Returns `false` if the address is already alive or probed, if it
+    /// was found unreachable recently or if there are too many addresses being
+    /// probed.
+    pub fn add_udp_candidate(&mut self, addr: SocketAddr) -> bool {
+        let is_alive = self.udp_addr_v4.as_ref().map_or(false, |udp_addr| udp_addr.addr() == addr && udp_addr.is_alive()) ||
+            self.udp_addr_v6.as_ref().map_or(false, |udp_addr| udp_addr.addr() == addr && udp_addr.is_alive());
+        let is_unreachable = self.udp_unreachable.iter()
+            .any(|unreachable| unreachable.addr == addr && !unreachable.can_be_probed());
+        if is_alive || is_unreachable ||
+            self.udp_candidates.len() >= MAX_UDP_CANDIDATES ||
+            self.udp_candidates.iter().any(|candidate| candidate.addr == addr) {
+            return false;
+        }
+
+        self.udp_candidates.push(UdpCandidate::new(addr));
+        true
+    }
+
+    /// Get UDP addresses of the peer that should be probed now marking that
+    /// probes are sent to them.
+    pub fn next_udp_probes(&mut self) -> Vec<SocketAddr> {
+        let now = clock_now();
+        self.udp_candidates.iter_mut()
+            .filter(|candidate| candidate.probe_should_be_sent())
+            .map(|candidate| {
+                candidate.probes_sent += 1;
+                candidate.probe_sent_time = Some(now);
+                candidate.addr
+            })
+            .collect()
+    }
+
+    /// Remove UDP addresses of the peer that didn't answer to any probe and
+    /// remember them so that they are not probed again until the backoff
+    /// interval passes. Returns the removed addresses.
+    pub fn remove_unreachable_udp_candidates(&mut self) -> Vec<SocketAddr> {
+        let (unreachable, candidates) = self.udp_candidates.drain(..)
+            .partition::<Vec<_>, _>(UdpCandidate::is_unreachable);
+        self.udp_candidates = candidates;
+        let addrs = unreachable.into_iter().map(|candidate| candidate.addr).collect::<Vec<_>>();
+        for &addr in &addrs {
+            self.add_udp_unreachable(addr);
+        }
+        addrs
+    }
+
+    /// Remember UDP address that didn't answer to probes. When there are too
+    /// many such addresses the one found unreachable earliest is forgotten.
+    fn add_udp_unreachable(&mut self, addr: SocketAddr) {
+        let now = clock_now();
+        if let Some(unreachable) = self.udp_unreachable.iter_mut().find(|unreachable| unreachable.addr == addr) {
+            unreachable.failures = unreachable.failures.saturating_add(1);
+            unreachable.time = now;
+            return;
+        }
+
+        if self.udp_unreachable.len() >= MAX_UDP_UNREACHABLE_ADDRS {
+            if let Some(index) = self.udp_unreachable.iter()
+                .enumerate()
+                .min_by_key(|(_, unreachable)| unreachable.time)
+                .map(|(index, _)| index) {
+                self.udp_unreachable.swap_remove(index);
+            }
+        }
+
+        self.udp_unreachable.push(UnreachableUdpAddr {
+            addr,
+            failures: 1,
+            time: now,
+        });
+    }
+
+    /// Check if a packet should be sent via TCP relays to keep TCP path usable
+    /// while direct UDP connection is alive.
+    pub fn tcp_keepalive_should_be_sent(&self) -> bool {
+        self.is_established() &&
+            self.is_udp_alive() &&
+            self.tcp_keepalive_time.map_or(true, |time| clock_elapsed(time) >= TCP_KEEPALIVE_INTERVAL)
+    }
+
+    /// Check if session keys should be rotated according to the config.
+    pub fn rekey_should_be_started(&self, config: &RekeyConfig) -> bool {
+        self.is_established() &&
+            self.rekey_status.is_none() &&
+            self.peer_capabilities.map_or(false, |capabilities| capabilities & CAPABILITY_REKEY != 0) &&
+            (self.session_packets_sent >= config.packets || clock_elapsed(self.session_start_time) >= config.interval)
+    }
+
+    /// Start using new session keys after rotation. Keys of the current
+    /// session are still accepted for incoming packets for a while. Does
+    /// nothing if the connection is not established.
+    pub fn replace_session(
+        &mut self,
+        session_pk: PublicKey,
+        session_sk: SecretKey,
+        sent_nonce: Nonce,
+        received_nonce: Nonce,
+        session_precomputed_key: PrecomputedKey
+    ) {
+        if let ConnectionStatus::Established { received_nonce: old_received_nonce, session_precomputed_key: ref old_session_precomputed_key, .. } = self.status {
+            self.previous_session = Some(PreviousSession::new(old_received_nonce, old_session_precomputed_key.clone()));
+            self.status = ConnectionStatus::Established {
+                sent_nonce,
+                received_nonce,
+                session_precomputed_key,
+            };
+            self.session_pk = session_pk;
+            self.session_sk = session_sk;
+            self.session_start_time = clock_now();
+            self.session_packets_sent = 0;
+            self.rekey_status = None;
+        }
+    }
+
     /// Calculate packets receive rate.
     fn calculate_recv_rate(&mut self, now: Instant) {
         let dt = now - self.stats_calculation_time;
@@ -608,6 +977,30 @@ impl CryptoConnection {
         self.packet_send_rate_requested = packet_send_rate_requested;
     }
 
+    /// Replenish send budget with packets that can be sent during the time
+    /// since the last stats calculation. Budget can't exceed the number of
+    /// packets that can be sent in a second.
+    fn replenish_send_budget(&mut self, now: Instant) {
+        let dt = now - self.stats_calculation_time;
+        let budget = self.send_budget + self.packet_send_rate * (dt.as_secs() as f64 + f64::from(dt.subsec_millis()) / 1000.0);
+        self.send_budget = budget.min(self.packet_send_rate);
+    }
+
+    /// Number of packets from `send_queue` that can be sent right now. Until
+    /// send array has `CRYPTO_MIN_QUEUE_LENGTH` packets they can be sent
+    /// regardless of the packets send rate.
+    pub fn packets_allowed(&self) -> usize {
+        let send_array_len = self.send_array.len();
+        let free_space = CRYPTO_PACKET_BUFFER_SIZE - send_array_len;
+        let min_queue_space = CRYPTO_MIN_QUEUE_LENGTH.saturating_sub(send_array_len) as usize;
+        (self.send_budget as usize).max(min_queue_space).min(free_space as usize)
+    }
+
+    /// Spend send budget on `count` packets sent from `send_queue`.
+    pub fn spend_send_budget(&mut self, count: usize) {
+        self.send_budget = (self.send_budget - count as f64).max(0.0);
+    }
+
     /// Reset congestion counters after they were used for stats calculation.
     fn reset_congestion_counters(&mut self, now: Instant) {
         self.packets_received = 0;
@@ -621,6 +1014,7 @@ impl CryptoConnection {
         let now = clock_now();
         self.calculate_recv_rate(now);
         self.calculate_send_rate(now);
+        self.replenish_send_budget(now);
         self.reset_congestion_counters(now);
     }
 
@@ -808,6 +1202,60 @@ mod tests {
         assert!((connection.packet_recv_rate - 6000.0).abs() < 200.0);
     }
 
+    #[tokio::test]
+    async fn update_congestion_stats_send_budget() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        tokio::time::pause();
+
+        connection.stats_calculation_time = clock_now();
+        connection.packet_send_rate = 1000.0;
+        connection.send_budget = 0.0;
+
+        tokio::time::advance(PACKET_COUNTER_AVERAGE_INTERVAL).await;
+
+        connection.update_congestion_stats();
+
+        // budget is replenished with the new rate for the last interval
+        let expected = connection.packet_send_rate * PACKET_COUNTER_AVERAGE_INTERVAL_MS as f64 / 1000.0;
+        assert!((connection.send_budget - expected).abs() < 0.001);
+
+        connection.spend_send_budget(1000);
+        assert_eq!(connection.send_budget, 0.0);
+    }
+
+    #[test]
+    fn packets_allowed() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        // send array is empty so CRYPTO_MIN_QUEUE_LENGTH packets can be sent
+        assert_eq!(connection.packets_allowed(), CRYPTO_MIN_QUEUE_LENGTH as usize);
+
+        for _ in 0 .. CRYPTO_MIN_QUEUE_LENGTH {
+            connection.send_array.push_back(SentPacket::new(vec![42])).unwrap();
+        }
+        assert_eq!(connection.packets_allowed(), 0);
+
+        connection.send_budget = 10.5;
+        assert_eq!(connection.packets_allowed(), 10);
+
+        // can't exceed free space in send array
+        connection.send_budget = f64::from(CRYPTO_PACKET_BUFFER_SIZE);
+        assert_eq!(connection.packets_allowed(), (CRYPTO_PACKET_BUFFER_SIZE - CRYPTO_MIN_QUEUE_LENGTH) as usize);
+    }
+
     #[test]
     fn request_packet_interval() {
         crypto_init().unwrap();
@@ -886,6 +1334,64 @@ mod tests {
         assert!(!connection.is_established());
     }
 
+    #[tokio::test]
+    async fn transport() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        assert_eq!(connection.transport(false), ConnectionTransport::Tcp);
+
+        let addr_v4 = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V4(addr_v4));
+        assert_eq!(connection.transport(false), ConnectionTransport::UdpV4(addr_v4));
+        assert_eq!(connection.transport(true), ConnectionTransport::Tcp);
+
+        let addr_v6 = "[::1]:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V6(addr_v6));
+        assert_eq!(connection.transport(false), ConnectionTransport::UdpV6(addr_v6));
+
+        tokio::time::pause();
+        tokio::time::advance(UDP_DIRECT_TIMEOUT + Duration::from_secs(1)).await;
+
+        // UDP is not alive anymore
+        assert_eq!(connection.transport(false), ConnectionTransport::Tcp);
+    }
+
+    #[test]
+    fn info() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let now = clock_now();
+        connection.rtt = Duration::from_millis(42);
+        connection.packet_send_rate = 100.0;
+        connection.send_array.push_back(SentPacket::new(vec![16, 42])).unwrap();
+        connection.send_array.push_back(SentPacket::new(vec![16, 43])).unwrap();
+        connection.send_queue.push(PacketPriority::Lossy, vec![200, 42]).unwrap();
+        connection.total_packets_resent = 7;
+        connection.established_time = Some(now);
+
+        assert_eq!(connection.info(false), ConnectionInfo {
+            transport: ConnectionTransport::Tcp,
+            rtt: Duration::from_millis(42),
+            send_rate: 100.0,
+            packets_in_flight: 2,
+            packets_queued: 1,
+            packets_resent: 7,
+            established_time: Some(now),
+        });
+    }
+
     #[test]
     fn set_get_udp_addr_v4() {
         crypto_init().unwrap();
@@ -1050,4 +1556,275 @@ mod tests {
 
         assert_eq!(connection.get_udp_addr(), None);
     }
+
+    #[tokio::test]
+    async fn capabilities_should_be_sent() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        // connection is not established
+        assert!(!connection.capabilities_should_be_sent());
+
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+        assert!(connection.capabilities_should_be_sent());
+
+        connection.capabilities_sent = 1;
+        connection.capabilities_sent_time = Some(clock_now());
+        assert!(!connection.capabilities_should_be_sent());
+
+        tokio::time::advance(CRYPTO_SEND_PACKET_INTERVAL).await;
+        assert!(connection.capabilities_should_be_sent());
+
+        connection.capabilities_sent = MAX_NUM_CAPABILITIES_TRIES;
+        assert!(!connection.capabilities_should_be_sent());
+
+        connection.capabilities_sent = 1;
+        connection.peer_capabilities = Some(0);
+        assert!(!connection.capabilities_should_be_sent());
+    }
+
+    #[tokio::test]
+    async fn rekey_should_be_started() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+        connection.session_start_time = clock_now();
+
+        let config = RekeyConfig {
+            packets: 10,
+            interval: Duration::from_secs(60),
+        };
+
+        connection.session_packets_sent = 10;
+        // peer doesn't support rotation
+        assert!(!connection.rekey_should_be_started(&config));
+        connection.peer_capabilities = Some(0);
+        assert!(!connection.rekey_should_be_started(&config));
+
+        connection.peer_capabilities = Some(CAPABILITY_REKEY);
+        assert!(connection.rekey_should_be_started(&config));
+
+        connection.session_packets_sent = 9;
+        assert!(!connection.rekey_should_be_started(&config));
+
+        tokio::time::advance(config.interval).await;
+        assert!(connection.rekey_should_be_started(&config));
+
+        // rotation is in progress
+        let (session_pk, session_sk) = gen_keypair();
+        connection.rekey_status = Some(RekeyStatus::Requested {
+            session_pk,
+            session_sk,
+            sent_nonce: gen_nonce(),
+            time: clock_now(),
+        });
+        assert!(!connection.rekey_should_be_started(&config));
+    }
+
+    #[test]
+    fn replace_session() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let (session_pk, session_sk) = gen_keypair();
+        let sent_nonce = gen_nonce();
+        let received_nonce = gen_nonce();
+        let session_precomputed_key = precompute(&gen_keypair().0, &session_sk);
+
+        // connection is not established
+        connection.replace_session(session_pk, session_sk.clone(), sent_nonce, received_nonce, session_precomputed_key.clone());
+        assert_ne!(connection.session_pk, session_pk);
+
+        let old_received_nonce = gen_nonce();
+        let old_session_precomputed_key = precompute(&gen_keypair().0, &gen_keypair().1);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: old_received_nonce,
+            session_precomputed_key: old_session_precomputed_key.clone(),
+        };
+        connection.session_packets_sent = 42;
+
+        connection.replace_session(session_pk, session_sk.clone(), sent_nonce, received_nonce, session_precomputed_key.clone());
+
+        assert_eq!(connection.status, ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key,
+        });
+        assert_eq!(connection.session_pk, session_pk);
+        assert_eq!(connection.session_sk, session_sk);
+        assert_eq!(connection.session_packets_sent, 0);
+        assert!(connection.rekey_status.is_none());
+
+        let previous_session = connection.previous_session.unwrap();
+        assert_eq!(previous_session.received_nonce, old_received_nonce);
+        assert_eq!(previous_session.session_precomputed_key, old_session_precomputed_key);
+    }
+
+    #[test]
+    fn add_udp_candidate() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let alive_addr = "127.0.0.1:33445".parse().unwrap();
+        connection.set_udp_addr(alive_addr);
+
+        // address is already alive
+        assert!(!connection.add_udp_candidate(alive_addr));
+
+        let addr = "127.0.0.2:33445".parse().unwrap();
+        assert!(connection.add_udp_candidate(addr));
+        // address is already probed
+        assert!(!connection.add_udp_candidate(addr));
+
+        for i in 1 .. MAX_UDP_CANDIDATES as u16 {
+            assert!(connection.add_udp_candidate(SocketAddr::new("127.0.0.3".parse().unwrap(), i)));
+        }
+        // too many addresses are probed
+        assert!(!connection.add_udp_candidate("127.0.0.4:33445".parse().unwrap()));
+        assert_eq!(connection.udp_candidates.len(), MAX_UDP_CANDIDATES);
+
+        // candidate is removed when it's confirmed
+        connection.set_udp_addr(addr);
+        assert_eq!(connection.udp_candidates.len(), MAX_UDP_CANDIDATES - 1);
+        assert_eq!(connection.get_udp_addr_v4(), Some(addr));
+    }
+
+    #[tokio::test]
+    async fn next_udp_probes() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:33445".parse().unwrap();
+        assert!(connection.add_udp_candidate(addr));
+
+        for _ in 0 .. MAX_UDP_PROBES {
+            assert_eq!(connection.next_udp_probes(), vec![addr]);
+            // probes are sent once per interval
+            assert!(connection.next_udp_probes().is_empty());
+            assert!(connection.remove_unreachable_udp_candidates().is_empty());
+            tokio::time::advance(UDP_PROBE_INTERVAL).await;
+        }
+
+        // all probes are sent and none was answered
+        assert!(connection.next_udp_probes().is_empty());
+        assert_eq!(connection.remove_unreachable_udp_candidates(), vec![addr]);
+        assert!(connection.udp_candidates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreachable_udp_candidate_backoff() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:33445".parse().unwrap();
+        for &backoff in &[UDP_UNREACHABLE_BACKOFF, UDP_UNREACHABLE_BACKOFF * 2] {
+            assert!(connection.add_udp_candidate(addr));
+            for _ in 0 .. MAX_UDP_PROBES {
+                assert_eq!(connection.next_udp_probes(), vec![addr]);
+                tokio::time::advance(UDP_PROBE_INTERVAL).await;
+            }
+            assert_eq!(connection.remove_unreachable_udp_candidates(), vec![addr]);
+
+            // the address isn't probed again until the backoff interval passes
+            assert!(!connection.add_udp_candidate(addr));
+            tokio::time::advance(backoff - Duration::from_secs(1)).await;
+            assert!(!connection.add_udp_candidate(addr));
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+
+        // confirmed address is forgotten
+        connection.set_udp_addr(addr);
+        assert!(connection.udp_unreachable.is_empty());
+    }
+
+    #[test]
+    fn unreachable_udp_addr_backoff() {
+        let mut unreachable = UnreachableUdpAddr {
+            addr: "127.0.0.1:33445".parse().unwrap(),
+            failures: 1,
+            time: clock_now(),
+        };
+        assert_eq!(unreachable.backoff(), UDP_UNREACHABLE_BACKOFF);
+        unreachable.failures = 3;
+        assert_eq!(unreachable.backoff(), UDP_UNREACHABLE_BACKOFF * 4);
+        unreachable.failures = std::u32::MAX;
+        assert_eq!(unreachable.backoff(), MAX_UDP_UNREACHABLE_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn tcp_keepalive_should_be_sent() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.set_udp_addr("127.0.0.1:33445".parse().unwrap());
+
+        // connection is not established
+        assert!(!connection.tcp_keepalive_should_be_sent());
+
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+        assert!(connection.tcp_keepalive_should_be_sent());
+
+        connection.tcp_keepalive_time = Some(clock_now());
+        assert!(!connection.tcp_keepalive_should_be_sent());
+
+        tokio::time::advance(UDP_DIRECT_TIMEOUT).await;
+        // UDP is dead so packets are sent via TCP anyway
+        assert!(!connection.tcp_keepalive_should_be_sent());
+
+        connection.set_udp_addr("127.0.0.1:33445".parse().unwrap());
+        tokio::time::advance(TCP_KEEPALIVE_INTERVAL - UDP_DIRECT_TIMEOUT).await;
+        assert!(connection.tcp_keepalive_should_be_sent());
+    }
 }
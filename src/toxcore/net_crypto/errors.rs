@@ -85,9 +85,9 @@ error_kind! {
         #[doc = "Error indicates that sending dhtpk packet error."]
         #[fail(display = "Sending dhtpk packet error")]
         SendToDhtpk,
-        #[doc = "Error indicates that sending connection status error."]
-        #[fail(display = "Sending connection status error")]
-        SendToConnectionStatus,
+        #[doc = "Error indicates that sending connection event error."]
+        #[fail(display = "Sending connection event error")]
+        SendToConnectionEvent,
         #[doc = "Error indicates that NetCrypto can't handle packet in current connection state."]
         #[fail(display = "Can't handle CookieResponse in current connection state")]
         InvalidState,
@@ -141,6 +141,12 @@ error_kind! {
             #[doc = "The packet id that is invalid."]
             id: u8,
         },
+        #[doc = "Error indicates that net_crypto control packet can't be parsed."]
+        #[fail(display = "Invalid control packet with id: {:?}", id)]
+        InvalidControlPacket {
+            #[doc = "The id of the invalid packet."]
+            id: u8,
+        },
     }
 }
 
@@ -169,6 +175,12 @@ impl HandlePacketError {
             id,
         })
     }
+
+    pub(crate) fn invalid_control_packet(id: u8) -> HandlePacketError {
+        HandlePacketError::from(HandlePacketErrorKind::InvalidControlPacket {
+            id,
+        })
+    }
 }
 
 error_kind! {
@@ -184,9 +196,9 @@ error_kind! {
         #[doc = "Error indicates that sending response packet error."]
         #[fail(display = "Sending response error")]
         SendTo,
-        #[doc = "Error indicates that sending connection status error."]
-        #[fail(display = "Sending connection status error")]
-        SendToConnectionStatus,
+        #[doc = "Error indicates that sending connection event error."]
+        #[fail(display = "Sending connection event error")]
+        SendToConnectionEvent,
     }
 }
 
@@ -241,9 +253,9 @@ error_kind! {
         #[doc = "Failed to send kill packet."]
         #[fail(display = "Failed to send kill packet")]
         SendTo,
-        #[doc = "Error indicates that sending connection status error."]
-        #[fail(display = "Sending connection status error")]
-        SendToConnectionStatus,
+        #[doc = "Error indicates that sending connection event error."]
+        #[fail(display = "Sending connection event error")]
+        SendToConnectionEvent,
     }
 }
 
@@ -11,16 +11,23 @@ TCP relays.
 */
 
 mod crypto_connection;
+mod connections;
 mod packets_array;
+mod send_queue;
+mod rekey;
 pub mod errors;
 
 pub use self::crypto_connection::*;
+use self::connections::*;
 use self::packets_array::*;
+pub use self::send_queue::{PacketPriority, SendQueue, SEND_QUEUE_SIZE};
+pub use self::rekey::*;
 use self::errors::*;
 
-use std::collections::{HashMap, HashSet};
-use std::net::{SocketAddr, IpAddr};
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
 use std::u16;
 
@@ -36,6 +43,7 @@ use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packet::{Packet as DhtPacket, *};
 use crate::toxcore::dht::precomputed_cache::*;
+use crate::toxcore::dht::ip_port::normalize_saddr;
 use crate::toxcore::io_tokio::*;
 use crate::toxcore::tcp::packet::{DataPayload as TcpDataPayload};
 use crate::toxcore::time::*;
@@ -81,10 +89,9 @@ type TcpTx = mpsc::Sender<(TcpDataPayload, PublicKey)>;
 /// key is a DHT key.
 type DhtPkTx = mpsc::UnboundedSender<(PublicKey, PublicKey)>;
 
-/// Shorthand for the transmit half of the message channel for sending a
-/// connection status when it becomes connected or disconnected. The key is a
-/// long term key of the connection.
-type ConnectionStatusTx = mpsc::UnboundedSender<(PublicKey, bool)>;
+/// Shorthand for the transmit half of the message channel for sending
+/// connection events. The key is a long term key of the connection.
+type ConnectionEventTx = mpsc::UnboundedSender<(PublicKey, ConnectionEvent)>;
 
 /// Shorthand for the transmit half of the message channel for sending lossless
 /// packets. The key is a long term public key of the peer that sent this
@@ -178,9 +185,9 @@ pub struct NetCrypto {
     /// `CryptoConnection` then `NetCrypto` module will send message to this
     /// sink.
     dht_pk_tx: Arc<RwLock<Option<DhtPkTx>>>,
-    /// Sink to send a connection status when it becomes connected or
-    /// disconnected. The key is a long term key of the connection.
-    connection_status_tx: Arc<RwLock<Option<ConnectionStatusTx>>>,
+    /// Sink to send connection events. The key is a long term key of the
+    /// connection.
+    connection_event_tx: Arc<RwLock<Option<ConnectionEventTx>>>,
     /// Sink to send lossless packets. The key is a long term public key of the
     /// peer that sent this packet.
     lossless_tx: LosslessTx,
@@ -200,14 +207,20 @@ pub struct NetCrypto {
     /// List of friends used to check whether should we accept an incoming
     /// `NetCrypto` connection.
     friends: Arc<RwLock<HashSet<PublicKey>>>,
-    /// Connection by long term public key of DHT node map
-    connections: Arc<RwLock<HashMap<PublicKey, Arc<RwLock<CryptoConnection>>>>>,
-    /// Long term keys by IP address of DHT node map. `SocketAddr` can't be used
-    /// as a key since it contains additional info for `IPv6` address.
-    keys_by_addr: Arc<RwLock<HashMap<(IpAddr, /*port*/ u16), PublicKey>>>,
+    /// Connection by long term public key of DHT node map. Every connection
+    /// has its own lock so packets from different peers can be handled in
+    /// parallel.
+    connections: Arc<RwLock<Connections>>,
     /// Lru cache for precomputed keys. It stores precomputed keys to avoid
     /// redundant calculations.
     precomputed_keys: PrecomputedCache,
+    /// If TCP-only mode is enabled `NetCrypto` never sends packets to UDP
+    /// socket and ignores UDP addresses of friends so that packets are sent
+    /// only via TCP relays.
+    tcp_only: Arc<RwLock<bool>>,
+    /// If set session keys of connections are rotated according to this
+    /// config when peers support it.
+    rekey_config: Arc<RwLock<Option<RekeyConfig>>>,
 }
 
 impl NetCrypto {
@@ -217,7 +230,7 @@ impl NetCrypto {
             udp_tx: args.udp_tx,
             tcp_tx: Default::default(),
             dht_pk_tx: Default::default(),
-            connection_status_tx: Default::default(),
+            connection_event_tx: Default::default(),
             lossless_tx: args.lossless_tx,
             lossy_tx: args.lossy_tx,
             dht_pk: args.dht_pk,
@@ -226,9 +239,10 @@ impl NetCrypto {
             real_sk: args.real_sk,
             symmetric_key: secretbox::gen_key(),
             friends: Arc::new(RwLock::new(HashSet::new())),
-            connections: Arc::new(RwLock::new(HashMap::new())),
-            keys_by_addr: Arc::new(RwLock::new(HashMap::new())),
+            connections: Arc::new(RwLock::new(Connections::new())),
             precomputed_keys: args.precomputed_keys,
+            tcp_only: Arc::new(RwLock::new(false)),
+            rekey_config: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -262,28 +276,17 @@ impl NetCrypto {
         connections.insert(peer_real_pk, connection);
     }
 
-    /// Clear stored addresses from `keys_by_addr`.
-    fn clear_keys_by_addr(&self, connection: &CryptoConnection) {
-        if connection.udp_addr_v4.is_some() || connection.udp_addr_v6.is_some() {
-            let mut keys_by_addr = self.keys_by_addr.write();
-            if let Some(addr) = connection.get_udp_addr_v4() {
-                keys_by_addr.remove(&(addr.ip(), addr.port()));
-            }
-            if let Some(addr) = connection.get_udp_addr_v6() {
-                keys_by_addr.remove(&(addr.ip(), addr.port()));
-            }
-        }
+    /// Send connection event to the appropriate sink.
+    fn send_connection_event(&self, connection: &CryptoConnection, event: ConnectionEvent) -> impl Future<Output = Result<(), mpsc::SendError>> {
+        let tx = self.connection_event_tx.read().clone();
+        maybe_send_unbounded(tx, (connection.peer_real_pk, event))
     }
 
-    /// Send connection status to the appropriate sink when it becomes connected
-    /// or disconnected.
-    fn send_connection_status(&self, connection: &CryptoConnection, status: bool) -> impl Future<Output = Result<(), mpsc::SendError>> {
-        if connection.is_established() != status {
-            let tx = (&*self.connection_status_tx.read()).clone();
-            let peer_real_pk = connection.peer_real_pk;
-            let res = maybe_send_unbounded(tx, (peer_real_pk, status));
-
-            Either::Left(res)
+    /// Send `Disconnected` event to the appropriate sink if the connection is
+    /// established.
+    fn send_disconnected_event(&self, connection: &CryptoConnection, reason: DisconnectReason) -> impl Future<Output = Result<(), mpsc::SendError>> {
+        if connection.is_established() {
+            Either::Left(self.send_connection_event(connection, ConnectionEvent::Disconnected(reason)))
         } else {
             Either::Right(future::ok(()))
         }
@@ -302,12 +305,19 @@ impl NetCrypto {
     /// Kill a connection sending `PACKET_ID_KILL` packet and removing it from
     /// the connections list.
     pub fn kill_connection(&self, real_pk: PublicKey) -> impl Future<Output = Result<(), KillConnectionError>> {
+        self.kill_connection_with_reason(real_pk, DisconnectReason::Local)
+    }
+
+    /// Kill a connection sending `PACKET_ID_KILL` packet and removing it from
+    /// the connections list. The `Disconnected` event will be sent with the
+    /// specified reason.
+    pub fn kill_connection_with_reason(&self, real_pk: PublicKey, reason: DisconnectReason) -> impl Future<Output = Result<(), KillConnectionError>> {
         if let Some(connection) = self.connections.write().remove(&real_pk) {
             let mut connection = connection.write();
-            self.clear_keys_by_addr(&connection);
+            connection.send_queue.close();
 
-            let status_future = self.send_connection_status(&connection, false)
-                .map_err(|e| e.context(KillConnectionErrorKind::SendToConnectionStatus).into());
+            let status_future = self.send_disconnected_event(&connection, reason)
+                .map_err(|e| e.context(KillConnectionErrorKind::SendToConnectionEvent).into());
             let kill_future = self.send_kill_packet(&mut connection)
                 .map_err(|e| e.context(KillConnectionErrorKind::SendTo).into());
 
@@ -324,28 +334,81 @@ impl NetCrypto {
 
     /// Set friend's UDP IP address when it gets known.
     pub fn set_friend_udp_addr(&self, real_pk: PublicKey, saddr: SocketAddr) {
-        let connections = self.connections.read();
-        let mut connection = if let Some(connection) = connections.get(&real_pk) {
-            connection.write()
+        if self.is_tcp_only() {
+            return
+        }
+
+        let saddr = normalize_saddr(saddr);
+        let mut connections = self.connections.write();
+        let connection = if let Some(connection) = connections.get(&real_pk) {
+            connection.clone()
         } else {
             return
         };
+        let mut connection = connection.write();
 
         if connection.get_udp_addr_v4() == Some(saddr) || connection.get_udp_addr_v6() == Some(saddr) {
             return
         }
 
-        let mut keys_by_addr = self.keys_by_addr.write();
+        // Connection with alive direct UDP is not switched to the new address
+        // until the peer answers from it
+        if connection.is_established() && connection.is_udp_alive() {
+            NetCrypto::add_udp_candidate(&mut connections, &mut connection, saddr);
+            return
+        }
+
         let current_addr = if saddr.is_ipv4() {
             connection.get_udp_addr_v4()
         } else {
             connection.get_udp_addr_v6()
         };
-        if let Some(saddr) = current_addr {
-            keys_by_addr.remove(&(saddr.ip(), saddr.port()));
+        if let Some(current_addr) = current_addr {
+            connections.remove_addr(current_addr, &real_pk);
         }
         connection.set_udp_addr(saddr);
-        keys_by_addr.insert((saddr.ip(), saddr.port()), real_pk);
+        connections.insert_addr(saddr, real_pk);
+    }
+
+    /// Probe UDP addresses of a friend to upgrade established connection to
+    /// direct UDP. Addresses can be found by DHT, e.g. returned by close nodes
+    /// of the friend or after hole punching. Does nothing if direct UDP
+    /// connection is already alive.
+    pub fn probe_friend_udp_addrs(&self, real_pk: PublicKey, addrs: &[SocketAddr]) {
+        if self.is_tcp_only() {
+            return
+        }
+
+        let mut connections = self.connections.write();
+        let connection = if let Some(connection) = connections.get(&real_pk) {
+            connection.clone()
+        } else {
+            return
+        };
+        let mut connection = connection.write();
+
+        if !connection.is_established() || connection.is_udp_alive() {
+            return
+        }
+
+        for &addr in addrs {
+            NetCrypto::add_udp_candidate(&mut connections, &mut connection, normalize_saddr(addr));
+        }
+    }
+
+    /// Add UDP address that should be probed to the connection. Packets from
+    /// this address are accepted so that the peer can answer. Addresses that
+    /// already belong to another connection are skipped since candidates come
+    /// from untrusted sources and must not take over packets of other peers.
+    fn add_udp_candidate(connections: &mut Connections, connection: &mut CryptoConnection, addr: SocketAddr) {
+        if connections.key_by_addr(addr).map_or(false, |pk| pk != connection.peer_real_pk) {
+            trace!("Skipping UDP candidate {} that belongs to another connection", addr);
+            return
+        }
+
+        if connection.add_udp_candidate(addr) {
+            connections.insert_addr(addr, connection.peer_real_pk);
+        }
     }
 
     /// Send lossless packet to a friend via established connection.
@@ -369,17 +432,128 @@ impl NetCrypto {
         }
     }
 
+    /// Check if the packet with specified priority can be added to the send
+    /// queue of the connection. If it can't the current task will be woken up
+    /// when there is free space in the queue or when the connection is removed.
+    pub fn poll_ready(&self, cx: &mut Context, real_pk: PublicKey, priority: PacketPriority) -> Poll<Result<(), SendLosslessPacketError>> {
+        if let Some(connection) = self.connection_by_key(real_pk) {
+            connection.write().send_queue.poll_ready(cx, priority).map(Ok)
+        } else {
+            Poll::Ready(Err(SendLosslessPacketErrorKind::NoConnection.into()))
+        }
+    }
+
+    /// Check if the friend received lossless packet with specified number.
+    /// Packet numbers are valid only for the connection they were sent with,
+    /// so they should be forgotten when the connection is lost.
+    pub fn is_packet_delivered(&self, real_pk: PublicKey, packet_number: u32) -> Result<bool, SendLosslessPacketError> {
+        if let Some(connection) = self.connection_by_key(real_pk) {
+            let connection = connection.read();
+            let buffer_start = connection.send_array.buffer_start;
+            let buffer_end = connection.send_array.buffer_end;
+            // packets before the start of the send buffer are acknowledged
+            Ok(packet_number.wrapping_sub(buffer_start) >= buffer_end.wrapping_sub(buffer_start))
+        } else {
+            Err(SendLosslessPacketErrorKind::NoConnection.into())
+        }
+    }
+
+    /// Send packet to a friend via send queue. Unlike `send_lossless` it
+    /// doesn't fail when there are too many packets in flight but waits until
+    /// there is free space in the queue. Queued packets are sent in order of
+    /// their priorities when congestion control allows it. Packets with
+    /// `PacketPriority::Lossy` priority must have lossy packet ID. The
+    /// returned future is resolved with the number of the packet once it's
+    /// taken from the queue and sent. The number of a lossless packet can be
+    /// passed to `is_packet_delivered` to check if the friend received it.
+    /// Lossless packets that failed to send are sent again when the friend
+    /// requests them so such failures are not returned. If the connection is
+    /// killed before the packet is sent `NoConnection` error is returned.
+    pub fn send_queued(&self, real_pk: PublicKey, packet: Vec<u8>, priority: PacketPriority) -> impl Future<Output = Result<u32, SendLosslessPacketError>> {
+        let is_valid_id = packet.first().map_or(false, |&packet_id| if priority == PacketPriority::Lossy {
+            (PACKET_ID_LOSSY_RANGE_START ..= PACKET_ID_LOSSY_RANGE_END).contains(&packet_id)
+        } else {
+            packet_id > PACKET_ID_CRYPTO_RANGE_END && packet_id < PACKET_ID_LOSSY_RANGE_START
+        });
+        if !is_valid_id {
+            return Either::Right(future::err(SendLosslessPacketErrorKind::InvalidPacketId.into()));
+        }
+
+        let net_crypto = self.clone();
+        let mut packet = Some(packet);
+        Either::Left(async move {
+            // wait for free space and push the packet under the same lock so
+            // that it can't be taken by another task
+            let (number_rx, flush_future) = future::poll_fn(|cx| {
+                let connection = match net_crypto.connection_by_key(real_pk) {
+                    Some(connection) => connection,
+                    None => return Poll::Ready(Err(SendLosslessPacketError::from(SendLosslessPacketErrorKind::NoConnection))),
+                };
+                let mut connection = connection.write();
+                futures::ready!(connection.send_queue.poll_ready(cx, priority));
+                let packet = packet.take().unwrap_or_default();
+                // poll_ready guarantees that there is free space
+                let number_rx = connection.send_queue.push_numbered(priority, packet)
+                    .map_err(|_| SendLosslessPacketError::from(SendLosslessPacketErrorKind::FullSendArray))?;
+                Poll::Ready(Ok((number_rx, net_crypto.flush_send_queue(&mut connection))))
+            }).await?;
+
+            if let Err(e) = flush_future.await {
+                debug!("Failed to send queued packets: {}", e);
+            }
+
+            // the sender is dropped with the send queue when the connection
+            // is killed
+            number_rx.await
+                .map_err(|_| SendLosslessPacketErrorKind::NoConnection.into())
+        })
+    }
+
+    /// Send packets from the send queue as many as congestion control allows.
+    /// Packets are sent only when the connection is established.
+    fn flush_send_queue(&self, connection: &mut CryptoConnection) -> impl Future<Output = Result<(), SendDataError>> + Send {
+        if !connection.is_established() || connection.send_queue.is_empty() {
+            return Either::Right(future::ok(()));
+        }
+
+        let packets = connection.send_queue.drain(connection.packets_allowed());
+        connection.spend_send_budget(packets.len());
+
+        let mut futures = Vec::with_capacity(packets.len());
+        for (priority, packet) in packets {
+            let packet_number = connection.send_array.buffer_end;
+            if priority != PacketPriority::Lossy {
+                // packets_allowed guarantees that there is free space
+                drop(connection.send_array.push_back(SentPacket::new(packet.data.clone())));
+                connection.packets_sent += 1;
+            }
+            if let Some(number_tx) = packet.number_tx {
+                // the receiver might be dropped if nobody waits for the number
+                number_tx.send(packet_number).ok();
+            }
+            futures.push(self.send_data_packet(connection, packet.data, packet_number));
+        }
+
+        Either::Left(future::try_join_all(futures).map_ok(drop))
+    }
+
     /// Send `Packet` packet to UDP socket
     fn send_to_udp(&self, addr: SocketAddr, packet: DhtPacket) -> impl Future<Output = Result<(), mpsc::SendError>> + Send {
+        let tcp_only = self.is_tcp_only();
         let mut tx = self.udp_tx.clone();
         async move {
+            if tcp_only {
+                trace!("Dropping UDP packet to {} in TCP-only mode", addr);
+                return Ok(())
+            }
             tx.send((packet, addr)).await
         }
     }
 
     /// Get long term `PublicKey` of the peer by its UDP address
     fn key_by_addr(&self, addr: SocketAddr) -> Option<PublicKey> {
-        self.keys_by_addr.read().get(&(addr.ip(), addr.port())).cloned()
+        let addr = normalize_saddr(addr);
+        self.connections.read().key_by_addr(addr)
     }
 
     /// Get crypto connection by long term `PublicKey`
@@ -387,10 +561,9 @@ impl NetCrypto {
         self.connections.read().get(&pk).cloned()
     }
 
-    /// Get crypto connection by long term `PublicKey`
+    /// Get crypto connection by DHT `PublicKey`
     fn connection_by_dht_key(&self, pk: PublicKey) -> Option<Arc<RwLock<CryptoConnection>>> {
-        // TODO: should it be optimized?
-        self.connections.read().values().find(|connection| connection.read().peer_dht_pk == pk).cloned()
+        self.connections.read().get_by_dht_pk(&pk).cloned()
     }
 
     /// Create `CookieResponse` packet with `Cookie` requested by `CookieRequest` packet
@@ -477,6 +650,7 @@ impl NetCrypto {
     /// Handle `CookieResponse` packet received from UDP socket
     pub fn handle_udp_cookie_response(&self, packet: &CookieResponse, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let addr = normalize_saddr(addr);
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
@@ -618,10 +792,9 @@ impl NetCrypto {
                 // PublicKey. In this case we kill the old connection and create
                 // a new one.
 
-                self.clear_keys_by_addr(&connection);
-                let status_future = self.send_connection_status(&connection, false)
+                let status_future = self.send_disconnected_event(&connection, DisconnectReason::Replaced)
                     .map_err(|e|
-                        e.context(HandlePacketErrorKind::SendToConnectionStatus).into()
+                        e.context(HandlePacketErrorKind::SendToConnectionEvent).into()
                     );
                 let kill_future = self.send_kill_packet(&mut connection)
                     .map_err(|e|
@@ -642,8 +815,6 @@ impl NetCrypto {
                     ));
                 }
 
-                self.clear_keys_by_addr(&connection);
-
                 Either::Right(future::ok(()))
             }
         } else {
@@ -661,10 +832,13 @@ impl NetCrypto {
         );
         if let Some(addr) = addr {
             connection.set_udp_addr(addr);
-            self.keys_by_addr.write().insert((addr.ip(), addr.port()), cookie.real_pk);
         }
         let connection = Arc::new(RwLock::new(connection));
+        // the old connection is replaced together with its UDP addresses
         connections.insert(cookie.real_pk, connection);
+        if let Some(addr) = addr {
+            connections.insert_addr(addr, cookie.real_pk);
+        }
 
         let msg = (cookie.real_pk, cookie.dht_pk);
         let dht_pk_future = maybe_send_unbounded(self.dht_pk_tx.read().clone(), msg)
@@ -679,6 +853,7 @@ impl NetCrypto {
     /// Handle `CryptoHandshake` packet received from UDP socket
     pub fn handle_udp_crypto_handshake(&self, packet: &CryptoHandshake, addr: SocketAddr)
         -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let addr = normalize_saddr(addr);
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
             let mut connection = connection.write();
@@ -785,6 +960,11 @@ impl NetCrypto {
         let tx = self.lossless_tx.clone();
 
         while let Some(packet) = recv_array.pop_front() {
+            // packets with reserved IDs are handled by net_crypto itself
+            if packet.data.first().map_or(true, |&packet_id| packet_id <= PACKET_ID_CRYPTO_RANGE_END) {
+                continue;
+            }
+
             let res = tx.unbounded_send((pk, packet.data))
                 .map_err(|e| e.into_send_error());
 
@@ -808,12 +988,192 @@ impl NetCrypto {
         last_sent_time
     }
 
+    /// Decrypt `CryptoData` packet using `received_nonce` as the base nonce of
+    /// the peer. Returns the payload and the updated base nonce.
+    fn decrypt_crypto_data(packet: &CryptoData, session_precomputed_key: &PrecomputedKey, mut received_nonce: Nonce)
+        -> Result<(CryptoDataPayload, Nonce), GetPayloadError> {
+        let cur_last_bytes = CryptoData::nonce_last_bytes(received_nonce);
+        let (diff, _) = packet.nonce_last_bytes.overflowing_sub(cur_last_bytes);
+        let mut packet_nonce = received_nonce;
+        increment_nonce_number(&mut packet_nonce, u64::from(diff));
+
+        let payload = packet.get_payload(session_precomputed_key, &packet_nonce)?;
+
+        // Update nonce if diff is big enough
+        if diff > NONCE_DIFF_THRESHOLD * 2 {
+            increment_nonce_number(&mut received_nonce, u64::from(NONCE_DIFF_THRESHOLD));
+        }
+
+        Ok((payload, received_nonce))
+    }
+
+    /// Decrypt `CryptoData` packet that can't be decrypted with the current
+    /// session keys. It might be encrypted with keys of the session that we
+    /// agreed on responding to `RekeyRequest` or with keys of the previous
+    /// session. In the first case the new keys become current. Returns the
+    /// payload and the nonces with the key of the current session.
+    fn decrypt_rekeyed_crypto_data(connection: &mut CryptoConnection, packet: &CryptoData)
+        -> Option<(CryptoDataPayload, Nonce, Nonce, PrecomputedKey)> {
+        if let Some(RekeyStatus::Responded {
+            session_pk,
+            ref session_sk,
+            sent_nonce,
+            received_nonce,
+            ref session_precomputed_key,
+            ..
+        }) = connection.rekey_status {
+            if let Ok((payload, received_nonce)) = NetCrypto::decrypt_crypto_data(packet, session_precomputed_key, received_nonce) {
+                // The peer received our `RekeyResponse` and switched to the new keys
+                let session_sk = session_sk.clone();
+                let session_precomputed_key = session_precomputed_key.clone();
+                connection.replace_session(session_pk, session_sk, sent_nonce, received_nonce, session_precomputed_key.clone());
+                return Some((payload, sent_nonce, received_nonce, session_precomputed_key));
+            }
+        }
+
+        if let ConnectionStatus::Established { sent_nonce, received_nonce, ref session_precomputed_key } = connection.status {
+            if let Some(ref mut previous_session) = connection.previous_session {
+                let decrypted = NetCrypto::decrypt_crypto_data(
+                    packet,
+                    &previous_session.session_precomputed_key,
+                    previous_session.received_nonce
+                );
+                if let Ok((payload, previous_received_nonce)) = decrypted {
+                    previous_session.received_nonce = previous_received_nonce;
+                    return Some((payload, sent_nonce, received_nonce, session_precomputed_key.clone()));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Handle `RekeyRequest` packet generating new session keys and sending
+    /// them back with `RekeyResponse` packet. When both sides requested
+    /// rotation simultaneously the request with bigger session key wins.
+    fn handle_rekey_request(connection: &mut CryptoConnection, request: &RekeyRequest) {
+        if let Some(RekeyStatus::Requested { session_pk, .. }) = connection.rekey_status {
+            if session_pk.as_ref() > request.session_pk.as_ref() {
+                return;
+            }
+        }
+
+        let (session_pk, session_sk) = gen_keypair();
+        connection.rekey_status = Some(RekeyStatus::Responded {
+            request_pk: request.session_pk,
+            session_pk,
+            session_sk: session_sk.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: request.base_nonce,
+            session_precomputed_key: precompute(&request.session_pk, &session_sk),
+            time: clock_now(),
+            response_queued: false,
+        });
+
+        NetCrypto::queue_rekey_response(connection);
+    }
+
+    /// Put `RekeyResponse` packet to the send queue if we responded to
+    /// `RekeyRequest` but didn't manage to do it yet because the queue was
+    /// full. The request is already acknowledged so the peer won't resend it.
+    fn queue_rekey_response(connection: &mut CryptoConnection) {
+        if let Some(RekeyStatus::Responded { request_pk, session_pk, sent_nonce, ref mut response_queued, .. }) = connection.rekey_status {
+            if *response_queued {
+                return;
+            }
+
+            let response = RekeyResponse {
+                request_pk,
+                session_pk,
+                base_nonce: sent_nonce,
+            };
+            let mut buf = vec![0; 1 + PUBLICKEYBYTES * 2 + NONCEBYTES];
+            let (_, size) = response.to_bytes((&mut buf, 0)).unwrap();
+            buf.truncate(size);
+
+            if connection.send_queue.push(PacketPriority::Control, buf).is_ok() {
+                *response_queued = true;
+            } else {
+                warn!("Failed to respond to rekey request: send queue is full");
+            }
+        }
+    }
+
+    /// Handle `RekeyResponse` packet switching to the new session keys if it's
+    /// the response to our pending rotation request. Responses to requests that
+    /// were aborted are ignored.
+    fn handle_rekey_response(connection: &mut CryptoConnection, response: &RekeyResponse) {
+        if let Some(RekeyStatus::Requested { session_pk, ref session_sk, sent_nonce, .. }) = connection.rekey_status {
+            if response.request_pk != session_pk {
+                debug!("Ignoring rekey response to another request from {:?}", connection.peer_real_pk);
+                return;
+            }
+
+            let session_sk = session_sk.clone();
+            let session_precomputed_key = precompute(&response.session_pk, &session_sk);
+            connection.replace_session(session_pk, session_sk, sent_nonce, response.base_nonce, session_precomputed_key);
+        }
+    }
+
+    /// Send our capabilities to the peer if it didn't announce its own ones.
+    /// This packet is not stored in the send array since it's not necessary
+    /// for it to be delivered.
+    fn send_capabilities_packet(&self, connection: &mut CryptoConnection) -> impl Future<Output = Result<(), SendDataError>> + Send {
+        if !connection.capabilities_should_be_sent() {
+            return Either::Right(future::ok(()));
+        }
+
+        connection.capabilities_sent += 1;
+        connection.capabilities_sent_time = Some(clock_now());
+
+        let capabilities = Capabilities {
+            capabilities: CAPABILITY_REKEY,
+        };
+        let mut buf = vec![0; 2];
+        let (_, size) = capabilities.to_bytes((&mut buf, 0)).unwrap();
+        buf.truncate(size);
+
+        let packet_number = connection.send_array.buffer_end;
+        Either::Left(self.send_data_packet(connection, buf, packet_number))
+    }
+
+    /// Start session keys rotation sending `RekeyRequest` packet if it's
+    /// necessary according to the config.
+    fn start_rekey(connection: &mut CryptoConnection, config: &RekeyConfig) {
+        if !connection.rekey_should_be_started(config) {
+            return;
+        }
+
+        let (session_pk, session_sk) = gen_keypair();
+        let sent_nonce = gen_nonce();
+        let request = RekeyRequest {
+            session_pk,
+            base_nonce: sent_nonce,
+        };
+        let mut buf = vec![0; 1 + PUBLICKEYBYTES + NONCEBYTES];
+        let (_, size) = request.to_bytes((&mut buf, 0)).unwrap();
+        buf.truncate(size);
+
+        if connection.send_queue.push(PacketPriority::Control, buf).is_ok() {
+            connection.rekey_status = Some(RekeyStatus::Requested {
+                session_pk,
+                session_sk,
+                sent_nonce,
+                time: clock_now(),
+            });
+        }
+    }
+
     /** Handle `CryptoData` packet
 
     Every data packet contains `buffer_start` index. All packets with index
     lower than `buffer_start` index were received by other side. So we can
     delete all these packets from sent packets array.
 
+    If the packet was received via UDP its source address becomes the UDP
+    address of the connection, but only once the packet is decrypted so that
+    spoofed packets can't switch the connection to another address.
+
     Then depending on type of the data packet we can do:
     - kill type: kill the connection
     - request type: mark packets from the sent packets buffer that they should
@@ -825,9 +1185,9 @@ impl NetCrypto {
     fn handle_crypto_data(&self,
         connection: &mut CryptoConnection,
         packet: &CryptoData,
-        udp: bool
+        udp_addr: Option<SocketAddr>
     ) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
-        let (sent_nonce, mut received_nonce, session_precomputed_key) =
+        let (sent_nonce, received_nonce, session_precomputed_key) =
             match connection.status {
                 ConnectionStatus::NotConfirmed {
                     sent_nonce,
@@ -850,18 +1210,27 @@ impl NetCrypto {
                 }
             };
 
-        let cur_last_bytes = CryptoData::nonce_last_bytes(received_nonce);
-        let (diff, _) = packet.nonce_last_bytes.overflowing_sub(cur_last_bytes);
-        let mut packet_nonce = received_nonce;
-        increment_nonce_number(&mut packet_nonce, u64::from(diff));
-
-        let payload = match packet.get_payload(&session_precomputed_key, &packet_nonce) {
-            Ok(payload) => payload,
+        let decrypted = match NetCrypto::decrypt_crypto_data(packet, &session_precomputed_key, received_nonce) {
+            Ok((payload, received_nonce)) => {
+                if connection.rekey_status.as_ref().map_or(false, RekeyStatus::is_abandoned) {
+                    debug!("Session keys rotation with {:?} was abandoned by the peer", connection.peer_real_pk);
+                    connection.rekey_status = None;
+                }
+                Ok((payload, sent_nonce, received_nonce, session_precomputed_key))
+            },
+            Err(e) => NetCrypto::decrypt_rekeyed_crypto_data(connection, packet).ok_or(e),
+        };
+        let (payload, sent_nonce, received_nonce, session_precomputed_key) = match decrypted {
+            Ok(decrypted) => decrypted,
             Err(e) => return future::err(
                 e.context(HandlePacketErrorKind::GetPayload).into()
             ).boxed()
         };
 
+        if let Some(addr) = udp_addr {
+            connection.set_udp_addr(addr);
+        }
+
         // Find the time when the last acknowledged packet was sent
         let mut last_sent_time = NetCrypto::last_sent_time(
             &connection.send_array,
@@ -885,22 +1254,26 @@ impl NetCrypto {
 
         if packet_id == PACKET_ID_KILL {
             // Kill the connection
-            let status_future = self.send_connection_status(&connection, false)
-                .map_err(|e| e.context(HandlePacketErrorKind::SendToConnectionStatus).into())
+            let status_future = self.send_disconnected_event(&connection, DisconnectReason::Killed)
+                .map_err(|e| e.context(HandlePacketErrorKind::SendToConnectionEvent).into())
                 .boxed();
-            self.connections.write().remove(&connection.peer_real_pk);
-            self.clear_keys_by_addr(&connection);
+            // the connection is removed by the caller when its lock is
+            // released since `connections` lock has to be taken first
+            connection.killed = true;
+            connection.send_queue.close();
             return status_future;
         }
 
-        // Update nonce if diff is big enough
-        if diff > NONCE_DIFF_THRESHOLD * 2 {
-            increment_nonce_number(&mut received_nonce, u64::from(NONCE_DIFF_THRESHOLD));
-        }
-
-        let status_future = self.send_connection_status(&connection, true)
-            .map_err(|e| e.context(HandlePacketErrorKind::SendToConnectionStatus).into());
-
+        let status_future = if connection.is_established() {
+            Either::Left(future::ok(()))
+        } else {
+            let transport = connection.transport(self.is_tcp_only());
+            connection.established_time = Some(clock_now());
+            connection.session_start_time = clock_now();
+            connection.reported_transport = Some(transport);
+            Either::Right(self.send_connection_event(&connection, ConnectionEvent::Connected(transport))
+                .map_err(|e| e.context(HandlePacketErrorKind::SendToConnectionEvent).into()))
+        };
         connection.status = ConnectionStatus::Established {
             sent_nonce,
             received_nonce,
@@ -909,7 +1282,7 @@ impl NetCrypto {
 
         let result = if packet_id == PACKET_ID_REQUEST {
             // Use const RTT in case of TCP connection
-            let rtt = if udp { connection.rtt } else { TCP_RTT };
+            let rtt = if udp_addr.is_some() { connection.rtt } else { TCP_RTT };
             NetCrypto::handle_request_packet(
                 &mut connection.send_array,
                 &payload.data[1..],
@@ -919,6 +1292,36 @@ impl NetCrypto {
             // want to handle this packet even if connection is too slow
             connection.recv_array.set_buffer_end(payload.packet_number).ok();
             future::ok(()).boxed()
+        } else if packet_id == PACKET_ID_CAPABILITIES {
+            let capabilities = match Capabilities::from_bytes(&payload.data) {
+                Ok((_, capabilities)) => capabilities,
+                Err(_) => return future::err(HandlePacketError::invalid_control_packet(packet_id)).boxed(),
+            };
+            connection.peer_capabilities = Some(capabilities.capabilities);
+            // Update end index of received buffer ignoring the error - we still
+            // want to handle this packet even if connection is too slow
+            connection.recv_array.set_buffer_end(payload.packet_number).ok();
+            future::ok(()).boxed()
+        } else if packet_id == PACKET_ID_REKEY_REQUEST || packet_id == PACKET_ID_REKEY_RESPONSE {
+            // Rekey packets are lossless so they are stored in the received
+            // packets buffer but they are handled right away
+            if let Err(e) = connection.recv_array.insert(payload.packet_number, RecvPacket::new(payload.data.clone())) {
+                return future::err(e.context(HandlePacketErrorKind::PacketsArrayError).into()).boxed()
+            }
+            connection.packets_received += 1;
+            let handled = if packet_id == PACKET_ID_REKEY_REQUEST {
+                RekeyRequest::from_bytes(&payload.data)
+                    .map(|(_, request)| NetCrypto::handle_rekey_request(connection, &request))
+            } else {
+                RekeyResponse::from_bytes(&payload.data)
+                    .map(|(_, response)| NetCrypto::handle_rekey_response(connection, &response))
+            };
+            if handled.is_err() {
+                return future::err(HandlePacketError::invalid_control_packet(packet_id)).boxed()
+            }
+            self.process_ready_lossless_packets(&mut connection.recv_array, connection.peer_real_pk)
+                .map_err(|e| e.context(HandlePacketErrorKind::SendToLossless).into())
+                .boxed()
         } else if packet_id > PACKET_ID_CRYPTO_RANGE_END && packet_id < PACKET_ID_LOSSY_RANGE_START {
             if let Err(e) = connection.recv_array.insert(payload.packet_number, RecvPacket::new(payload.data)) {
                 return future::err(e.context(HandlePacketErrorKind::PacketsArrayError).into()).boxed()
@@ -944,7 +1347,7 @@ impl NetCrypto {
             return future::err(HandlePacketError::packet_id(packet_id)).boxed()
         };
 
-        // TODO: update rtt only when udp is true?
+        // TODO: update rtt only when received via UDP?
         if let Some(last_sent_time) = last_sent_time {
             // Update rtt if it's become lower
             let elapsed = clock_elapsed(last_sent_time);
@@ -958,11 +1361,12 @@ impl NetCrypto {
 
     /// Handle `CryptoData` packet received from UDP socket
     pub fn handle_udp_crypto_data(&self, packet: &CryptoData, addr: SocketAddr) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let addr = normalize_saddr(addr);
         let connection = self.key_by_addr(addr).and_then(|pk| self.connection_by_key(pk));
         if let Some(connection) = connection {
-            let mut connection = connection.write();
-            connection.set_udp_addr(addr);
-            Either::Left(self.handle_crypto_data(&mut connection, packet, /* udp */ true))
+            let future = self.handle_crypto_data(&mut connection.write(), packet, Some(addr));
+            self.remove_if_killed(&connection);
+            Either::Left(future)
         } else {
             Either::Right(future::err(HandlePacketError::no_udp_connection(addr)))
         }
@@ -972,19 +1376,37 @@ impl NetCrypto {
     pub fn handle_tcp_crypto_data(&self, packet: &CryptoData, sender_pk: PublicKey) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         let connection = self.connection_by_dht_key(sender_pk);
         if let Some(connection) = connection {
-            let mut connection = connection.write();
-            Either::Left(self.handle_crypto_data(&mut connection, packet, /* udp */ false))
+            let future = self.handle_crypto_data(&mut connection.write(), packet, None);
+            self.remove_if_killed(&connection);
+            Either::Left(future)
         } else {
             Either::Right(future::err(HandlePacketError::no_tcp_connection(sender_pk)))
         }
     }
 
+    /// Remove the connection if the peer sent us `PACKET_ID_KILL` packet. The
+    /// connection must not be locked.
+    fn remove_if_killed(&self, connection: &Arc<RwLock<CryptoConnection>>) {
+        let real_pk = {
+            let connection = connection.read();
+            if !connection.killed {
+                return;
+            }
+            connection.peer_real_pk
+        };
+        // the connection might be replaced by a new one while we weren't
+        // holding the lock
+        self.connections.write().remove_exact(&real_pk, connection);
+    }
+
     /// Send packet to crypto connection choosing TCP or UDP protocol
     fn send_packet(&self, packet: Packet, connection: &mut CryptoConnection) -> impl Future<Output = Result<(), SendPacketError>> + Send {
-        // TODO: can backpressure be used instead of congestion control? It
-        // seems it's possible to implement wrapper for bounded sender with
-        // priority queue and just send packets there
-        let udp_future = if let Some(addr) = connection.get_udp_addr() {
+        let udp_addr = if self.is_tcp_only() {
+            None
+        } else {
+            connection.get_udp_addr()
+        };
+        let udp_future = if let Some(addr) = udp_addr {
             if connection.is_udp_alive() {
                 return Either::Left(Box::new(self.send_to_udp(addr, packet.into()))
                     .map_err(|e| e.context(SendPacketErrorKind::Udp).into()))
@@ -1032,6 +1454,16 @@ impl NetCrypto {
     /// Send `CryptoData` packet if the connection is established.
     fn send_data_packet(&self, connection: &mut CryptoConnection, data: Vec<u8>, packet_number: u32)
         -> impl Future<Output = Result<(), SendDataError>> + Send {
+        match NetCrypto::encrypt_data_packet(connection, data, packet_number) {
+            Some(packet) => Either::Right(self.send_packet(Packet::CryptoData(packet), connection)
+                .map_err(|e| e.context(SendDataErrorKind::SendTo).into())),
+            None => Either::Left(future::err(SendDataError::from(SendDataErrorKind::NoConnection))),
+        }
+    }
+
+    /// Encrypt data packet with the current session keys. Returns `None` if
+    /// the connection has no session keys yet.
+    fn encrypt_data_packet(connection: &mut CryptoConnection, data: Vec<u8>, packet_number: u32) -> Option<CryptoData> {
         let packet = match connection.status {
             ConnectionStatus::NotConfirmed { ref mut sent_nonce, ref session_precomputed_key, .. }
             | ConnectionStatus::Established { ref mut sent_nonce, ref session_precomputed_key, .. } => {
@@ -1044,10 +1476,40 @@ impl NetCrypto {
                 increment_nonce(sent_nonce);
                 packet
             },
-            _ => return Either::Left(future::err(SendDataError::from(SendDataErrorKind::NoConnection))),
+            _ => return None,
         };
-        Either::Right(self.send_packet(Packet::CryptoData(packet), connection)
-            .map_err(|e| e.context(SendDataErrorKind::SendTo).into()))
+        connection.session_packets_sent += 1;
+        Some(packet)
+    }
+
+    /// Send request packet directly to the not confirmed UDP address of the
+    /// peer. Request packet is used as a probe since it doesn't change the
+    /// state of the peer. When the peer answers from this address it becomes
+    /// the current UDP address of the connection.
+    fn send_udp_probe(&self, connection: &mut CryptoConnection, addr: SocketAddr) -> impl Future<Output = Result<(), SendDataError>> + Send {
+        let data = NetCrypto::generate_request_packet(&connection.recv_array);
+        let packet_number = connection.send_array.buffer_end;
+        match NetCrypto::encrypt_data_packet(connection, data, packet_number) {
+            Some(packet) => Either::Left(self.send_to_udp(addr, DhtPacket::CryptoData(packet))
+                .map_err(|e| e.context(SendDataErrorKind::SendTo).into())),
+            None => Either::Right(future::err(SendDataError::from(SendDataErrorKind::NoConnection))),
+        }
+    }
+
+    /// Send request packet via TCP relays while direct UDP connection is alive
+    /// so that TCP path can be used as a fallback when UDP dies.
+    fn send_tcp_keepalive(&self, connection: &mut CryptoConnection) -> impl Future<Output = Result<(), SendDataError>> + Send {
+        connection.tcp_keepalive_time = Some(clock_now());
+        let data = NetCrypto::generate_request_packet(&connection.recv_array);
+        let packet_number = connection.send_array.buffer_end;
+        match NetCrypto::encrypt_data_packet(connection, data, packet_number) {
+            Some(packet) => {
+                let tcp_tx = self.tcp_tx.read().clone();
+                Either::Left(maybe_send_bounded(tcp_tx, (Packet::CryptoData(packet).into(), connection.peer_dht_pk))
+                    .map_err(|e| e.context(SendDataErrorKind::SendTo).into()))
+            },
+            None => Either::Right(future::err(SendDataError::from(SendDataErrorKind::NoConnection))),
+        }
     }
 
     /// Send request packet with indices of not received packets.
@@ -1070,6 +1532,8 @@ impl NetCrypto {
                 (i, packet.data.clone())
             }).collect::<Vec<_>>();
 
+        connection.total_packets_resent += packets.len() as u64;
+
         let futures: Vec<_> = packets.into_iter().map(|(i, data)|
             self.send_data_packet(connection, data, i)
         ).collect();
@@ -1081,33 +1545,34 @@ impl NetCrypto {
     fn main_loop(&self) -> impl Future<Output = Result<(), SendDataError>> + Send {
         use std::pin::Pin;
 
-        let mut connections = self.connections.write();
-        let mut keys_by_addr = self.keys_by_addr.write();
+        let tcp_only = self.is_tcp_only();
+        let rekey_config = *self.rekey_config.read();
+        let has_tcp = self.tcp_tx.read().is_some();
         let mut futures: Vec<Pin<Box<dyn Future<Output = Result<_, _>> + Send>>> = Vec::new();
+        let mut timed_out = Vec::new();
+        let mut unreachable_addrs = Vec::new();
 
-        // Only one cycle over all connections to prevent many lock acquirements
-        connections.retain(|_pk, connection| {
-            let mut connection = connection.write();
+        // Connections are locked one by one and the connections list is not
+        // locked while they are processed so that incoming packets can be
+        // handled in the meantime
+        let connections = self.connections.read().values().cloned().collect::<Vec<_>>();
+        for connection_lock in connections {
+            let mut connection = connection_lock.write();
 
             if connection.is_timed_out() {
-                if let Some(addr) = connection.get_udp_addr_v4() {
-                    keys_by_addr.remove(&(addr.ip(), addr.port()));
-                }
-                if let Some(addr) = connection.get_udp_addr_v6() {
-                    keys_by_addr.remove(&(addr.ip(), addr.port()));
-                }
-
-                if connection.is_established() {
-                    let status_future = self.send_connection_status(&connection, false)
-                        .map_err(|e| e.context(SendDataErrorKind::SendToConnectionStatus).into());
-                    futures.push(Box::pin(status_future));
-                }
+                // only not established connections can be timed out
+                let status_future = self.send_connection_event(&connection, ConnectionEvent::Disconnected(DisconnectReason::HandshakeFailed))
+                    .map_err(|e| e.context(SendDataErrorKind::SendToConnectionEvent).into());
+                futures.push(Box::pin(status_future));
 
                 if connection.is_established() || connection.is_not_confirmed() {
                     futures.push(Box::pin(self.send_kill_packet(&mut connection)));
                 }
 
-                return false;
+                connection.send_queue.close();
+
+                timed_out.push((connection.peer_real_pk, connection_lock.clone()));
+                continue;
             }
 
             let send_future = self.send_status_packet(&mut connection)
@@ -1134,14 +1599,62 @@ impl NetCrypto {
                     }
                 }
 
-                // TODO: either use send_rate or remove it
+                let transport = connection.transport(tcp_only);
+                if connection.reported_transport != Some(transport) {
+                    connection.reported_transport = Some(transport);
+                    let event_future = self.send_connection_event(&connection, ConnectionEvent::TransportChanged(transport))
+                        .map_err(|e| e.context(SendDataErrorKind::SendToConnectionEvent).into());
+                    futures.push(Box::pin(event_future));
+                }
+
                 connection.update_congestion_stats();
 
+                if connection.previous_session.as_ref().map_or(false, PreviousSession::is_expired) {
+                    connection.previous_session = None;
+                }
+
+                if connection.rekey_status.as_ref().map_or(false, RekeyStatus::is_expired) {
+                    debug!("Session keys rotation with {:?} timed out", connection.peer_real_pk);
+                    connection.rekey_status = None;
+                }
+                NetCrypto::queue_rekey_response(&mut connection);
+
+                if !tcp_only {
+                    let peer_real_pk = connection.peer_real_pk;
+                    unreachable_addrs.extend(connection.remove_unreachable_udp_candidates()
+                        .into_iter()
+                        .map(|addr| (addr, peer_real_pk)));
+
+                    for addr in connection.next_udp_probes() {
+                        futures.push(Box::pin(self.send_udp_probe(&mut connection, addr)));
+                    }
+
+                    if has_tcp && connection.tcp_keepalive_should_be_sent() {
+                        futures.push(Box::pin(self.send_tcp_keepalive(&mut connection)));
+                    }
+                }
+
+                if let Some(ref rekey_config) = rekey_config {
+                    futures.push(Box::pin(self.send_capabilities_packet(&mut connection)));
+                    NetCrypto::start_rekey(&mut connection, rekey_config);
+                }
+
                 futures.push(Box::pin(self.send_requested_packets(&mut connection)));
+                futures.push(Box::pin(self.flush_send_queue(&mut connection)));
             }
+        }
 
-            true
-        });
+        if !timed_out.is_empty() || !unreachable_addrs.is_empty() {
+            let mut connections = self.connections.write();
+            for (real_pk, connection) in timed_out {
+                // the connection might be replaced by a new one while we
+                // weren't holding the lock
+                connections.remove_exact(&real_pk, &connection);
+            }
+            for (addr, real_pk) in unreachable_addrs {
+                connections.remove_addr(addr, &real_pk);
+            }
+        }
 
         future::try_join_all(futures).map_ok(drop)
     }
@@ -1174,21 +1687,53 @@ impl NetCrypto {
         }
     }
 
+    /// Get statistics of the connection to a friend with specified long term
+    /// `PublicKey`. Returns `None` if there is no such connection.
+    pub fn connection_info(&self, real_pk: PublicKey) -> Option<ConnectionInfo> {
+        let tcp_only = self.is_tcp_only();
+        self.connections.read()
+            .get(&real_pk)
+            .map(|connection| connection.read().info(tcp_only))
+    }
+
     /// Set sink to send DHT `PublicKey` when it gets known.
     pub fn set_dht_pk_sink(&self, dht_pk_tx: DhtPkTx) {
         *self.dht_pk_tx.write() = Some(dht_pk_tx);
     }
 
-    /// Set sink to send a connection status when it becomes connected or
-    /// disconnected.
-    pub fn set_connection_status_sink(&self, connection_status_tx: ConnectionStatusTx) {
-        *self.connection_status_tx.write() = Some(connection_status_tx);
+    /// Set sink to send connection events.
+    pub fn set_connection_event_sink(&self, connection_event_tx: ConnectionEventTx) {
+        *self.connection_event_tx.write() = Some(connection_event_tx);
     }
 
     /// Set sink for sending TCP packets via relays.
     pub fn set_tcp_sink(&self, tcp_tx: TcpTx) {
         *self.tcp_tx.write() = Some(tcp_tx);
     }
+
+    /// Enable/disable TCP-only mode. In this mode packets are sent only via
+    /// TCP relays and direct UDP connections are never attempted.
+    pub fn set_tcp_only(&self, tcp_only: bool) {
+        *self.tcp_only.write() = tcp_only;
+    }
+
+    /// Set config of session keys rotation. When it's set we announce that we
+    /// support rotation and rotate keys of connections to peers that announced
+    /// it as well. We always respond to rotation requests regardless of this
+    /// config.
+    pub fn set_rekey_config(&self, rekey_config: Option<RekeyConfig>) {
+        *self.rekey_config.write() = rekey_config;
+    }
+
+    /// Get config of session keys rotation.
+    pub fn rekey_config(&self) -> Option<RekeyConfig> {
+        *self.rekey_config.read()
+    }
+
+    /// Check if TCP-only mode is enabled.
+    pub fn is_tcp_only(&self) -> bool {
+        *self.tcp_only.read()
+    }
 }
 
 #[cfg(test)]
@@ -1235,6 +1780,20 @@ mod tests {
         pub fn get_session_pk(&self, friend_pk: &PublicKey) -> Option<PublicKey> {
             self.connections.read().get(friend_pk).map(|connection| connection.read().session_pk)
         }
+
+        /// Mark sent packets before `buffer_start` as received by the friend.
+        pub fn ack_sent_packets(&self, friend_pk: &PublicKey, buffer_start: u32) {
+            let connections = self.connections.read();
+            let mut connection = connections[friend_pk].write();
+            connection.send_array.set_buffer_start(buffer_start).unwrap();
+        }
+
+        /// Fill the send queue so that new packets have to wait for free space.
+        pub fn fill_send_queue(&self, friend_pk: &PublicKey, priority: PacketPriority) {
+            let connections = self.connections.read();
+            let mut connection = connections[friend_pk].write();
+            while connection.send_queue.push(priority, vec![PACKET_ID_CRYPTO_RANGE_END + 1]).is_ok() { }
+        }
     }
 
     #[test]
@@ -1642,7 +2201,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let cookie = EncryptedCookie {
             nonce: secretbox::gen_nonce(),
@@ -2138,7 +2697,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
         let base_nonce = gen_nonce();
@@ -2297,7 +2856,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let (new_peer_dht_pk, _new_peer_dht_sk) = gen_keypair();
         let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
@@ -2317,9 +2876,15 @@ mod tests {
         };
         let crypto_handshake = CryptoHandshake::new(&real_precomputed_key, &crypto_handshake_payload, our_encrypted_cookie);
 
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
         let new_addr = "127.0.0.2:12345".parse().unwrap();
         net_crypto.handle_udp_crypto_handshake(&crypto_handshake, new_addr).await.unwrap();
 
+        let (event, _connection_event_rx) = connection_event_rx.into_future().await;
+        assert_eq!(event, Some((peer_real_pk, ConnectionEvent::Disconnected(DisconnectReason::Replaced))));
+
         // the old connection should be replaced with the new one
 
         let connections = net_crypto.connections.read();
@@ -2338,8 +2903,8 @@ mod tests {
         let payload = packet.get_payload(&real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
 
-        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
-        assert!(net_crypto.keys_by_addr.read().contains_key(&(new_addr.ip(), new_addr.port())));
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+        assert!(net_crypto.connections.read().key_by_addr(new_addr).is_some());
 
         // the old connection should be killed
 
@@ -2386,7 +2951,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
         let base_nonce = gen_nonce();
@@ -2425,8 +2990,8 @@ mod tests {
         let payload = packet.get_payload(&real_precomputed_key).unwrap();
         assert_eq!(payload.cookie_hash, cookie.hash());
 
-        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
-        assert!(net_crypto.keys_by_addr.read().contains_key(&(new_addr.ip(), new_addr.port())));
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+        assert!(net_crypto.connections.read().key_by_addr(new_addr).is_some());
     }
 
     #[tokio::test]
@@ -2471,7 +3036,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let real_precomputed_key = precompute(&real_pk, &peer_real_sk);
         let base_nonce = gen_nonce();
@@ -2584,7 +3149,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2602,11 +3168,11 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn handle_crypto_data_lossy_increment_nonce() {
+    async fn handle_crypto_data_established() {
         crypto_init().unwrap();
         let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
         let (real_pk, real_sk) = gen_keypair();
         let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
@@ -2626,28 +3192,107 @@ mod tests {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
+        let crypto_handshake = CryptoHandshake {
+            cookie: EncryptedCookie {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 88]
+            },
+            nonce: gen_nonce(),
+            payload: vec![42; 248]
+        };
+
         let received_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
-        connection.status = ConnectionStatus::Established {
+        connection.status = ConnectionStatus::NotConfirmed {
             sent_nonce: gen_nonce(),
             received_nonce,
             session_precomputed_key: session_precomputed_key.clone(),
+            packet: StatusPacketWithTime::new_crypto_handshake(crypto_handshake),
         };
 
-        // Make the diff between nonces is bigger than the threshold
-        let mut packet_nonce = received_nonce;
-        increment_nonce_number(&mut packet_nonce, u64::from(2 * NONCE_DIFF_THRESHOLD + 1));
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
 
-        let crypto_data_payload = CryptoDataPayload {
-            buffer_start: 0,
+        tokio::time::pause();
+        let now = clock_now();
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).await.unwrap();
+
+        assert!(connection.is_established());
+        assert_eq!(connection.established_time, Some(now));
+        assert_eq!(connection.reported_transport, Some(ConnectionTransport::Tcp));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        // established time shouldn't be changed by subsequent packets
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, None).await.unwrap();
+
+        assert_eq!(connection.established_time, Some(now));
+
+        drop(net_crypto.connection_event_tx);
+
+        // connected event should be sent only once
+        let events = connection_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![(peer_real_pk, ConnectionEvent::Connected(ConnectionTransport::Tcp))]);
+    }
+
+    #[tokio::test]
+    async fn handle_crypto_data_lossy_increment_nonce() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        // Make the diff between nonces is bigger than the threshold
+        let mut packet_nonce = received_nonce;
+        increment_nonce_number(&mut packet_nonce, u64::from(2 * NONCE_DIFF_THRESHOLD + 1));
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
             packet_number: 0,
             data: vec![PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, packet_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
 
         // The diff between nonces is bigger than the threshold so received
         // nonce should be changed increased
@@ -2723,7 +3368,8 @@ mod tests {
 
         tokio::time::advance(Duration::from_millis(250)).await;
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2788,7 +3434,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketsArrayError);
 
@@ -2857,9 +3504,10 @@ mod tests {
         let crypto_data_3 = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload_3);
 
         // Send packets in random order
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_2, /* udp */ true).await.unwrap();
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_3, /* udp */ true).await.unwrap();
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data_1, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data_2, Some(addr)).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data_3, Some(addr)).await.unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data_1, Some(addr)).await.unwrap();
 
         // The diff between nonces is not bigger than the threshold so received
         // nonce shouldn't be changed
@@ -2930,7 +3578,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketsArrayError);
 
@@ -2982,7 +3631,7 @@ mod tests {
 
         let connection = Arc::new(RwLock::new(connection));
         net_crypto.connections.write().insert(peer_real_pk, connection.clone());
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
@@ -2991,10 +3640,17 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, /* udp */ true).await.unwrap();
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        net_crypto.handle_udp_crypto_data(&crypto_data, addr).await.unwrap();
 
-        assert!(net_crypto.connections.read().is_empty());
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        assert!(connection.read().killed);
+        assert!(net_crypto.connections.read().values().next().is_none());
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+
+        let (event, _connection_event_rx) = connection_event_rx.into_future().await;
+        assert_eq!(event, Some((peer_real_pk, ConnectionEvent::Disconnected(DisconnectReason::Killed))));
     }
 
     #[tokio::test]
@@ -3065,7 +3721,8 @@ mod tests {
 
         tokio::time::advance(Duration::from_secs(1)).await;
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
 
         assert!(connection.send_array.get(0).unwrap().requested);
         assert!(connection.send_array.get(1).is_none());
@@ -3128,7 +3785,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await.unwrap();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
 
         assert!(!connection.send_array.get(0).unwrap().requested);
         assert!(!connection.send_array.get(1).unwrap().requested);
@@ -3179,7 +3837,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::PacketId { id: 255 });
 
@@ -3233,7 +3892,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::DataEmpty);
 
@@ -3281,7 +3941,8 @@ mod tests {
         };
         let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
 
-        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, /* udp */ true).await;
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let res = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await;
         assert!(res.is_err());
         assert_eq!(*res.err().unwrap().kind(), HandlePacketErrorKind::CannotHandleCryptoData);
     }
@@ -3328,7 +3989,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         let crypto_data_payload = CryptoDataPayload {
             buffer_start: 0,
@@ -3571,6 +4232,60 @@ mod tests {
         assert_eq!(received, TcpDataPayload::CryptoData(packet));
     }
 
+    #[tokio::test]
+    async fn send_packet_tcp_only() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(1);
+        let (tcp_tx, tcp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_tcp_sink(tcp_tx);
+        net_crypto.set_tcp_only(true);
+        assert!(net_crypto.is_tcp_only());
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        // UDP address is considered alive right after setting but it must not
+        // be used
+        connection.set_udp_addr(addr);
+
+        let packet = CryptoData {
+            nonce_last_bytes: 123,
+            payload: vec![42; 123]
+        };
+
+        net_crypto.send_packet(Packet::CryptoData(packet.clone()), &mut connection).await.unwrap();
+
+        let (received, _tcp_rx) = tcp_rx.into_future().await;
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+        assert_eq!(received, TcpDataPayload::CryptoData(packet));
+
+        // Necessary to drop udp_tx so that udp_rx.collect::<Vec<_>>() can be finished
+        drop(net_crypto);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+    }
+
     #[tokio::test]
     async fn send_packet_tcp() {
         crypto_init().unwrap();
@@ -3691,12 +4406,83 @@ mod tests {
         assert!(connection.is_timed_out());
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
+
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        net_crypto.main_loop().await.unwrap();
+
+        assert!(net_crypto.connections.read().values().next().is_none());
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+
+        let (event, _connection_event_rx) = connection_event_rx.into_future().await;
+        assert_eq!(event, Some((peer_real_pk, ConnectionEvent::Disconnected(DisconnectReason::HandshakeFailed))));
+    }
+
+    #[tokio::test]
+    async fn main_loop_transport_changed() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
+        };
+        // the connection was established via TCP
+        connection.reported_transport = Some(ConnectionTransport::Tcp);
+        // and then UDP packet was received
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V4(addr));
+        // don't send request packets
+        connection.request_packet_sent_time = Some(clock_now());
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        net_crypto.main_loop().await.unwrap();
+        // transport is not changed since the last call
+        net_crypto.main_loop().await.unwrap();
+
+        assert_eq!(connection.read().reported_transport, Some(ConnectionTransport::UdpV4(addr)));
+
+        // UDP is not used in TCP-only mode
+        net_crypto.set_tcp_only(true);
         net_crypto.main_loop().await.unwrap();
 
-        assert!(net_crypto.connections.read().is_empty());
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        drop(net_crypto.connection_event_tx);
+
+        let events = connection_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            (peer_real_pk, ConnectionEvent::TransportChanged(ConnectionTransport::UdpV4(addr))),
+            (peer_real_pk, ConnectionEvent::TransportChanged(ConnectionTransport::Tcp)),
+        ]);
     }
 
     #[tokio::test]
@@ -3739,7 +4525,7 @@ mod tests {
         };
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         net_crypto.main_loop().await.unwrap();
 
@@ -3808,7 +4594,7 @@ mod tests {
         }).is_ok());
 
         net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
         net_crypto.main_loop().await.unwrap();
 
@@ -3825,7 +4611,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn send_status_packet_established() {
+    async fn main_loop_sends_capabilities() {
         crypto_init().unwrap();
         let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -3844,32 +4630,54 @@ mod tests {
             precomputed_keys,
         });
 
+        net_crypto.set_rekey_config(Some(RekeyConfig::default()));
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
         let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce: gen_nonce(),
+            sent_nonce,
             received_nonce,
-            session_precomputed_key,
+            session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        // send status packet with connection.status is Established
-        net_crypto.send_status_packet(&mut connection).await.unwrap();
+        connection.request_packet_sent_time = Some(clock_now());
 
-        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
-        drop(net_crypto.udp_tx);
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+        net_crypto.main_loop().await.unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        let (_, capabilities) = Capabilities::from_bytes(&payload.data).unwrap();
+        assert_eq!(capabilities.capabilities, CAPABILITY_REKEY);
+
+        let connection = connection.read();
+        assert_eq!(connection.capabilities_sent, 1);
+        // capabilities packet shouldn't be resent
+        assert_eq!(connection.send_array.len(), 0);
+        // rotation can't be started until peer announces its capabilities
+        assert!(connection.rekey_status.is_none());
     }
 
     #[tokio::test]
-    async fn send_data_packet() {
+    async fn main_loop_starts_rekey() {
         crypto_init().unwrap();
         let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -3888,6 +4696,11 @@ mod tests {
             precomputed_keys,
         });
 
+        net_crypto.set_rekey_config(Some(RekeyConfig {
+            packets: 100,
+            interval: DEFAULT_REKEY_INTERVAL,
+        }));
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
@@ -3897,7 +4710,7 @@ mod tests {
         connection.set_udp_addr(addr);
 
         let received_nonce = gen_nonce();
-        let mut sent_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
@@ -3907,11 +4720,14 @@ mod tests {
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        connection.recv_array.buffer_start = 23;
-        connection.recv_array.buffer_end = 25;
+        connection.request_packet_sent_time = Some(clock_now());
+        connection.peer_capabilities = Some(CAPABILITY_REKEY);
+        connection.session_packets_sent = 100;
 
-        let data = vec![42; 123];
-        net_crypto.send_data_packet(&mut connection, data.clone(), 7).await.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        net_crypto.main_loop().await.unwrap();
 
         let (received, _udp_rx) = udp_rx.into_future().await;
         let (received, addr_to_send) = received.unwrap();
@@ -3920,19 +4736,21 @@ mod tests {
 
         let packet = unpack!(received, DhtPacket::CryptoData);
         let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
-        assert_eq!(payload.buffer_start, 23);
-        assert_eq!(payload.packet_number, 7);
-        assert_eq!(payload.data, data);
+        let (_, request) = RekeyRequest::from_bytes(&payload.data).unwrap();
 
-        increment_nonce(&mut sent_nonce);
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, sent_nonce), sent_nonce);
-        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
+        let connection = connection.read();
+        let rekey_status = connection.rekey_status.clone().unwrap();
+        assert_eq!(unpack!(rekey_status.clone(), RekeyStatus::Requested, session_pk), request.session_pk);
+        assert_eq!(unpack!(rekey_status, RekeyStatus::Requested, sent_nonce), request.base_nonce);
+        // request should be resent until it's delivered
+        assert_eq!(connection.send_array.len(), 1);
     }
 
     #[tokio::test]
-    async fn send_request_packet() {
+    async fn main_loop_restarts_expired_rekey() {
         crypto_init().unwrap();
-        let (udp_tx, udp_rx) = mpsc::channel(1);
+        tokio::time::pause();
+        let (udp_tx, _udp_rx) = mpsc::channel(8);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -3949,6 +4767,11 @@ mod tests {
             precomputed_keys,
         });
 
+        net_crypto.set_rekey_config(Some(RekeyConfig {
+            packets: 100,
+            interval: DEFAULT_REKEY_INTERVAL,
+        }));
+
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
@@ -3957,52 +4780,54 @@ mod tests {
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.set_udp_addr(addr);
 
-        let received_nonce = gen_nonce();
-        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce,
-            received_nonce,
-            session_precomputed_key: session_precomputed_key.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
         };
 
-        connection.recv_array.buffer_end = 270;
-        assert!(connection.recv_array.insert(2, RecvPacket {
-            data: vec![42; 123],
-        }).is_ok());
-        for i in 5 .. 269 {
-            assert!(connection.recv_array.insert(i, RecvPacket {
-                data: vec![42; 123],
-            }).is_ok());
-        }
+        connection.request_packet_sent_time = Some(clock_now());
+        connection.peer_capabilities = Some(CAPABILITY_REKEY);
+        connection.session_packets_sent = 100;
 
-        tokio::time::pause();
-        let now = clock_now();
-        let delay = Duration::from_secs(1);
-        tokio::time::advance(delay).await;
+        let (session_pk, session_sk) = gen_keypair();
+        connection.rekey_status = Some(RekeyStatus::Requested {
+            session_pk,
+            session_sk,
+            sent_nonce: gen_nonce(),
+            time: clock_now(),
+        });
 
-        net_crypto.send_request_packet(&mut connection).await.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        assert_eq!(connection.request_packet_sent_time, Some(now + delay));
+        net_crypto.main_loop().await.unwrap();
 
-        let (received, _udp_rx) = udp_rx.into_future().await;
-        let (received, addr_to_send) = received.unwrap();
+        // the peer still has time to respond
+        let rekey_status = connection.read().rekey_status.clone().unwrap();
+        assert_eq!(unpack!(rekey_status, RekeyStatus::Requested, session_pk), session_pk);
 
-        assert_eq!(addr_to_send, addr);
+        tokio::time::advance(REKEY_TIMEOUT).await;
+        connection.write().request_packet_sent_time = Some(clock_now());
 
-        let packet = unpack!(received, DhtPacket::CryptoData);
-        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
-        assert_eq!(payload.buffer_start, 0);
-        assert_eq!(payload.packet_number, 0);
-        assert_eq!(payload.data, vec![PACKET_ID_REQUEST, 1, 1, 2, 1, 0, 10]);
+        net_crypto.main_loop().await.unwrap();
+
+        // the rotation is started again with new keys
+        let connection = connection.read();
+        let rekey_status = connection.rekey_status.clone().unwrap();
+        assert_ne!(unpack!(rekey_status, RekeyStatus::Requested, session_pk), session_pk);
+        let request_data = connection.send_array.get(0).unwrap().data.clone();
+        let (_, request) = RekeyRequest::from_bytes(&request_data).unwrap();
+        assert_ne!(request.session_pk, session_pk);
     }
 
     #[tokio::test]
-    async fn send_request_packet_too_many_missing_packets() {
+    async fn main_loop_aborts_expired_rekey_response() {
         crypto_init().unwrap();
-        let (udp_tx, udp_rx) = mpsc::channel(1);
+        tokio::time::pause();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4024,43 +4849,52 @@ mod tests {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
-
-        let received_nonce = gen_nonce();
-        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
-        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce,
-            received_nonce,
-            session_precomputed_key: session_precomputed_key.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&peer_session_pk, &session_sk),
         };
+        // avoid sending any packets
+        connection.request_packet_sent_time = Some(clock_now());
 
-        connection.recv_array.buffer_end = MAX_CRYPTO_DATA_SIZE as u32 + 42;
+        let (session_pk, session_sk) = gen_keypair();
+        connection.rekey_status = Some(RekeyStatus::Responded {
+            request_pk: gen_keypair().0,
+            session_pk,
+            session_sk: session_sk.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &session_sk),
+            time: clock_now(),
+            response_queued: false,
+        });
 
-        net_crypto.send_request_packet(&mut connection).await.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+        // the response can't be queued while the send queue is full
+        net_crypto.fill_send_queue(&peer_real_pk, PacketPriority::Control);
 
-        let (received, _udp_rx) = udp_rx.into_future().await;
-        let (received, addr_to_send) = received.unwrap();
+        net_crypto.main_loop().await.unwrap();
 
-        assert_eq!(addr_to_send, addr);
+        assert!(connection.read().rekey_status.is_some());
 
-        let packet = unpack!(received, DhtPacket::CryptoData);
-        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
-        assert_eq!(payload.buffer_start, 0);
-        assert_eq!(payload.packet_number, 0);
-        assert_eq!(payload.data.len(), MAX_CRYPTO_DATA_SIZE);
+        tokio::time::advance(REKEY_TIMEOUT).await;
+        connection.write().request_packet_sent_time = Some(clock_now());
+        net_crypto.fill_send_queue(&peer_real_pk, PacketPriority::Control);
+
+        net_crypto.main_loop().await.unwrap();
+
+        // the response wasn't queued in time
+        assert!(connection.read().rekey_status.is_none());
     }
 
     #[tokio::test]
-    async fn send_requested_packets() {
-        tokio::time::pause();
-        let now = clock_now();
-
+    async fn handle_crypto_data_abandoned_rekey_response() {
         crypto_init().unwrap();
-        let (udp_tx, udp_rx) = mpsc::channel(2);
+        tokio::time::pause();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4082,59 +4916,73 @@ mod tests {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
-
-        let received_nonce = gen_nonce();
-        let sent_nonce = gen_nonce();
+        let mut received_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce,
+            sent_nonce: gen_nonce(),
             received_nonce,
-            session_precomputed_key,
+            session_precomputed_key: session_precomputed_key.clone(),
         };
+        // avoid sending any packets
+        connection.request_packet_sent_time = Some(clock_now());
 
-        connection.send_array.buffer_end = 7;
-        assert!(connection.send_array.insert(2, SentPacket {
-            data: vec![42; 123],
-            sent_time: now,
-            requested: true,
-        }).is_ok());
-        assert!(connection.send_array.insert(4, SentPacket {
-            data: vec![42; 123],
-            sent_time: now,
-            requested: false,
-        }).is_ok());
-        assert!(connection.send_array.insert(5, SentPacket {
-            data: vec![42; 123],
-            sent_time: now,
-            requested: true,
-        }).is_ok());
-
-        let delay = Duration::from_secs(1);
-        tokio::time::advance(delay).await;
+        let (new_session_pk, new_session_sk) = gen_keypair();
+        connection.rekey_status = Some(RekeyStatus::Responded {
+            request_pk: gen_keypair().0,
+            session_pk: new_session_pk,
+            session_sk: new_session_sk.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &new_session_sk),
+            time: clock_now(),
+            response_queued: true,
+        });
 
-        net_crypto.send_requested_packets(&mut connection).await.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        assert!(!connection.send_array.get(2).unwrap().requested);
-        assert!(!connection.send_array.get(4).unwrap().requested);
-        assert!(!connection.send_array.get(5).unwrap().requested);
-        assert_eq!(connection.send_array.get(2).unwrap().sent_time, now + delay);
-        assert_eq!(connection.send_array.get(4).unwrap().sent_time, now);
-        assert_eq!(connection.send_array.get(5).unwrap().sent_time, now + delay);
+        let mut packet_number = 0;
+        let mut send_packet = || {
+            let crypto_data_payload = CryptoDataPayload {
+                buffer_start: 0,
+                packet_number,
+                data: vec![PACKET_ID_LOSSY_RANGE_START, 42],
+            };
+            let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+            packet_number += 1;
+            increment_nonce(&mut received_nonce);
+            crypto_data
+        };
 
-        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
-        drop(net_crypto.udp_tx);
+        // packets with the current keys might be sent before the peer received
+        // our response
+        let crypto_data = send_packet();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let future = net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, Some(addr));
+        future.await.unwrap();
+        assert!(connection.read().rekey_status.is_some());
+
+        // the response is queued so the new keys are kept while the peer is
+        // silent
+        tokio::time::advance(REKEY_TIMEOUT).await;
+        connection.write().request_packet_sent_time = Some(clock_now());
+        net_crypto.main_loop().await.unwrap();
+        assert!(connection.read().rekey_status.is_some());
 
-        assert_eq!(udp_rx.collect::<Vec<_>>().await.len(), 2);
+        // the peer still uses the current keys so it gave up the rotation
+        let crypto_data = send_packet();
+        let future = net_crypto.handle_crypto_data(&mut connection.write(), &crypto_data, Some(addr));
+        future.await.unwrap();
+        assert!(connection.read().rekey_status.is_none());
     }
 
     #[tokio::test]
-    async fn send_lossless() {
+    async fn main_loop_removes_expired_previous_session() {
         crypto_init().unwrap();
-        let (udp_tx, udp_rx) = mpsc::channel(2);
+        tokio::time::pause();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4156,54 +5004,37 @@ mod tests {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
-
-        let received_nonce = gen_nonce();
-        let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
         let (_session_pk, session_sk) = gen_keypair();
         let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
         connection.status = ConnectionStatus::Established {
-            sent_nonce,
-            received_nonce,
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
             session_precomputed_key: session_precomputed_key.clone(),
         };
+        connection.previous_session = Some(PreviousSession::new(gen_nonce(), session_precomputed_key));
+        // avoid sending any packets
+        connection.request_packet_sent_time = Some(clock_now());
 
         let connection = Arc::new(RwLock::new(connection));
         net_crypto.connections.write().insert(peer_real_pk, connection.clone());
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
-
-        let data = vec![16, 42];
-
-        net_crypto.send_lossless(peer_real_pk, data.clone()).await.unwrap();
-
-        let connection = connection.read();
-
-        assert_eq!(connection.packets_sent, 1);
-
-        // the packet should be added to send_array
 
-        assert_eq!(connection.send_array.buffer[0].clone().unwrap().data, data);
+        net_crypto.main_loop().await.unwrap();
 
-        // the packet should be sent to node
+        assert!(connection.read().previous_session.is_some());
 
-        let (received, _udp_rx) = udp_rx.into_future().await;
-        let (received, addr_to_send) = received.unwrap();
+        tokio::time::advance(PREVIOUS_SESSION_TIMEOUT).await;
+        connection.write().request_packet_sent_time = Some(clock_now());
 
-        assert_eq!(addr_to_send, addr);
+        net_crypto.main_loop().await.unwrap();
 
-        let packet = unpack!(received, DhtPacket::CryptoData);
-        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
-        assert_eq!(payload.buffer_start, 0);
-        assert_eq!(payload.packet_number, 0);
-        assert_eq!(payload.data, data);
+        assert!(connection.read().previous_session.is_none());
     }
 
     #[tokio::test]
-    async fn send_lossless_no_connection() {
+    async fn send_status_packet_established() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4220,16 +5051,34 @@ mod tests {
             precomputed_keys,
         });
 
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let error = net_crypto.send_lossless(peer_real_pk, vec![16, 42]).await.err().unwrap();
-        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key,
+        };
+
+        // send status packet with connection.status is Established
+        net_crypto.send_status_packet(&mut connection).await.unwrap();
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
     }
 
     #[tokio::test]
-    async fn send_lossless_invalid_packet_id() {
+    async fn send_data_packet() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4246,53 +5095,51 @@ mod tests {
             precomputed_keys,
         });
 
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let error = net_crypto.send_lossless(peer_real_pk, vec![10, 42]).await.err().unwrap();
-        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::InvalidPacketId);
-    }
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
 
-    #[tokio::test]
-    async fn add_connection() {
-        crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
-        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
-        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
-        let (dht_pk, dht_sk) = gen_keypair();
-        let (real_pk, real_sk) = gen_keypair();
-        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
-        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
-            udp_tx,
-            lossless_tx,
-            lossy_tx,
-            dht_pk,
-            dht_sk,
-            real_pk,
-            real_sk,
-            precomputed_keys,
-        });
+        let received_nonce = gen_nonce();
+        let mut sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        let (peer_real_pk, _peer_real_sk) = gen_keypair();
-        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
-        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        connection.recv_array.buffer_start = 23;
+        connection.recv_array.buffer_end = 25;
 
-        let connections = net_crypto.connections.read();
-        let connection = connections[&peer_real_pk].read();
+        let data = vec![42; 123];
+        net_crypto.send_data_packet(&mut connection, data.clone(), 7).await.unwrap();
 
-        assert_eq!(connection.peer_real_pk, peer_real_pk);
-        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
 
-        let status_packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
-        let cookie_request = unpack!(status_packet.packet, StatusPacket::CookieRequest);
-        let cookie_request_payload = cookie_request.get_payload(&precompute(&dht_pk, &peer_dht_sk)).unwrap();
+        assert_eq!(addr_to_send, addr);
 
-        assert_eq!(cookie_request_payload.pk, real_pk);
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 23);
+        assert_eq!(payload.packet_number, 7);
+        assert_eq!(payload.data, data);
+
+        increment_nonce(&mut sent_nonce);
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, sent_nonce), sent_nonce);
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, received_nonce), received_nonce);
     }
 
     #[tokio::test]
-    async fn add_connection_already_exists() {
+    async fn send_request_packet() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4303,31 +5150,66 @@ mod tests {
             lossless_tx,
             lossy_tx,
             dht_pk,
-            dht_sk,
+            dht_sk: dht_sk.clone(),
             real_pk,
             real_sk,
             precomputed_keys,
         });
 
-        let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
-        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        // adding a friend that already exists won't do anything
-        let (another_peer_dht_pk, _another_peer_dht_sk) = gen_keypair();
-        net_crypto.add_connection(peer_real_pk, another_peer_dht_pk);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
 
-        let connections = net_crypto.connections.read();
-        let connection = connections[&peer_real_pk].read();
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        assert_eq!(connection.peer_real_pk, peer_real_pk);
-        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
-    }
+        connection.recv_array.buffer_end = 270;
+        assert!(connection.recv_array.insert(2, RecvPacket {
+            data: vec![42; 123],
+        }).is_ok());
+        for i in 5 .. 269 {
+            assert!(connection.recv_array.insert(i, RecvPacket {
+                data: vec![42; 123],
+            }).is_ok());
+        }
 
-    #[test]
-    fn set_friend_udp_addr() {
+        tokio::time::pause();
+        let now = clock_now();
+        let delay = Duration::from_secs(1);
+        tokio::time::advance(delay).await;
+
+        net_crypto.send_request_packet(&mut connection).await.unwrap();
+
+        assert_eq!(connection.request_packet_sent_time, Some(now + delay));
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data, vec![PACKET_ID_REQUEST, 1, 1, 2, 1, 0, 10]);
+    }
+
+    #[tokio::test]
+    async fn send_request_packet_too_many_missing_packets() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4338,34 +5220,54 @@ mod tests {
             lossless_tx,
             lossy_tx,
             dht_pk,
-            dht_sk,
+            dht_sk: dht_sk.clone(),
             real_pk,
             real_sk,
             precomputed_keys,
         });
 
-        let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
-        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        let addr_v4 = "127.0.0.1:12345".parse().unwrap();
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr_v4);
-        let addr_v6 = "[::]:12345".parse().unwrap();
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr_v6);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
 
-        let connections = net_crypto.connections.read();
-        let connection = connections[&peer_real_pk].read();
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        assert_eq!(connection.get_udp_addr_v4(), Some(addr_v4));
-        assert_eq!(connection.get_udp_addr_v6(), Some(addr_v6));
-        assert_eq!(net_crypto.keys_by_addr.read()[&(addr_v4.ip(), addr_v4.port())], peer_real_pk);
-        assert_eq!(net_crypto.keys_by_addr.read()[&(addr_v6.ip(), addr_v6.port())], peer_real_pk);
+        connection.recv_array.buffer_end = MAX_CRYPTO_DATA_SIZE as u32 + 42;
+
+        net_crypto.send_request_packet(&mut connection).await.unwrap();
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data.len(), MAX_CRYPTO_DATA_SIZE);
     }
 
-    #[test]
-    fn set_friend_udp_addr_update() {
+    #[tokio::test]
+    async fn send_requested_packets() {
+        tokio::time::pause();
+        let now = clock_now();
+
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, udp_rx) = mpsc::channel(2);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4376,38 +5278,71 @@ mod tests {
             lossless_tx,
             lossy_tx,
             dht_pk,
-            dht_sk,
+            dht_sk: dht_sk.clone(),
             real_pk,
             real_sk,
             precomputed_keys,
         });
 
-        let (peer_real_pk, _peer_real_sk) = gen_keypair();
         let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
-        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
         let addr = "127.0.0.1:12345".parse().unwrap();
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
-        // setting the same address won't do anything
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        connection.set_udp_addr(addr);
 
-        let addr = "127.0.0.1:12346".parse().unwrap();
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key,
+        };
 
-        let connections = net_crypto.connections.read();
-        let connection = connections[&peer_real_pk].read();
+        connection.send_array.buffer_end = 7;
+        assert!(connection.send_array.insert(2, SentPacket {
+            data: vec![42; 123],
+            sent_time: now,
+            requested: true,
+        }).is_ok());
+        assert!(connection.send_array.insert(4, SentPacket {
+            data: vec![42; 123],
+            sent_time: now,
+            requested: false,
+        }).is_ok());
+        assert!(connection.send_array.insert(5, SentPacket {
+            data: vec![42; 123],
+            sent_time: now,
+            requested: true,
+        }).is_ok());
 
-        assert_eq!(connection.get_udp_addr_v4(), Some(addr));
+        let delay = Duration::from_secs(1);
+        tokio::time::advance(delay).await;
+
+        net_crypto.send_requested_packets(&mut connection).await.unwrap();
+
+        assert!(!connection.send_array.get(2).unwrap().requested);
+        assert!(!connection.send_array.get(4).unwrap().requested);
+        assert!(!connection.send_array.get(5).unwrap().requested);
+        assert_eq!(connection.send_array.get(2).unwrap().sent_time, now + delay);
+        assert_eq!(connection.send_array.get(4).unwrap().sent_time, now);
+        assert_eq!(connection.send_array.get(5).unwrap().sent_time, now + delay);
+        assert_eq!(connection.total_packets_resent, 2);
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
 
-        let keys_by_addr = net_crypto.keys_by_addr.read();
-        assert_eq!(keys_by_addr[&(addr.ip(), addr.port())], peer_real_pk);
-        assert_eq!(keys_by_addr.len(), 1);
+        assert_eq!(udp_rx.collect::<Vec<_>>().await.len(), 2);
     }
 
-    #[test]
-    fn set_friend_udp_addr_no_connection() {
+    #[tokio::test]
+    async fn connection_info() {
         crypto_init().unwrap();
-        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
         let (lossy_tx, _lossy_rx) = mpsc::unbounded();
         let (dht_pk, dht_sk) = gen_keypair();
@@ -4418,23 +5353,39 @@ mod tests {
             lossless_tx,
             lossy_tx,
             dht_pk,
-            dht_sk,
+            dht_sk: dht_sk.clone(),
             real_pk,
             real_sk,
             precomputed_keys,
         });
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
-        // setting an address to nonexistent connection won't do anything
-        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(SocketAddr::V4(addr));
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+        assert_eq!(info.transport, ConnectionTransport::UdpV4(addr));
+        assert_eq!(info.rtt, DEFAULT_RTT);
+        assert_eq!(info.packets_in_flight, 0);
+        assert_eq!(info.packets_queued, 0);
+        assert_eq!(info.established_time, None);
 
-        assert!(net_crypto.keys_by_addr.read().is_empty());
+        // only TCP relays are used in TCP-only mode
+        net_crypto.set_tcp_only(true);
+        let info = net_crypto.connection_info(peer_real_pk).unwrap();
+        assert_eq!(info.transport, ConnectionTransport::Tcp);
+
+        assert!(net_crypto.connection_info(gen_keypair().0).is_none());
     }
 
     #[tokio::test]
-    async fn kill_connection() {
+    async fn send_lossless() {
         crypto_init().unwrap();
         let (udp_tx, udp_rx) = mpsc::channel(2);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -4458,6 +5409,9 @@ mod tests {
         let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
         let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
 
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
         let received_nonce = gen_nonce();
         let sent_nonce = gen_nonce();
         let (peer_session_pk, _peer_session_sk) = gen_keypair();
@@ -4469,16 +5423,23 @@ mod tests {
             session_precomputed_key: session_precomputed_key.clone(),
         };
 
-        let addr = "127.0.0.1:12345".parse().unwrap();
-        connection.set_udp_addr(addr);
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
 
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        let data = vec![16, 42];
 
-        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+        net_crypto.send_lossless(peer_real_pk, data.clone()).await.unwrap();
 
-        assert!(!net_crypto.connections.read().contains_key(&peer_real_pk));
-        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
+        let connection = connection.read();
+
+        assert_eq!(connection.packets_sent, 1);
+
+        // the packet should be added to send_array
+
+        assert_eq!(connection.send_array.buffer[0].clone().unwrap().data, data);
+
+        // the packet should be sent to node
 
         let (received, _udp_rx) = udp_rx.into_future().await;
         let (received, addr_to_send) = received.unwrap();
@@ -4489,11 +5450,11 @@ mod tests {
         let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
         assert_eq!(payload.buffer_start, 0);
         assert_eq!(payload.packet_number, 0);
-        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+        assert_eq!(payload.data, data);
     }
 
     #[tokio::test]
-    async fn kill_connection_no_connection() {
+    async fn send_lossless_no_connection() {
         crypto_init().unwrap();
         let (udp_tx, _udp_rx) = mpsc::channel(2);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -4514,12 +5475,38 @@ mod tests {
 
         let (peer_real_pk, _peer_real_sk) = gen_keypair();
 
-        let error = net_crypto.kill_connection(peer_real_pk).await.err().unwrap();
-        assert_eq!(*error.kind(), KillConnectionErrorKind::NoConnection);
+        let error = net_crypto.send_lossless(peer_real_pk, vec![16, 42]).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
     }
 
     #[tokio::test]
-    async fn kill_connection_not_established() {
+    async fn send_lossless_invalid_packet_id() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        let error = net_crypto.send_lossless(peer_real_pk, vec![10, 42]).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::InvalidPacketId);
+    }
+
+    #[tokio::test]
+    async fn send_queued() {
         crypto_init().unwrap();
         let (udp_tx, udp_rx) = mpsc::channel(2);
         let (lossless_tx, _lossless_rx) = mpsc::unbounded();
@@ -4546,17 +5533,1517 @@ mod tests {
         let addr = "127.0.0.1:12345".parse().unwrap();
         connection.set_udp_addr(addr);
 
-        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
-        net_crypto.keys_by_addr.write().insert((addr.ip(), addr.port()), peer_real_pk);
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
 
-        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
 
-        assert!(!net_crypto.connections.read().contains_key(&peer_real_pk));
-        assert!(!net_crypto.keys_by_addr.read().contains_key(&(addr.ip(), addr.port())));
+        let lossless_data = vec![16, 42];
+        let lossy_data = vec![200, 42];
 
-        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
-        drop(net_crypto.udp_tx);
+        assert_eq!(net_crypto.send_queued(peer_real_pk, lossless_data.clone(), PacketPriority::Lossless).await.unwrap(), 0);
+        assert_eq!(net_crypto.send_queued(peer_real_pk, lossy_data.clone(), PacketPriority::Lossy).await.unwrap(), 1);
 
-        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+        {
+            let connection = connection.read();
+
+            assert!(connection.send_queue.is_empty());
+            assert_eq!(connection.packets_sent, 1);
+
+            // only lossless packet should be added to send_array
+            assert_eq!(connection.send_array.len(), 1);
+            assert_eq!(connection.send_array.buffer[0].clone().unwrap().data, lossless_data);
+        }
+
+        // both packets should be sent to node
+        let received = udp_rx.take(2).collect::<Vec<_>>().await;
+
+        let payloads = received.into_iter().enumerate().map(|(i, (packet, addr_to_send))| {
+            assert_eq!(addr_to_send, addr);
+            let packet = unpack!(packet, DhtPacket::CryptoData);
+            let mut nonce = sent_nonce;
+            increment_nonce_number(&mut nonce, i as u64);
+            packet.get_payload(&session_precomputed_key, &nonce).unwrap()
+        }).collect::<Vec<_>>();
+
+        assert_eq!(payloads[0].packet_number, 0);
+        assert_eq!(payloads[0].data, lossless_data);
+        assert_eq!(payloads[1].packet_number, 1);
+        assert_eq!(payloads[1].data, lossy_data);
+    }
+
+    #[tokio::test]
+    async fn send_queued_not_established() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        let mut future = Box::pin(net_crypto.send_queued(peer_real_pk, vec![16, 42], PacketPriority::Control));
+        assert!(futures::poll!(&mut future).is_pending());
+
+        // the packet should wait until the connection is established
+        {
+            let connection = connection.read();
+            assert_eq!(connection.send_queue.len(), 1);
+            assert_eq!(connection.send_array.len(), 0);
+        }
+
+        // the packet is dropped with the connection
+        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+
+        let error = future.await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
+    }
+
+    #[tokio::test]
+    async fn send_queued_wait_for_space() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        for _ in 0 .. SEND_QUEUE_SIZE {
+            connection.send_queue.push(PacketPriority::Lossless, vec![16, 42]).unwrap();
+        }
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+
+        let mut future = Box::pin(net_crypto.send_queued(peer_real_pk, vec![16, 43], PacketPriority::Lossless));
+        assert!(futures::poll!(&mut future).is_pending());
+
+        // the connection is removed so the packet can't be sent
+        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+
+        let error = future.await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
+    }
+
+    #[tokio::test]
+    async fn send_queued_invalid_packet_id() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        let error = net_crypto.send_queued(peer_real_pk, vec![10, 42], PacketPriority::Control).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::InvalidPacketId);
+        let error = net_crypto.send_queued(peer_real_pk, vec![200, 42], PacketPriority::Lossless).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::InvalidPacketId);
+        let error = net_crypto.send_queued(peer_real_pk, vec![16, 42], PacketPriority::Lossy).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::InvalidPacketId);
+        let error = net_crypto.send_queued(peer_real_pk, vec![200, 42], PacketPriority::Lossy).await.err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
+    }
+
+    #[test]
+    fn is_packet_delivered() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+        connection.send_array.push_back(SentPacket::new(vec![16, 42])).unwrap();
+        connection.send_array.push_back(SentPacket::new(vec![16, 43])).unwrap();
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        assert!(!net_crypto.is_packet_delivered(peer_real_pk, 0).unwrap());
+        assert!(!net_crypto.is_packet_delivered(peer_real_pk, 1).unwrap());
+
+        // the friend received the first packet
+        connection.write().send_array.set_buffer_start(1).unwrap();
+
+        assert!(net_crypto.is_packet_delivered(peer_real_pk, 0).unwrap());
+        assert!(!net_crypto.is_packet_delivered(peer_real_pk, 1).unwrap());
+
+        let error = net_crypto.is_packet_delivered(gen_keypair().0, 0).err().unwrap();
+        assert_eq!(*error.kind(), SendLosslessPacketErrorKind::NoConnection);
+    }
+
+    #[tokio::test]
+    async fn add_connection() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.peer_real_pk, peer_real_pk);
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+
+        let status_packet = unpack!(connection.status.clone(), ConnectionStatus::CookieRequesting, packet);
+        let cookie_request = unpack!(status_packet.packet, StatusPacket::CookieRequest);
+        let cookie_request_payload = cookie_request.get_payload(&precompute(&dht_pk, &peer_dht_sk)).unwrap();
+
+        assert_eq!(cookie_request_payload.pk, real_pk);
+    }
+
+    #[tokio::test]
+    async fn connection_by_dht_key() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let (another_peer_real_pk, _another_peer_real_sk) = gen_keypair();
+        let (another_peer_dht_pk, _another_peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(another_peer_real_pk, another_peer_dht_pk);
+
+        let connection = net_crypto.connection_by_dht_key(peer_dht_pk).unwrap();
+        assert_eq!(connection.read().peer_real_pk, peer_real_pk);
+        let connection = net_crypto.connection_by_dht_key(another_peer_dht_pk).unwrap();
+        assert_eq!(connection.read().peer_real_pk, another_peer_real_pk);
+
+        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+
+        assert!(net_crypto.connection_by_dht_key(peer_dht_pk).is_none());
+        assert!(net_crypto.connection_by_dht_key(another_peer_dht_pk).is_some());
+    }
+
+    #[tokio::test]
+    async fn add_connection_already_exists() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        // adding a friend that already exists won't do anything
+        let (another_peer_dht_pk, _another_peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, another_peer_dht_pk);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.peer_real_pk, peer_real_pk);
+        assert_eq!(connection.peer_dht_pk, peer_dht_pk);
+    }
+
+    #[tokio::test]
+    async fn main_loop_upgrades_to_udp() {
+        crypto_init().unwrap();
+        let (udp_tx, mut udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+        // the connection was established via TCP
+        connection.reported_transport = Some(ConnectionTransport::Tcp);
+        // don't send request packets
+        connection.request_packet_sent_time = Some(clock_now());
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[SocketAddr::V4(addr)]);
+
+        net_crypto.main_loop().await.unwrap();
+
+        let (received, addr_to_send) = udp_rx.next().await.unwrap();
+
+        assert_eq!(addr_to_send, SocketAddr::V4(addr));
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data[0], PACKET_ID_REQUEST);
+
+        // traffic is not switched to the probed address until the peer answers
+        assert_eq!(connection.read().get_udp_addr(), None);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![0, 0, PACKET_ID_LOSSY_RANGE_START, 1, 2, 3]
+        };
+
+        // packet that can't be decrypted doesn't confirm the address
+        let invalid_precomputed_key = precompute(&gen_keypair().0, &session_sk);
+        let crypto_data = CryptoData::new(&invalid_precomputed_key, received_nonce, &crypto_data_payload);
+        assert!(net_crypto.handle_udp_crypto_data(&crypto_data, SocketAddr::V4(addr)).await.is_err());
+        assert_eq!(connection.read().get_udp_addr(), None);
+        assert_eq!(connection.read().udp_candidates.len(), 1);
+
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+        net_crypto.handle_udp_crypto_data(&crypto_data, SocketAddr::V4(addr)).await.unwrap();
+
+        assert_eq!(connection.read().get_udp_addr(), Some(SocketAddr::V4(addr)));
+        assert!(connection.read().udp_candidates.is_empty());
+
+        net_crypto.main_loop().await.unwrap();
+
+        drop(net_crypto.connection_event_tx);
+
+        let events = connection_event_rx.collect::<Vec<_>>().await;
+        assert_eq!(events, vec![
+            (peer_real_pk, ConnectionEvent::TransportChanged(ConnectionTransport::UdpV4(addr))),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn main_loop_removes_unreachable_udp_candidates() {
+        tokio::time::pause();
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(MAX_UDP_PROBES as usize);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+
+        for _ in 0 ..= MAX_UDP_PROBES {
+            // don't send request packets
+            connection.write().request_packet_sent_time = Some(clock_now());
+            net_crypto.main_loop().await.unwrap();
+            tokio::time::advance(UDP_PROBE_INTERVAL).await;
+        }
+
+        assert!(connection.read().udp_candidates.is_empty());
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+
+        // unreachable address isn't probed again until the backoff interval passes
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+        assert!(connection.read().udp_candidates.is_empty());
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+
+        tokio::time::advance(UDP_UNREACHABLE_BACKOFF).await;
+
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+        assert_eq!(connection.read().udp_candidates, vec![UdpCandidate::new(addr)]);
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+    }
+
+    #[tokio::test]
+    async fn main_loop_sends_tcp_keepalive() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+        let (tcp_tx, tcp_rx) = mpsc::channel(1);
+        net_crypto.set_tcp_sink(tcp_tx);
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+        // don't send request packets
+        connection.request_packet_sent_time = Some(clock_now());
+
+        let connection = Arc::new(RwLock::new(connection));
+        net_crypto.connections.write().insert(peer_real_pk, connection.clone());
+
+        net_crypto.main_loop().await.unwrap();
+
+        let (received, _tcp_rx) = tcp_rx.into_future().await;
+        let (received, key_to_send) = received.unwrap();
+
+        assert_eq!(key_to_send, peer_dht_pk);
+
+        let packet = unpack!(received, TcpDataPayload::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.data[0], PACKET_ID_REQUEST);
+
+        assert!(connection.read().tcp_keepalive_time.is_some());
+    }
+
+    #[test]
+    fn set_friend_udp_addr() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr_v4 = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr_v4);
+        let addr_v6 = "[::]:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr_v6);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.get_udp_addr_v4(), Some(addr_v4));
+        assert_eq!(connection.get_udp_addr_v6(), Some(addr_v6));
+        assert_eq!(net_crypto.connections.read().key_by_addr(addr_v4), Some(peer_real_pk));
+        assert_eq!(net_crypto.connections.read().key_by_addr(addr_v6), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn set_friend_udp_addr_tcp_only() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        net_crypto.set_tcp_only(true);
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        assert_eq!(net_crypto.connection_saddr(&peer_real_pk), None);
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+    }
+
+    #[test]
+    fn set_friend_udp_addr_update() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+        // setting the same address won't do anything
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        let addr = "127.0.0.1:12346".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.get_udp_addr_v4(), Some(addr));
+
+        assert_eq!(connections.key_by_addr(addr), Some(peer_real_pk));
+        assert_eq!(connections.key_by_addr("127.0.0.1:12345".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn set_friend_udp_addr_ipv4_mapped() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr_v6: SocketAddr = "[::ffff:127.0.0.1]:12345".parse().unwrap();
+        let addr_v4: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr_v6);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        assert_eq!(connection.get_udp_addr_v4(), Some(addr_v4));
+        assert_eq!(connection.get_udp_addr_v6(), None);
+
+        // packets from dual-stack socket have IPv4-mapped addresses
+        assert_eq!(net_crypto.key_by_addr(addr_v6), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(addr_v4), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn set_friend_udp_addr_udp_alive() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
+
+        let new_addr = "127.0.0.1:12346".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, new_addr);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        // alive address is not replaced until the peer answers from the new one
+        assert_eq!(connection.get_udp_addr_v4(), Some(addr));
+        assert_eq!(connection.udp_candidates, vec![UdpCandidate::new(new_addr)]);
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(new_addr), Some(peer_real_pk));
+    }
+
+    #[tokio::test]
+    async fn set_friend_udp_addr_udp_not_alive() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+
+        tokio::time::pause();
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
+
+        tokio::time::advance(UDP_DIRECT_TIMEOUT + Duration::from_secs(1)).await;
+
+        let new_addr = "127.0.0.1:12346".parse().unwrap();
+        net_crypto.set_friend_udp_addr(peer_real_pk, new_addr);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        // address that doesn't receive packets anymore is replaced right away
+        assert_eq!(connection.get_udp_addr_v4(), Some(new_addr));
+        assert!(connection.udp_candidates.is_empty());
+        assert_eq!(net_crypto.key_by_addr(addr), None);
+        assert_eq!(net_crypto.key_by_addr(new_addr), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn probe_friend_udp_addrs() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        // connection is not established
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+        assert!(net_crypto.connections.read()[&peer_real_pk].read().udp_candidates.is_empty());
+
+        net_crypto.connections.read()[&peer_real_pk].write().status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+
+        // UDP is not used in TCP-only mode
+        net_crypto.set_tcp_only(true);
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+        assert!(net_crypto.connections.read()[&peer_real_pk].read().udp_candidates.is_empty());
+
+        net_crypto.set_tcp_only(false);
+        let addr_v6: SocketAddr = "[::ffff:127.0.0.2]:12345".parse().unwrap();
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr, addr_v6, addr]);
+
+        let connections = net_crypto.connections.read();
+        let connection = connections[&peer_real_pk].read();
+
+        // IPv4-mapped addresses are normalized and duplicates are ignored
+        let addr_v4 = "127.0.0.2:12345".parse().unwrap();
+        assert_eq!(connection.udp_candidates, vec![UdpCandidate::new(addr), UdpCandidate::new(addr_v4)]);
+        assert_eq!(connection.get_udp_addr(), None);
+        assert_eq!(net_crypto.key_by_addr(addr), Some(peer_real_pk));
+        assert_eq!(net_crypto.key_by_addr(addr_v4), Some(peer_real_pk));
+    }
+
+    #[test]
+    fn probe_friend_udp_addrs_of_another_peer() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        net_crypto.add_connection(peer_real_pk, peer_dht_pk);
+        net_crypto.connections.read()[&peer_real_pk].write().status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &gen_keypair().1),
+        };
+
+        let (another_peer_real_pk, _another_peer_real_sk) = gen_keypair();
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.connections.write().insert_addr(addr, another_peer_real_pk);
+
+        net_crypto.probe_friend_udp_addrs(peer_real_pk, &[addr]);
+
+        // address of another peer is not taken over
+        assert!(net_crypto.connections.read()[&peer_real_pk].read().udp_candidates.is_empty());
+        assert_eq!(net_crypto.key_by_addr(addr), Some(another_peer_real_pk));
+    }
+
+    #[test]
+    fn set_friend_udp_addr_no_connection() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk,
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        // setting an address to nonexistent connection won't do anything
+        net_crypto.set_friend_udp_addr(peer_real_pk, addr);
+
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+    }
+
+    #[tokio::test]
+    async fn kill_connection() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let sent_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce,
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
+
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        net_crypto.kill_connection(peer_real_pk).await.unwrap();
+
+        assert!(!net_crypto.connections.read().contains_key(&peer_real_pk));
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+
+        let (event, _connection_event_rx) = connection_event_rx.into_future().await;
+        assert_eq!(event, Some((peer_real_pk, ConnectionEvent::Disconnected(DisconnectReason::Local))));
+
+        let (received, _udp_rx) = udp_rx.into_future().await;
+        let (received, addr_to_send) = received.unwrap();
+
+        assert_eq!(addr_to_send, addr);
+
+        let packet = unpack!(received, DhtPacket::CryptoData);
+        let payload = packet.get_payload(&session_precomputed_key, &sent_nonce).unwrap();
+        assert_eq!(payload.buffer_start, 0);
+        assert_eq!(payload.packet_number, 0);
+        assert_eq!(payload.data, vec![PACKET_ID_KILL]);
+    }
+
+    #[tokio::test]
+    async fn kill_connection_no_connection() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+
+        let error = net_crypto.kill_connection(peer_real_pk).await.err().unwrap();
+        assert_eq!(*error.kind(), KillConnectionErrorKind::NoConnection);
+    }
+
+    #[tokio::test]
+    async fn kill_connection_not_established() {
+        crypto_init().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel(2);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        connection.set_udp_addr(addr);
+
+        net_crypto.connections.write().insert(peer_real_pk, Arc::new(RwLock::new(connection)));
+        net_crypto.connections.write().insert_addr(addr, peer_real_pk);
+
+        let (connection_event_tx, connection_event_rx) = mpsc::unbounded();
+        net_crypto.set_connection_event_sink(connection_event_tx);
+
+        net_crypto.kill_connection_with_reason(peer_real_pk, DisconnectReason::Timeout).await.unwrap();
+
+        assert!(!net_crypto.connections.read().contains_key(&peer_real_pk));
+        assert!(net_crypto.connections.read().key_by_addr(addr).is_none());
+
+        // Necessary to drop udp_tx so that udp_rx.collect() can be finished
+        drop(net_crypto.udp_tx);
+        drop(net_crypto.connection_event_tx);
+
+        assert!(udp_rx.collect::<Vec<_>>().await.is_empty());
+        // connection wasn't established so there should be no events
+        assert!(connection_event_rx.collect::<Vec<_>>().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handle_crypto_data_capabilities() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_CAPABILITIES, CAPABILITY_REKEY],
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
+
+        assert_eq!(connection.peer_capabilities, Some(CAPABILITY_REKEY));
+        // capabilities packet is not stored in the received packets buffer
+        assert_eq!(connection.recv_array.buffer_start, 0);
+        assert_eq!(connection.recv_array.buffer_end, 0);
+    }
+
+    #[tokio::test]
+    async fn handle_crypto_data_invalid_capabilities() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data: vec![PACKET_ID_CAPABILITIES],
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        let error = net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.err().unwrap();
+        assert_eq!(*error.kind(), HandlePacketErrorKind::InvalidControlPacket { id: PACKET_ID_CAPABILITIES });
+
+        assert_eq!(connection.peer_capabilities, None);
+    }
+
+    #[tokio::test]
+    async fn handle_crypto_data_rekey_request() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce,
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let (peer_new_session_pk, peer_new_session_sk) = gen_keypair();
+        let peer_new_nonce = gen_nonce();
+        let request = RekeyRequest {
+            session_pk: peer_new_session_pk,
+            base_nonce: peer_new_nonce,
+        };
+        let mut data = vec![0; 1 + PUBLICKEYBYTES + NONCEBYTES];
+        let (_, size) = request.to_bytes((&mut data, 0)).unwrap();
+        data.truncate(size);
+
+        let crypto_data_payload = CryptoDataPayload {
+            buffer_start: 0,
+            packet_number: 0,
+            data,
+        };
+        let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+        let addr = "127.0.0.1:12345".parse().unwrap();
+        net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
+
+        // request is handled as lossless packet
+        assert_eq!(connection.recv_array.buffer_start, 1);
+
+        let rekey_status = connection.rekey_status.clone().unwrap();
+        let new_session_pk = unpack!(rekey_status.clone(), RekeyStatus::Responded, session_pk);
+        assert_eq!(unpack!(rekey_status.clone(), RekeyStatus::Responded, received_nonce), peer_new_nonce);
+        assert_eq!(
+            unpack!(rekey_status.clone(), RekeyStatus::Responded, session_precomputed_key),
+            precompute(&new_session_pk, &peer_new_session_sk)
+        );
+
+        // response is queued
+        let response_data = connection.send_queue.drain(1).pop().unwrap().1.data;
+        let (_, response) = RekeyResponse::from_bytes(&response_data).unwrap();
+        assert_eq!(response.request_pk, peer_new_session_pk);
+        assert_eq!(response.session_pk, new_session_pk);
+        assert_eq!(response.base_nonce, unpack!(rekey_status, RekeyStatus::Responded, sent_nonce));
+
+        // the current session is not changed until the peer uses new keys
+        assert_eq!(unpack!(connection.status, ConnectionStatus::Established, session_precomputed_key), session_precomputed_key);
+    }
+
+    #[test]
+    fn handle_rekey_request_send_queue_full() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        for _ in 0 .. SEND_QUEUE_SIZE {
+            connection.send_queue.push(PacketPriority::Control, vec![16, 42]).unwrap();
+        }
+
+        let request = RekeyRequest {
+            session_pk: gen_keypair().0,
+            base_nonce: gen_nonce(),
+        };
+        NetCrypto::handle_rekey_request(&mut connection, &request);
+
+        // the request is acknowledged so we should respond to it later
+        let rekey_status = connection.rekey_status.clone().unwrap();
+        assert!(!unpack!(rekey_status, RekeyStatus::Responded, response_queued));
+
+        connection.send_queue.drain(1);
+        NetCrypto::queue_rekey_response(&mut connection);
+
+        let rekey_status = connection.rekey_status.clone().unwrap();
+        assert!(unpack!(rekey_status.clone(), RekeyStatus::Responded, response_queued));
+
+        let response_data = connection.send_queue.drain(SEND_QUEUE_SIZE).pop().unwrap().1.data;
+        let (_, response) = RekeyResponse::from_bytes(&response_data).unwrap();
+        assert_eq!(response.session_pk, unpack!(rekey_status.clone(), RekeyStatus::Responded, session_pk));
+        assert_eq!(response.base_nonce, unpack!(rekey_status, RekeyStatus::Responded, sent_nonce));
+    }
+
+    #[test]
+    fn handle_rekey_response_to_another_request() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, _real_sk) = gen_keypair();
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+        let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+        connection.status = ConnectionStatus::Established {
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: session_precomputed_key.clone(),
+        };
+
+        let (new_session_pk, new_session_sk) = gen_keypair();
+        let rekey_status = RekeyStatus::Requested {
+            session_pk: new_session_pk,
+            session_sk: new_session_sk,
+            sent_nonce: gen_nonce(),
+            time: clock_now(),
+        };
+        connection.rekey_status = Some(rekey_status.clone());
+
+        // response to the request that was aborted before
+        let response = RekeyResponse {
+            request_pk: gen_keypair().0,
+            session_pk: gen_keypair().0,
+            base_nonce: gen_nonce(),
+        };
+        NetCrypto::handle_rekey_response(&mut connection, &response);
+
+        assert_eq!(connection.rekey_status, Some(rekey_status));
+        assert_eq!(unpack!(connection.status.clone(), ConnectionStatus::Established, session_precomputed_key), session_precomputed_key);
+
+        let response = RekeyResponse {
+            request_pk: new_session_pk,
+            ..response
+        };
+        NetCrypto::handle_rekey_response(&mut connection, &response);
+
+        assert!(connection.rekey_status.is_none());
+        assert_ne!(unpack!(connection.status, ConnectionStatus::Established, session_precomputed_key), session_precomputed_key);
+    }
+
+    #[tokio::test]
+    async fn handle_crypto_data_rekey_request_simultaneous() {
+        crypto_init().unwrap();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (lossless_tx, _lossless_rx) = mpsc::unbounded();
+        let (lossy_tx, _lossy_rx) = mpsc::unbounded();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk.clone(), 1);
+        let net_crypto = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx,
+            lossless_tx,
+            lossy_tx,
+            dht_pk,
+            dht_sk: dht_sk.clone(),
+            real_pk,
+            real_sk,
+            precomputed_keys,
+        });
+
+        let (peer_dht_pk, _peer_dht_sk) = gen_keypair();
+        let (peer_real_pk, _peer_real_sk) = gen_keypair();
+        let dht_precomputed_key = precompute(&peer_dht_pk, &dht_sk);
+
+        let received_nonce = gen_nonce();
+        let (peer_session_pk, _peer_session_sk) = gen_keypair();
+        let (_session_pk, session_sk) = gen_keypair();
+        let session_precomputed_key = precompute(&peer_session_pk, &session_sk);
+
+        let mut new_keys = [gen_keypair(), gen_keypair()];
+        new_keys.sort_by(|(pk_1, _), (pk_2, _)| pk_1.as_ref().cmp(pk_2.as_ref()));
+        let (smaller_pk, smaller_sk) = new_keys[0].clone();
+        let (bigger_pk, bigger_sk) = new_keys[1].clone();
+
+        // the request with bigger key wins
+        for &(our_pk, ref our_sk, peer_pk, should_respond) in &[
+            (bigger_pk, bigger_sk, smaller_pk, false),
+            (smaller_pk, smaller_sk, bigger_pk, true),
+        ] {
+            let mut connection = CryptoConnection::new(&dht_precomputed_key, dht_pk, real_pk, peer_real_pk, peer_dht_pk);
+            connection.status = ConnectionStatus::Established {
+                sent_nonce: gen_nonce(),
+                received_nonce,
+                session_precomputed_key: session_precomputed_key.clone(),
+            };
+            connection.rekey_status = Some(RekeyStatus::Requested {
+                session_pk: our_pk,
+                session_sk: our_sk.clone(),
+                sent_nonce: gen_nonce(),
+                time: clock_now(),
+            });
+
+            let request = RekeyRequest {
+                session_pk: peer_pk,
+                base_nonce: gen_nonce(),
+            };
+            let mut data = vec![0; 1 + PUBLICKEYBYTES + NONCEBYTES];
+            let (_, size) = request.to_bytes((&mut data, 0)).unwrap();
+            data.truncate(size);
+
+            let crypto_data_payload = CryptoDataPayload {
+                buffer_start: 0,
+                packet_number: 0,
+                data,
+            };
+            let crypto_data = CryptoData::new(&session_precomputed_key, received_nonce, &crypto_data_payload);
+
+            let addr = "127.0.0.1:12345".parse().unwrap();
+            net_crypto.handle_crypto_data(&mut connection, &crypto_data, Some(addr)).await.unwrap();
+
+            match connection.rekey_status {
+                Some(RekeyStatus::Responded { .. }) => assert!(should_respond),
+                Some(RekeyStatus::Requested { session_pk, .. }) => {
+                    assert!(!should_respond);
+                    assert_eq!(session_pk, our_pk);
+                },
+                None => panic!("Rekey status should be set"),
+            }
+            assert_eq!(connection.send_queue.is_empty(), !should_respond);
+        }
+    }
+
+    #[tokio::test]
+    async fn rekey() {
+        crypto_init().unwrap();
+
+        fn drain(rx: &mut mpsc::Receiver<(DhtPacket, SocketAddr)>) -> Vec<CryptoData> {
+            let mut packets = Vec::new();
+            while let Some(Some((packet, _))) = rx.next().now_or_never() {
+                packets.push(unpack!(packet, DhtPacket::CryptoData));
+            }
+            packets
+        }
+
+        let (udp_tx_1, mut udp_rx_1) = mpsc::channel(32);
+        let (lossless_tx_1, mut lossless_rx_1) = mpsc::unbounded();
+        let (lossy_tx_1, _lossy_rx_1) = mpsc::unbounded();
+        let (dht_pk_1, dht_sk_1) = gen_keypair();
+        let (real_pk_1, real_sk_1) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk_1.clone(), 1);
+        let net_crypto_1 = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx_1,
+            lossless_tx: lossless_tx_1,
+            lossy_tx: lossy_tx_1,
+            dht_pk: dht_pk_1,
+            dht_sk: dht_sk_1,
+            real_pk: real_pk_1,
+            real_sk: real_sk_1,
+            precomputed_keys,
+        });
+
+        let (udp_tx_2, mut udp_rx_2) = mpsc::channel(32);
+        let (lossless_tx_2, mut lossless_rx_2) = mpsc::unbounded();
+        let (lossy_tx_2, _lossy_rx_2) = mpsc::unbounded();
+        let (dht_pk_2, dht_sk_2) = gen_keypair();
+        let (real_pk_2, real_sk_2) = gen_keypair();
+        let precomputed_keys = PrecomputedCache::new(dht_sk_2.clone(), 1);
+        let net_crypto_2 = NetCrypto::new(NetCryptoNewArgs {
+            udp_tx: udp_tx_2,
+            lossless_tx: lossless_tx_2,
+            lossy_tx: lossy_tx_2,
+            dht_pk: dht_pk_2,
+            dht_sk: dht_sk_2,
+            real_pk: real_pk_2,
+            real_sk: real_sk_2,
+            precomputed_keys,
+        });
+
+        let (session_pk_1, session_sk_1) = gen_keypair();
+        let (session_pk_2, session_sk_2) = gen_keypair();
+        let nonce_1 = gen_nonce();
+        let nonce_2 = gen_nonce();
+        net_crypto_1.add_established_connection(dht_pk_2, real_pk_2, nonce_1, nonce_2, precompute(&session_pk_2, &session_sk_1));
+        net_crypto_2.add_established_connection(dht_pk_1, real_pk_1, nonce_2, nonce_1, precompute(&session_pk_1, &session_sk_2));
+
+        let addr_1 = "127.0.0.1:33445".parse().unwrap();
+        let addr_2 = "127.0.0.1:33446".parse().unwrap();
+        net_crypto_1.connection_by_key(real_pk_2).unwrap().write().set_udp_addr(addr_2);
+        net_crypto_1.connections.write().insert_addr(addr_2, real_pk_2);
+        net_crypto_2.connection_by_key(real_pk_1).unwrap().write().set_udp_addr(addr_1);
+        net_crypto_2.connections.write().insert_addr(addr_1, real_pk_1);
+
+        net_crypto_1.set_rekey_config(Some(RekeyConfig {
+            packets: 1,
+            interval: DEFAULT_REKEY_INTERVAL,
+        }));
+        net_crypto_1.connection_by_key(real_pk_2).unwrap().write().peer_capabilities = Some(CAPABILITY_REKEY);
+
+        // the first node sends a packet with the initial keys and requests
+        // rotation since the limit is reached
+        net_crypto_1.send_lossless(real_pk_2, vec![16, 1]).await.unwrap();
+        net_crypto_1.main_loop().await.unwrap();
+        for packet in drain(&mut udp_rx_1) {
+            net_crypto_2.handle_udp_crypto_data(&packet, addr_1).await.unwrap();
+        }
+        assert!(net_crypto_2.connection_by_key(real_pk_1).unwrap().read().rekey_status.is_some());
+
+        // the second node responds and sends a packet with the initial keys
+        // that is delivered after the response
+        net_crypto_2.main_loop().await.unwrap();
+        net_crypto_2.send_lossless(real_pk_1, vec![16, 2]).await.unwrap();
+        for packet in drain(&mut udp_rx_2) {
+            net_crypto_1.handle_udp_crypto_data(&packet, addr_2).await.unwrap();
+        }
+        {
+            let connection = net_crypto_1.connection_by_key(real_pk_2).unwrap();
+            let connection = connection.read();
+            assert!(connection.rekey_status.is_none());
+            assert!(connection.previous_session.is_some());
+        }
+
+        // the first node sends a packet with new keys
+        net_crypto_1.send_lossless(real_pk_2, vec![16, 3]).await.unwrap();
+        for packet in drain(&mut udp_rx_1) {
+            net_crypto_2.handle_udp_crypto_data(&packet, addr_1).await.unwrap();
+        }
+
+        // all lossless packets are delivered
+        assert_eq!(lossless_rx_1.next().await.unwrap(), (real_pk_2, vec![16, 2]));
+        assert_eq!(lossless_rx_2.next().await.unwrap(), (real_pk_1, vec![16, 1]));
+        assert_eq!(lossless_rx_2.next().await.unwrap(), (real_pk_1, vec![16, 3]));
+
+        let connection_1 = net_crypto_1.connection_by_key(real_pk_2).unwrap();
+        let connection_2 = net_crypto_2.connection_by_key(real_pk_1).unwrap();
+        let connection_1 = connection_1.read();
+        let connection_2 = connection_2.read();
+        assert!(connection_2.rekey_status.is_none());
+        assert!(connection_2.previous_session.is_some());
+        let key_1 = unpack!(connection_1.status.clone(), ConnectionStatus::Established, session_precomputed_key);
+        let key_2 = unpack!(connection_2.status.clone(), ConnectionStatus::Established, session_precomputed_key);
+        assert_eq!(key_1, key_2);
+        assert_ne!(key_1, precompute(&session_pk_2, &session_sk_1));
+        assert_eq!(connection_1.session_packets_sent, 1);
     }
 }
@@ -0,0 +1,390 @@
+/*! Session keys rotation for long-lived crypto connections.
+
+Rotation is an in-band exchange of new session keys over the established
+connection:
+
+1. The initiator generates a new session key pair and a new base nonce and
+   sends them in `RekeyRequest` lossless packet encrypted with the current keys.
+2. The peer generates its own session key pair and base nonce, computes the new
+   shared key and sends them back in `RekeyResponse` lossless packet together
+   with the session key from the request. It continues sending packets with the
+   current keys but accepts packets encrypted with the new ones.
+3. The initiator receives `RekeyResponse` and switches to the new keys if the
+   response echoes the session key of its pending request. Other responses are
+   ignored.
+4. The peer switches to the new keys when it receives the first packet
+   encrypted with them.
+
+Both sides keep the previous keys for `PREVIOUS_SESSION_TIMEOUT` so packets
+that were in flight during the rotation are not lost. Lossless packets that
+are resent after the rotation are encrypted with the new keys. If the rotation
+isn't completed in `REKEY_TIMEOUT` it's aborted and the initiator starts it
+again with new keys. The peer can't know when the initiator receives the
+response, so once the response is queued it keeps the new keys until a packet
+encrypted with them arrives, or until a packet encrypted with the current keys
+arrives after `REKEY_TIMEOUT`, which means the initiator gave up.
+
+Rotation is used only when the peer announced `CAPABILITY_REKEY` with
+`Capabilities` packet. Clients that don't support rotation, e.g. c-toxcore,
+drop this packet since it has a reserved ID and never announce the capability
+themselves.
+
+`Capabilities`, `RekeyRequest` and `RekeyResponse` packets are an extension of
+the Tox protocol. Their IDs 3, 4 and 5 are taken from the range reserved for
+`net_crypto` itself (0 - 15) which is not used by other Tox clients.
+
+*/
+
+use std::time::{Duration, Instant};
+
+use nom::number::complete::be_u8;
+
+use crate::toxcore::binary_io::*;
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::time::*;
+
+/// Packet with this ID contains capabilities of the sender. It's not stored in
+/// the send array so it's not resent.
+pub const PACKET_ID_CAPABILITIES: u8 = 3;
+
+/// Packet with this ID contains new session keys of the rotation initiator.
+pub const PACKET_ID_REKEY_REQUEST: u8 = 4;
+
+/// Packet with this ID contains new session keys of the peer that responds to
+/// the rotation request.
+pub const PACKET_ID_REKEY_RESPONSE: u8 = 5;
+
+/// Capability bit that means that session keys rotation is supported.
+pub const CAPABILITY_REKEY: u8 = 0x01;
+
+/// How many times we should send our capabilities to a peer that didn't
+/// announce its own.
+pub const MAX_NUM_CAPABILITIES_TRIES: u8 = 8;
+
+/// How long keys of the previous session are accepted after rotation.
+pub const PREVIOUS_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we wait for the peer to complete session keys rotation before it's
+/// aborted.
+pub const REKEY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of data packets after which session keys are rotated.
+pub const DEFAULT_REKEY_PACKETS: u64 = 1 << 24;
+
+/// Default amount of time after which session keys are rotated.
+pub const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When session keys should be rotated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RekeyConfig {
+    /// Rotate keys after this number of data packets sent with them.
+    pub packets: u64,
+    /// Rotate keys after this amount of time.
+    pub interval: Duration,
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        RekeyConfig {
+            packets: DEFAULT_REKEY_PACKETS,
+            interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+}
+
+/// State of session keys rotation that is in progress.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RekeyStatus {
+    /// We sent `RekeyRequest` and wait for `RekeyResponse`.
+    Requested {
+        /// New `PublicKey` for the session
+        session_pk: PublicKey,
+        /// New `SecretKey` for the session
+        session_sk: SecretKey,
+        /// Nonce that should be used to encrypt outgoing packets with new keys
+        sent_nonce: Nonce,
+        /// Time when `RekeyRequest` was sent
+        time: Instant,
+    },
+    /// We sent `RekeyResponse` and wait for the first packet encrypted with the
+    /// new keys.
+    Responded {
+        /// `PublicKey` from `RekeyRequest` that is echoed in `RekeyResponse`
+        request_pk: PublicKey,
+        /// New `PublicKey` for the session
+        session_pk: PublicKey,
+        /// New `SecretKey` for the session
+        session_sk: SecretKey,
+        /// Nonce that should be used to encrypt outgoing packets with new keys
+        sent_nonce: Nonce,
+        /// Nonce that should be used to decrypt incoming packets with new keys
+        received_nonce: Nonce,
+        /// New `PrecomputedKey` for the session
+        session_precomputed_key: PrecomputedKey,
+        /// Time when `RekeyRequest` was received
+        time: Instant,
+        /// Whether `RekeyResponse` was put to the send queue. If the queue was
+        /// full it should be put there later since the request is already
+        /// acknowledged and won't be resent by the peer.
+        response_queued: bool,
+    },
+}
+
+impl RekeyStatus {
+    /// Check if the rotation wasn't completed in time and should be aborted.
+    /// After `RekeyResponse` is queued the initiator can switch to the new keys
+    /// at any time so such rotation never expires.
+    pub fn is_expired(&self) -> bool {
+        let time = match *self {
+            RekeyStatus::Requested { time, .. } => time,
+            RekeyStatus::Responded { response_queued: true, .. } => return false,
+            RekeyStatus::Responded { time, .. } => time,
+        };
+        clock_elapsed(time) >= REKEY_TIMEOUT
+    }
+
+    /// Check if the initiator gave up the rotation we responded to. It's
+    /// called when a packet encrypted with the current keys is received.
+    pub fn is_abandoned(&self) -> bool {
+        match *self {
+            RekeyStatus::Responded { time, .. } => clock_elapsed(time) >= REKEY_TIMEOUT,
+            RekeyStatus::Requested { .. } => false,
+        }
+    }
+}
+
+/// Keys of the previous session that are still accepted for incoming packets
+/// after rotation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PreviousSession {
+    /// Nonce that should be used to decrypt incoming packets
+    pub received_nonce: Nonce,
+    /// `PrecomputedKey` of the previous session
+    pub session_precomputed_key: PrecomputedKey,
+    /// Time when the session was replaced
+    pub replaced_time: Instant,
+}
+
+impl PreviousSession {
+    /// Create new `PreviousSession` that was replaced just now.
+    pub fn new(received_nonce: Nonce, session_precomputed_key: PrecomputedKey) -> Self {
+        PreviousSession {
+            received_nonce,
+            session_precomputed_key,
+            replaced_time: clock_now(),
+        }
+    }
+
+    /// Check if keys of this session shouldn't be accepted anymore.
+    pub fn is_expired(&self) -> bool {
+        clock_elapsed(self.replaced_time) >= PREVIOUS_SESSION_TIMEOUT
+    }
+}
+
+/** Capabilities of the sender.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`1`        | `0x03`
+`1`        | Capabilities bitmask
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Capabilities bitmask.
+    pub capabilities: u8,
+}
+
+impl FromBytes for Capabilities {
+    named!(from_bytes<Capabilities>, do_parse!(
+        tag!(&[PACKET_ID_CAPABILITIES][..]) >>
+        capabilities: be_u8 >>
+        eof!() >>
+        (Capabilities { capabilities })
+    ));
+}
+
+impl ToBytes for Capabilities {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PACKET_ID_CAPABILITIES) >>
+            gen_be_u8!(self.capabilities)
+        )
+    }
+}
+
+/** Request to rotate session keys.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`1`        | `0x04`
+`32`       | New session `PublicKey`
+`24`       | New base `Nonce`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RekeyRequest {
+    /// New session `PublicKey` of the sender.
+    pub session_pk: PublicKey,
+    /// Nonce that the sender will use to encrypt packets with new keys.
+    pub base_nonce: Nonce,
+}
+
+impl FromBytes for RekeyRequest {
+    named!(from_bytes<RekeyRequest>, do_parse!(
+        tag!(&[PACKET_ID_REKEY_REQUEST][..]) >>
+        session_pk: call!(PublicKey::from_bytes) >>
+        base_nonce: call!(Nonce::from_bytes) >>
+        eof!() >>
+        (RekeyRequest { session_pk, base_nonce })
+    ));
+}
+
+impl ToBytes for RekeyRequest {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PACKET_ID_REKEY_REQUEST) >>
+            gen_slice!(self.session_pk.as_ref()) >>
+            gen_slice!(self.base_nonce.as_ref())
+        )
+    }
+}
+
+/** Response to the request to rotate session keys.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`1`        | `0x05`
+`32`       | Session `PublicKey` from the request
+`32`       | New session `PublicKey`
+`24`       | New base `Nonce`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RekeyResponse {
+    /// New session `PublicKey` from `RekeyRequest` this response is for.
+    pub request_pk: PublicKey,
+    /// New session `PublicKey` of the sender.
+    pub session_pk: PublicKey,
+    /// Nonce that the sender will use to encrypt packets with new keys.
+    pub base_nonce: Nonce,
+}
+
+impl FromBytes for RekeyResponse {
+    named!(from_bytes<RekeyResponse>, do_parse!(
+        tag!(&[PACKET_ID_REKEY_RESPONSE][..]) >>
+        request_pk: call!(PublicKey::from_bytes) >>
+        session_pk: call!(PublicKey::from_bytes) >>
+        base_nonce: call!(Nonce::from_bytes) >>
+        eof!() >>
+        (RekeyResponse { request_pk, session_pk, base_nonce })
+    ));
+}
+
+impl ToBytes for RekeyResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(PACKET_ID_REKEY_RESPONSE) >>
+            gen_slice!(self.request_pk.as_ref()) >>
+            gen_slice!(self.session_pk.as_ref()) >>
+            gen_slice!(self.base_nonce.as_ref())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        capabilities_encode_decode,
+        Capabilities {
+            capabilities: CAPABILITY_REKEY,
+        }
+    );
+
+    encode_decode_test!(
+        rekey_request_encode_decode,
+        RekeyRequest {
+            session_pk: gen_keypair().0,
+            base_nonce: gen_nonce(),
+        }
+    );
+
+    encode_decode_test!(
+        rekey_response_encode_decode,
+        RekeyResponse {
+            request_pk: gen_keypair().0,
+            session_pk: gen_keypair().0,
+            base_nonce: gen_nonce(),
+        }
+    );
+
+    #[tokio::test]
+    async fn previous_session_is_expired() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let session = PreviousSession::new(gen_nonce(), precompute(&gen_keypair().0, &gen_keypair().1));
+        assert!(!session.is_expired());
+
+        tokio::time::advance(PREVIOUS_SESSION_TIMEOUT).await;
+
+        assert!(session.is_expired());
+    }
+
+    #[tokio::test]
+    async fn rekey_status_is_expired() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let (session_pk, session_sk) = gen_keypair();
+        let status = RekeyStatus::Requested {
+            session_pk,
+            session_sk,
+            sent_nonce: gen_nonce(),
+            time: clock_now(),
+        };
+        assert!(!status.is_expired());
+
+        tokio::time::advance(REKEY_TIMEOUT).await;
+
+        assert!(status.is_expired());
+        assert!(!status.is_abandoned());
+    }
+
+    #[tokio::test]
+    async fn rekey_status_responded_is_expired() {
+        crypto_init().unwrap();
+        tokio::time::pause();
+
+        let (session_pk, session_sk) = gen_keypair();
+        let status = |response_queued| RekeyStatus::Responded {
+            request_pk: gen_keypair().0,
+            session_pk,
+            session_sk: session_sk.clone(),
+            sent_nonce: gen_nonce(),
+            received_nonce: gen_nonce(),
+            session_precomputed_key: precompute(&gen_keypair().0, &session_sk),
+            time: clock_now(),
+            response_queued,
+        };
+        let not_queued = status(false);
+        let queued = status(true);
+        assert!(!not_queued.is_expired());
+        assert!(!queued.is_abandoned());
+
+        tokio::time::advance(REKEY_TIMEOUT).await;
+
+        assert!(not_queued.is_expired());
+        // the response is queued so the peer can switch to the new keys anytime
+        assert!(!queued.is_expired());
+        assert!(queued.is_abandoned());
+    }
+}
@@ -0,0 +1,305 @@
+/*! The implementation of packets send queue with priorities
+*/
+
+use std::collections::VecDeque;
+use std::task::{Context, Poll, Waker};
+
+use futures::channel::oneshot;
+
+/// Maximum number of packets of one priority that can wait in the send queue.
+pub const SEND_QUEUE_SIZE: usize = 1024;
+
+/// Priority of a packet waiting in the send queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PacketPriority {
+    /// Lossless packets that control the connection state, e.g. alive packets
+    /// or status updates. They have the highest priority.
+    Control,
+    /// Ordinary lossless packets like messages and file chunks.
+    Lossless,
+    /// Lossy packets like audio or video frames. They have the lowest priority.
+    Lossy,
+}
+
+impl PacketPriority {
+    /// All priorities from the highest to the lowest.
+    const ALL: [PacketPriority; 3] = [PacketPriority::Control, PacketPriority::Lossless, PacketPriority::Lossy];
+
+    /// Index of the queue for this priority.
+    fn index(self) -> usize {
+        match self {
+            PacketPriority::Control => 0,
+            PacketPriority::Lossless => 1,
+            PacketPriority::Lossy => 2,
+        }
+    }
+
+    /// How many packets of this priority are sent in a row before packets of
+    /// the next priority get their turn.
+    fn weight(self) -> usize {
+        match self {
+            PacketPriority::Control => 4,
+            PacketPriority::Lossless => 2,
+            PacketPriority::Lossy => 1,
+        }
+    }
+}
+
+/// Packet waiting in the send queue.
+#[derive(Debug)]
+pub struct QueuedPacket {
+    /// Packet data
+    pub data: Vec<u8>,
+    /// Sender to notify about the number the packet gets when it's sent
+    pub number_tx: Option<oneshot::Sender<u32>>,
+}
+
+impl Clone for QueuedPacket {
+    /// Clone the packet without the sender of its number since the sender
+    /// can't be cloned.
+    fn clone(&self) -> Self {
+        QueuedPacket {
+            data: self.data.clone(),
+            number_tx: None,
+        }
+    }
+}
+
+/// Queue of packets waiting to be sent when congestion control allows it.
+/// Packets of different priorities are sent in weighted round-robin order so
+/// that packets of lower priority are not starved.
+#[derive(Clone, Debug, Default)]
+pub struct SendQueue {
+    /// Packets waiting to be sent, one queue per priority.
+    queues: [VecDeque<QueuedPacket>; 3],
+    /// Tasks waiting for free space in the queue, one list per priority.
+    wakers: [Vec<Waker>; 3],
+}
+
+impl PartialEq for SendQueue {
+    fn eq(&self, other: &SendQueue) -> bool {
+        self.queues.iter().zip(other.queues.iter()).all(|(queue, other_queue)|
+            queue.iter().map(|packet| &packet.data).eq(other_queue.iter().map(|packet| &packet.data))
+        )
+    }
+}
+
+impl SendQueue {
+    /// Create new empty `SendQueue`.
+    pub fn new() -> SendQueue {
+        SendQueue::default()
+    }
+
+    /// Total number of packets waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    /// Check if there are no packets waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Check if a packet with specified priority can be added to the queue.
+    /// If it can't the current task will be woken up when some packets of
+    /// this priority are sent.
+    pub fn poll_ready(&mut self, cx: &mut Context, priority: PacketPriority) -> Poll<()> {
+        let index = priority.index();
+        if self.queues[index].len() < SEND_QUEUE_SIZE {
+            Poll::Ready(())
+        } else {
+            let wakers = &mut self.wakers[index];
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+
+    /// Add a packet to the queue. The packet is returned back if the queue for
+    /// its priority is full.
+    pub fn push(&mut self, priority: PacketPriority, packet: Vec<u8>) -> Result<(), Vec<u8>> {
+        self.push_packet(priority, QueuedPacket {
+            data: packet,
+            number_tx: None,
+        }).map_err(|packet| packet.data)
+    }
+
+    /// Add a packet to the queue. Returned receiver gets the number of the
+    /// packet when it's sent or is canceled if the queue is dropped before.
+    /// The packet is returned back if the queue for its priority is full.
+    pub fn push_numbered(&mut self, priority: PacketPriority, packet: Vec<u8>) -> Result<oneshot::Receiver<u32>, Vec<u8>> {
+        let (number_tx, number_rx) = oneshot::channel();
+        self.push_packet(priority, QueuedPacket {
+            data: packet,
+            number_tx: Some(number_tx),
+        }).map_err(|packet| packet.data)?;
+        Ok(number_rx)
+    }
+
+    /// Add a packet to the queue if the queue for its priority is not full.
+    fn push_packet(&mut self, priority: PacketPriority, packet: QueuedPacket) -> Result<(), QueuedPacket> {
+        let queue = &mut self.queues[priority.index()];
+        if queue.len() < SEND_QUEUE_SIZE {
+            queue.push_back(packet);
+            Ok(())
+        } else {
+            Err(packet)
+        }
+    }
+
+    /// Take up to `limit` packets that should be sent now and wake up tasks
+    /// waiting for free space in the queue.
+    pub fn drain(&mut self, mut limit: usize) -> Vec<(PacketPriority, QueuedPacket)> {
+        let mut packets = Vec::new();
+        while limit > 0 && !self.is_empty() {
+            for &priority in PacketPriority::ALL.iter() {
+                let queue = &mut self.queues[priority.index()];
+                let count = priority.weight().min(queue.len()).min(limit);
+                packets.extend(queue.drain(.. count).map(|packet| (priority, packet)));
+                limit -= count;
+            }
+        }
+
+        for &priority in PacketPriority::ALL.iter() {
+            if self.queues[priority.index()].len() < SEND_QUEUE_SIZE {
+                self.wakers[priority.index()].drain(..).for_each(Waker::wake);
+            }
+        }
+
+        packets
+    }
+
+    /// Drop all queued packets and wake up all tasks waiting for free space in
+    /// the queue. Should be called when the connection is removed.
+    pub fn close(&mut self) {
+        self.queues.iter_mut().for_each(VecDeque::clear);
+        self.wakers.iter_mut()
+            .flat_map(|wakers| wakers.drain(..))
+            .for_each(Waker::wake);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::task::noop_waker;
+
+    #[test]
+    fn push_len() {
+        let mut queue = SendQueue::new();
+        assert!(queue.is_empty());
+
+        queue.push(PacketPriority::Lossless, vec![42]).unwrap();
+        queue.push(PacketPriority::Lossy, vec![192]).unwrap();
+
+        assert!(!queue.is_empty());
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn push_full() {
+        let mut queue = SendQueue::new();
+        for _ in 0 .. SEND_QUEUE_SIZE {
+            queue.push(PacketPriority::Lossless, vec![42]).unwrap();
+        }
+
+        assert_eq!(queue.push(PacketPriority::Lossless, vec![43]), Err(vec![43]));
+        // other priorities have their own space
+        assert!(queue.push(PacketPriority::Control, vec![16]).is_ok());
+    }
+
+    #[test]
+    fn poll_ready() {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let mut queue = SendQueue::new();
+        assert_eq!(queue.poll_ready(&mut cx, PacketPriority::Lossless), Poll::Ready(()));
+
+        for _ in 0 .. SEND_QUEUE_SIZE {
+            queue.push(PacketPriority::Lossless, vec![42]).unwrap();
+        }
+
+        assert_eq!(queue.poll_ready(&mut cx, PacketPriority::Lossless), Poll::Pending);
+        assert_eq!(queue.poll_ready(&mut cx, PacketPriority::Lossy), Poll::Ready(()));
+        assert_eq!(queue.wakers[PacketPriority::Lossless.index()].len(), 1);
+
+        queue.drain(1);
+
+        assert!(queue.wakers[PacketPriority::Lossless.index()].is_empty());
+        assert_eq!(queue.poll_ready(&mut cx, PacketPriority::Lossless), Poll::Ready(()));
+    }
+
+    #[test]
+    fn drain_weighted() {
+        let mut queue = SendQueue::new();
+        for i in 0 .. 8 {
+            queue.push(PacketPriority::Control, vec![16, i]).unwrap();
+            queue.push(PacketPriority::Lossless, vec![64, i]).unwrap();
+            queue.push(PacketPriority::Lossy, vec![192, i]).unwrap();
+        }
+
+        let priorities = queue.drain(10)
+            .into_iter()
+            .map(|(priority, _)| priority)
+            .collect::<Vec<_>>();
+
+        assert_eq!(priorities, vec![
+            PacketPriority::Control,
+            PacketPriority::Control,
+            PacketPriority::Control,
+            PacketPriority::Control,
+            PacketPriority::Lossless,
+            PacketPriority::Lossless,
+            PacketPriority::Lossy,
+            PacketPriority::Control,
+            PacketPriority::Control,
+            PacketPriority::Control,
+        ]);
+        assert_eq!(queue.len(), 14);
+    }
+
+    #[test]
+    fn drain_keeps_order() {
+        let mut queue = SendQueue::new();
+        for i in 0 .. 5 {
+            queue.push(PacketPriority::Lossless, vec![64, i]).unwrap();
+        }
+
+        let packets = queue.drain(10)
+            .into_iter()
+            .map(|(_, packet)| packet.data)
+            .collect::<Vec<_>>();
+
+        assert_eq!(packets, (0 .. 5).map(|i| vec![64, i]).collect::<Vec<_>>());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn push_numbered() {
+        let mut queue = SendQueue::new();
+        let mut number_rx = queue.push_numbered(PacketPriority::Lossless, vec![64]).unwrap();
+        queue.push(PacketPriority::Lossless, vec![65]).unwrap();
+
+        let packets = queue.drain(2);
+        assert_eq!(packets[0].1.data, vec![64]);
+        assert!(packets[1].1.number_tx.is_none());
+
+        let (_, packet) = packets.into_iter().next().unwrap();
+        packet.number_tx.unwrap().send(42).unwrap();
+        assert_eq!(number_rx.try_recv(), Ok(Some(42)));
+    }
+
+    #[test]
+    fn push_numbered_closed() {
+        let mut queue = SendQueue::new();
+        let mut number_rx = queue.push_numbered(PacketPriority::Lossless, vec![64]).unwrap();
+
+        queue.close();
+
+        assert!(number_rx.try_recv().is_err());
+        assert!(queue.is_empty());
+    }
+}
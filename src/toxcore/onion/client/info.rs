@@ -0,0 +1,194 @@
+/*!
+Read-only snapshots of the onion client state.
+
+They help to find out why a friend can't be found: whether we are announced
+ourselves, whether friend's close nodes are found and whether onion paths
+work at all.
+*/
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::dht::packed_node::PackedNode;
+use crate::toxcore::onion::client::*;
+use crate::toxcore::onion::client::path_constraints::PathRejections;
+use crate::toxcore::onion::client::paths_pool::StoredOnionPath;
+use crate::toxcore::onion::packet::AnnounceStatus;
+
+/// Snapshot of a node we announce ourselves to or search a friend through.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionNodeInfo {
+    /// Node's `PublicKey`.
+    pub pk: PublicKey,
+    /// Node's IP address.
+    pub saddr: SocketAddr,
+    /// Announce status from last response from this node.
+    pub announce_status: AnnounceStatus,
+    /// Whether friend's data `PublicKey` was received from this node.
+    pub has_data_pk: bool,
+    /// Number of requests sent to this node without any response.
+    pub unsuccessful_pings: u32,
+    /// Time when the last request was sent to this node.
+    pub ping_time: Instant,
+    /// Time when the last response was received from this node.
+    pub response_time: Instant,
+    /// Whether this node is considered stable.
+    pub is_stable: bool,
+    /// Whether this node is timed out.
+    pub is_timed_out: bool,
+}
+
+impl From<&OnionNode> for OnionNodeInfo {
+    fn from(node: &OnionNode) -> Self {
+        OnionNodeInfo {
+            pk: node.pk,
+            saddr: node.saddr,
+            announce_status: node.announce_status,
+            has_data_pk: node.data_pk.is_some(),
+            unsuccessful_pings: node.unsuccessful_pings,
+            ping_time: node.ping_time,
+            response_time: node.response_time,
+            is_stable: node.is_stable(),
+            is_timed_out: node.is_timed_out(),
+        }
+    }
+}
+
+/// Snapshot of a friend we are looking for.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionFriendInfo {
+    /// Friend's long term `PublicKey`.
+    pub real_pk: PublicKey,
+    /// Friend's DHT `PublicKey` if it's known.
+    pub dht_pk: Option<PublicKey>,
+    /// Whether we connected to this friend. Connected friends are not
+    /// searched.
+    pub connected: bool,
+    /// Nodes close to friend's long term `PublicKey`.
+    pub close_nodes: Vec<OnionNodeInfo>,
+    /// How many times we sent search requests to friend's close nodes.
+    pub search_count: u32,
+    /// Current interval between search requests to the same node.
+    pub search_interval: Duration,
+    /// Time when this friend was seen online last time.
+    pub last_seen: Option<Instant>,
+    /// Time when our DHT `PublicKey` was sent to this friend via onion last
+    /// time.
+    pub last_dht_pk_onion_sent: Option<Instant>,
+    /// Time when our DHT `PublicKey` was sent to this friend via DHT request
+    /// last time.
+    pub last_dht_pk_dht_sent: Option<Instant>,
+}
+
+impl OnionFriendInfo {
+    /// Number of close nodes that know friend's data `PublicKey`, i.e. nodes
+    /// friend is announced to.
+    pub fn found_nodes_count(&self) -> usize {
+        self.close_nodes.iter()
+            .filter(|node| node.has_data_pk && !node.is_timed_out)
+            .count()
+    }
+}
+
+impl From<&OnionFriend> for OnionFriendInfo {
+    fn from(friend: &OnionFriend) -> Self {
+        OnionFriendInfo {
+            real_pk: friend.real_pk,
+            dht_pk: friend.dht_pk,
+            connected: friend.connected,
+            close_nodes: friend.close_nodes.iter().map(OnionNodeInfo::from).collect(),
+            search_count: friend.search_count,
+            search_interval: friend.search_interval(),
+            last_seen: friend.last_seen,
+            last_dht_pk_onion_sent: friend.last_dht_pk_onion_sent,
+            last_dht_pk_dht_sent: friend.last_dht_pk_dht_sent,
+        }
+    }
+}
+
+/// Snapshot of a stored onion path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionPathInfo {
+    /// Nodes the path consists of.
+    pub nodes: [PackedNode; 3],
+    /// Whether the first node of the path is a TCP relay.
+    pub is_tcp: bool,
+    /// Whether the path is used for friends searching rather than for
+    /// ourselves announcing.
+    pub friend: bool,
+    /// Time when this path was created.
+    pub creation_time: Instant,
+    /// Time when we received a response via this path last time.
+    pub last_success: Option<Instant>,
+    /// How many responses we received via this path.
+    pub successes: u32,
+    /// How many requests sent via this path didn't get a response in time.
+    pub timeouts: u32,
+    /// Whether this path is considered stable.
+    pub is_stable: bool,
+    /// Whether this path is timed out and will be replaced.
+    pub is_timed_out: bool,
+}
+
+impl OnionPathInfo {
+    fn new(stored_path: &StoredOnionPath, friend: bool) -> Self {
+        let nodes = &stored_path.path.nodes;
+        let packed_node = |i: usize| PackedNode::new(nodes[i].saddr, &nodes[i].public_key);
+        OnionPathInfo {
+            nodes: [packed_node(0), packed_node(1), packed_node(2)],
+            is_tcp: stored_path.path.path_type == OnionPathType::TCP,
+            friend,
+            creation_time: stored_path.creation_time,
+            last_success: stored_path.last_success,
+            successes: stored_path.successes,
+            timeouts: stored_path.timeouts,
+            is_stable: stored_path.is_stable(),
+            is_timed_out: stored_path.is_timed_out(),
+        }
+    }
+}
+
+/// Snapshot of the onion client state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionClientStatus {
+    /// Nodes we announce ourselves to.
+    pub announce_nodes: Vec<OnionNodeInfo>,
+    /// Friends we are looking for.
+    pub friends: Vec<OnionFriendInfo>,
+    /// Stored onion paths, both for announcing and for friends searching.
+    pub paths: Vec<OnionPathInfo>,
+    /// Number of nodes in the pool for building random paths.
+    pub path_nodes_count: usize,
+    /// Nodes and paths rejected because of path constraints.
+    pub path_rejections: PathRejections,
+}
+
+impl OnionClientStatus {
+    pub(super) fn new(state: &OnionClientState) -> Self {
+        let self_paths = state.paths_pool.self_paths().iter()
+            .map(|path| OnionPathInfo::new(path, false));
+        let friend_paths = state.paths_pool.friend_paths().iter()
+            .map(|path| OnionPathInfo::new(path, true));
+        OnionClientStatus {
+            announce_nodes: state.announce_list.iter().map(OnionNodeInfo::from).collect(),
+            friends: state.friends.values().map(OnionFriendInfo::from).collect(),
+            paths: self_paths.chain(friend_paths).collect(),
+            path_nodes_count: state.paths_pool.path_nodes.len(),
+            path_rejections: state.paths_pool.rejections,
+        }
+    }
+
+    /// Number of nodes we are announced to.
+    pub fn announced_nodes_count(&self) -> usize {
+        self.announce_nodes.iter()
+            .filter(|node| node.announce_status == AnnounceStatus::Announced && !node.is_timed_out)
+            .count()
+    }
+
+    /// Whether we are announced to at least one node so that friends can
+    /// find us.
+    pub fn is_announced(&self) -> bool {
+        self.announced_nodes_count() > 0
+    }
+}
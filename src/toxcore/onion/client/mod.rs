@@ -1,8 +1,10 @@
 //! Onion client implementation.
 
 mod errors;
+pub mod info;
 mod nodes_pool;
 mod onion_path;
+pub mod path_constraints;
 mod paths_pool;
 
 use std::collections::HashMap;
@@ -25,7 +27,9 @@ use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::dht::kbucket::*;
 use crate::toxcore::ip_port::*;
 use crate::toxcore::onion::client::errors::*;
+use crate::toxcore::onion::client::info::*;
 use crate::toxcore::onion::client::onion_path::*;
+use crate::toxcore::onion::client::path_constraints::*;
 use crate::toxcore::onion::client::paths_pool::*;
 use crate::toxcore::onion::onion_announce::initial_ping_id;
 use crate::toxcore::onion::packet::*;
@@ -157,6 +161,23 @@ impl OnionFriend {
             connected: false,
         }
     }
+
+    /// Interval between search requests to the same node. It's short right
+    /// after the friend was added and grows with the time we didn't see the
+    /// friend.
+    pub fn search_interval(&self) -> Duration {
+        if self.search_count < SEARCH_COUNT_FRIEND_ANNOUNCE_BEGINNING {
+            ANNOUNCE_FRIEND_BEGINNING
+        } else {
+            let backoff_interval = self.last_seen.map_or_else(
+                || ONION_FRIEND_MAX_PING_INTERVAL,
+                |last_seen| clock_elapsed(last_seen) / ONION_FRIEND_BACKOFF_FACTOR
+            );
+            backoff_interval
+                .min(ONION_FRIEND_MAX_PING_INTERVAL)
+                .max(ANNOUNCE_FRIEND)
+        }
+    }
 }
 
 /// Type for onion close nodes.
@@ -374,6 +395,12 @@ impl OnionClient {
         self.state.lock().friend_request_tx = Some(friend_request_sink)
     }
 
+    /// Set rules that nodes of new onion paths should satisfy. Already built
+    /// paths are not affected.
+    pub fn set_path_constraints(&self, constraints: PathConstraints) {
+        self.state.lock().paths_pool.constraints = constraints;
+    }
+
     /// Check if a node was pinged recently.
     fn is_pinged_recently(&self, pk: PublicKey, search_pk: PublicKey, request_queue: &RequestQueue<AnnounceRequestData>) -> bool {
         let check_pks = |data: &AnnounceRequestData| -> bool {
@@ -510,7 +537,7 @@ impl OnionClient {
                 continue;
             }
 
-            let path = if let Some(path) = state.paths_pool.random_path(&self.dht, &self.tcp_connections, announce_data.friend_pk.is_some()) {
+            let path = if let Some(path) = state.paths_pool.random_path(&self.dht, &self.tcp_connections, announce_data.friend_pk.is_some(), &node.pk) {
                 path
             } else {
                 continue
@@ -555,17 +582,20 @@ impl OnionClient {
             .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::SendTo).into());
 
         let friend_dht_pk = dht_pk_announce.dht_pk;
-        let futures = dht_pk_announce.nodes.into_iter().map(|node| match node.ip_port.protocol {
-            ProtocolType::UDP => {
-                let packed_node = PackedNode::new(node.ip_port.to_saddr(), &node.pk);
-                Either::Left(self.dht.ping_node(&packed_node)
-                    .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::PingNode).into()))
-            },
-            ProtocolType::TCP => {
-                Either::Right(self.tcp_connections.add_relay_connection(node.ip_port.to_saddr(), node.pk, friend_dht_pk)
-                    .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::AddRelay).into()))
-            }
-        }).collect::<Vec<_>>();
+        let tcp_only = self.dht.is_tcp_only();
+        let futures = dht_pk_announce.nodes.into_iter()
+            .filter(|node| !tcp_only || node.ip_port.protocol == ProtocolType::TCP)
+            .map(|node| match node.ip_port.protocol {
+                ProtocolType::UDP => {
+                    let packed_node = PackedNode::new(node.ip_port.to_saddr(), &node.pk);
+                    Either::Left(self.dht.ping_node(&packed_node)
+                        .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::PingNode).into()))
+                },
+                ProtocolType::TCP => {
+                    Either::Right(self.tcp_connections.add_relay_connection(node.ip_port.to_saddr(), node.pk, friend_dht_pk)
+                        .map_err(|e| e.context(HandleDhtPkAnnounceErrorKind::AddRelay).into()))
+                }
+            }).collect::<Vec<_>>();
 
         Either::Right(
             future::try_join(
@@ -696,9 +726,9 @@ impl OnionClient {
             if clock_elapsed(node.ping_time) >= interval || ping_random && random_limit_usize(capacity) == 0 {
                 // Last chance for a long-lived node
                 let path = if node.is_last_ping_attempt() && node.is_stable() {
-                    paths_pool.random_path(&self.dht, &self.tcp_connections, friend_pk.is_some())
+                    paths_pool.random_path(&self.dht, &self.tcp_connections, friend_pk.is_some(), &node.pk)
                 } else {
-                    paths_pool.get_or_random_path(&self.dht, &self.tcp_connections, node.path_id, friend_pk.is_some())
+                    paths_pool.get_or_random_path(&self.dht, &self.tcp_connections, node.path_id, friend_pk.is_some(), &node.pk)
                 };
 
                 let path = if let Some(path) = path {
@@ -733,7 +763,7 @@ impl OnionClient {
                     break
                 };
 
-                let path = if let Some(path) = paths_pool.random_path(&self.dht, &self.tcp_connections, friend_pk.is_some()) {
+                let path = if let Some(path) = paths_pool.random_path(&self.dht, &self.tcp_connections, friend_pk.is_some(), &node.pk) {
                     path
                 } else {
                     break
@@ -807,7 +837,7 @@ impl OnionClient {
                 continue
             };
 
-            let path = if let Some(path) = paths_pool.get_or_random_path(&self.dht, &self.tcp_connections, node.path_id, true) {
+            let path = if let Some(path) = paths_pool.get_or_random_path(&self.dht, &self.tcp_connections, node.path_id, true, &node.pk) {
                 path
             } else {
                 continue
@@ -884,17 +914,7 @@ impl OnionClient {
                 data_pk: None,
             };
 
-            let interval = if friend.search_count < SEARCH_COUNT_FRIEND_ANNOUNCE_BEGINNING {
-                ANNOUNCE_FRIEND_BEGINNING
-            } else {
-                let backoff_interval = friend.last_seen.map_or_else(
-                    || ONION_FRIEND_MAX_PING_INTERVAL,
-                    |last_seen| clock_elapsed(last_seen) / ONION_FRIEND_BACKOFF_FACTOR
-                );
-                backoff_interval
-                    .min(ONION_FRIEND_MAX_PING_INTERVAL)
-                    .max(ANNOUNCE_FRIEND)
-            };
+            let interval = friend.search_interval();
 
             let (friend_future, packets_sent) = self.ping_close_nodes(
                 &mut friend.close_nodes,
@@ -915,7 +935,7 @@ impl OnionClient {
                 futures.push(Box::pin(self.send_dht_pk_onion(friend, &mut state.paths_pool)));
             }
 
-            if friend.last_dht_pk_dht_sent.map_or(true, |time| clock_elapsed(time) > DHT_DHTPK_SEND_INTERVAL) {
+            if !self.dht.is_tcp_only() && friend.last_dht_pk_dht_sent.map_or(true, |time| clock_elapsed(time) > DHT_DHTPK_SEND_INTERVAL) {
                 futures.push(Box::pin(self.send_dht_pk_dht_request(friend)));
             }
         }
@@ -925,6 +945,14 @@ impl OnionClient {
             .map_err(|e| e.context(RunErrorKind::SendTo).into())
     }
 
+    /// Remove timed out announce requests and count them for paths they were
+    /// sent through.
+    fn clear_timed_out_requests(&self, state: &mut OnionClientState) {
+        for request_data in state.announce_requests.take_timed_out() {
+            state.paths_pool.add_timeout(request_data.path_id, request_data.friend_pk.is_some());
+        }
+    }
+
     /// Populate nodes pool from DHT for building random paths.
     fn populate_path_nodes(&self, state: &mut OnionClientState) {
         for node in self.dht.random_friend_nodes(MAX_ONION_ANNOUNCE_NODES) {
@@ -932,6 +960,17 @@ impl OnionClient {
         }
     }
 
+    /// Get a snapshot of announce nodes, friends searching progress and onion
+    /// paths.
+    pub fn status(&self) -> OnionClientStatus {
+        OnionClientStatus::new(&self.state.lock())
+    }
+
+    /// Get a snapshot of friend searching progress.
+    pub fn friend_status(&self, real_pk: &PublicKey) -> Option<OnionFriendInfo> {
+        self.state.lock().friends.get(real_pk).map(OnionFriendInfo::from)
+    }
+
     /// Run periodical announcements and friends searching.
     pub fn run(self) -> impl Future<Output = Result<(), RunError>> + Send {
         let interval = Duration::from_secs(1);
@@ -943,6 +982,7 @@ impl OnionClient {
                 trace!("Onion client sender wake up");
 
                 let mut state = self.state.lock();
+                self.clear_timed_out_requests(&mut state);
                 self.populate_path_nodes(&mut state);
 
                 future::try_join(
@@ -1195,6 +1235,109 @@ mod tests {
         assert_eq!(state.friends[&friend_pk].dht_pk, Some(friend_dht_pk));
     }
 
+    #[test]
+    fn status() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        // make DHT connected so that we will build UDP onion paths
+        dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+
+        let (friend_pk, _friend_sk) = gen_keypair();
+        onion_client.add_friend(friend_pk);
+
+        let mut state = onion_client.state.lock();
+
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 12346 + i);
+            state.paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        let path = state.paths_pool.random_path(&onion_client.dht, &onion_client.tcp_connections, false, &gen_keypair().0).unwrap();
+
+        let now = clock_now();
+        let node_pk = gen_keypair().0;
+        let node = OnionNode {
+            pk: node_pk,
+            saddr: "127.0.0.1:12349".parse().unwrap(),
+            path_id: path.id(),
+            ping_id: Some(sha256::hash(&[1, 2, 3])),
+            data_pk: None,
+            unsuccessful_pings: 0,
+            added_time: now,
+            ping_time: now,
+            response_time: now,
+            announce_status: AnnounceStatus::Announced,
+        };
+        assert!(state.announce_list.try_add(&real_pk, node, /* evict */ true));
+
+        drop(state);
+
+        let status = onion_client.status();
+        assert!(status.is_announced());
+        assert_eq!(status.announced_nodes_count(), 1);
+        assert_eq!(status.announce_nodes[0].pk, node_pk);
+        assert_eq!(status.path_nodes_count, 3);
+        assert_eq!(status.paths.len(), 1);
+        assert!(!status.paths[0].friend);
+        assert_eq!(status.friends.len(), 1);
+
+        let friend_status = onion_client.friend_status(&friend_pk).unwrap();
+        assert_eq!(friend_status.real_pk, friend_pk);
+        assert_eq!(friend_status.search_interval, ANNOUNCE_FRIEND_BEGINNING);
+        assert_eq!(friend_status.found_nodes_count(), 0);
+        assert!(onion_client.friend_status(&gen_keypair().0).is_none());
+    }
+
+    #[tokio::test]
+    async fn clear_timed_out_requests() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (real_pk, real_sk) = gen_keypair();
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+        let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+        // make DHT connected so that we will build UDP onion paths
+        dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+        let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+        let onion_client = OnionClient::new(dht, tcp_connections, real_sk, real_pk);
+
+        let mut state = onion_client.state.lock();
+
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 12346 + i);
+            state.paths_pool.path_nodes.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        let path = state.paths_pool.random_path(&onion_client.dht, &onion_client.tcp_connections, false, &gen_keypair().0).unwrap();
+
+        tokio::time::pause();
+
+        let request_data = AnnounceRequestData {
+            pk: gen_keypair().0,
+            saddr: "127.0.0.1:12349".parse().unwrap(),
+            path_id: path.id(),
+            friend_pk: None,
+        };
+        let request_id = state.announce_requests.new_ping_id(request_data);
+
+        drop(state);
+
+        tokio::time::advance(ANNOUNCE_TIMEOUT + Duration::from_secs(1)).await;
+
+        let mut state = onion_client.state.lock();
+        onion_client.clear_timed_out_requests(&mut state);
+
+        assert!(state.announce_requests.check_ping_id(request_id, |_| true).is_none());
+
+        drop(state);
+
+        let status = onion_client.status();
+        assert_eq!(status.paths[0].timeouts, 1);
+        assert_eq!(status.paths[0].successes, 0);
+    }
+
     #[tokio::test]
     async fn handle_announce_response_announced() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -1893,7 +2036,9 @@ mod tests {
         // map needed to decrypt onion packets later
         let mut key_by_addr = HashMap::new();
         let addr = "127.0.0.1".parse().unwrap();
-        for i in 0 .. 3 {
+        // destination node can't be a part of the path so we need at least 4
+        // nodes
+        for i in 0 .. 4 {
             let saddr = SocketAddr::new(addr, 12346 + i);
             let (pk, sk) = gen_keypair();
             key_by_addr.insert(saddr, sk);
@@ -2022,7 +2167,9 @@ mod tests {
         // map needed to decrypt onion packets later
         let mut key_by_addr = HashMap::new();
         let addr = "127.0.0.1".parse().unwrap();
-        for i in 0 .. 3 {
+        // destination node can't be a part of the path so we need at least 4
+        // nodes
+        for i in 0 .. 4 {
             let saddr = SocketAddr::new(addr, 12346 + i);
             let (pk, sk) = gen_keypair();
             key_by_addr.insert(saddr, sk);
@@ -1,10 +1,12 @@
 //! Nodes pool.
 
 use std::collections::VecDeque;
+use std::time::Duration;
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::onion::client::onion_path::{OnionPath, OnionPathType};
+use crate::toxcore::onion::client::path_constraints::*;
 
 /// Maximum number of nodes that onion can store for building random paths.
 const MAX_PATH_NODES: usize = 32;
@@ -57,45 +59,116 @@ impl NodesPool {
         self.nodes.len()
     }
 
-    /// Build new random onion path with first UDP node.
-    pub fn udp_path(&self) -> Option<OnionPath> {
-        if self.len() < MIN_NODES_POOL_SIZE {
+    /// Choose a random node for the next hop of onion path. The node should
+    /// differ from already chosen hops and satisfy `PathConstraints`.
+    fn choose_hop(
+        &self,
+        hops: &[PackedNode],
+        destination: Option<&PublicKey>,
+        constraints: &PathConstraints,
+        rejections: &mut PathRejections,
+        uptime: &dyn Fn(&PublicKey) -> Option<Duration>,
+    ) -> Option<PackedNode> {
+        let mut candidates = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            if hops.iter().any(|hop| hop.pk == node.pk) {
+                continue;
+            }
+            if constraints.exclude_destination && destination == Some(&node.pk) {
+                rejections.destination += 1;
+                continue;
+            }
+            if constraints.distinct_subnets && hops.iter().any(|hop| is_same_subnet(hop.ip(), node.ip())) {
+                rejections.same_subnet += 1;
+                continue;
+            }
+            candidates.push(node);
+        }
+
+        if candidates.is_empty() {
             return None;
         }
-        // non-empty nodes pool will always return some node
-        let node_1 = self.rand().unwrap();
-        let mut node_2;
-        loop {
-            node_2 = self.rand().unwrap();
-            if node_2 != node_1 {
-                break;
+
+        if !constraints.prefer_uptime {
+            return Some(*candidates[random_limit_usize(candidates.len())]);
+        }
+
+        // choose the node with the longest uptime from several random
+        // candidates so that other nodes still have a chance to be chosen
+        (0 .. UPTIME_CANDIDATES)
+            .map(|_| *candidates[random_limit_usize(candidates.len())])
+            .max_by_key(|node| uptime(&node.pk))
+    }
+
+    /// Build new random onion path that satisfies `PathConstraints`. The first
+    /// node is chosen from the pool if it's not specified.
+    fn constrained_path(
+        &self,
+        first: Option<PackedNode>,
+        destination: Option<&PublicKey>,
+        constraints: &PathConstraints,
+        rejections: &mut PathRejections,
+        uptime: &dyn Fn(&PublicKey) -> Option<Duration>,
+    ) -> Option<[PackedNode; 3]> {
+        let mut hops = Vec::with_capacity(3);
+        if let Some(first) = first {
+            if constraints.exclude_destination && destination == Some(&first.pk) {
+                rejections.destination += 1;
+                return None;
             }
+            hops.push(first);
         }
-        let mut node_3;
-        loop {
-            node_3 = self.rand().unwrap();
-            if node_3 != node_1 && node_3 != node_2 {
-                break;
+        while hops.len() < 3 {
+            if let Some(node) = self.choose_hop(&hops, destination, constraints, rejections, uptime) {
+                hops.push(node);
+            } else {
+                rejections.not_enough_nodes += 1;
+                return None;
             }
         }
-        Some(OnionPath::new([node_1, node_2, node_3], OnionPathType::UDP))
+        Some([hops[0], hops[1], hops[2]])
     }
 
-    /// Build new random onion path with first TCP node.
-    pub fn tcp_path(&self, node_1: PackedNode) -> Option<OnionPath> {
-        if self.len() < MIN_NODES_POOL_SIZE - 1 {
+    /// Build new random onion path with first UDP node without any
+    /// constraints.
+    #[cfg(test)]
+    pub fn udp_path(&self) -> Option<OnionPath> {
+        self.constrained_udp_path(None, &PathConstraints::none(), &mut PathRejections::default(), &|_| None)
+    }
+
+    /// Build new random onion path with first UDP node that satisfies
+    /// `PathConstraints`. `uptime` function should return how long a node is
+    /// kept in the DHT close list.
+    pub fn constrained_udp_path(
+        &self,
+        destination: Option<&PublicKey>,
+        constraints: &PathConstraints,
+        rejections: &mut PathRejections,
+        uptime: &dyn Fn(&PublicKey) -> Option<Duration>,
+    ) -> Option<OnionPath> {
+        if self.len() < MIN_NODES_POOL_SIZE {
             return None;
         }
-        // non-empty nodes pool will always return some node
-        let node_2 = self.rand().unwrap();
-        let mut node_3;
-        loop {
-            node_3 = self.rand().unwrap();
-            if node_3 != node_2 {
-                break;
-            }
+        self.constrained_path(None, destination, constraints, rejections, uptime)
+            .map(|nodes| OnionPath::new(nodes, OnionPathType::UDP))
+    }
+
+    /// Build new random onion path with first TCP node that satisfies
+    /// `PathConstraints`. `uptime` function should return how long a node is
+    /// kept in the DHT close list.
+    pub fn constrained_tcp_path(
+        &self,
+        node_1: PackedNode,
+        destination: Option<&PublicKey>,
+        constraints: &PathConstraints,
+        rejections: &mut PathRejections,
+        uptime: &dyn Fn(&PublicKey) -> Option<Duration>,
+    ) -> Option<OnionPath> {
+        if self.len() < MIN_NODES_POOL_SIZE - 1 {
+            return None;
         }
-        Some(OnionPath::new([node_1, node_2, node_3], OnionPathType::TCP))
+        self.constrained_path(Some(node_1), destination, constraints, rejections, uptime)
+            .map(|nodes| OnionPath::new(nodes, OnionPathType::TCP))
     }
 }
 
@@ -171,4 +244,96 @@ mod tests {
         let nodes_pool = NodesPool::new();
         assert!(nodes_pool.rand().is_none());
     }
+
+    #[test]
+    fn constrained_udp_path_distinct_subnets() {
+        let mut nodes_pool = NodesPool::new();
+        let node_1 = PackedNode::new("8.8.4.4:33445".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new("8.8.8.8:33445".parse().unwrap(), &gen_keypair().0);
+        let node_3 = PackedNode::new("1.1.1.1:33445".parse().unwrap(), &gen_keypair().0);
+        nodes_pool.put(node_1);
+        nodes_pool.put(node_2);
+        nodes_pool.put(node_3);
+
+        let mut rejections = PathRejections::default();
+        let path = nodes_pool.constrained_udp_path(None, &PathConstraints::default(), &mut rejections, &|_| None);
+        assert!(path.is_none());
+        assert!(rejections.same_subnet > 0);
+        assert_eq!(rejections.not_enough_nodes, 1);
+
+        let node_4 = PackedNode::new("9.9.9.9:33445".parse().unwrap(), &gen_keypair().0);
+        nodes_pool.put(node_4);
+
+        for _ in 0 .. 10 {
+            let path = nodes_pool.constrained_udp_path(None, &PathConstraints::default(), &mut rejections, &|_| None).unwrap();
+            let ips = path.nodes.iter().map(|node| node.saddr.ip()).collect::<Vec<_>>();
+            assert!(!(ips.contains(&node_1.ip()) && ips.contains(&node_2.ip())));
+        }
+    }
+
+    #[test]
+    fn constrained_udp_path_lan_nodes() {
+        let mut nodes_pool = NodesPool::new();
+        for i in 0 .. 3 {
+            let saddr = SocketAddr::new("192.168.1.1".parse().unwrap(), 33445 + i);
+            nodes_pool.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+
+        let mut rejections = PathRejections::default();
+        let path = nodes_pool.constrained_udp_path(None, &PathConstraints::default(), &mut rejections, &|_| None);
+        assert!(path.is_some());
+        assert_eq!(rejections, PathRejections::default());
+    }
+
+    #[test]
+    fn constrained_tcp_path_exclude_destination() {
+        let mut nodes_pool = NodesPool::new();
+        let relay = PackedNode::new("8.8.8.8:33445".parse().unwrap(), &gen_keypair().0);
+        let node_1 = PackedNode::new("1.1.1.1:33445".parse().unwrap(), &gen_keypair().0);
+        let node_2 = PackedNode::new("9.9.9.9:33445".parse().unwrap(), &gen_keypair().0);
+        let node_3 = PackedNode::new("4.4.4.4:33445".parse().unwrap(), &gen_keypair().0);
+        nodes_pool.put(node_1);
+        nodes_pool.put(node_2);
+        nodes_pool.put(node_3);
+
+        let mut rejections = PathRejections::default();
+        let constraints = PathConstraints::default();
+
+        for _ in 0 .. 10 {
+            let path = nodes_pool.constrained_tcp_path(relay, Some(&node_1.pk), &constraints, &mut rejections, &|_| None).unwrap();
+            assert_eq!(path.path_type, OnionPathType::TCP);
+            assert_eq!(path.nodes[0].public_key, relay.pk);
+            assert!(path.nodes.iter().all(|node| node.public_key != node_1.pk));
+        }
+        // the destination is rejected for both hops chosen from the pool
+        assert_eq!(rejections.destination, 20);
+
+        let path = nodes_pool.constrained_tcp_path(relay, Some(&relay.pk), &constraints, &mut rejections, &|_| None);
+        assert!(path.is_none());
+        assert_eq!(rejections.destination, 21);
+    }
+
+    #[test]
+    fn choose_hop_prefers_uptime() {
+        let mut nodes_pool = NodesPool::new();
+        for i in 0 .. 10 {
+            let saddr = SocketAddr::new("127.0.0.1".parse().unwrap(), 33445 + i);
+            nodes_pool.put(PackedNode::new(saddr, &gen_keypair().0));
+        }
+        let stable_pk = nodes_pool.nodes[0].pk;
+        let uptime = |pk: &PublicKey| if *pk == stable_pk {
+            Some(Duration::from_secs(3600))
+        } else {
+            None
+        };
+
+        let mut rejections = PathRejections::default();
+        let chosen = (0 .. 1000)
+            .flat_map(|_| nodes_pool.choose_hop(&[], None, &PathConstraints::default(), &mut rejections, &uptime))
+            .filter(|node| node.pk == stable_pk)
+            .count();
+        // random choice would give ~100 while the choice of the best from 3
+        // random candidates gives ~271
+        assert!(chosen > 180);
+    }
 }
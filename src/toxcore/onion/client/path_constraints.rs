@@ -0,0 +1,121 @@
+/*!
+Diversity constraints for onion paths.
+
+If an attacker controls several nodes of an onion path it can link our long
+term `PublicKey` to our IP address. It's cheap to run many nodes in the same
+subnet so we don't allow more than one hop from the same /16 IPv4 or /32 IPv6
+prefix. Also nodes that are kept in our DHT close list for a long time are
+preferred since it's harder to flood the network with such nodes.
+*/
+
+use std::net::IpAddr;
+
+use crate::toxcore::dht::ip_port::IsGlobal;
+
+/// Number of random candidates to choose the node with the longest uptime
+/// from when `PathConstraints::prefer_uptime` is enabled.
+pub const UPTIME_CANDIDATES: usize = 3;
+
+/// Rules that nodes of an onion path should satisfy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PathConstraints {
+    /// Don't allow two hops from the same /16 IPv4 or /32 IPv6 prefix. Only
+    /// global addresses are checked so that paths can be built in LAN.
+    pub distinct_subnets: bool,
+    /// Don't allow the destination node to be a hop of the path.
+    pub exclude_destination: bool,
+    /// Prefer nodes that are kept in the DHT close list for a long time.
+    pub prefer_uptime: bool,
+}
+
+impl PathConstraints {
+    /// Constraints that allow any distinct nodes to be used.
+    pub fn none() -> Self {
+        PathConstraints {
+            distinct_subnets: false,
+            exclude_destination: false,
+            prefer_uptime: false,
+        }
+    }
+}
+
+impl Default for PathConstraints {
+    fn default() -> Self {
+        PathConstraints {
+            distinct_subnets: true,
+            exclude_destination: true,
+            prefer_uptime: true,
+        }
+    }
+}
+
+/// Counters of nodes and paths rejected because of `PathConstraints`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PathRejections {
+    /// How many times a node was not chosen as a hop because it's in the same
+    /// subnet as another hop.
+    pub same_subnet: u64,
+    /// How many times a node or a stored path was not used because it
+    /// contains the destination node.
+    pub destination: u64,
+    /// How many times a path was not built because there were not enough
+    /// nodes satisfying the constraints.
+    pub not_enough_nodes: u64,
+}
+
+/// Get the prefix of a global IP address which is /16 for IPv4 and /32 for
+/// IPv6. Returns `None` for non-global addresses.
+pub fn subnet(ip: IpAddr) -> Option<IpAddr> {
+    if !IsGlobal::is_global(&ip) {
+        return None;
+    }
+
+    Some(match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            [octets[0], octets[1], 0, 0].into()
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            [segments[0], segments[1], 0, 0, 0, 0, 0, 0].into()
+        },
+    })
+}
+
+/// Check if both addresses are global and belong to the same subnet.
+pub fn is_same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (subnet(a), subnet(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_v4() {
+        assert_eq!(subnet("8.8.4.4".parse().unwrap()), Some("8.8.0.0".parse().unwrap()));
+        assert_eq!(subnet("192.168.1.1".parse().unwrap()), None);
+        assert_eq!(subnet("127.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn subnet_v6() {
+        assert_eq!(
+            subnet("2a03:b0c0:3:d0::ac:5001".parse().unwrap()),
+            Some("2a03:b0c0::".parse().unwrap())
+        );
+        assert_eq!(subnet("::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn same_subnet() {
+        assert!(is_same_subnet("8.8.4.4".parse().unwrap(), "8.8.8.8".parse().unwrap()));
+        assert!(!is_same_subnet("8.8.4.4".parse().unwrap(), "8.9.4.4".parse().unwrap()));
+        assert!(!is_same_subnet("192.168.1.1".parse().unwrap(), "192.168.1.2".parse().unwrap()));
+        assert!(is_same_subnet("2a03:b0c0:3::1".parse().unwrap(), "2a03:b0c0:4::1".parse().unwrap()));
+        assert!(!is_same_subnet("8.8.4.4".parse().unwrap(), "2a03:b0c0:4::1".parse().unwrap()));
+    }
+}
@@ -5,6 +5,7 @@ use crate::toxcore::dht::packed_node::PackedNode;
 use crate::toxcore::dht::server::{Server as DhtServer};
 use crate::toxcore::onion::client::nodes_pool::*;
 use crate::toxcore::onion::client::onion_path::*;
+use crate::toxcore::onion::client::path_constraints::*;
 use crate::toxcore::time::*;
 use crate::toxcore::onion::client::TIME_TO_STABLE;
 use crate::toxcore::tcp::client::{Connections as TcpConnections};
@@ -40,6 +41,10 @@ pub struct StoredOnionPath {
     /// How many times we attempted to use this path without receiving a
     /// response.
     pub attempts: u32,
+    /// How many responses we received via this path.
+    pub successes: u32,
+    /// How many requests sent via this path didn't get a response in time.
+    pub timeouts: u32,
 }
 
 impl StoredOnionPath {
@@ -52,6 +57,8 @@ impl StoredOnionPath {
             last_used: now,
             last_success: None,
             attempts: ONION_PATH_MAX_NO_RESPONSE_USES / 2,
+            successes: 0,
+            timeouts: 0,
         }
     }
 
@@ -59,6 +66,12 @@ impl StoredOnionPath {
     pub fn update_success(&mut self) {
         self.last_success = Some(clock_now());
         self.attempts = 0;
+        self.successes = self.successes.saturating_add(1);
+    }
+
+    /// Update timeouts number after a request sent via this path timed out.
+    pub fn update_timeout(&mut self) {
+        self.timeouts = self.timeouts.saturating_add(1);
     }
 
     /// Check if we never received a response from this path.
@@ -86,6 +99,11 @@ impl StoredOnionPath {
                 clock_elapsed(self.last_used) < ONION_PATH_TIMEOUT)
     }
 
+    /// Check if one of the nodes of this path has the specified `PublicKey`.
+    pub fn contains(&self, pk: &PublicKey) -> bool {
+        self.path.nodes.iter().any(|node| node.public_key == *pk)
+    }
+
     /// Mark this path each time it was used to send request.
     pub fn use_path(&mut self) {
         self.last_used = clock_now();
@@ -102,6 +120,10 @@ pub struct PathsPool {
     self_paths: Vec<StoredOnionPath>,
     /// List of used random onion paths for friends searching.
     friend_paths: Vec<StoredOnionPath>,
+    /// Rules that nodes of new paths should satisfy.
+    pub constraints: PathConstraints,
+    /// Counters of nodes and paths rejected because of constraints.
+    pub rejections: PathRejections,
 }
 
 impl PathsPool {
@@ -111,13 +133,16 @@ impl PathsPool {
             path_nodes: NodesPool::new(),
             self_paths: Vec::new(),
             friend_paths: Vec::new(),
+            constraints: PathConstraints::default(),
+            rejections: PathRejections::default(),
         }
     }
 
-    /// Get a random onion path. Can be either one of existent paths or newly
-    /// generated. If we are not connected to DHT the first node from this path
-    /// will be a TCP node.
-    pub fn random_path(&mut self, dht: &DhtServer, tcp_connections: &TcpConnections, friend: bool) -> Option<OnionPath> {
+    /// Get a random onion path to send a packet to the destination node. Can
+    /// be either one of existent paths or newly generated. If we are not
+    /// connected to DHT or DHT is in TCP-only mode the first node from this
+    /// path will be a TCP node.
+    pub fn random_path(&mut self, dht: &DhtServer, tcp_connections: &TcpConnections, friend: bool, destination: &PublicKey) -> Option<OnionPath> {
         let paths = if friend {
             &mut self.friend_paths
         } else {
@@ -127,26 +152,44 @@ impl PathsPool {
         paths.retain(|stored_path| !stored_path.is_timed_out());
 
         let path_number = random_limit_usize(NUMBER_ONION_PATHS);
+        let mut rejected_index = None;
         if let Some(stored_path) = paths.get_mut(path_number) {
-            stored_path.use_path();
-            return Some(stored_path.path.clone());
+            if self.constraints.exclude_destination && stored_path.contains(destination) {
+                self.rejections.destination += 1;
+                rejected_index = Some(path_number);
+            } else {
+                stored_path.use_path();
+                return Some(stored_path.path.clone());
+            }
         }
 
-        let path = if dht.is_connected() {
-            self.path_nodes.udp_path()
+        let uptime = |pk: &PublicKey| dht.node_uptime(pk);
+        let path = if !dht.is_tcp_only() && dht.is_connected() {
+            self.path_nodes.constrained_udp_path(Some(destination), &self.constraints, &mut self.rejections, &uptime)
         } else if let Some(relay) = tcp_connections.get_random_relay() {
-            self.path_nodes.tcp_path(relay)
+            self.path_nodes.constrained_tcp_path(relay, Some(destination), &self.constraints, &mut self.rejections, &uptime)
         } else {
             None
         };
 
+        let paths = if friend {
+            &mut self.friend_paths
+        } else {
+            &mut self.self_paths
+        };
+
         if let Some(path) = path {
             let path_id = path.id();
             if let Some(stored_path) = paths.iter_mut().find(|stored_path| stored_path.path.id() == path_id) {
                 stored_path.use_path();
             } else {
                 let stored_path = StoredOnionPath::new(path.clone());
-                paths.push(stored_path);
+                match rejected_index {
+                    // replace the rejected path so that the pool doesn't grow
+                    // beyond NUMBER_ONION_PATHS
+                    Some(index) if paths.len() >= NUMBER_ONION_PATHS => paths[index] = stored_path,
+                    _ => paths.push(stored_path),
+                }
             }
             Some(path)
         } else {
@@ -154,25 +197,30 @@ impl PathsPool {
         }
     }
 
-    /// Get path by its `OnionPathId`. If there is no path with such id a new
-    /// path will be generated.
-    pub fn get_or_random_path(&mut self, dht: &DhtServer, tcp_connections: &TcpConnections, path_id: OnionPathId, friend: bool) -> Option<OnionPath> {
+    /// Get path by its `OnionPathId`. If there is no path with such id or the
+    /// path contains the destination node a new path will be generated.
+    pub fn get_or_random_path(&mut self, dht: &DhtServer, tcp_connections: &TcpConnections, path_id: OnionPathId, friend: bool, destination: &PublicKey) -> Option<OnionPath> {
         let paths = if friend {
             &mut self.friend_paths
         } else {
             &mut self.self_paths
         };
 
-        let stored_path = paths
+        let mut stored_path = paths
             .iter_mut()
             .find(|stored_path| stored_path.path.id() == path_id)
             .filter(|stored_path| !stored_path.is_timed_out());
 
+        if self.constraints.exclude_destination && stored_path.as_ref().map_or(false, |stored_path| stored_path.contains(destination)) {
+            self.rejections.destination += 1;
+            stored_path = None;
+        }
+
         if let Some(stored_path) = stored_path {
             stored_path.use_path();
             Some(stored_path.path.clone())
         } else {
-            self.random_path(dht, tcp_connections, friend)
+            self.random_path(dht, tcp_connections, friend, destination)
         }
     }
 
@@ -206,6 +254,29 @@ impl PathsPool {
             }
         }
     }
+
+    /// Count a request sent via the path that didn't get a response in time.
+    pub fn add_timeout(&mut self, path_id: OnionPathId, friend: bool) {
+        let paths = if friend {
+            &mut self.friend_paths
+        } else {
+            &mut self.self_paths
+        };
+
+        if let Some(path) = paths.iter_mut().find(|stored_path| stored_path.path.id() == path_id) {
+            path.update_timeout();
+        }
+    }
+
+    /// Stored paths used for ourselves announcing.
+    pub fn self_paths(&self) -> &[StoredOnionPath] {
+        &self.self_paths
+    }
+
+    /// Stored paths used for friends searching.
+    pub fn friend_paths(&self) -> &[StoredOnionPath] {
+        &self.friend_paths
+    }
 }
 
 impl Default for PathsPool {
@@ -245,11 +316,43 @@ mod tests {
                     }
 
                     assert_eq!(paths_pool.$paths.len(), NUMBER_ONION_PATHS);
-                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends).unwrap();
+                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends, &gen_keypair().0).unwrap();
                     assert_eq!(paths_pool.$paths.len(), NUMBER_ONION_PATHS);
                     assert!(paths_pool.$paths.iter().any(|stored_path| stored_path.path.id() == path.id()));
                 }
 
+                #[test]
+                fn random_path_stored_contains_destination() {
+                    let (dht_pk, dht_sk) = gen_keypair();
+                    let (udp_tx, _udp_rx) = mpsc::channel(1);
+                    let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+                    let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+                    // make DHT connected so that we will build UDP onion paths
+                    dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+                    let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+                    let mut paths_pool = PathsPool::new();
+                    for _ in 0 .. MIN_NODES_POOL_SIZE {
+                        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                        paths_pool.path_nodes.put(node);
+                    }
+                    // all stored paths contain the destination node
+                    let destination = PackedNode::new("127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+                    for _ in 0 .. NUMBER_ONION_PATHS {
+                        let node_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                        let node_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+                        let path = OnionPath::new([node_1, node_2, destination], OnionPathType::UDP);
+                        paths_pool.$paths.push(StoredOnionPath::new(path));
+                    }
+
+                    for _ in 0 .. NUMBER_ONION_PATHS * 2 {
+                        let path = paths_pool.random_path(&dht, &tcp_connections, $friends, &destination.pk).unwrap();
+                        assert!(!path.nodes.iter().any(|node| node.public_key == destination.pk));
+                        assert_eq!(paths_pool.$paths.len(), NUMBER_ONION_PATHS);
+                        assert!(paths_pool.$paths.iter().any(|stored_path| stored_path.path.id() == path.id()));
+                    }
+                    assert!(paths_pool.rejections.destination > 0);
+                }
+
                 #[test]
                 fn random_path_new_udp_random() {
                     let (dht_pk, dht_sk) = gen_keypair();
@@ -265,7 +368,7 @@ mod tests {
                         paths_pool.path_nodes.put(node);
                     }
 
-                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends).unwrap();
+                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends, &gen_keypair().0).unwrap();
                     assert_eq!(path, paths_pool.$paths[0].path);
                     assert_eq!(path.path_type, OnionPathType::UDP);
                 }
@@ -285,12 +388,37 @@ mod tests {
                         paths_pool.path_nodes.put(node);
                     }
 
-                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends).unwrap();
+                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends, &gen_keypair().0).unwrap();
                     assert_eq!(path, paths_pool.$paths[0].path);
                     assert_eq!(path.nodes[0].public_key, relay_pk);
                     assert_eq!(path.path_type, OnionPathType::TCP);
                 }
 
+                #[test]
+                fn random_path_new_tcp_only() {
+                    let (dht_pk, dht_sk) = gen_keypair();
+                    let (udp_tx, _udp_rx) = mpsc::channel(1);
+                    let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+                    let mut dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+                    dht.enable_tcp_only(true);
+                    // DHT is connected but UDP onion paths must not be built
+                    dht.add_node(PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0));
+                    let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+                    let mut paths_pool = PathsPool::new();
+                    for _ in 0 .. MIN_NODES_POOL_SIZE {
+                        let node = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                        paths_pool.path_nodes.put(node);
+                    }
+
+                    // no relays - no paths
+                    assert!(paths_pool.random_path(&dht, &tcp_connections, $friends, &gen_keypair().0).is_none());
+
+                    let (_relay_incoming_rx, _relay_outgoing_rx, relay_pk) = tcp_connections.add_client();
+                    let path = paths_pool.random_path(&dht, &tcp_connections, $friends, &gen_keypair().0).unwrap();
+                    assert_eq!(path.nodes[0].public_key, relay_pk);
+                    assert_eq!(path.path_type, OnionPathType::TCP);
+                }
+
                 #[test]
                 fn get_or_random_path_stored() {
                     let (dht_pk, dht_sk) = gen_keypair();
@@ -305,7 +433,7 @@ mod tests {
                     let path = OnionPath::new([node_1, node_2, node_3], OnionPathType::UDP);
                     paths_pool.$paths.push(StoredOnionPath::new(path.clone()));
 
-                    assert_eq!(paths_pool.get_or_random_path(&dht, &tcp_connections, path.id(), $friends).unwrap(), path);
+                    assert_eq!(paths_pool.get_or_random_path(&dht, &tcp_connections, path.id(), $friends, &gen_keypair().0).unwrap(), path);
                     assert_eq!(paths_pool.$paths[0].attempts, ONION_PATH_MAX_NO_RESPONSE_USES / 2 + 1);
                 }
 
@@ -328,7 +456,7 @@ mod tests {
                         keys: [gen_keypair().0, gen_keypair().0, gen_keypair().0],
                         path_type: OnionPathType::UDP,
                     };
-                    let path = paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends).unwrap();
+                    let path = paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends, &gen_keypair().0).unwrap();
                     assert_ne!(path.id(), path_id);
                     assert_eq!(path, paths_pool.$paths[0].path);
                     assert_eq!(path.path_type, OnionPathType::UDP);
@@ -353,7 +481,7 @@ mod tests {
                         keys: [gen_keypair().0, gen_keypair().0, gen_keypair().0],
                         path_type: OnionPathType::TCP,
                     };
-                    let path = paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends).unwrap();
+                    let path = paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends, &gen_keypair().0).unwrap();
                     assert_ne!(path.id(), path_id);
                     assert_eq!(path, paths_pool.$paths[0].path);
                     assert_eq!(path.nodes[0].public_key, relay_pk);
@@ -372,6 +500,51 @@ mod tests {
                     paths_pool.$paths.push(stored_path.clone());
                     assert_eq!(paths_pool.get_stored_path(path_id, $friends), Some(&stored_path));
                 }
+
+                #[test]
+                fn get_or_random_path_stored_contains_destination() {
+                    let (dht_pk, dht_sk) = gen_keypair();
+                    let (udp_tx, _udp_rx) = mpsc::channel(1);
+                    let (tcp_incoming_tx, _tcp_incoming_rx) = mpsc::unbounded();
+                    let dht = DhtServer::new(udp_tx, dht_pk, dht_sk.clone());
+                    let tcp_connections = TcpConnections::new(dht_pk, dht_sk, tcp_incoming_tx);
+                    let mut paths_pool = PathsPool::new();
+                    let node_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                    let node_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+                    let node_3 = PackedNode::new("127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+                    let path = OnionPath::new([node_1, node_2, node_3], OnionPathType::UDP);
+                    let path_id = path.id();
+                    paths_pool.$paths.push(StoredOnionPath::new(path));
+
+                    // DHT is not connected and there are no relays so a new
+                    // path can't be built
+                    assert!(paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends, &node_3.pk).is_none());
+                    assert!(paths_pool.rejections.destination > 0);
+
+                    paths_pool.constraints = PathConstraints::none();
+                    let path = paths_pool.get_or_random_path(&dht, &tcp_connections, path_id, $friends, &node_3.pk).unwrap();
+                    assert_eq!(path.id(), path_id);
+                }
+
+                #[test]
+                fn set_timeouts_and_add_timeout() {
+                    let mut paths_pool = PathsPool::new();
+                    let node_1 = PackedNode::new("127.0.0.1:12345".parse().unwrap(), &gen_keypair().0);
+                    let node_2 = PackedNode::new("127.0.0.1:12346".parse().unwrap(), &gen_keypair().0);
+                    let node_3 = PackedNode::new("127.0.0.1:12347".parse().unwrap(), &gen_keypair().0);
+                    let path = OnionPath::new([node_1, node_2, node_3], OnionPathType::UDP);
+                    let path_id = path.id();
+                    paths_pool.$paths.push(StoredOnionPath::new(path));
+
+                    paths_pool.set_timeouts(path_id, $friends);
+                    paths_pool.set_timeouts(path_id, $friends);
+                    paths_pool.add_timeout(path_id, $friends);
+
+                    let stored_path = &paths_pool.$paths()[0];
+                    assert_eq!(stored_path.successes, 2);
+                    assert_eq!(stored_path.timeouts, 1);
+                    assert_eq!(stored_path.attempts, 0);
+                }
             }
         }
     }
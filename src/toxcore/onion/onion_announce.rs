@@ -3,10 +3,13 @@
 
 use std::io::{ErrorKind, Error};
 use std::net::{IpAddr, SocketAddr};
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nom::number::complete::{be_u16, be_u64};
 
 use crate::toxcore::binary_io::*;
 use crate::toxcore::crypto_core::*;
+use crate::toxcore::ip_port::*;
 use crate::toxcore::time::*;
 use crate::toxcore::onion::packet::*;
 use crate::toxcore::dht::kbucket::Distance;
@@ -14,8 +17,9 @@ use crate::toxcore::dht::kbucket::Distance;
 /// Number of secret random bytes to make onion ping id unique for each node.
 pub const SECRET_BYTES_SIZE: usize = 32;
 
-/// Maximum number of entries in onion announce list. When number of entries
-/// exceeds this value farthest nodes are dropped using DHT distance function.
+/// Default maximum number of entries in onion announce list. When number of
+/// entries exceeds this value farthest nodes are dropped using DHT distance
+/// function.
 pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
 
 /// Interval of time when onion ping id is valid after it was generated.
@@ -23,10 +27,29 @@ pub const ONION_ANNOUNCE_MAX_ENTRIES: usize = 160;
 /// 2 * `PING_ID_TIMEOUT`.
 pub const PING_ID_TIMEOUT: Duration = Duration::from_secs(300);
 
-/// Diration of time for which announce entry can be stored in onion announce list
-/// without re-announcing.
+/// Default duration of time for which announce entry can be stored in onion
+/// announce list without re-announcing.
 pub const ONION_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(300);
 
+/// Settings of onion announce list.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceConfig {
+    /// Maximum number of entries in onion announce list.
+    pub max_entries: usize,
+    /// Duration of time for which announce entry can be stored in onion
+    /// announce list without re-announcing.
+    pub timeout: Duration,
+}
+
+impl Default for OnionAnnounceConfig {
+    fn default() -> Self {
+        OnionAnnounceConfig {
+            max_entries: ONION_ANNOUNCE_MAX_ENTRIES,
+            timeout: ONION_ANNOUNCE_TIMEOUT,
+        }
+    }
+}
+
 /// Create onion ping id filled with zeros.
 pub fn initial_ping_id() -> sha256::Digest {
     // can not fail since slice has enough length
@@ -36,7 +59,7 @@ pub fn initial_ping_id() -> sha256::Digest {
 /** Entry that corresponds to announced onion node.
 
 When node successfully announce itself this entry is added to announced nodes
-list. It's considered expired after `ONION_ANNOUNCE_TIMEOUT`.
+list. It's considered expired after `OnionAnnounceConfig::timeout`.
 
 */
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -70,15 +93,144 @@ impl OnionAnnounceEntry {
 
     /** Check if this entry is timed out.
 
-    Entry considered timed out after `ONION_ANNOUNCE_TIMEOUT` since it
-    was created.
+    Entry considered timed out after `timeout` since it was created.
 
     */
-    pub fn is_timed_out(&self) -> bool {
-        clock_elapsed(self.time) >= ONION_ANNOUNCE_TIMEOUT
+    pub fn is_timed_out(&self, timeout: Duration) -> bool {
+        clock_elapsed(self.time) >= timeout
+    }
+}
+
+/** Persistable copy of `OnionAnnounceEntry`.
+
+Unlike `OnionAnnounceEntry` it holds system time of announcement so that time
+spent between saving and restoring is taken into account.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`32`       | Long term `PublicKey` of announced node
+`19`       | `IpPort` of announced node with padding
+`32`       | `PublicKey` that should be used to encrypt data packets
+`8`        | Unix time of announcement in seconds
+`2`        | Length of `OnionReturn`
+variable   | `OnionReturn`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceEntrySnapshot {
+    /// Long term PublicKey of announced node
+    pub pk: PublicKey,
+    /// Address of announced node
+    pub saddr: SocketAddr,
+    /// PublicKey that should be used to encrypt data packets for announced node
+    pub data_pk: PublicKey,
+    /// Time when the node was announced
+    pub time: SystemTime,
+    /// Onion return that should be used to send data packets to announced node
+    pub onion_return: OnionReturn,
+}
+
+impl FromBytes for OnionAnnounceEntrySnapshot {
+    named!(from_bytes<OnionAnnounceEntrySnapshot>, do_parse!(
+        pk: call!(PublicKey::from_bytes) >>
+        ip_port: call!(IpPort::from_udp_bytes, IpPortPadding::WithPadding) >>
+        data_pk: call!(PublicKey::from_bytes) >>
+        time: be_u64 >>
+        onion_return: flat_map!(length_data!(be_u16), OnionReturn::from_bytes) >>
+        (OnionAnnounceEntrySnapshot {
+            pk,
+            saddr: ip_port.to_saddr(),
+            data_pk,
+            time: UNIX_EPOCH + Duration::from_secs(time),
+            onion_return,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceEntrySnapshot {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.pk.as_ref()) >>
+            gen_call!(|buf, ip_port| IpPort::to_udp_bytes(ip_port, buf, IpPortPadding::WithPadding), &IpPort::from_udp_saddr(self.saddr)) >>
+            gen_slice!(self.data_pk.as_ref()) >>
+            gen_be_u64!(unix_time(self.time)) >>
+            gen_be_u16!((secretbox::NONCEBYTES + self.onion_return.payload.len()) as u16) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+/** Persistable copy of `OnionAnnounce` state.
+
+It allows to keep announced nodes after a restart so that they don't have to
+announce themselves again. Secret bytes are saved as well so that onion ping
+ids given before the restart remain valid.
+
+Serialized form:
+
+Length     | Content
+---------- | ------
+`32`       | Secret bytes of onion node
+variable   | Entries of onion announce list
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionAnnounceSnapshot {
+    /// Secret bytes of onion node to make onion ping id unique
+    pub secret_bytes: [u8; SECRET_BYTES_SIZE],
+    /// Announced nodes
+    pub entries: Vec<OnionAnnounceEntrySnapshot>,
+}
+
+impl FromBytes for OnionAnnounceSnapshot {
+    named!(from_bytes<OnionAnnounceSnapshot>, do_parse!(
+        secret_bytes: take!(SECRET_BYTES_SIZE) >>
+        entries: many0!(complete!(OnionAnnounceEntrySnapshot::from_bytes)) >>
+        eof!() >>
+        (OnionAnnounceSnapshot {
+            secret_bytes: {
+                let mut bytes = [0; SECRET_BYTES_SIZE];
+                bytes.copy_from_slice(secret_bytes);
+                bytes
+            },
+            entries,
+        })
+    ));
+}
+
+impl ToBytes for OnionAnnounceSnapshot {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(&self.secret_bytes) >>
+            gen_many_ref!(&self.entries, |buf, entry| OnionAnnounceEntrySnapshot::to_bytes(entry, buf))
+        )
     }
 }
 
+/// Subtract `duration` from `instant` saturating to the earliest `Instant`
+/// that can be represented so that very old times don't cause overflow.
+fn saturating_sub_instant(instant: Instant, duration: Duration) -> Instant {
+    if let Some(time) = instant.checked_sub(duration) {
+        return time;
+    }
+
+    // find the biggest duration that still can be subtracted with millisecond
+    // precision
+    let mut low = Duration::from_secs(0);
+    let mut high = duration;
+    while high - low > Duration::from_millis(1) {
+        let middle = low + (high - low) / 2;
+        if instant.checked_sub(middle).is_some() {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    instant - low
+}
+
 /// Size of serialized `OnionPingData` struct.
 const ONION_PING_DATA_SIZE: usize =
     SECRET_BYTES_SIZE +
@@ -157,21 +309,113 @@ pub struct OnionAnnounce {
     /// List of announced onion nodes
     entries: Vec<OnionAnnounceEntry>,
     /// Short term DHT `PublicKey`
-    dht_pk: PublicKey
+    dht_pk: PublicKey,
+    /// Settings of onion announce list
+    config: OnionAnnounceConfig,
 }
 
 impl OnionAnnounce {
     /// Create new `OnionAnnounce` instance.
     pub fn new(dht_pk: PublicKey) -> OnionAnnounce {
+        OnionAnnounce::with_config(dht_pk, OnionAnnounceConfig::default())
+    }
+
+    /// Create new `OnionAnnounce` instance with custom settings.
+    pub fn with_config(dht_pk: PublicKey, config: OnionAnnounceConfig) -> OnionAnnounce {
         let mut secret_bytes = [0; SECRET_BYTES_SIZE];
         randombytes_into(&mut secret_bytes);
         OnionAnnounce {
             secret_bytes,
-            entries: Vec::with_capacity(ONION_ANNOUNCE_MAX_ENTRIES),
-            dht_pk
+            entries: Vec::with_capacity(config.max_entries),
+            dht_pk,
+            config,
+        }
+    }
+
+    /// Settings of onion announce list.
+    pub fn config(&self) -> OnionAnnounceConfig {
+        self.config
+    }
+
+    /// Change settings of onion announce list. If the new limit of entries is
+    /// lower than the current number of entries then farthest entries are
+    /// dropped.
+    pub fn set_config(&mut self, config: OnionAnnounceConfig) {
+        self.config = config;
+        self.entries.truncate(config.max_entries);
+    }
+
+    /// Number of entries in onion announce list including timed out ones that
+    /// were not removed yet.
+    pub fn entries_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Remove timed out entries from onion announce list. Returns the number
+    /// of removed entries.
+    pub fn remove_timed_out(&mut self) -> usize {
+        let timeout = self.config.timeout;
+        let len = self.entries.len();
+        self.entries.retain(|e| !e.is_timed_out(timeout));
+        len - self.entries.len()
+    }
+
+    /// Make persistable copy of secret bytes and not timed out entries.
+    pub fn snapshot(&self) -> OnionAnnounceSnapshot {
+        let now = SystemTime::now();
+        let entries = self.entries.iter()
+            .filter(|e| !e.is_timed_out(self.config.timeout))
+            .map(|e| OnionAnnounceEntrySnapshot {
+                pk: e.pk,
+                saddr: SocketAddr::new(e.ip_addr, e.port),
+                data_pk: e.data_pk,
+                time: now - clock_elapsed(e.time),
+                onion_return: e.onion_return.clone(),
+            })
+            .collect();
+        OnionAnnounceSnapshot {
+            secret_bytes: self.secret_bytes,
+            entries,
         }
     }
 
+    /** Restore secret bytes and entries from a snapshot replacing the current
+    ones.
+
+    Entries that timed out while the node was not running are skipped. Time of
+    entries that are older than the earliest time the clock can represent is
+    saturated to this time. If snapshot contains more entries than allowed by
+    settings then farthest entries are skipped as well.
+
+    */
+    pub fn restore(&mut self, snapshot: OnionAnnounceSnapshot) {
+        let now = SystemTime::now();
+        let clock = clock_now();
+        let timeout = self.config.timeout;
+        self.secret_bytes = snapshot.secret_bytes;
+        self.entries = snapshot.entries.into_iter()
+            .filter_map(|e| {
+                // entries from the future are considered as just announced
+                let age = now.duration_since(e.time).unwrap_or_default();
+                if age >= timeout {
+                    return None;
+                }
+                Some(OnionAnnounceEntry {
+                    pk: e.pk,
+                    ip_addr: e.saddr.ip(),
+                    port: e.saddr.port(),
+                    onion_return: e.onion_return,
+                    data_pk: e.data_pk,
+                    time: saturating_sub_instant(clock, age),
+                })
+            })
+            .collect();
+        let dht_pk = self.dht_pk;
+        self.entries.sort_by(|a, b| dht_pk.distance(&a.pk, &b.pk));
+        self.entries.dedup_by_key(|e| e.pk);
+        self.entries.truncate(self.config.max_entries);
+    }
+
     /** Calculate onion ping id using sha256 hash of arguments together with
     secret bytes stored in this struct.
 
@@ -194,17 +438,18 @@ impl OnionAnnounce {
     fn find_in_entries(&self, pk: PublicKey) -> Option<&OnionAnnounceEntry> {
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &pk)) {
             //TODO: use Option::filter when it's stabilized
-            Ok(idx) => if self.entries[idx].is_timed_out() { None } else { self.entries.get(idx) },
+            Ok(idx) => if self.entries[idx].is_timed_out(self.config.timeout) { None } else { self.entries.get(idx) },
             Err(_) => None
         }
     }
 
     /** Try to add announce entry to onion announce list.
 
-    Firstly we remove all timed out entries. Then if:
+    Firstly we remove all timed out entries. They are also removed by timer
+    but a request can come earlier. Then if:
     - announce list already contains entry with such `PublicKey` then update
       entry and return it
-    - announce list with new entry does not exceed `ONION_ANNOUNCE_MAX_ENTRIES`
+    - announce list with new entry does not exceed `OnionAnnounceConfig::max_entries`
       length add entry to the list and return it
     - the farthest entry from DHT `PublicKey` is farther than new entry then
       replace it with new entry
@@ -214,8 +459,7 @@ impl OnionAnnounce {
 
     */
     fn add_to_entries(&mut self, entry: OnionAnnounceEntry) -> Option<&OnionAnnounceEntry> {
-        //TODO: remove timed out entries by timer?
-        self.entries.retain(|e| !e.is_timed_out());
+        self.remove_timed_out();
         match self.entries.binary_search_by(|e| self.dht_pk.distance(&e.pk, &entry.pk)) {
             Ok(idx) => {
                 // node with such pk already announced - just update the entry
@@ -223,11 +467,11 @@ impl OnionAnnounce {
                 self.entries.get(idx)
             },
             Err(idx) => {
-                if self.entries.len() < ONION_ANNOUNCE_MAX_ENTRIES {
+                if self.entries.len() < self.config.max_entries {
                     // adding new entry does not exceed the limit - just add it
                     self.entries.insert(idx, entry);
                     self.entries.get(idx)
-                } else if idx < ONION_ANNOUNCE_MAX_ENTRIES {
+                } else if idx < self.config.max_entries {
                     // the farthest entry is farther than new entry - replace it
                     self.entries.pop();
                     self.entries.insert(idx, entry);
@@ -340,6 +584,35 @@ mod tests {
 
     const ONION_RETURN_3_PAYLOAD_SIZE: usize = ONION_RETURN_3_SIZE - secretbox::NONCEBYTES;
 
+    encode_decode_test!(
+        onion_announce_snapshot_encode_decode,
+        OnionAnnounceSnapshot {
+            secret_bytes: [42; SECRET_BYTES_SIZE],
+            entries: vec![
+                OnionAnnounceEntrySnapshot {
+                    pk: gen_keypair().0,
+                    saddr: "1.2.3.4:12345".parse().unwrap(),
+                    data_pk: gen_keypair().0,
+                    time: UNIX_EPOCH + Duration::from_secs(1_588_000_000),
+                    onion_return: OnionReturn {
+                        nonce: secretbox::gen_nonce(),
+                        payload: vec![42; ONION_RETURN_3_SIZE - secretbox::NONCEBYTES]
+                    },
+                },
+                OnionAnnounceEntrySnapshot {
+                    pk: gen_keypair().0,
+                    saddr: "[::1]:12345".parse().unwrap(),
+                    data_pk: gen_keypair().0,
+                    time: UNIX_EPOCH + Duration::from_secs(1_588_000_001),
+                    onion_return: OnionReturn {
+                        nonce: secretbox::gen_nonce(),
+                        payload: vec![43; ONION_RETURN_3_SIZE - secretbox::NONCEBYTES]
+                    },
+                },
+            ],
+        }
+    );
+
     #[test]
     fn announce_entry_valid() {
         crypto_init().unwrap();
@@ -353,7 +626,7 @@ mod tests {
             },
             gen_keypair().0
         );
-        assert!(!entry.is_timed_out());
+        assert!(!entry.is_timed_out(ONION_ANNOUNCE_TIMEOUT));
     }
 
     #[tokio::test]
@@ -373,7 +646,7 @@ mod tests {
         tokio::time::pause();
         tokio::time::advance(ONION_ANNOUNCE_TIMEOUT + Duration::from_secs(1)).await;
 
-        assert!(entry.is_timed_out());
+        assert!(entry.is_timed_out(ONION_ANNOUNCE_TIMEOUT));
     }
 
     #[test]
@@ -461,6 +734,128 @@ mod tests {
         assert!(onion_announce.find_in_entries(entry_pk).is_none());
     }
 
+    #[tokio::test]
+    async fn remove_timed_out() {
+        crypto_init().unwrap();
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+
+        tokio::time::pause();
+
+        assert!(onion_announce.add_to_entries(create_random_entry("1.2.3.4:12345".parse().unwrap())).is_some());
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2).await;
+        assert!(onion_announce.add_to_entries(create_random_entry("1.2.3.4:12346".parse().unwrap())).is_some());
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2).await;
+
+        assert_eq!(onion_announce.remove_timed_out(), 1);
+        assert_eq!(onion_announce.entries_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn custom_config() {
+        crypto_init().unwrap();
+        let config = OnionAnnounceConfig {
+            max_entries: 2,
+            timeout: Duration::from_secs(10),
+        };
+        let mut onion_announce = OnionAnnounce::with_config(gen_keypair().0, config);
+
+        for i in 0..3 {
+            let saddr = SocketAddr::new("1.2.3.4".parse().unwrap(), 12345 + i as u16);
+            onion_announce.add_to_entries(create_random_entry(saddr));
+        }
+        assert_eq!(onion_announce.entries_count(), 2);
+
+        tokio::time::pause();
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        assert_eq!(onion_announce.remove_timed_out(), 2);
+
+        onion_announce.add_to_entries(create_random_entry("1.2.3.4:12345".parse().unwrap()));
+        onion_announce.add_to_entries(create_random_entry("1.2.3.4:12346".parse().unwrap()));
+        onion_announce.set_config(OnionAnnounceConfig { max_entries: 1, ..config });
+        assert_eq!(onion_announce.entries_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn snapshot_restore() {
+        crypto_init().unwrap();
+        let dht_pk = gen_keypair().0;
+        let mut onion_announce = OnionAnnounce::new(dht_pk);
+
+        tokio::time::pause();
+
+        let entry = create_random_entry("1.2.3.4:12345".parse().unwrap());
+        let entry_pk = entry.pk;
+        let entry_data_pk = entry.data_pk;
+        onion_announce.add_to_entries(entry);
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2).await;
+
+        let snapshot = onion_announce.snapshot();
+        let mut buf = [0; 1024];
+        let (_, size) = snapshot.to_bytes((&mut buf, 0)).unwrap();
+        let (_, snapshot) = OnionAnnounceSnapshot::from_bytes(&buf[..size]).unwrap();
+
+        // restore to the instance with a different DHT key as it's not saved
+        let mut restored = OnionAnnounce::new(gen_keypair().0);
+        restored.restore(snapshot);
+
+        assert_eq!(restored.secret_bytes, onion_announce.secret_bytes);
+        let found = restored.find_in_entries(entry_pk).unwrap();
+        assert_eq!(found.data_pk, entry_data_pk);
+        assert_eq!(found.ip_addr, "1.2.3.4".parse::<IpAddr>().unwrap());
+
+        // restored entry keeps its age so it times out at the same time
+        tokio::time::advance(ONION_ANNOUNCE_TIMEOUT / 2 + Duration::from_secs(1)).await;
+        assert!(restored.find_in_entries(entry_pk).is_none());
+    }
+
+    #[test]
+    fn restore_skips_timed_out_entries() {
+        crypto_init().unwrap();
+        let mut onion_announce = OnionAnnounce::new(gen_keypair().0);
+
+        let entry = |time| OnionAnnounceEntrySnapshot {
+            pk: gen_keypair().0,
+            saddr: "1.2.3.4:12345".parse().unwrap(),
+            data_pk: gen_keypair().0,
+            time,
+            onion_return: OnionReturn {
+                nonce: secretbox::gen_nonce(),
+                payload: vec![42; 42]
+            },
+        };
+        let fresh_entry = entry(SystemTime::now());
+        let fresh_pk = fresh_entry.pk;
+        let snapshot = OnionAnnounceSnapshot {
+            secret_bytes: [42; SECRET_BYTES_SIZE],
+            entries: vec![
+                entry(SystemTime::now() - ONION_ANNOUNCE_TIMEOUT - Duration::from_secs(1)),
+                fresh_entry,
+            ],
+        };
+
+        onion_announce.restore(snapshot);
+
+        assert_eq!(onion_announce.entries_count(), 1);
+        assert!(onion_announce.find_in_entries(fresh_pk).is_some());
+    }
+
+    #[test]
+    fn saturating_sub_instant_in_range() {
+        let now = Instant::now();
+        let duration = Duration::from_millis(1);
+        assert_eq!(saturating_sub_instant(now, duration), now - duration);
+    }
+
+    #[test]
+    fn saturating_sub_instant_overflow() {
+        let now = Instant::now();
+        let time = saturating_sub_instant(now, Duration::from_secs(std::u64::MAX));
+        assert!(time <= now);
+        // it's the earliest time with millisecond precision
+        assert!(time.checked_sub(Duration::from_millis(2)).is_none());
+    }
+
     ////////////////////////////////////////////////////////////////////////////////////////
     // Tests for OnionAnnounce::add_to_entries
     #[test]
@@ -1,6 +1,7 @@
 /*!
 Statistics of incoming/outgoing packets
-This is used by both Udp codec and Tcp codec.
+This is used by both Udp codec and Tcp codec. It also contains counters of
+connections rejected by TCP relay server.
 */
 
 use std::sync::Arc;
@@ -27,6 +28,12 @@ pub struct Counters {
     incoming: AtomicU64,
     /// Outgoing packets count for Udp/Tcp
     outgoing: AtomicU64,
+    /// Tcp connections rejected because of connection limits
+    rejected_connections: AtomicU64,
+    /// Tcp connections rejected because of handshake rate limit
+    throttled_handshakes: AtomicU64,
+    /// Tcp connections closed because the handshake wasn't finished in time
+    handshake_timeouts: AtomicU64,
 }
 
 impl Counters {
@@ -49,6 +56,36 @@ impl Counters {
     pub fn outgoing(&self) -> u64 {
         self.outgoing.load(Ordering::Relaxed)
     }
+
+    /// Add 1 to rejected connections counter
+    pub fn increase_rejected_connections(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to throttled handshakes counter
+    pub fn increase_throttled_handshakes(&self) {
+        self.throttled_handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add 1 to handshake timeouts counter
+    pub fn increase_handshake_timeouts(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get rejected connections counter
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Get throttled handshakes counter
+    pub fn throttled_handshakes(&self) -> u64 {
+        self.throttled_handshakes.load(Ordering::Relaxed)
+    }
+
+    /// Get handshake timeouts counter
+    pub fn handshake_timeouts(&self) -> u64 {
+        self.handshake_timeouts.load(Ordering::Relaxed)
+    }
 }
 
 #[cfg(test)]
@@ -71,4 +108,16 @@ mod tests {
         stats.counters.increase_outgoing();
         assert_eq!(2, stats.counters.outgoing());
     }
+
+    #[test]
+    fn tcp_server_counters() {
+        let stats = Stats::new();
+        stats.counters.increase_rejected_connections();
+        stats.counters.increase_throttled_handshakes();
+        stats.counters.increase_throttled_handshakes();
+        stats.counters.increase_handshake_timeouts();
+        assert_eq!(1, stats.counters.rejected_connections());
+        assert_eq!(2, stats.counters.throttled_handshakes());
+        assert_eq!(1, stats.counters.handshake_timeouts());
+    }
 }
@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use failure::Fail;
 use futures::{future, Future, FutureExt, TryFutureExt, StreamExt, SinkExt};
@@ -21,6 +21,7 @@ use crate::toxcore::tcp::links::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::time::*;
 use crate::toxcore::tcp::client::errors::*;
+use crate::toxcore::tcp::client::proxy::ProxyConfig;
 
 /// Buffer size (in packets) for outgoing packets. This number shouldn't be high
 /// to minimize latency. If some relay can't take more packets we can use
@@ -28,6 +29,138 @@ use crate::toxcore::tcp::client::errors::*;
 /// the relay to send them.
 const CLIENT_CHANNEL_SIZE: usize = 2;
 
+/// Default interval between `PingRequest` packets sent to a relay.
+pub const TCP_CLIENT_PING_FREQUENCY: Duration = Duration::from_secs(30);
+
+/// Default time after which the relay is considered disconnected if it didn't
+/// send `PongResponse` to our `PingRequest`.
+pub const TCP_CLIENT_PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we check whether we should send `PingRequest` packet or whether
+/// the relay didn't answer in time.
+const TCP_CLIENT_PING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Latency that is assumed for a relay if neither handshake time nor RTT are
+/// known.
+const UNKNOWN_LATENCY_PENALTY: Duration = Duration::from_secs(1);
+
+/// Penalty to the relay's score for every lost connection.
+const DISCONNECT_PENALTY: Duration = Duration::from_secs(2);
+
+/// Penalty to the relay's score for every rejected `RouteRequest`.
+const FAILED_ROUTE_REQUEST_PENALTY: Duration = Duration::from_millis(500);
+
+/// Penalty to the relay's score for every unsuccessful connection attempt in a
+/// row.
+const CONNECTION_ATTEMPT_PENALTY: Duration = Duration::from_secs(5);
+
+/// Delay before the first reconnection attempt after an unsuccessful one. It's
+/// doubled for every next unsuccessful attempt.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between reconnection attempts.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(120);
+
+/// Settings of keepalive pings sent to a relay.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeepaliveConfig {
+    /// Interval between `PingRequest` packets.
+    pub interval: Duration,
+    /// Time to wait for `PongResponse` before the relay is considered
+    /// disconnected.
+    pub timeout: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            interval: TCP_CLIENT_PING_FREQUENCY,
+            timeout: TCP_CLIENT_PING_TIMEOUT,
+        }
+    }
+}
+
+/// State of keepalive pings of the current connection to a relay.
+#[derive(Clone, Debug)]
+struct PingState {
+    /// `ping_id` of the sent `PingRequest` we are waiting `PongResponse` for.
+    ping_id: Option<u64>,
+    /// Time when the last `PingRequest` was sent or the connection was
+    /// established.
+    sent_time: Instant,
+    /// Round-trip time measured by the last answered `PingRequest`.
+    rtt: Option<Duration>,
+}
+
+impl PingState {
+    fn new() -> Self {
+        PingState {
+            ping_id: None,
+            sent_time: clock_now(),
+            rtt: None,
+        }
+    }
+}
+
+/// Statistics of a relay that are kept across connections.
+#[derive(Clone, Copy, Debug, Default)]
+struct RelayStats {
+    /// Time it took to establish the last connection including handshake.
+    handshake_time: Option<Duration>,
+    /// Number of established connections that were lost.
+    disconnects: u32,
+    /// Number of `RouteRequest` packets rejected by the relay.
+    failed_route_requests: u32,
+    /// Time when the last connection terminated or the last connection
+    /// attempt failed.
+    disconnected_time: Option<Instant>,
+}
+
+/// Snapshot of statistics used to estimate how healthy a relay is.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RelayHealth {
+    /// Time it took to establish the last connection including handshake.
+    pub handshake_time: Option<Duration>,
+    /// Round-trip time measured by the last answered `PingRequest`.
+    pub rtt: Option<Duration>,
+    /// Number of established connections that were lost.
+    pub disconnects: u32,
+    /// Number of unsuccessful connection attempts in a row.
+    pub connection_attempts: u32,
+    /// Number of `RouteRequest` packets rejected by the relay.
+    pub failed_route_requests: u32,
+}
+
+impl RelayHealth {
+    /// Score of the relay in milliseconds. It's an estimated latency with
+    /// penalties for lost connections, failed connection attempts and
+    /// rejected route requests. Lower score means healthier relay.
+    pub fn score(&self) -> u64 {
+        let latency = self.rtt
+            .or(self.handshake_time)
+            .unwrap_or(UNKNOWN_LATENCY_PENALTY);
+        let penalty = |penalty: Duration, count: u32| (penalty.as_millis() as u64).saturating_mul(u64::from(count));
+
+        (latency.as_millis() as u64)
+            .saturating_add(penalty(DISCONNECT_PENALTY, self.disconnects))
+            .saturating_add(penalty(CONNECTION_ATTEMPT_PENALTY, self.connection_attempts))
+            .saturating_add(penalty(FAILED_ROUTE_REQUEST_PENALTY, self.failed_route_requests))
+    }
+}
+
+/// Delay before the next connection attempt after the specified number of
+/// unsuccessful attempts in a row. It grows exponentially.
+fn reconnect_delay(connection_attempts: u32) -> Duration {
+    if connection_attempts == 0 {
+        return Duration::from_secs(0);
+    }
+
+    let factor = 1u32.checked_shl(connection_attempts - 1).unwrap_or(std::u32::MAX);
+    RECONNECT_BASE_DELAY
+        .checked_mul(factor)
+        .map_or(RECONNECT_MAX_DELAY, |delay| delay.min(RECONNECT_MAX_DELAY))
+}
+
 /// Packet that can be received from a TCP relay and should be handled outside
 /// of connections module.
 #[derive(Debug, PartialEq, Clone)]
@@ -82,6 +215,15 @@ pub struct Client {
     /// List of nodes we want to be connected to. When the connection to the
     /// relay establishes we send `RouteRequest` packets with these `PublicKey`s.
     connections: Arc<RwLock<HashSet<PublicKey>>>,
+    /// Proxy to connect to the relay through. If it's `None` the connection
+    /// is made directly.
+    proxy: Arc<RwLock<Option<ProxyConfig>>>,
+    /// Settings of keepalive pings.
+    keepalive: Arc<RwLock<KeepaliveConfig>>,
+    /// State of keepalive pings of the current connection.
+    ping: Arc<RwLock<PingState>>,
+    /// Statistics used to estimate the relay's health.
+    stats: Arc<RwLock<RelayStats>>,
 }
 
 impl Client {
@@ -96,9 +238,59 @@ impl Client {
             connection_attempts: Arc::new(RwLock::new(0)),
             links: Arc::new(RwLock::new(Links::new())),
             connections: Arc::new(RwLock::new(HashSet::new())),
+            proxy: Arc::new(RwLock::new(None)),
+            keepalive: Arc::new(RwLock::new(KeepaliveConfig::default())),
+            ping: Arc::new(RwLock::new(PingState::new())),
+            stats: Arc::new(RwLock::new(RelayStats::default())),
+        }
+    }
+
+    /// Set proxy to connect to the relay through. It will be used starting
+    /// from the next connection attempt.
+    pub fn set_proxy(&self, proxy: Option<ProxyConfig>) {
+        *self.proxy.write() = proxy;
+    }
+
+    /// Get proxy this relay is connected through.
+    pub fn proxy(&self) -> Option<ProxyConfig> {
+        self.proxy.read().clone()
+    }
+
+    /// Set settings of keepalive pings.
+    pub fn set_keepalive(&self, keepalive: KeepaliveConfig) {
+        *self.keepalive.write() = keepalive;
+    }
+
+    /// Round-trip time to the relay measured by the last answered
+    /// `PingRequest`.
+    pub fn rtt(&self) -> Option<Duration> {
+        if self.is_connected() {
+            self.ping.read().rtt
+        } else {
+            None
+        }
+    }
+
+    /// Get a snapshot of statistics that show how healthy the relay is.
+    pub fn health(&self) -> RelayHealth {
+        let stats = *self.stats.read();
+        RelayHealth {
+            handshake_time: stats.handshake_time,
+            rtt: self.rtt(),
+            disconnects: stats.disconnects,
+            connection_attempts: self.connection_attempts(),
+            failed_route_requests: stats.failed_route_requests,
         }
     }
 
+    /// Check if enough time has passed since the last connection attempt to
+    /// try again. The delay grows exponentially with every unsuccessful
+    /// attempt.
+    pub fn is_reconnect_allowed(&self) -> bool {
+        let delay = reconnect_delay(self.connection_attempts());
+        self.stats.read().disconnected_time.map_or(true, |time| clock_elapsed(time) >= delay)
+    }
+
     /// Handle packet received from TCP relay.
     pub async fn handle_packet(&self, packet: Packet) -> Result<(), HandlePacketError> {
         match packet {
@@ -146,6 +338,9 @@ impl Client {
         let index = if let Some(index) = packet.connection_id.index() {
             index
         } else {
+            // zero connection id means that the relay rejected our request
+            let mut stats = self.stats.write();
+            stats.failed_route_requests = stats.failed_route_requests.saturating_add(1);
             return future::err(
                 HandlePacketErrorKind::InvalidConnectionId.into()
             )
@@ -211,11 +406,53 @@ impl Client {
         )).map_err(|e| e.context(HandlePacketErrorKind::SendTo).into())
     }
 
-    fn handle_pong_response(&self, _packet: &PongResponse) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
-        // TODO check ping_id
+    fn handle_pong_response(&self, packet: &PongResponse) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
+        let mut ping = self.ping.write();
+        if ping.ping_id.is_some() && ping.ping_id == Some(packet.ping_id) {
+            ping.ping_id = None;
+            ping.rtt = Some(clock_elapsed(ping.sent_time));
+        } else {
+            trace!("Unexpected PongResponse from relay {:?} with ping_id {}", self.pk, packet.ping_id);
+        }
         future::ok(())
     }
 
+    /// Send `PingRequest` packet if the ping interval has passed. Returns an
+    /// error if the relay didn't answer to the previous `PingRequest` in time.
+    /// The ping is dropped instead of waiting when the channel to the relay
+    /// is full so it will be sent on the next check.
+    fn send_keepalive(&self, ping_tx: &mut mpsc::Sender<Packet>) -> Result<(), SpawnError> {
+        let keepalive = *self.keepalive.read();
+        let mut ping = self.ping.write();
+
+        if ping.ping_id.is_some() {
+            return if clock_elapsed(ping.sent_time) > keepalive.timeout {
+                Err(SpawnErrorKind::PingTimeout.into())
+            } else {
+                Ok(())
+            }
+        }
+
+        if clock_elapsed(ping.sent_time) < keepalive.interval {
+            return Ok(());
+        }
+
+        // zero ping_id is not allowed
+        let ping_id = random_u64().max(1);
+        match ping_tx.try_send(Packet::PingRequest(PingRequest { ping_id })) {
+            Ok(()) => {
+                ping.ping_id = Some(ping_id);
+                ping.sent_time = clock_now();
+                Ok(())
+            },
+            Err(ref e) if e.is_full() => {
+                trace!("Dropped PingRequest to relay {:?} because the channel is full", self.pk);
+                Ok(())
+            },
+            Err(e) => Err(e.into_send_error().context(SpawnErrorKind::SendTo).into()),
+        }
+    }
+
     fn handle_oob_send(&self, _packet: &OobSend) -> impl Future<Output = Result<(), HandlePacketError>> + Send {
         future::err(
             HandlePacketErrorKind::MustNotSend.into()
@@ -285,7 +522,7 @@ impl Client {
     /// Spawn a connection to this TCP relay if it is not connected already. The
     /// connection is spawned via `tokio::spawn` so the result future will be
     /// completed after first poll.
-    async fn spawn_inner(self, dht_sk: SecretKey, dht_pk: PublicKey) -> Result<(), SpawnError> { // TODO: send pings periodically
+    async fn spawn_inner(self, dht_sk: SecretKey, dht_pk: PublicKey) -> Result<(), SpawnError> {
         let relay_pk = self.pk;
         match *self.status.write() {
             ref mut status @ ClientStatus::Disconnected
@@ -294,8 +531,15 @@ impl Client {
             _ => return Ok(()),
         }
 
-        let socket = TcpStream::connect(&self.addr).await
-            .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Io)))?;
+        let connect_time = clock_now();
+        let proxy = self.proxy.read().clone();
+        let socket = if let Some(proxy) = proxy {
+            proxy.connect(self.addr).await
+                .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Proxy)))?
+        } else {
+            TcpStream::connect(&self.addr).await
+                .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Io)))?
+        };
 
         let (socket, channel) =
             make_client_handshake(socket, &dht_pk, &dht_sk, &relay_pk).await
@@ -308,6 +552,9 @@ impl Client {
             secure_socket.split();
         let (to_server_tx, to_server_rx) =
             mpsc::channel(CLIENT_CHANNEL_SIZE);
+        // pinger has its own sender so that it can't fill the channel with
+        // more than one extra packet
+        let mut ping_tx = to_server_tx.clone();
 
         match *self.status.write() {
             ref mut status @ ClientStatus::Connecting =>
@@ -317,6 +564,8 @@ impl Client {
 
         *self.connection_attempts.write() = 0;
         *self.connected_time.write() = Some(clock_now());
+        *self.ping.write() = PingState::new();
+        self.stats.write().handshake_time = Some(clock_elapsed(connect_time));
 
         let route_requests = async {
             self.send_route_requests().await
@@ -347,10 +596,20 @@ impl Client {
             Result::<(), SpawnError>::Ok(())
         };
 
+        let pinger = async {
+            let mut wakeups = tokio::time::interval(TCP_CLIENT_PING_CHECK_INTERVAL);
+            while wakeups.next().await.is_some() {
+                self.send_keepalive(&mut ping_tx)?;
+            }
+
+            Result::<(), SpawnError>::Ok(())
+        };
+
         let rw = async move {
             futures::select! {
                 res = reader.fuse() => res,
                 res = writer.fuse() => res,
+                res = pinger.fuse() => res,
             }
         };
 
@@ -363,14 +622,25 @@ impl Client {
 
         future
             .then(move |res| {
-                match *self_c.status.write() {
-                    ClientStatus::Sleeping => { },
-                    ref mut status => *status = ClientStatus::Disconnected,
-                }
+                let was_connected = self_c.connected_time.read().is_some();
+                let sleeping = match *self_c.status.write() {
+                    ClientStatus::Sleeping => true,
+                    ref mut status => {
+                        *status = ClientStatus::Disconnected;
+                        false
+                    },
+                };
                 if res.is_err() {
                     let mut connection_attempts = self_c.connection_attempts.write();
                     *connection_attempts = connection_attempts.saturating_add(1);
                 }
+                {
+                    let mut stats = self_c.stats.write();
+                    if was_connected && !sleeping {
+                        stats.disconnects = stats.disconnects.saturating_add(1);
+                    }
+                    stats.disconnected_time = Some(clock_now());
+                }
                 *self_c.connected_time.write() = None;
                 self_c.links.write().clear();
                 future::ready(res)
@@ -385,7 +655,7 @@ impl Client {
     /// Spawn a connection to this TCP relay if it is not connected already. The
     /// connection is spawned via `tokio::spawn` so the result future will be
     /// completed after first poll.
-    pub async fn spawn(self, dht_sk: SecretKey, dht_pk: PublicKey) -> Result<(), SpawnError> { // TODO: send pings periodically
+    pub async fn spawn(self, dht_sk: SecretKey, dht_pk: PublicKey) -> Result<(), SpawnError> {
         tokio::spawn(self.run(dht_sk, dht_pk));
         Ok(())
     }
@@ -568,7 +838,9 @@ pub mod tests {
     use crate::toxcore::dht::packet::CryptoData;
     use crate::toxcore::ip_port::*;
     use crate::toxcore::onion::packet::*;
-    use crate::toxcore::tcp::server::{Server, ServerExt};
+    use crate::toxcore::tcp::client::proxy::ProxyCredentials;
+    use crate::toxcore::tcp::client::proxy::tests::run_socks5_proxy;
+    use crate::toxcore::tcp::server::{Server, ServerExt, ServerLimits};
 
     pub fn create_client() -> (mpsc::UnboundedReceiver<(PublicKey, IncomingPacket)>, mpsc::Receiver<Packet>, Client) {
         crypto_init().unwrap();
@@ -671,6 +943,7 @@ pub mod tests {
 
         let error = client.handle_packet(route_response).await.err().unwrap();
         assert_eq!(*error.kind(), HandlePacketErrorKind::InvalidConnectionId);
+        assert_eq!(client.health().failed_route_requests, 1);
     }
 
     #[tokio::test]
@@ -798,6 +1071,179 @@ pub mod tests {
         client.handle_packet(pong_response).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn handle_pong_response_matching() {
+        let (_incoming_rx, _outgoing_rx, client) = create_client();
+
+        tokio::time::pause();
+
+        client.ping.write().ping_id = Some(42);
+        client.ping.write().sent_time = clock_now();
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+
+        // pong with wrong ping_id is ignored
+        client.handle_packet(Packet::PongResponse(PongResponse { ping_id: 43 })).await.unwrap();
+        assert_eq!(client.ping.read().ping_id, Some(42));
+        assert_eq!(client.rtt(), None);
+
+        client.handle_packet(Packet::PongResponse(PongResponse { ping_id: 42 })).await.unwrap();
+        assert_eq!(client.ping.read().ping_id, None);
+        assert_eq!(client.rtt(), Some(Duration::from_millis(100)));
+    }
+
+    fn ping_tx(client: &Client) -> mpsc::Sender<Packet> {
+        match *client.status.read() {
+            ClientStatus::Connected(ref tx) => tx.clone(),
+            _ => panic!("Client is not connected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_keepalive() {
+        let (_incoming_rx, outgoing_rx, client) = create_client();
+        let mut ping_tx = ping_tx(&client);
+
+        tokio::time::pause();
+        *client.ping.write() = PingState::new();
+
+        // ping interval has not passed yet
+        client.send_keepalive(&mut ping_tx).unwrap();
+        assert!(client.ping.read().ping_id.is_none());
+
+        tokio::time::advance(TCP_CLIENT_PING_FREQUENCY).await;
+
+        client.send_keepalive(&mut ping_tx).unwrap();
+        let ping_id = client.ping.read().ping_id.unwrap();
+
+        let (packet, _outgoing_rx) = outgoing_rx.into_future().await;
+        let packet = unpack!(packet.unwrap(), Packet::PingRequest);
+        assert_eq!(packet.ping_id, ping_id);
+        assert_ne!(ping_id, 0);
+
+        // the next ping is not sent until the pong is received
+        tokio::time::advance(TCP_CLIENT_PING_TIMEOUT).await;
+        client.send_keepalive(&mut ping_tx).unwrap();
+        assert_eq!(client.ping.read().ping_id, Some(ping_id));
+    }
+
+    #[tokio::test]
+    async fn send_keepalive_timeout() {
+        let (_incoming_rx, _outgoing_rx, client) = create_client();
+        let mut ping_tx = ping_tx(&client);
+
+        tokio::time::pause();
+        client.set_keepalive(KeepaliveConfig {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+        });
+        *client.ping.write() = PingState::new();
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        client.send_keepalive(&mut ping_tx).unwrap();
+        assert!(client.ping.read().ping_id.is_some());
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        let error = client.send_keepalive(&mut ping_tx).err().unwrap();
+        assert_eq!(*error.kind(), SpawnErrorKind::PingTimeout);
+    }
+
+    #[tokio::test]
+    async fn send_keepalive_channel_full() {
+        let (_incoming_rx, mut outgoing_rx, client) = create_client();
+        let mut ping_tx = ping_tx(&client);
+
+        tokio::time::pause();
+        *client.ping.write() = PingState::new();
+
+        // fill the channel so that our sender becomes blocked
+        while ping_tx.try_send(Packet::PongResponse(PongResponse { ping_id: 42 })).is_ok() { }
+
+        tokio::time::advance(TCP_CLIENT_PING_FREQUENCY).await;
+
+        // the ping is dropped without blocking
+        client.send_keepalive(&mut ping_tx).unwrap();
+        assert!(client.ping.read().ping_id.is_none());
+
+        // the ping is sent when there is free space in the channel
+        outgoing_rx.next().await.unwrap();
+        client.send_keepalive(&mut ping_tx).unwrap();
+        assert!(client.ping.read().ping_id.is_some());
+    }
+
+    #[test]
+    fn relay_health_score() {
+        let unknown = RelayHealth::default();
+        let fast = RelayHealth {
+            rtt: Some(Duration::from_millis(50)),
+            ..RelayHealth::default()
+        };
+        let slow_handshake = RelayHealth {
+            handshake_time: Some(Duration::from_millis(300)),
+            ..RelayHealth::default()
+        };
+        let unstable = RelayHealth {
+            rtt: Some(Duration::from_millis(50)),
+            disconnects: 1,
+            ..RelayHealth::default()
+        };
+        let unreachable = RelayHealth {
+            connection_attempts: 1,
+            ..RelayHealth::default()
+        };
+
+        assert_eq!(fast.score(), 50);
+        assert_eq!(slow_handshake.score(), 300);
+        assert!(fast.score() < slow_handshake.score());
+        assert!(slow_handshake.score() < unknown.score());
+        assert!(unknown.score() < unstable.score());
+        assert!(unstable.score() < unreachable.score());
+
+        // doesn't overflow
+        let failed = RelayHealth {
+            connection_attempts: std::u32::MAX,
+            disconnects: std::u32::MAX,
+            failed_route_requests: std::u32::MAX,
+            ..RelayHealth::default()
+        };
+        assert!(failed.score() > unreachable.score());
+    }
+
+    #[test]
+    fn reconnect_delay_exponential() {
+        assert_eq!(reconnect_delay(0), Duration::from_secs(0));
+        assert_eq!(reconnect_delay(1), RECONNECT_BASE_DELAY);
+        assert_eq!(reconnect_delay(2), RECONNECT_BASE_DELAY * 2);
+        assert_eq!(reconnect_delay(3), RECONNECT_BASE_DELAY * 4);
+        assert_eq!(reconnect_delay(20), RECONNECT_MAX_DELAY);
+        assert_eq!(reconnect_delay(std::u32::MAX), RECONNECT_MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn is_reconnect_allowed() {
+        let (_incoming_rx, _outgoing_rx, client) = create_client();
+        client.disconnect();
+
+        // never disconnected before
+        assert!(client.is_reconnect_allowed());
+
+        tokio::time::pause();
+        client.stats.write().disconnected_time = Some(clock_now());
+
+        // lost established connection
+        set_connection_attempts(&client, 0);
+        assert!(client.is_reconnect_allowed());
+
+        set_connection_attempts(&client, 3);
+        assert!(!client.is_reconnect_allowed());
+
+        tokio::time::advance(RECONNECT_BASE_DELAY * 3).await;
+        assert!(!client.is_reconnect_allowed());
+
+        tokio::time::advance(RECONNECT_BASE_DELAY).await;
+        assert!(client.is_reconnect_allowed());
+    }
+
     #[tokio::test]
     async fn handle_oob_send() {
         let (_incoming_rx, _outgoing_rx, client) = create_client();
@@ -1219,7 +1665,7 @@ pub mod tests {
 
         let server = Server::new();
         let stats = Stats::new();
-        let server_future = server.run(listener, server_sk, stats, 2)
+        let server_future = server.run(listener, server_sk, stats, ServerLimits::new(2))
             .map_err(Error::from);
         tokio::spawn(server_future);
 
@@ -1245,6 +1691,8 @@ pub mod tests {
 
         assert!(client_1.connected_time().is_some());
         assert!(client_2.connected_time().is_some());
+        assert!(client_1.health().handshake_time.is_some());
+        assert!(client_2.health().handshake_time.is_some());
         assert_eq!(client_1.connection_attempts(), 0);
         assert_eq!(client_2.connection_attempts(), 0);
 
@@ -1288,6 +1736,54 @@ pub mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn spawn_via_socks5_proxy() {
+        crypto_init().unwrap();
+        // run server
+        let (server_pk, server_sk) = gen_keypair();
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = Server::new();
+        let stats = Stats::new();
+        let server_future = server.run(listener, server_sk, stats, ServerLimits::new(2))
+            .map_err(Error::from);
+        tokio::spawn(server_future);
+
+        // run proxy
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        let credentials = ProxyCredentials {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        };
+        tokio::spawn(run_socks5_proxy(proxy_listener, Some(credentials.clone()), addr));
+
+        // run client
+        let (client_pk, client_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let client = Client::new(server_pk, addr, incoming_tx);
+        client.set_proxy(Some(ProxyConfig::Socks5 {
+            addr: proxy_addr,
+            credentials: Some(credentials),
+        }));
+        client.clone().spawn(client_sk, client_pk).await.unwrap();
+
+        // wait until connection is established
+        let mut interval = tokio::time::interval(Duration::from_millis(10));
+        while interval.next().await.is_some() {
+            match *client.status.read() {
+                ClientStatus::Connecting => continue,
+                ClientStatus::Connected(_) => break,
+                ref other => panic!("Invalid status: {:?}", other),
+            }
+        }
+
+        assert!(client.connected_time().is_some());
+    }
+
     #[tokio::test]
     async fn run_unsuccessful() {
         // run server
@@ -1319,5 +1815,8 @@ pub mod tests {
 
         // connection_attempts should be increased
         assert_eq!(*client.connection_attempts.read(), 1);
+        // the relay was not connected so it's not a lost connection
+        assert_eq!(client.health().disconnects, 0);
+        assert!(client.stats.read().disconnected_time.is_some());
     }
 }
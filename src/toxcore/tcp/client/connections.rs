@@ -28,6 +28,7 @@ use crate::toxcore::tcp::client::client::*;
 use crate::toxcore::tcp::packet::*;
 use crate::toxcore::time::*;
 use crate::toxcore::tcp::client::errors::*;
+use crate::toxcore::tcp::client::proxy::ProxyConfig;
 use failure::Fail;
 
 /// The amount of maximum connections for each friend.
@@ -38,8 +39,12 @@ const MAX_FRIEND_TCP_CONNECTIONS: usize =  6;
 const RECOMMENDED_FRIEND_TCP_CONNECTIONS: usize =  MAX_FRIEND_TCP_CONNECTIONS / 2;
 
 /// How many attempts to reconnect to the relay we should make before we
-/// consider this relay unreachable and drop it.
-const MAX_RECONNECTION_ATTEMPTS: u32 = 1;
+/// consider this relay unreachable and drop it. Attempts are made with
+/// exponentially growing delay so the relay has a chance to recover.
+const MAX_RECONNECTION_ATTEMPTS: u32 = 3;
+
+/// Number of the healthiest relays a random relay is chosen from.
+const HEALTHY_RELAYS_CANDIDATES: usize = 3;
 
 const TCP_CONNECTION_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -81,6 +86,16 @@ impl NodeConnection {
             .iter()
             .flat_map(move |relay_pk| clients.get(relay_pk).into_iter())
     }
+
+    /// Get relays sorted by their health so that the healthiest relay goes
+    /// first.
+    fn healthy_clients<'b>(&self, clients: &'b HashMap<PublicKey, Client>) -> Vec<&'b Client> {
+        let mut clients = self.clients(clients)
+            .map(|client| (client.health().score(), client))
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|&(score, _)| score);
+        clients.into_iter().map(|(_, client)| client).collect()
+    }
 }
 
 /// TCP connections provides reliable connection to a friend via multiple TCP
@@ -100,6 +115,10 @@ pub struct Connections {
     /// List of DHT nodes we are connected to via TCP relays. Key is a
     /// `PublicKey` of DHT node.
     connections: Arc<RwLock<HashMap<PublicKey, NodeConnection>>>,
+    /// Proxy that is used for all connections to TCP relays.
+    proxy: Arc<RwLock<Option<ProxyConfig>>>,
+    /// Settings of keepalive pings for all connections to TCP relays.
+    keepalive: Arc<RwLock<KeepaliveConfig>>,
 }
 
 impl Connections {
@@ -111,7 +130,38 @@ impl Connections {
             incoming_tx,
             clients: Arc::new(RwLock::new(HashMap::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
+            proxy: Arc::new(RwLock::new(None)),
+            keepalive: Arc::new(RwLock::new(KeepaliveConfig::default())),
+        }
+    }
+
+    /// Set proxy that should be used for all connections to TCP relays.
+    /// Relays that are already connected keep their connections and will use
+    /// the proxy after reconnecting.
+    pub fn set_proxy(&self, proxy: Option<ProxyConfig>) {
+        for client in self.clients.read().values() {
+            client.set_proxy(proxy.clone());
+        }
+        *self.proxy.write() = proxy;
+    }
+
+    /// Set settings of keepalive pings for all connections to TCP relays. A
+    /// relay that doesn't answer to `PingRequest` in time is considered
+    /// disconnected and will be reconnected or removed by the main loop.
+    pub fn set_keepalive(&self, keepalive: KeepaliveConfig) {
+        for client in self.clients.read().values() {
+            client.set_keepalive(keepalive);
         }
+        *self.keepalive.write() = keepalive;
+    }
+
+    /// Create new `Client` that connects to the relay through the configured
+    /// proxy and with the configured keepalive settings.
+    fn new_client(&self, relay_pk: PublicKey, relay_addr: SocketAddr) -> Client {
+        let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+        client.set_proxy(self.proxy.read().clone());
+        client.set_keepalive(*self.keepalive.read());
+        client
     }
 
     /// Add relay we are supposed to be connected to. These relays are necessary
@@ -120,7 +170,7 @@ impl Connections {
     /// they should be added via `add_relay_connection` method.
     pub fn add_relay_global(&self, relay_addr: SocketAddr, relay_pk: PublicKey) -> impl Future<Output = Result<(), ConnectionError>> + Send {
         if let hash_map::Entry::Vacant(vacant) = self.clients.write().entry(relay_pk) {
-            let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+            let client = self.new_client(relay_pk, relay_addr);
             vacant.insert(client.clone());
             Either::Left(client.spawn(self.dht_sk.clone(), self.dht_pk)
                 .map_err(|e| e.context(ConnectionErrorKind::Spawn).into()))
@@ -148,7 +198,7 @@ impl Connections {
             ).count();
 
             if online_connections_count < RECOMMENDED_FRIEND_TCP_CONNECTIONS && connections_count < MAX_FRIEND_TCP_CONNECTIONS {
-                let client = Client::new(relay_pk, relay_addr, self.incoming_tx.clone());
+                let client = self.new_client(relay_pk, relay_addr);
                 clients.insert(relay_pk, client.clone());
                 connection.connections.insert(relay_pk);
                 let future =
@@ -179,7 +229,9 @@ impl Connections {
         }
     }
 
-    /// Remove connection to a friend via relays.
+    /// Remove connection to a friend via relays. Relays are kept while there
+    /// is at least one node connected via them, so relays that were used only
+    /// for this node will be removed by the main loop.
     pub fn remove_connection(&self, node_pk: PublicKey) -> impl Future<Output = Result<(), ConnectionError>> + Send {
         if let Some(connection) = self.connections.write().remove(&node_pk) {
             let clients = self.clients.read();
@@ -188,18 +240,24 @@ impl Connections {
                 .collect::<Vec<_>>();
             Either::Left(future::try_join_all(futures).map_ok(drop))
         } else {
-            // TODO: what if we didn't receive relays from friend and delete him?
+            // there are no relays for this node, e.g. the friend didn't share
+            // any relays with us and we didn't find any via onion
             Either::Right( future::err(
                 ConnectionErrorKind::NoConnection.into()
             ))
         }
     }
 
-    /// Inner function to add a connection to our friend via relay.
+    /// Inner function to add a connection to our friend via relay. The relay
+    /// is ignored if we already connected to this friend via
+    /// `MAX_FRIEND_TCP_CONNECTIONS` relays.
     fn add_connection_inner(&self, client: &Client, node_pk: PublicKey) -> impl Future<Output = Result<(), ConnectionError>> + Send {
-        // TODO: check MAX_FRIEND_TCP_CONNECTIONS?
         let mut connections = self.connections.write();
         let connection = connections.entry(node_pk).or_insert_with(NodeConnection::new);
+        if !connection.connections.contains(&client.pk) && connection.connections.len() >= MAX_FRIEND_TCP_CONNECTIONS {
+            trace!("Too many TCP connections to {:?}, ignoring relay {}", node_pk, client.addr);
+            return Either::Left(future::ok(()));
+        }
         connection.connections.insert(client.pk);
 
         let future = if connection.status == NodeConnectionStatus::TCP && client.is_sleeping() {
@@ -210,11 +268,11 @@ impl Connections {
             Either::Right(future::ok(()))
         };
 
-        future::try_join(
+        Either::Right(future::try_join(
             future,
             client.add_connection(node_pk)
                 .map_err(|e| e.context(ConnectionErrorKind::AddConnection).into())
-        ).map_ok(drop)
+        ).map_ok(drop))
     }
 
     /// Send `Data` packet to a node via one of the relays.
@@ -228,7 +286,7 @@ impl Connections {
             // errors are ignored
             // TODO: return error if stream is exhausted?
             if let Some(connection) = connections.get(&node_pk) {
-                for c in connection.clients(&clients) {
+                for c in connection.healthy_clients(&clients) {
                     let res = c.send_data(node_pk, data.clone()).await;
 
                     if res.is_ok() { break }
@@ -290,44 +348,52 @@ impl Connections {
         }
     }
 
-    /// Get a random TCP relay we are connected to.
-    pub fn get_random_relay(&self) -> Option<PackedNode> {
-        let relays = self.clients
+    /// Get health statistics of the relay with specified `PublicKey`.
+    pub fn relay_health(&self, relay_pk: &PublicKey) -> Option<RelayHealth> {
+        self.clients.read().get(relay_pk).map(Client::health)
+    }
+
+    /// Get TCP relays we are connected to sorted by their health so that the
+    /// healthiest relay goes first.
+    fn healthy_relays(&self) -> Vec<PackedNode> {
+        let mut relays = self.clients
             .read()
             .values()
             .filter(|client| client.is_connected())
-            .map(|client| PackedNode::new(client.addr, &client.pk))
+            .map(|client| (client.health().score(), PackedNode::new(client.addr, &client.pk)))
             .collect::<Vec<_>>();
+        relays.sort_by_key(|&(score, _)| score);
+        relays.into_iter().map(|(_, relay)| relay).collect()
+    }
 
-        if relays.is_empty() {
-            None
-        } else {
-            Some(relays[random_limit_usize(relays.len())])
-        }
+    /// Get a random TCP relay we are connected to. It's chosen from the
+    /// healthiest relays.
+    pub fn get_random_relay(&self) -> Option<PackedNode> {
+        self.get_random_relays(1).pop()
     }
 
-    /// Get up to `count` random TCP relays we are connected to.
+    /// Get up to `count` random TCP relays we are connected to. They are
+    /// chosen from the healthiest relays.
     pub fn get_random_relays(&self, count: u8) -> Vec<PackedNode> {
-        let relays = self.clients
-            .read()
-            .values()
-            .filter(|client| client.is_connected())
-            .map(|client| PackedNode::new(client.addr, &client.pk))
-            .collect::<Vec<_>>();
+        let mut relays = self.healthy_relays();
 
-        if relays.is_empty() {
-            return Vec::new();
-        }
+        let count = count as usize;
+        relays.truncate(count.max(HEALTHY_RELAYS_CANDIDATES));
 
-        // TODO: shuffle relays instead
-        let skip = random_limit_usize(relays.len());
-        let take = (count as usize).min(relays.len());
-        relays.into_iter().cycle().skip(skip).take(take).collect()
+        // partial Fisher-Yates shuffle
+        let take = count.min(relays.len());
+        for i in 0 .. take {
+            let j = i + random_limit_usize(relays.len() - i);
+            relays.swap(i, j);
+        }
+        relays.truncate(take);
+        relays
     }
 
     /// Main loop that should be run periodically. It removes unreachable and
-    /// redundant relays, reconnects to relays if a connection was lost, puts
-    /// relays to sleep if they are not used right now.
+    /// redundant relays, reconnects to relays if a connection was lost, keeps
+    /// the amount of relays for every node within limits, puts relays to sleep
+    /// if they are not used right now.
     fn main_loop(&self) -> impl Future<Output = Result<(), ConnectionError>> + Send {
         let mut clients = self.clients.write();
         let mut connections = self.connections.write();
@@ -344,6 +410,8 @@ impl Connections {
             if client.is_disconnected() {
                 if connected && client.connection_attempts() > MAX_RECONNECTION_ATTEMPTS {
                     false
+                } else if !client.is_reconnect_allowed() {
+                    true
                 } else {
                     let future = client.clone().spawn(self.dht_sk.clone(), self.dht_pk)
                         .map_err(|e| e.context(ConnectionErrorKind::Spawn).into());
@@ -355,24 +423,62 @@ impl Connections {
             }
         );
 
-        // find out which relays are used right now
-        let used_relays = connections.values()
-            .filter(|connection| connection.status == NodeConnectionStatus::TCP)
-            .flat_map(|connection| connection.connections.iter().cloned())
-            .collect::<HashSet<_>>();
+        // remove deleted relays from connections
+        for connection in connections.values_mut() {
+            connection.connections.retain(|relay_pk| clients.contains_key(relay_pk));
+        }
+
+        let mut route_futures = Vec::new();
+
+        // keep the amount of relays for every node within limits
+        for (&node_pk, connection) in connections.iter_mut() {
+            // relays are not needed much when we have direct UDP connection
+            let max_connections = if connection.status == NodeConnectionStatus::UDP {
+                RECOMMENDED_FRIEND_TCP_CONNECTIONS
+            } else {
+                MAX_FRIEND_TCP_CONNECTIONS
+            };
+
+            // drop the least healthy relays
+            let surplus = connection.healthy_clients(&clients)
+                .into_iter()
+                .skip(max_connections)
+                .cloned()
+                .collect::<Vec<_>>();
+            for client in surplus {
+                connection.connections.remove(&client.pk);
+                // ignore sending errors since the relay can be disconnected
+                route_futures.push(client.remove_connection(node_pk)
+                    .then(|_| future::ok(()))
+                    .boxed());
+            }
+
+            // add the healthiest connected relays if there are not enough of
+            // them, idle relays will be kept awake since they become used
+            if connection.status == NodeConnectionStatus::TCP && connection.connections.len() < RECOMMENDED_FRIEND_TCP_CONNECTIONS {
+                let mut candidates = clients.values()
+                    .filter(|client| client.is_connected() && !connection.connections.contains(&client.pk))
+                    .map(|client| (client.health().score(), client))
+                    .collect::<Vec<_>>();
+                candidates.sort_by_key(|&(score, _)| score);
+                let missing = RECOMMENDED_FRIEND_TCP_CONNECTIONS - connection.connections.len();
+                for (_, client) in candidates.into_iter().take(missing) {
+                    connection.connections.insert(client.pk);
+                    route_futures.push(client.add_connection(node_pk)
+                        .map_err(|e| e.context(ConnectionErrorKind::AddConnection).into())
+                        .boxed());
+                }
+            }
+        }
 
         // send to sleep not used right now relays
+        let used_relays = Connections::used_relays(&connections);
         clients.values()
-            .filter(move |client|
-                // only connected relays have connected_time
-                client.connected_time().map_or(
-                    false,
-                    |connected_time| clock_elapsed(connected_time) > TCP_CONNECTION_ANNOUNCE_TIMEOUT
-                ) && !used_relays.contains(&client.pk)
-            )
+            .filter(|client| Connections::is_idle(client, &used_relays))
             .for_each(|client| client.sleep());
 
-        // remove not used relays
+        // remove not used relays, they don't have connections so there is no
+        // need to remove them from connections
         let mut clients_len = clients.len();
         clients.retain(|_, client|
             if clients_len > RECOMMENDED_FRIEND_TCP_CONNECTIONS && client.connections_count() == 0 {
@@ -384,14 +490,29 @@ impl Connections {
             }
         );
 
-        // remove deleted relays from connections
-        for connection in connections.values_mut() {
-            connection.connections.retain(|relay_pk| clients.contains_key(relay_pk));
+        future::try_join(
+            future::try_join_all(futures),
+            future::try_join_all(route_futures),
+        ).map_ok(drop)
+    }
 
-            // TODO: remove connections if there are too many?
-        }
+    /// Find out which relays are used right now, i.e. there is a node that is
+    /// not connected to us directly via UDP and uses this relay.
+    fn used_relays(connections: &HashMap<PublicKey, NodeConnection>) -> HashSet<PublicKey> {
+        connections.values()
+            .filter(|connection| connection.status == NodeConnectionStatus::TCP)
+            .flat_map(|connection| connection.connections.iter().cloned())
+            .collect()
+    }
 
-        future::try_join_all(futures).map_ok(drop)
+    /// Check if the relay is connected but not used right now so it can be put
+    /// to sleep.
+    fn is_idle(client: &Client, used_relays: &HashSet<PublicKey>) -> bool {
+        // only connected relays have connected_time
+        client.connected_time().map_or(
+            false,
+            |connected_time| clock_elapsed(connected_time) > TCP_CONNECTION_ANNOUNCE_TIMEOUT
+        ) && !used_relays.contains(&client.pk)
     }
 
     /// Run TCP periodical tasks. Result future will never be completed
@@ -450,6 +571,28 @@ mod tests {
         assert!(connections.clients.read().contains_key(&relay_pk));
     }
 
+    #[test]
+    fn set_proxy() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (_incoming_rx, _outgoing_rx, relay_pk_1) = connections.add_client();
+
+        let proxy = ProxyConfig::Http { addr: "127.0.0.1:3128".parse().unwrap() };
+        connections.set_proxy(Some(proxy.clone()));
+
+        // ignore result future since it spawns the connection which should be
+        // executed inside tokio context
+        let (relay_pk_2, _relay_sk_2) = gen_keypair();
+        drop(connections.add_relay_global("0.0.0.0:12347".parse().unwrap(), relay_pk_2));
+
+        let clients = connections.clients.read();
+        assert_eq!(clients[&relay_pk_1].proxy(), Some(proxy.clone()));
+        assert_eq!(clients[&relay_pk_2].proxy(), Some(proxy));
+    }
+
     #[tokio::test]
     async fn add_relay_global_exists() {
         crypto_init().unwrap();
@@ -862,6 +1005,56 @@ mod tests {
         assert_eq!(relays.len(), 2);
     }
 
+    #[test]
+    fn get_random_relays_healthy() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let mut healthy_relays = HashSet::new();
+        let mut receivers = Vec::new();
+        for i in 0 .. HEALTHY_RELAYS_CANDIDATES + 2 {
+            let (incoming_rx, outgoing_rx, relay) = create_client();
+            // the last two relays had troubles with connection
+            if i >= HEALTHY_RELAYS_CANDIDATES {
+                set_connection_attempts(&relay, 2);
+            } else {
+                healthy_relays.insert(relay.pk);
+            }
+            receivers.push((incoming_rx, outgoing_rx));
+            connections.clients.write().insert(relay.pk, relay);
+        }
+
+        for _ in 0 .. 10 {
+            let relay = connections.get_random_relay().unwrap();
+            assert!(healthy_relays.contains(&relay.pk));
+        }
+
+        let relays = connections.get_random_relays(HEALTHY_RELAYS_CANDIDATES as u8);
+        let relays = relays.into_iter().map(|relay| relay.pk).collect::<HashSet<_>>();
+        assert_eq!(relays, healthy_relays);
+
+        // all relays are returned when asked for more
+        let relays = connections.get_random_relays(HEALTHY_RELAYS_CANDIDATES as u8 + 2);
+        assert_eq!(relays.len(), HEALTHY_RELAYS_CANDIDATES + 2);
+        assert_eq!(relays.iter().map(|relay| relay.pk).collect::<HashSet<_>>().len(), relays.len());
+    }
+
+    #[test]
+    fn relay_health() {
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (_incoming_rx, _outgoing_rx, relay) = create_client();
+        let relay_pk = relay.pk;
+        set_connection_attempts(&relay, 2);
+        connections.clients.write().insert(relay_pk, relay);
+
+        assert_eq!(connections.relay_health(&relay_pk).unwrap().connection_attempts, 2);
+        assert!(connections.relay_health(&gen_keypair().0).is_none());
+    }
+
     #[test]
     fn get_random_relays_empty() {
         let (dht_pk, dht_sk) = gen_keypair();
@@ -881,15 +1074,26 @@ mod tests {
 
         let (_incoming_rx_1, _outgoing_rx_1, relay_1) = create_client();
         let (_incoming_rx_2, _outgoing_rx_2, relay_2) = create_client();
+        let (_incoming_rx_3, _outgoing_rx_3, relay_3) = create_client();
+        let (_incoming_rx_4, _outgoing_rx_4, relay_4) = create_client();
         let relay_pk_1 = relay_1.pk;
         let relay_pk_2 = relay_2.pk;
+        let relay_pk_3 = relay_3.pk;
+        let relay_pk_4 = relay_4.pk;
 
         let (node_pk_1, _node_sk_1) = gen_keypair();
         let (node_pk_2, _node_sk_2) = gen_keypair();
 
+        relay_1.add_connection(node_pk_1).await.unwrap();
+        relay_2.add_connection(node_pk_2).await.unwrap();
+        relay_3.add_connection(node_pk_1).await.unwrap();
+        relay_4.add_connection(node_pk_1).await.unwrap();
+
+        // the first node has enough relays so that the second relay won't be
+        // added to it
         connections.connections.write().insert(node_pk_1, NodeConnection {
             status: NodeConnectionStatus::TCP,
-            connections: [relay_pk_1].iter().cloned().collect(),
+            connections: [relay_pk_1, relay_pk_3, relay_pk_4].iter().cloned().collect(),
         });
         connections.connections.write().insert(node_pk_2, NodeConnection {
             status: NodeConnectionStatus::UDP,
@@ -898,6 +1102,8 @@ mod tests {
 
         connections.clients.write().insert(relay_pk_1, relay_1);
         connections.clients.write().insert(relay_pk_2, relay_2);
+        connections.clients.write().insert(relay_pk_3, relay_3);
+        connections.clients.write().insert(relay_pk_4, relay_4);
 
         tokio::time::pause();
         // time when we don't wait for connections to appear
@@ -1003,4 +1209,221 @@ mod tests {
 
         assert!(relay_0_c.is_disconnected());
     }
+
+    #[tokio::test]
+    async fn main_loop_keep_relay_used_by_another_node() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (_incoming_rx_0, _outgoing_rx_0, relay_0) = create_client();
+        let (_incoming_rx_1, _outgoing_rx_1, relay_1) = create_client();
+        let (_incoming_rx_2, _outgoing_rx_2, relay_2) = create_client();
+        let (_incoming_rx_3, _outgoing_rx_3, relay_3) = create_client();
+        let relay_0_c = relay_0.clone();
+        let relay_pk_0 = relay_0.pk;
+        let relay_pk_1 = relay_1.pk;
+        let relay_pk_2 = relay_2.pk;
+        let relay_pk_3 = relay_3.pk;
+
+        connections.clients.write().insert(relay_pk_0, relay_0);
+        connections.clients.write().insert(relay_pk_1, relay_1);
+        connections.clients.write().insert(relay_pk_2, relay_2);
+        connections.clients.write().insert(relay_pk_3, relay_3);
+
+        let (node_pk_1, _node_sk_1) = gen_keypair();
+        let (node_pk_2, _node_sk_2) = gen_keypair();
+
+        // the first relay is shared by both nodes, e.g. both friends shared it
+        // with us
+        connections.add_connection(relay_pk_0, node_pk_1).await.unwrap();
+        connections.add_connection(relay_pk_1, node_pk_1).await.unwrap();
+        connections.add_connection(relay_pk_1, node_pk_2).await.unwrap();
+        connections.add_connection(relay_pk_2, node_pk_2).await.unwrap();
+        connections.add_connection(relay_pk_3, node_pk_2).await.unwrap();
+
+        connections.remove_connection(node_pk_1).await.unwrap();
+
+        connections.main_loop().await.unwrap();
+
+        let clients = connections.clients.read();
+
+        // the relay used only by the removed node is dropped
+        assert!(!clients.contains_key(&relay_pk_0));
+        assert!(relay_0_c.is_disconnected());
+        // the shared relay is kept while the second node uses it
+        assert!(clients.contains_key(&relay_pk_1));
+        assert!(clients[&relay_pk_1].has_connection(node_pk_2));
+        assert!(connections.connections.read()[&node_pk_2].connections.contains(&relay_pk_1));
+    }
+
+    #[tokio::test]
+    async fn add_connection_too_many() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (node_pk, _node_sk) = gen_keypair();
+
+        let relays_pks = (0 .. MAX_FRIEND_TCP_CONNECTIONS).map(|_| gen_keypair().0).collect();
+        connections.connections.write().insert(node_pk, NodeConnection {
+            status: NodeConnectionStatus::TCP,
+            connections: relays_pks,
+        });
+
+        let (_incoming_rx, _outgoing_rx, client) = create_client();
+        let relay_pk = client.pk;
+        let client_c = client.clone();
+
+        connections.clients.write().insert(relay_pk, client);
+
+        connections.add_connection(relay_pk, node_pk).await.unwrap();
+
+        let connections = connections.connections.read();
+        let connection = connections.get(&node_pk).unwrap();
+
+        assert_eq!(connection.connections.len(), MAX_FRIEND_TCP_CONNECTIONS);
+        assert!(!connection.connections.contains(&relay_pk));
+        assert!(!client_c.has_connection(node_pk));
+    }
+
+    #[tokio::test]
+    async fn main_loop_remove_surplus() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (node_pk, _node_sk) = gen_keypair();
+
+        let mut receivers = Vec::new();
+        let mut relays = Vec::new();
+        for i in 0 .. MAX_FRIEND_TCP_CONNECTIONS {
+            let (incoming_rx, outgoing_rx, relay) = create_client();
+            relay.add_connection(node_pk).await.unwrap();
+            // the first relays are the least healthy
+            set_connection_attempts(&relay, (MAX_FRIEND_TCP_CONNECTIONS - i) as u32);
+            receivers.push((incoming_rx, outgoing_rx));
+            relays.push(relay);
+        }
+
+        connections.connections.write().insert(node_pk, NodeConnection {
+            status: NodeConnectionStatus::UDP,
+            connections: relays.iter().map(|relay| relay.pk).collect(),
+        });
+        for relay in &relays {
+            connections.clients.write().insert(relay.pk, relay.clone());
+        }
+
+        connections.main_loop().await.unwrap();
+
+        let connections = connections.connections.read();
+        let connection = connections.get(&node_pk).unwrap();
+
+        assert_eq!(connection.connections.len(), RECOMMENDED_FRIEND_TCP_CONNECTIONS);
+        for (i, relay) in relays.iter().enumerate() {
+            let kept = i >= MAX_FRIEND_TCP_CONNECTIONS - RECOMMENDED_FRIEND_TCP_CONNECTIONS;
+            assert_eq!(connection.connections.contains(&relay.pk), kept);
+            assert_eq!(relay.has_connection(node_pk), kept);
+        }
+    }
+
+    #[tokio::test]
+    async fn main_loop_add_relays() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (_incoming_rx_0, _outgoing_rx_0, relay_0) = create_client();
+        let (_incoming_rx_1, _outgoing_rx_1, relay_1) = create_client();
+        let (_incoming_rx_2, _outgoing_rx_2, relay_2) = create_client();
+        let (_incoming_rx_3, _outgoing_rx_3, relay_3) = create_client();
+        let relay_pk_0 = relay_0.pk;
+        let relay_pk_1 = relay_1.pk;
+        let relay_pk_2 = relay_2.pk;
+        let relay_pk_3 = relay_3.pk;
+
+        // the least healthy relay shouldn't be used
+        set_connection_attempts(&relay_3, 1);
+
+        let (node_pk, _node_sk) = gen_keypair();
+
+        relay_0.add_connection(node_pk).await.unwrap();
+
+        connections.connections.write().insert(node_pk, NodeConnection {
+            status: NodeConnectionStatus::TCP,
+            connections: [relay_pk_0].iter().cloned().collect(),
+        });
+
+        connections.clients.write().insert(relay_pk_0, relay_0);
+        connections.clients.write().insert(relay_pk_1, relay_1);
+        connections.clients.write().insert(relay_pk_2, relay_2);
+        connections.clients.write().insert(relay_pk_3, relay_3);
+
+        connections.main_loop().await.unwrap();
+
+        let clients = connections.clients.read();
+        let connections = connections.connections.read();
+        let connection = connections.get(&node_pk).unwrap();
+
+        assert_eq!(connection.connections.len(), RECOMMENDED_FRIEND_TCP_CONNECTIONS);
+        assert!(connection.connections.contains(&relay_pk_0));
+        assert!(connection.connections.contains(&relay_pk_1));
+        assert!(connection.connections.contains(&relay_pk_2));
+        assert!(!connection.connections.contains(&relay_pk_3));
+
+        assert!(clients.get(&relay_pk_1).unwrap().has_connection(node_pk));
+        assert!(clients.get(&relay_pk_2).unwrap().has_connection(node_pk));
+    }
+
+    #[tokio::test]
+    async fn main_loop_add_idle_relays() {
+        crypto_init().unwrap();
+        let (dht_pk, dht_sk) = gen_keypair();
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::new(dht_pk, dht_sk, incoming_tx);
+
+        let (_incoming_rx_0, _outgoing_rx_0, relay_0) = create_client();
+        let (_incoming_rx_1, _outgoing_rx_1, relay_1) = create_client();
+        let (_incoming_rx_2, _outgoing_rx_2, relay_2) = create_client();
+        let relay_pk_0 = relay_0.pk;
+        let relay_pk_1 = relay_1.pk;
+        let relay_pk_2 = relay_2.pk;
+
+        let (node_pk, _node_sk) = gen_keypair();
+
+        relay_0.add_connection(node_pk).await.unwrap();
+
+        connections.connections.write().insert(node_pk, NodeConnection {
+            status: NodeConnectionStatus::TCP,
+            connections: [relay_pk_0].iter().cloned().collect(),
+        });
+
+        connections.clients.write().insert(relay_pk_0, relay_0);
+        connections.clients.write().insert(relay_pk_1, relay_1);
+        connections.clients.write().insert(relay_pk_2, relay_2);
+
+        tokio::time::pause();
+        // relays 1 and 2 are not used by anyone so they are idle
+        tokio::time::advance(TCP_CONNECTION_ANNOUNCE_TIMEOUT + Duration::from_secs(1)).await;
+
+        connections.main_loop().await.unwrap();
+
+        let clients = connections.clients.read();
+        let connections = connections.connections.read();
+        let connection = connections.get(&node_pk).unwrap();
+
+        assert!(connection.connections.contains(&relay_pk_1));
+        assert!(connection.connections.contains(&relay_pk_2));
+
+        let relay_1 = clients.get(&relay_pk_1).unwrap();
+        let relay_2 = clients.get(&relay_pk_2).unwrap();
+        assert!(relay_1.has_connection(node_pk));
+        assert!(relay_2.has_connection(node_pk));
+        assert!(relay_1.is_connected());
+        assert!(relay_2.is_connected());
+    }
 }
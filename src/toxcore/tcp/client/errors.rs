@@ -70,6 +70,12 @@ error_kind! {
         #[doc = "Tcp codec encode error."]
         #[fail(display = "Tcp codec encode error")]
         Encode,
+        #[doc = "Connecting via proxy error."]
+        #[fail(display = "Connecting via proxy error")]
+        Proxy,
+        #[doc = "Relay didn't answer to PingRequest in time."]
+        #[fail(display = "Relay didn't answer to PingRequest in time")]
+        PingTimeout,
     }
 }
 
@@ -103,3 +109,31 @@ error_kind! {
         AddConnection,
     }
 }
+
+error_kind! {
+    #[doc = "Error that can happen when connecting to a relay via proxy."]
+    #[derive(Debug)]
+    ProxyError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, failure::Fail)]
+    ProxyErrorKind {
+        #[doc = "Proxy io error."]
+        #[fail(display = "Proxy io error")]
+        Io,
+        #[doc = "Proxy sent invalid response."]
+        #[fail(display = "Proxy sent invalid response")]
+        InvalidResponse,
+        #[doc = "Proxy doesn't support any of offered authentication methods."]
+        #[fail(display = "Proxy doesn't support any of offered authentication methods")]
+        NoAcceptableAuth,
+        #[doc = "Username or password is too long."]
+        #[fail(display = "Username or password is too long")]
+        InvalidCredentials,
+        #[doc = "Proxy rejected username or password."]
+        #[fail(display = "Proxy rejected username or password")]
+        AuthFailed,
+        #[doc = "Proxy failed to connect to the relay."]
+        #[fail(display = "Proxy failed to connect to the relay")]
+        ConnectFailed,
+    }
+}
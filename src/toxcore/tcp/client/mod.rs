@@ -5,7 +5,9 @@ mod connections;
 #[allow(clippy::module_inception)]
 mod client;
 mod errors;
+mod proxy;
 
 pub use self::connections::*;
 pub use self::client::*;
 pub use self::errors::*;
+pub use self::proxy::{ProxyConfig, ProxyCredentials};
@@ -0,0 +1,444 @@
+/*! Connecting to TCP relays through SOCKS5 and HTTP CONNECT proxies.
+
+Both protocols are handled only up to the moment when the proxy establishes
+the connection to the relay. After that the socket is used as if it was
+connected to the relay directly.
+*/
+
+use std::net::{IpAddr, SocketAddr};
+
+use failure::Fail;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::toxcore::tcp::client::errors::*;
+
+/// SOCKS protocol version.
+const SOCKS5_VERSION: u8 = 0x05;
+
+/// Version of username/password authentication subnegotiation.
+const SOCKS5_AUTH_VERSION: u8 = 0x01;
+
+/// SOCKS5 authentication method that doesn't require authentication.
+const SOCKS5_METHOD_NO_AUTH: u8 = 0x00;
+
+/// SOCKS5 username/password authentication method.
+const SOCKS5_METHOD_PASSWORD: u8 = 0x02;
+
+/// SOCKS5 reply meaning that none of offered methods is acceptable.
+const SOCKS5_METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+/// SOCKS5 `CONNECT` command.
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+
+/// SOCKS5 IPv4 address type.
+const SOCKS5_ATYP_IPV4: u8 = 0x01;
+
+/// SOCKS5 domain name address type.
+const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+
+/// SOCKS5 IPv6 address type.
+const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+/// SOCKS5 reply meaning that the request is granted.
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Maximum size of HTTP response headers we are ready to read from HTTP proxy.
+const HTTP_MAX_RESPONSE_SIZE: usize = 4096;
+
+/// Username and password for SOCKS5 authentication.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProxyCredentials {
+    /// Username. Should not be longer than 255 bytes.
+    pub username: String,
+    /// Password. Should not be longer than 255 bytes.
+    pub password: String,
+}
+
+/// Proxy that should be used for connections to TCP relays.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProxyConfig {
+    /// SOCKS5 proxy with optional username/password authentication.
+    Socks5 {
+        /// Address of the proxy.
+        addr: SocketAddr,
+        /// Credentials if the proxy requires authentication.
+        credentials: Option<ProxyCredentials>,
+    },
+    /// HTTP proxy that supports `CONNECT` method.
+    Http {
+        /// Address of the proxy.
+        addr: SocketAddr,
+    },
+}
+
+impl ProxyConfig {
+    /// Address of the proxy.
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            ProxyConfig::Socks5 { addr, .. } => addr,
+            ProxyConfig::Http { addr } => addr,
+        }
+    }
+
+    /// Connect to the target address through the proxy.
+    pub async fn connect(&self, target: SocketAddr) -> Result<TcpStream, ProxyError> {
+        let mut socket = TcpStream::connect(&self.addr()).await
+            .map_err(|e| ProxyError::from(e.context(ProxyErrorKind::Io)))?;
+
+        match *self {
+            ProxyConfig::Socks5 { ref credentials, .. } =>
+                socks5_connect(&mut socket, target, credentials.as_ref()).await?,
+            ProxyConfig::Http { .. } =>
+                http_connect(&mut socket, target).await?,
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Make SOCKS5 handshake asking the proxy to connect to the target address.
+async fn socks5_connect(socket: &mut TcpStream, target: SocketAddr, credentials: Option<&ProxyCredentials>) -> Result<(), ProxyError> {
+    let io_error = |e: std::io::Error| ProxyError::from(e.context(ProxyErrorKind::Io));
+
+    let greeting: &[u8] = if credentials.is_some() {
+        &[SOCKS5_VERSION, 2, SOCKS5_METHOD_NO_AUTH, SOCKS5_METHOD_PASSWORD]
+    } else {
+        &[SOCKS5_VERSION, 1, SOCKS5_METHOD_NO_AUTH]
+    };
+    socket.write_all(greeting).await.map_err(io_error)?;
+
+    let mut method = [0; 2];
+    socket.read_exact(&mut method).await.map_err(io_error)?;
+    if method[0] != SOCKS5_VERSION {
+        return Err(ProxyErrorKind::InvalidResponse.into());
+    }
+
+    match (method[1], credentials) {
+        (SOCKS5_METHOD_NO_AUTH, _) => { },
+        (SOCKS5_METHOD_PASSWORD, Some(credentials)) => {
+            let username = credentials.username.as_bytes();
+            let password = credentials.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(ProxyErrorKind::InvalidCredentials.into());
+            }
+
+            let mut request = Vec::with_capacity(3 + username.len() + password.len());
+            request.push(SOCKS5_AUTH_VERSION);
+            request.push(username.len() as u8);
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            socket.write_all(&request).await.map_err(io_error)?;
+
+            let mut status = [0; 2];
+            socket.read_exact(&mut status).await.map_err(io_error)?;
+            if status[1] != 0 {
+                return Err(ProxyErrorKind::AuthFailed.into());
+            }
+        },
+        (SOCKS5_METHOD_NOT_ACCEPTABLE, _) | (SOCKS5_METHOD_PASSWORD, None) =>
+            return Err(ProxyErrorKind::NoAcceptableAuth.into()),
+        _ => return Err(ProxyErrorKind::InvalidResponse.into()),
+    }
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(SOCKS5_ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            request.push(SOCKS5_ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    socket.write_all(&request).await.map_err(io_error)?;
+
+    let mut reply = [0; 4];
+    socket.read_exact(&mut reply).await.map_err(io_error)?;
+    if reply[0] != SOCKS5_VERSION {
+        return Err(ProxyErrorKind::InvalidResponse.into());
+    }
+    if reply[1] != SOCKS5_REPLY_SUCCEEDED {
+        return Err(ProxyErrorKind::ConnectFailed.into());
+    }
+
+    // skip bound address and port
+    let addr_len = match reply[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => socket.read_u8().await.map_err(io_error)? as usize,
+        _ => return Err(ProxyErrorKind::InvalidResponse.into()),
+    };
+    skip_bytes(socket, addr_len + 2).await.map_err(io_error)?;
+
+    Ok(())
+}
+
+/// Ask HTTP proxy to connect to the target address using `CONNECT` method.
+async fn http_connect(socket: &mut TcpStream, target: SocketAddr) -> Result<(), ProxyError> {
+    let io_error = |e: std::io::Error| ProxyError::from(e.context(ProxyErrorKind::Io));
+
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target);
+    socket.write_all(request.as_bytes()).await.map_err(io_error)?;
+
+    // Read the response byte by byte so that we don't consume any bytes sent
+    // by the relay after the response
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == HTTP_MAX_RESPONSE_SIZE {
+            return Err(ProxyErrorKind::InvalidResponse.into());
+        }
+        response.push(socket.read_u8().await.map_err(io_error)?);
+    }
+
+    let status_line = response.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    let mut parts = status_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/1.") =>
+            if code.starts_with('2') {
+                Ok(())
+            } else {
+                Err(ProxyErrorKind::ConnectFailed.into())
+            },
+        _ => Err(ProxyErrorKind::InvalidResponse.into()),
+    }
+}
+
+/// Read and drop the specified number of bytes.
+async fn skip_bytes<R: AsyncRead + Unpin>(reader: &mut R, count: usize) -> std::io::Result<()> {
+    let mut buf = vec![0; count];
+    reader.read_exact(&mut buf).await.map(drop)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    /// Run a SOCKS5 stand-in that accepts one connection, checks the
+    /// handshake and then forwards data between the client and the target.
+    pub async fn run_socks5_proxy(mut listener: TcpListener, credentials: Option<ProxyCredentials>, target: SocketAddr) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut header = [0; 2];
+        socket.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], SOCKS5_VERSION);
+        let mut methods = vec![0; header[1] as usize];
+        socket.read_exact(&mut methods).await.unwrap();
+
+        if let Some(credentials) = credentials {
+            assert!(methods.contains(&SOCKS5_METHOD_PASSWORD));
+            socket.write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_PASSWORD]).await.unwrap();
+
+            let mut header = [0; 2];
+            socket.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[0], SOCKS5_AUTH_VERSION);
+            let mut username = vec![0; header[1] as usize];
+            socket.read_exact(&mut username).await.unwrap();
+            let mut password = vec![0; socket.read_u8().await.unwrap() as usize];
+            socket.read_exact(&mut password).await.unwrap();
+
+            let status = if username == credentials.username.as_bytes() && password == credentials.password.as_bytes() { 0 } else { 1 };
+            socket.write_all(&[SOCKS5_AUTH_VERSION, status]).await.unwrap();
+            if status != 0 {
+                return;
+            }
+        } else {
+            assert!(methods.contains(&SOCKS5_METHOD_NO_AUTH));
+            socket.write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NO_AUTH]).await.unwrap();
+        }
+
+        let mut request = [0; 4];
+        socket.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..3], [SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0]);
+        let ip: IpAddr = match request[3] {
+            SOCKS5_ATYP_IPV4 => {
+                let mut octets = [0; 4];
+                socket.read_exact(&mut octets).await.unwrap();
+                octets.into()
+            },
+            SOCKS5_ATYP_IPV6 => {
+                let mut octets = [0; 16];
+                socket.read_exact(&mut octets).await.unwrap();
+                octets.into()
+            },
+            atyp => panic!("Unexpected address type {}", atyp),
+        };
+        let port = socket.read_u16().await.unwrap();
+        assert_eq!(SocketAddr::new(ip, port), target);
+
+        let mut target_socket = TcpStream::connect(&target).await.unwrap();
+
+        socket.write_all(&[SOCKS5_VERSION, SOCKS5_REPLY_SUCCEEDED, 0, SOCKS5_ATYP_IPV4, 127, 0, 0, 1, 0, 0]).await.unwrap();
+
+        let (mut reader, mut writer) = socket.split();
+        let (mut target_reader, mut target_writer) = target_socket.split();
+        // the connection can be closed by either side
+        let _ = futures::future::select(
+            tokio::io::copy(&mut reader, &mut target_writer),
+            tokio::io::copy(&mut target_reader, &mut writer),
+        ).await;
+    }
+
+    /// Run a server that accepts one connection and echoes all received data
+    /// back.
+    async fn run_echo_server(mut listener: TcpListener) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    }
+
+    async fn echo(mut socket: TcpStream) {
+        socket.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn socks5_no_auth() {
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = target_listener.local_addr().unwrap();
+        tokio::spawn(run_echo_server(target_listener));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy_future = run_socks5_proxy(listener, None, target);
+
+        let proxy = ProxyConfig::Socks5 { addr, credentials: None };
+        let client_future = async {
+            echo(proxy.connect(target).await.unwrap()).await;
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+
+    #[tokio::test]
+    async fn socks5_auth() {
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = target_listener.local_addr().unwrap();
+        tokio::spawn(run_echo_server(target_listener));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let credentials = ProxyCredentials {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let proxy_future = run_socks5_proxy(listener, Some(credentials.clone()), target);
+
+        let proxy = ProxyConfig::Socks5 { addr, credentials: Some(credentials) };
+        let client_future = async {
+            echo(proxy.connect(target).await.unwrap()).await;
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+
+    #[tokio::test]
+    async fn socks5_auth_failed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = "1.2.3.4:33445".parse().unwrap();
+        let proxy_future = run_socks5_proxy(listener, Some(ProxyCredentials {
+            username: "user".to_owned(),
+            password: "secret".to_owned(),
+        }), target);
+
+        let proxy = ProxyConfig::Socks5 { addr, credentials: Some(ProxyCredentials {
+            username: "user".to_owned(),
+            password: "wrong".to_owned(),
+        }) };
+        let client_future = async {
+            let error = proxy.connect(target).await.err().unwrap();
+            assert_eq!(*error.kind(), ProxyErrorKind::AuthFailed);
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+
+    #[tokio::test]
+    async fn socks5_no_acceptable_auth() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy_future = async {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            socket.read_exact(&mut greeting).await.unwrap();
+            socket.write_all(&[SOCKS5_VERSION, SOCKS5_METHOD_NOT_ACCEPTABLE]).await.unwrap();
+        };
+
+        let proxy = ProxyConfig::Socks5 { addr, credentials: None };
+        let client_future = async {
+            let error = proxy.connect("1.2.3.4:33445".parse().unwrap()).await.err().unwrap();
+            assert_eq!(*error.kind(), ProxyErrorKind::NoAcceptableAuth);
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+
+    async fn run_http_proxy(mut listener: TcpListener, target: SocketAddr, response: &'static [u8]) {
+        let (mut socket, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(socket.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with(&format!("CONNECT {} HTTP/1.1\r\n", target)));
+
+        // the response and the first data from the relay can come in one
+        // chunk
+        socket.write_all(response).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        socket.write_all(&buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = "[2a03:b0c0:3:d0::ac:5001]:33445".parse().unwrap();
+        let proxy_future = run_http_proxy(listener, target, b"HTTP/1.1 200 Connection established\r\nProxy-Agent: test\r\n\r\n");
+
+        let proxy = ProxyConfig::Http { addr };
+        let client_future = async {
+            let mut socket = proxy.connect(target).await.unwrap();
+            let mut buf = [0; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            socket.write_all(&buf).await.unwrap();
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+
+    #[tokio::test]
+    async fn http_connect_forbidden() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy_future = async {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(socket.read_u8().await.unwrap());
+            }
+            socket.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await.unwrap();
+        };
+
+        let proxy = ProxyConfig::Http { addr };
+        let client_future = async {
+            let error = proxy.connect("1.2.3.4:33445".parse().unwrap()).await.err().unwrap();
+            assert_eq!(*error.kind(), ProxyErrorKind::ConnectFailed);
+        };
+
+        futures::join!(proxy_future, client_future);
+    }
+}
@@ -0,0 +1,322 @@
+/*! Bandwidth accounting and throttling for TCP relay server
+*/
+
+use std::time::{Duration, Instant};
+
+use crate::toxcore::crypto_core::*;
+use crate::toxcore::ip_port::SIZE_IPPORT;
+use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::tcp::packet::*;
+use crate::toxcore::time::*;
+
+/// Interval of time for which bandwidth can be accumulated to be spent at
+/// once.
+const BANDWIDTH_BURST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with `OobSend` and `OnionRequest` packets when the relay is under
+/// load, i.e. when less than a half of the global bandwidth is left.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LowPriorityPolicy {
+    /// Forward them like `Data` packets.
+    Forward,
+    /// Charge them twice their size while the relay is under load so that
+    /// they wait longer than `Data` packets.
+    Deprioritise,
+    /// Drop them while the relay is under load.
+    Drop,
+}
+
+/// Bandwidth caps of TCP relay server. Only packets that are forwarded to
+/// other clients or to the onion subsystem are throttled. Packets exceeding
+/// the caps are delayed until enough bandwidth is restored. Packets from a
+/// client are handled in order, so the following packets are queued in the
+/// client's connection until then. Only packets that can never fit into the
+/// caps are dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BandwidthLimits {
+    /// Maximum amount of bytes per second a single client can send through the
+    /// relay. `None` means unlimited.
+    pub per_client: Option<u64>,
+    /// Maximum amount of bytes per second all clients can send through the
+    /// relay. Every client can spend at once only its fair share of this
+    /// bandwidth, and when the relay is under load the bandwidth is restored
+    /// with the fair share of this rate. `None` means unlimited.
+    pub global: Option<u64>,
+    /// What to do with `OobSend` and `OnionRequest` packets when the relay is
+    /// under load.
+    pub low_priority: LowPriorityPolicy,
+}
+
+impl Default for BandwidthLimits {
+    fn default() -> Self {
+        BandwidthLimits {
+            per_client: None,
+            global: None,
+            low_priority: LowPriorityPolicy::Forward,
+        }
+    }
+}
+
+/// Traffic of a client connected to TCP relay server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientTraffic {
+    /// `PublicKey` of the client.
+    pub pk: PublicKey,
+    /// Amount of bytes received from the client.
+    pub bytes_in: u64,
+    /// Amount of bytes sent to the client.
+    pub bytes_out: u64,
+    /// Number of packets from the client dropped because of bandwidth caps or
+    /// `LowPriorityPolicy::Drop` policy.
+    pub dropped_packets: u64,
+}
+
+impl ClientTraffic {
+    /// Total amount of bytes received from and sent to the client.
+    pub fn total(&self) -> u64 {
+        self.bytes_in.saturating_add(self.bytes_out)
+    }
+}
+
+/// Token bucket that is refilled with bytes at a given rate.
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    /// Amount of bytes that can be spent right now.
+    tokens: u64,
+    /// Time when the bucket was refilled last time.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create new full `TokenBucket` with the given capacity.
+    pub fn new(capacity: u64) -> TokenBucket {
+        TokenBucket {
+            tokens: capacity,
+            last_refill: clock_now(),
+        }
+    }
+
+    /// Refill the bucket with bytes accumulated since the last refill. The
+    /// bucket can't hold more than `capacity` bytes.
+    fn refill(&mut self, rate: u64, capacity: u64) {
+        let elapsed = clock_elapsed(self.last_refill);
+        let tokens = elapsed.as_micros().saturating_mul(u128::from(rate)) / 1_000_000;
+        if tokens > 0 || rate == 0 {
+            self.tokens = (u128::from(self.tokens) + tokens).min(u128::from(capacity)) as u64;
+            self.last_refill = clock_now();
+        } else {
+            // capacity can be decreased since the last refill
+            self.tokens = self.tokens.min(capacity);
+        }
+    }
+
+    /// Get amount of bytes that can be spent right now.
+    pub fn level(&mut self, rate: u64, capacity: u64) -> u64 {
+        self.refill(rate, capacity);
+        self.tokens
+    }
+
+    /// Spend bytes from the bucket. It should be checked with `level` before
+    /// that there are enough of them.
+    pub fn take(&mut self, amount: u64) {
+        self.tokens = self.tokens.saturating_sub(amount);
+    }
+}
+
+/// Maximum amount of bytes a bucket refilled with the given rate can hold.
+pub(crate) fn capacity(rate: u64) -> u64 {
+    rate.saturating_mul(BANDWIDTH_BURST_INTERVAL.as_secs())
+}
+
+/// Time needed to refill a bucket with `amount` bytes at the given rate.
+pub(crate) fn refill_time(amount: u64, rate: u64) -> Duration {
+    let rate = u128::from(rate.max(1));
+    let total = u128::from(amount) * 1_000_000;
+    let micros = total / rate + if total % rate == 0 { 0 } else { 1 };
+    Duration::from_micros(micros.min(u128::from(std::u64::MAX)) as u64)
+}
+
+/// Get size of the packet in serialized form. It's calculated from the packet
+/// fields so that the packet doesn't have to be serialized one more time.
+pub(crate) fn packet_size(packet: &Packet) -> u64 {
+    let size = match packet {
+        Packet::RouteRequest(_) => 1 + PUBLICKEYBYTES,
+        Packet::RouteResponse(_) => 2 + PUBLICKEYBYTES,
+        Packet::ConnectNotification(_) | Packet::DisconnectNotification(_) => 2,
+        Packet::PingRequest(_) | Packet::PongResponse(_) => 9,
+        Packet::OobSend(packet) => 1 + PUBLICKEYBYTES + packet.data.len(),
+        Packet::OobReceive(packet) => 1 + PUBLICKEYBYTES + packet.data.len(),
+        Packet::OnionRequest(packet) =>
+            1 + NONCEBYTES + SIZE_IPPORT + PUBLICKEYBYTES + packet.payload.len(),
+        Packet::OnionResponse(packet) => 1 + match packet.payload {
+            InnerOnionResponse::OnionAnnounceResponse(ref response) =>
+                9 + NONCEBYTES + response.payload.len(),
+            InnerOnionResponse::OnionDataResponse(ref response) =>
+                1 + NONCEBYTES + PUBLICKEYBYTES + response.payload.len(),
+        },
+        Packet::Data(packet) => 1 + match packet.data {
+            DataPayload::CookieRequest(ref request) =>
+                1 + PUBLICKEYBYTES + NONCEBYTES + request.payload.len(),
+            DataPayload::CookieResponse(ref response) =>
+                1 + NONCEBYTES + response.payload.len(),
+            DataPayload::CryptoHandshake(ref handshake) =>
+                1 + secretbox::NONCEBYTES + handshake.cookie.payload.len() + NONCEBYTES + handshake.payload.len(),
+            DataPayload::CryptoData(ref data) => 3 + data.payload.len(),
+        },
+    };
+    size as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::toxcore::binary_io::*;
+    use crate::toxcore::dht::packet::{CookieRequest, CookieResponse, CryptoData, CryptoHandshake, EncryptedCookie};
+    use crate::toxcore::ip_port::*;
+    use crate::toxcore::onion::packet::{OnionAnnounceResponse, OnionDataResponse};
+    use crate::toxcore::tcp::connection_id::ConnectionId;
+
+    #[test]
+    fn client_traffic_total() {
+        let traffic = ClientTraffic {
+            pk: gen_keypair().0,
+            bytes_in: 1,
+            bytes_out: std::u64::MAX,
+            dropped_packets: 0,
+        };
+        assert_eq!(traffic.total(), std::u64::MAX);
+    }
+
+    #[tokio::test]
+    async fn token_bucket() {
+        tokio::time::pause();
+
+        let mut bucket = TokenBucket::new(100);
+        assert_eq!(bucket.level(100, 100), 100);
+
+        bucket.take(80);
+        assert_eq!(bucket.level(100, 100), 20);
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.level(100, 100), 70);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.level(100, 100), 100);
+
+        // capacity is decreased
+        assert_eq!(bucket.level(100, 50), 50);
+    }
+
+    #[tokio::test]
+    async fn refill_time_restores_amount() {
+        tokio::time::pause();
+
+        let mut bucket = TokenBucket::new(1000);
+        bucket.take(1000);
+
+        let time = refill_time(333, 1000);
+        assert_eq!(time, Duration::from_millis(333));
+
+        tokio::time::advance(time).await;
+        assert_eq!(bucket.level(1000, 1000), 333);
+
+        // rounded up so that the whole amount is restored
+        assert_eq!(refill_time(1, 3), Duration::from_micros(333_334));
+    }
+
+    #[test]
+    fn packet_size_ping_request() {
+        let packet = Packet::PingRequest(PingRequest {
+            ping_id: 42,
+        });
+        assert_eq!(packet_size(&packet), 9);
+    }
+
+    #[test]
+    fn packet_size_matches_serialized_size() {
+        crypto_init().unwrap();
+        let connection_id = ConnectionId::from_index(42);
+        let data = |data: DataPayload| Packet::Data(Data {
+            connection_id,
+            data,
+        });
+        let packets = vec![
+            Packet::RouteRequest(RouteRequest {
+                pk: gen_keypair().0,
+            }),
+            Packet::RouteResponse(RouteResponse {
+                connection_id,
+                pk: gen_keypair().0,
+            }),
+            Packet::ConnectNotification(ConnectNotification {
+                connection_id,
+            }),
+            Packet::DisconnectNotification(DisconnectNotification {
+                connection_id,
+            }),
+            Packet::PongResponse(PongResponse {
+                ping_id: 42,
+            }),
+            Packet::OobSend(OobSend {
+                destination_pk: gen_keypair().0,
+                data: vec![42; 123],
+            }),
+            Packet::OobReceive(OobReceive {
+                sender_pk: gen_keypair().0,
+                data: vec![42; 123],
+            }),
+            Packet::OnionRequest(OnionRequest {
+                nonce: gen_nonce(),
+                ip_port: IpPort {
+                    protocol: ProtocolType::TCP,
+                    ip_addr: "5.6.7.8".parse().unwrap(),
+                    port: 12345,
+                },
+                temporary_pk: gen_keypair().0,
+                payload: vec![42; 234],
+            }),
+            Packet::OnionResponse(OnionResponse {
+                payload: InnerOnionResponse::OnionAnnounceResponse(OnionAnnounceResponse {
+                    sendback_data: 12345,
+                    nonce: gen_nonce(),
+                    payload: vec![42; 123],
+                }),
+            }),
+            Packet::OnionResponse(OnionResponse {
+                payload: InnerOnionResponse::OnionDataResponse(OnionDataResponse {
+                    nonce: gen_nonce(),
+                    temporary_pk: gen_keypair().0,
+                    payload: vec![42; 123],
+                }),
+            }),
+            data(DataPayload::CookieRequest(CookieRequest {
+                pk: gen_keypair().0,
+                nonce: gen_nonce(),
+                payload: vec![42; 88],
+            })),
+            data(DataPayload::CookieResponse(CookieResponse {
+                nonce: gen_nonce(),
+                payload: vec![42; 88],
+            })),
+            data(DataPayload::CryptoHandshake(CryptoHandshake {
+                cookie: EncryptedCookie {
+                    nonce: secretbox::gen_nonce(),
+                    payload: vec![42; 88],
+                },
+                nonce: gen_nonce(),
+                payload: vec![42; 248],
+            })),
+            data(DataPayload::CryptoData(CryptoData {
+                nonce_last_bytes: 42,
+                payload: vec![42; 123],
+            })),
+        ];
+
+        for packet in packets {
+            let mut buf = [0; MAX_TCP_PACKET_SIZE];
+            let (_, size) = packet.to_bytes((&mut buf, 0)).unwrap();
+            assert_eq!(packet_size(&packet), size as u64, "{:?}", packet);
+        }
+    }
+}
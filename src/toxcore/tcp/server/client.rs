@@ -6,16 +6,20 @@ use crate::toxcore::tcp::packet::*;
 use crate::toxcore::tcp::connection_id::ConnectionId;
 use crate::toxcore::tcp::links::Links;
 use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::tcp::server::bandwidth::*;
 use crate::toxcore::time::*;
 use crate::toxcore::utils::*;
 
 use std::io::{Error, ErrorKind};
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Instant, Duration};
 
 use futures::Future;
 use futures::channel::mpsc;
 use futures::{FutureExt, SinkExt};
+use parking_lot::Mutex;
 
 /// Interval of time for sending TCP PingRequest
 pub const TCP_PING_FREQUENCY: Duration = Duration::from_secs(30);
@@ -52,7 +56,16 @@ pub struct Client {
     /// Last time sent PingRequest packet
     last_pinged: Instant,
     /// Last time received PongResponse
-    last_pong_resp: Instant
+    last_pong_resp: Instant,
+    /// Amount of bytes received from the client
+    bytes_in: AtomicU64,
+    /// Amount of bytes sent to the client
+    bytes_out: Arc<AtomicU64>,
+    /// Number of packets from the client dropped because of bandwidth caps
+    dropped_packets: AtomicU64,
+    /// Bandwidth left for the client. It's created on the first use since its
+    /// rate depends on bandwidth limits of the server
+    bandwidth: Mutex<Option<TokenBucket>>,
 }
 
 impl Client {
@@ -67,7 +80,11 @@ impl Client {
             links: Links::new(),
             ping_id: 0,
             last_pinged: clock_now(),
-            last_pong_resp: clock_now()
+            last_pong_resp: clock_now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            dropped_packets: AtomicU64::new(0),
+            bandwidth: Mutex::new(None),
         }
     }
 
@@ -125,9 +142,56 @@ impl Client {
         &mut self.links
     }
 
+    /** Get traffic statistics of the Client
+    */
+    pub fn traffic(&self) -> ClientTraffic {
+        ClientTraffic {
+            pk: self.pk,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped_packets: self.dropped_packets.load(Ordering::Relaxed),
+        }
+    }
+
+    /** Count bytes received from the Client
+    */
+    pub fn add_bytes_in(&self, bytes: u64) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /** Count a packet from the Client dropped because of bandwidth caps
+    */
+    pub fn add_dropped_packet(&self) {
+        self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /** Get amount of bytes the Client can send through the relay right now
+    */
+    pub(crate) fn bandwidth_level(&self, rate: u64, capacity: u64) -> u64 {
+        self.bandwidth.lock()
+            .get_or_insert_with(|| TokenBucket::new(capacity))
+            .level(rate, capacity)
+    }
+
+    /** Spend bytes of the Client bandwidth
+    */
+    pub(crate) fn spend_bandwidth(&self, bytes: u64) {
+        if let Some(bucket) = self.bandwidth.lock().as_mut() {
+            bucket.take(bytes);
+        }
+    }
+
+    /** Reset the Client bandwidth so it will be created again with new rate
+    */
+    pub(crate) fn reset_bandwidth(&self) {
+        *self.bandwidth.lock() = None;
+    }
+
     /** Send a packet. This method does not ignore IO error
     */
     fn send(&self, packet: Packet) -> impl Future<Output = Result<(), Error>> + Send {
+        let size = packet_size(&packet);
+        let bytes_out = self.bytes_out.clone();
         let mut tx = self.tx.clone();
 
         async move {
@@ -145,7 +209,10 @@ impl Client {
                     ErrorKind::Other,
                     format!("Failed to send packet: {:?}", e)
                 )),
-                Ok(_) => Ok(())
+                Ok(_) => {
+                    bytes_out.fetch_add(size, Ordering::Relaxed);
+                    Ok(())
+                }
             }
         }
     }
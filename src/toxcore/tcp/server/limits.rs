@@ -0,0 +1,359 @@
+/*! Limits of incoming connections to TCP relay server
+*/
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::toxcore::dht::ip_port::ipv4_mapped_to_ipv4;
+use crate::toxcore::time::*;
+
+/// Default maximum number of concurrent connections from a single IP address.
+const DEFAULT_CONNECTIONS_PER_IP: usize = 16;
+
+/// Default maximum number of concurrent connections from a single subnet.
+const DEFAULT_CONNECTIONS_PER_SUBNET: usize = 64;
+
+/// Default prefix length of IPv4 subnet.
+const DEFAULT_IPV4_SUBNET_PREFIX: u8 = 24;
+
+/// Default prefix length of IPv6 subnet.
+const DEFAULT_IPV6_SUBNET_PREFIX: u8 = 64;
+
+/// Default maximum number of handshakes from a single IP address during
+/// `handshake_rate_window`.
+const DEFAULT_HANDSHAKES_PER_IP: usize = 8;
+
+/// Default interval of time during which handshakes are counted.
+const DEFAULT_HANDSHAKE_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Interval of time for the TCP handshake.
+pub(crate) const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limits of incoming connections to TCP relay server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ServerLimits {
+    /// Maximum number of concurrent connections.
+    pub connections: usize,
+    /// Maximum number of concurrent connections from a single IP address.
+    pub connections_per_ip: usize,
+    /// Maximum number of concurrent connections from a single subnet.
+    pub connections_per_subnet: usize,
+    /// Prefix length of IPv4 subnet used for `connections_per_subnet`.
+    pub ipv4_subnet_prefix: u8,
+    /// Prefix length of IPv6 subnet used for `connections_per_subnet`.
+    pub ipv6_subnet_prefix: u8,
+    /// Maximum number of handshakes from a single IP address during
+    /// `handshake_rate_window`. Every handshake costs a DH computation so
+    /// exceeding connections are closed before the handshake.
+    pub handshakes_per_ip: usize,
+    /// Interval of time during which handshakes are counted.
+    pub handshake_rate_window: Duration,
+    /// Time given to a client to finish the handshake. The connection is closed
+    /// if the handshake is not finished in time.
+    pub handshake_timeout: Duration,
+}
+
+impl ServerLimits {
+    /// Create new `ServerLimits` with specified maximum number of concurrent
+    /// connections and default values for other limits.
+    pub fn new(connections: usize) -> ServerLimits {
+        ServerLimits {
+            connections,
+            ..ServerLimits::default()
+        }
+    }
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            connections: std::usize::MAX,
+            connections_per_ip: DEFAULT_CONNECTIONS_PER_IP,
+            connections_per_subnet: DEFAULT_CONNECTIONS_PER_SUBNET,
+            ipv4_subnet_prefix: DEFAULT_IPV4_SUBNET_PREFIX,
+            ipv6_subnet_prefix: DEFAULT_IPV6_SUBNET_PREFIX,
+            handshakes_per_ip: DEFAULT_HANDSHAKES_PER_IP,
+            handshake_rate_window: DEFAULT_HANDSHAKE_RATE_WINDOW,
+            handshake_timeout: TCP_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+/// Reason why an incoming connection was rejected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    /// Global connections limit is reached.
+    Connections,
+    /// Connections limit for the IP address is reached.
+    ConnectionsPerIp,
+    /// Connections limit for the subnet is reached.
+    ConnectionsPerSubnet,
+    /// Too many handshakes from the IP address.
+    HandshakeRate,
+}
+
+/// Handshakes made from an IP address during the current window.
+#[derive(Clone, Copy, Debug)]
+struct HandshakeRate {
+    /// Time when the current window started.
+    window_start: Instant,
+    /// Number of handshakes made during the current window.
+    count: usize,
+}
+
+/// Counters of active connections and handshakes.
+#[derive(Default)]
+struct TrackerState {
+    /// Number of active connections.
+    connections: usize,
+    /// Number of active connections per IP address.
+    per_ip: HashMap<IpAddr, usize>,
+    /// Number of active connections per subnet.
+    per_subnet: HashMap<IpAddr, usize>,
+    /// Handshakes made per IP address.
+    handshakes: HashMap<IpAddr, HandshakeRate>,
+}
+
+/// Tracks active connections to the TCP relay server and checks them against
+/// `ServerLimits`.
+#[derive(Clone)]
+pub(crate) struct ConnectionsTracker {
+    /// Limits to check connections against.
+    limits: ServerLimits,
+    /// Counters of active connections and handshakes.
+    state: Arc<Mutex<TrackerState>>,
+}
+
+impl ConnectionsTracker {
+    /// Create new `ConnectionsTracker`.
+    pub fn new(limits: ServerLimits) -> ConnectionsTracker {
+        ConnectionsTracker {
+            limits,
+            state: Arc::new(Mutex::new(TrackerState::default())),
+        }
+    }
+
+    /// Get the subnet of the IP address.
+    fn subnet(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let prefix = u32::from(self.limits.ipv4_subnet_prefix.min(32));
+                let mask = std::u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                IpAddr::V4((u32::from(ip) & mask).into())
+            },
+            IpAddr::V6(ip) => {
+                let prefix = u32::from(self.limits.ipv6_subnet_prefix.min(128));
+                let mask = std::u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                IpAddr::V6((u128::from(ip) & mask).into())
+            },
+        }
+    }
+
+    /// Try to take a connection slot for the IP address. The slot is released
+    /// when returned `ConnectionSlot` is dropped. IPv4-mapped addresses from
+    /// dual-stack listener are counted as IPv4 addresses.
+    pub fn acquire(&self, ip: IpAddr) -> Result<ConnectionSlot, RejectReason> {
+        let ip = ipv4_mapped_to_ipv4(ip);
+        let subnet = self.subnet(ip);
+        let mut state = self.state.lock();
+
+        if state.connections >= self.limits.connections {
+            return Err(RejectReason::Connections);
+        }
+        if state.per_ip.get(&ip).cloned().unwrap_or(0) >= self.limits.connections_per_ip {
+            return Err(RejectReason::ConnectionsPerIp);
+        }
+        if state.per_subnet.get(&subnet).cloned().unwrap_or(0) >= self.limits.connections_per_subnet {
+            return Err(RejectReason::ConnectionsPerSubnet);
+        }
+
+        let now = clock_now();
+        let window = self.limits.handshake_rate_window;
+        let rate = state.handshakes.entry(ip).or_insert(HandshakeRate {
+            window_start: now,
+            count: 0,
+        });
+        if now.saturating_duration_since(rate.window_start) >= window {
+            rate.window_start = now;
+            rate.count = 0;
+        }
+        if rate.count >= self.limits.handshakes_per_ip {
+            return Err(RejectReason::HandshakeRate);
+        }
+        rate.count += 1;
+
+        state.connections += 1;
+        *state.per_ip.entry(ip).or_insert(0) += 1;
+        *state.per_subnet.entry(subnet).or_insert(0) += 1;
+
+        Ok(ConnectionSlot {
+            tracker: self.clone(),
+            ip,
+            subnet,
+        })
+    }
+
+    /// Release a connection slot taken for the IP address.
+    fn release(&self, ip: IpAddr, subnet: IpAddr) {
+        let mut state = self.state.lock();
+        state.connections = state.connections.saturating_sub(1);
+        decrease(&mut state.per_ip, ip);
+        decrease(&mut state.per_subnet, subnet);
+    }
+
+    /// Remove handshake counters which windows are expired.
+    pub fn clear_expired(&self) {
+        let window = self.limits.handshake_rate_window;
+        self.state.lock().handshakes.retain(|_, rate| clock_elapsed(rate.window_start) < window);
+    }
+
+    /// Get the limits connections are checked against.
+    pub fn limits(&self) -> &ServerLimits {
+        &self.limits
+    }
+}
+
+/// Decrease counter for the key removing it when it reaches zero.
+fn decrease(counters: &mut HashMap<IpAddr, usize>, key: IpAddr) {
+    if let Some(count) = counters.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counters.remove(&key);
+        }
+    }
+}
+
+/// Connection slot taken from `ConnectionsTracker`. It's released on drop.
+pub(crate) struct ConnectionSlot {
+    /// Tracker the slot is taken from.
+    tracker: ConnectionsTracker,
+    /// IP address of the connection.
+    ip: IpAddr,
+    /// Subnet of the connection.
+    subnet: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.tracker.release(self.ip, self.subnet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_limits_new() {
+        let limits = ServerLimits::new(42);
+        assert_eq!(limits.connections, 42);
+        assert_eq!(limits.connections_per_ip, DEFAULT_CONNECTIONS_PER_IP);
+    }
+
+    #[test]
+    fn subnet() {
+        let tracker = ConnectionsTracker::new(ServerLimits::default());
+        assert_eq!(tracker.subnet("1.2.3.4".parse().unwrap()), "1.2.3.0".parse::<IpAddr>().unwrap());
+        assert_eq!(tracker.subnet("1:2:3:4:5:6:7:8".parse().unwrap()), "1:2:3:4::".parse::<IpAddr>().unwrap());
+
+        let tracker = ConnectionsTracker::new(ServerLimits {
+            ipv4_subnet_prefix: 0,
+            ipv6_subnet_prefix: 128,
+            ..ServerLimits::default()
+        });
+        assert_eq!(tracker.subnet("1.2.3.4".parse().unwrap()), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(tracker.subnet("1:2:3:4:5:6:7:8".parse().unwrap()), "1:2:3:4:5:6:7:8".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn acquire_connections_limit() {
+        let tracker = ConnectionsTracker::new(ServerLimits::new(1));
+
+        let slot = tracker.acquire("1.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(tracker.acquire("5.6.7.8".parse().unwrap()).err(), Some(RejectReason::Connections));
+        assert_eq!(tracker.state.lock().connections, 1);
+
+        drop(slot);
+        assert_eq!(tracker.state.lock().connections, 0);
+        assert!(tracker.acquire("5.6.7.8".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn acquire_connections_per_ip_limit() {
+        let tracker = ConnectionsTracker::new(ServerLimits {
+            connections_per_ip: 1,
+            ..ServerLimits::default()
+        });
+
+        let _slot = tracker.acquire("1.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(tracker.acquire("1.2.3.4".parse().unwrap()).err(), Some(RejectReason::ConnectionsPerIp));
+        assert!(tracker.acquire("1.2.3.5".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn acquire_connections_per_subnet_limit() {
+        let tracker = ConnectionsTracker::new(ServerLimits {
+            connections_per_subnet: 2,
+            ..ServerLimits::default()
+        });
+
+        let _slot_1 = tracker.acquire("1.2.3.4".parse().unwrap()).unwrap();
+        let _slot_2 = tracker.acquire("1.2.3.5".parse().unwrap()).unwrap();
+        assert_eq!(tracker.acquire("1.2.3.6".parse().unwrap()).err(), Some(RejectReason::ConnectionsPerSubnet));
+        assert!(tracker.acquire("1.2.4.6".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn acquire_ipv4_mapped() {
+        let tracker = ConnectionsTracker::new(ServerLimits {
+            connections_per_ip: 1,
+            connections_per_subnet: 2,
+            ..ServerLimits::default()
+        });
+
+        let _slot_1 = tracker.acquire("1.2.3.4".parse().unwrap()).unwrap();
+        assert_eq!(tracker.acquire("::ffff:1.2.3.4".parse().unwrap()).err(), Some(RejectReason::ConnectionsPerIp));
+        let _slot_2 = tracker.acquire("::ffff:1.2.3.5".parse().unwrap()).unwrap();
+        assert_eq!(tracker.acquire("1.2.3.6".parse().unwrap()).err(), Some(RejectReason::ConnectionsPerSubnet));
+        assert_eq!(tracker.acquire("::ffff:1.2.3.6".parse().unwrap()).err(), Some(RejectReason::ConnectionsPerSubnet));
+    }
+
+    #[tokio::test]
+    async fn acquire_handshake_rate_limit() {
+        tokio::time::pause();
+
+        let tracker = ConnectionsTracker::new(ServerLimits {
+            handshakes_per_ip: 2,
+            ..ServerLimits::default()
+        });
+        let ip = "1.2.3.4".parse().unwrap();
+
+        drop(tracker.acquire(ip).unwrap());
+        drop(tracker.acquire(ip).unwrap());
+        assert_eq!(tracker.acquire(ip).err(), Some(RejectReason::HandshakeRate));
+
+        tokio::time::advance(DEFAULT_HANDSHAKE_RATE_WINDOW).await;
+
+        assert!(tracker.acquire(ip).is_ok());
+    }
+
+    #[tokio::test]
+    async fn clear_expired() {
+        tokio::time::pause();
+
+        let tracker = ConnectionsTracker::new(ServerLimits::default());
+        drop(tracker.acquire("1.2.3.4".parse().unwrap()).unwrap());
+
+        tracker.clear_expired();
+        assert_eq!(tracker.state.lock().handshakes.len(), 1);
+
+        tokio::time::advance(DEFAULT_HANDSHAKE_RATE_WINDOW).await;
+
+        tracker.clear_expired();
+        assert!(tracker.state.lock().handshakes.is_empty());
+    }
+}
@@ -1,11 +1,15 @@
 /*! The implementation of TCP relay server
 */
 
+mod bandwidth;
 mod client;
+mod limits;
 #[allow(clippy::module_inception)]
 mod server;
 mod server_ext;
 
+pub use self::bandwidth::{BandwidthLimits, LowPriorityPolicy, ClientTraffic};
 pub use self::client::Client;
+pub use self::limits::{ServerLimits, RejectReason};
 pub use self::server::Server;
 pub use self::server_ext::ServerExt;
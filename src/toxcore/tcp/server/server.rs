@@ -3,21 +3,23 @@
 
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::onion::packet::InnerOnionResponse;
+use crate::toxcore::tcp::server::bandwidth::*;
 use crate::toxcore::tcp::server::client::Client;
 use crate::toxcore::tcp::connection_id::ConnectionId;
 use crate::toxcore::tcp::links::*;
 use crate::toxcore::tcp::packet::*;
 
 use std::io::{Error, ErrorKind};
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use futures::{Future, FutureExt, TryFutureExt, SinkExt, future};
 use futures::future::Either;
 use futures::channel::mpsc;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 /** A `Server` is a structure that holds connected clients, manages their links and handles
 their responses. Notice that there is no actual network code here, the `Server` accepts packets
@@ -41,8 +43,21 @@ pub struct Server {
 struct ServerState {
     pub connected_clients: HashMap<PublicKey, Client>,
     pub keys_by_addr: HashMap<(IpAddr, /*port*/ u16), PublicKey>,
+    pub bandwidth_limits: BandwidthLimits,
+    // None if there is no global bandwidth cap
+    pub global_bandwidth: Mutex<Option<TokenBucket>>,
 }
 
+/// Result of spending bandwidth for a packet.
+enum Spending {
+    /// Bandwidth is spent, the packet can be handled.
+    Spent,
+    /// Not enough bandwidth is left, the packet should wait for the given time
+    /// and try again.
+    Wait(Duration),
+    /// The packet should be dropped.
+    Drop,
+}
 
 impl Server {
     /** Create a new `Server` without onion
@@ -55,6 +70,38 @@ impl Server {
     pub fn set_udp_onion_sink(&mut self, onion_sink: mpsc::Sender<(OnionRequest, SocketAddr)>) {
         self.onion_sink = Some(onion_sink)
     }
+    /** Set bandwidth caps for packets forwarded by the server. Bandwidth left
+    for every client is reset.
+    */
+    pub fn set_bandwidth_limits(&self, limits: BandwidthLimits) {
+        let mut state = self.state.write();
+        state.bandwidth_limits = limits;
+        *state.global_bandwidth.lock() = limits.global.map(|rate| TokenBucket::new(capacity(rate)));
+        for client in state.connected_clients.values() {
+            client.reset_bandwidth();
+        }
+    }
+    /** Get bandwidth caps for packets forwarded by the server.
+    */
+    pub fn bandwidth_limits(&self) -> BandwidthLimits {
+        self.state.read().bandwidth_limits
+    }
+    /** Get traffic statistics of the connected client.
+    */
+    pub fn client_traffic(&self, pk: &PublicKey) -> Option<ClientTraffic> {
+        self.state.read().connected_clients.get(pk).map(Client::traffic)
+    }
+    /** Get traffic statistics of `count` connected clients that used the most
+    bandwidth. The heaviest user goes first.
+    */
+    pub fn top_clients(&self, count: usize) -> Vec<ClientTraffic> {
+        let mut traffic = self.state.read().connected_clients.values()
+            .map(Client::traffic)
+            .collect::<Vec<_>>();
+        traffic.sort_by_key(|traffic| Reverse(traffic.total()));
+        traffic.truncate(count);
+        traffic
+    }
     /** Insert the client into `connected_clients`. If `connected_clients`
     contains a client with the same pk it will be terminated.
     */
@@ -75,9 +122,62 @@ impl Server {
         shutdown_future
     }
     /** The main processing function. Call in on each incoming packet from connected and
-    handshaked client.
+    handshaked client. Packets exceeding bandwidth caps are handled when enough
+    bandwidth is restored so the returned future waits for it.
     */
     pub fn handle_packet(&self, pk: &PublicKey, packet: Packet) -> impl Future<Output = Result<(), Error>> + Send {
+        let size = packet_size(&packet);
+        if let Some(client) = self.state.read().connected_clients.get(pk) {
+            client.add_bytes_in(size);
+        }
+
+        match self.try_spend_bandwidth(pk, &packet, size) {
+            Spending::Spent => self.handle_packet_inner(pk, packet).boxed(),
+            Spending::Drop => future::ok(()).boxed(),
+            Spending::Wait(delay) => {
+                trace!("Bandwidth cap is exceeded by {:?}, delaying packet for {:?}", pk, delay);
+                let server = self.clone();
+                let pk = *pk;
+                async move {
+                    let mut delay = delay;
+                    loop {
+                        tokio::time::delay_for(delay).await;
+                        match server.try_spend_bandwidth(&pk, &packet, size) {
+                            Spending::Spent => break,
+                            Spending::Drop => return Ok(()),
+                            Spending::Wait(new_delay) => delay = new_delay,
+                        }
+                    }
+                    server.handle_packet_inner(&pk, packet).await
+                }.boxed()
+            },
+        }
+    }
+    /** Spend bandwidth of the client for the packet if it's forwarded to other
+    clients or to the onion subsystem.
+    */
+    fn try_spend_bandwidth(&self, pk: &PublicKey, packet: &Packet, size: u64) -> Spending {
+        let low_priority = match packet {
+            Packet::Data(_) => false,
+            Packet::OobSend(_) | Packet::OnionRequest(_) => true,
+            _ => return Spending::Spent,
+        };
+
+        let state = self.state.read();
+        let client = match state.connected_clients.get(pk) {
+            Some(client) => client,
+            None => return Spending::Spent,
+        };
+        let spending = Server::spend_bandwidth(&state, client, size, low_priority);
+        if let Spending::Drop = spending {
+            trace!("Bandwidth cap can't be satisfied for {:?}, dropping packet", pk);
+            client.add_dropped_packet();
+        }
+        spending
+    }
+    /** Handle the packet from the client when bandwidth is spent for it.
+    */
+    fn handle_packet_inner(&self, pk: &PublicKey, packet: Packet) -> impl Future<Output = Result<(), Error>> + Send {
         // TODO: use anonymous sum types when rust has them
         // https://github.com/rust-lang/rfcs/issues/294
         match packet {
@@ -94,6 +194,80 @@ impl Server {
             Packet::Data(packet) => self.handle_data(pk, packet).boxed(),
         }
     }
+    /** Check if the packet of `size` bytes from the client fits into bandwidth
+    caps and spend bandwidth for it if so. Otherwise return how long the packet
+    should wait for bandwidth to be restored, or drop it if it can never fit.
+    `OobSend` and `OnionRequest` packets are low priority ones. When less than
+    a half of global bandwidth is left the server is under load and every
+    client gets a fair share of it.
+    */
+    fn spend_bandwidth(state: &ServerState, client: &Client, size: u64, low_priority: bool) -> Spending {
+        let limits = state.bandwidth_limits;
+        if limits.per_client.is_none() && limits.global.is_none() {
+            return Spending::Spent;
+        }
+
+        let mut global_bandwidth = state.global_bandwidth.lock();
+        let global_level = match (limits.global, global_bandwidth.as_mut()) {
+            (Some(rate), Some(bucket)) => Some(bucket.level(rate, capacity(rate))),
+            _ => None,
+        };
+        let under_load = match (limits.global, global_level) {
+            (Some(rate), Some(level)) => level < capacity(rate) / 2,
+            _ => false,
+        };
+
+        if low_priority && under_load && limits.low_priority == LowPriorityPolicy::Drop {
+            return Spending::Drop;
+        }
+        // low priority packets are charged twice only while the global bucket
+        // is short
+        let cost = if low_priority && under_load && limits.low_priority == LowPriorityPolicy::Deprioritise {
+            size.saturating_mul(2)
+        } else {
+            size
+        };
+        if let (Some(rate), Some(level)) = (limits.global, global_level) {
+            if cost > capacity(rate) {
+                return Spending::Drop;
+            }
+            if level < cost {
+                return Spending::Wait(refill_time(cost - level, rate));
+            }
+        }
+
+        // every client can spend at once only its fair share of global
+        // bandwidth, and when the server is under load it's refilled with fair
+        // share of global rate only
+        let fair_share = limits.global
+            .map(|rate| rate / state.connected_clients.len().max(1) as u64);
+        let client_rate = match (limits.per_client, limits.global, fair_share) {
+            (per_client, Some(global), Some(fair_share)) => {
+                let rate = if under_load { fair_share } else { global };
+                Some(per_client.map_or(rate, |per_client| per_client.min(rate)))
+            },
+            (per_client, _, _) => per_client,
+        };
+        let client_capacity = match (limits.per_client, fair_share) {
+            (Some(per_client), Some(fair_share)) => capacity(per_client.min(fair_share)),
+            (per_client, fair_share) => capacity(per_client.or(fair_share).unwrap_or(0)),
+        };
+        if let Some(rate) = client_rate {
+            if cost > client_capacity || rate == 0 {
+                return Spending::Drop;
+            }
+            let level = client.bandwidth_level(rate, client_capacity);
+            if level < cost {
+                return Spending::Wait(refill_time(cost - level, rate));
+            }
+            client.spend_bandwidth(cost);
+        }
+
+        if let Some(bucket) = global_bandwidth.as_mut() {
+            bucket.take(cost);
+        }
+        Spending::Spent
+    }
     /** Send `OnionResponse` packet to the client by it's `std::net::IpAddr`.
     */
     pub fn handle_udp_onion_response(&self, ip_addr: IpAddr, port: u16, payload: InnerOnionResponse) -> impl Future<Output = Result<(), Error>> + Send {
@@ -1712,4 +1886,223 @@ mod tests {
         assert!(!server.state.read().connected_clients.contains_key(&pk_2));
         assert!(server.state.read().connected_clients.contains_key(&pk_3));
     }
+
+    #[tokio::test]
+    async fn client_traffic() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2).await.unwrap();
+
+        let oob_send = Packet::OobSend(OobSend { destination_pk: client_pk_2, data: vec![13; 1024] });
+        let oob_receive = Packet::OobReceive(OobReceive { sender_pk: client_pk_1, data: vec![13; 1024] });
+        server.handle_packet(&client_pk_1, oob_send.clone()).await.unwrap();
+
+        let traffic_1 = server.client_traffic(&client_pk_1).unwrap();
+        assert_eq!(traffic_1.bytes_in, packet_size(&oob_send));
+        assert_eq!(traffic_1.bytes_out, 0);
+        let traffic_2 = server.client_traffic(&client_pk_2).unwrap();
+        assert_eq!(traffic_2.bytes_in, 0);
+        assert_eq!(traffic_2.bytes_out, packet_size(&oob_receive));
+
+        assert!(server.client_traffic(&gen_keypair().0).is_none());
+    }
+
+    #[tokio::test]
+    async fn client_traffic_send_failed() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2).await.unwrap();
+
+        // the connection to the second client is closed
+        drop(rx_2);
+
+        let oob_send = Packet::OobSend(OobSend { destination_pk: client_pk_2, data: vec![13; 1024] });
+        server.handle_packet(&client_pk_1, oob_send).await.unwrap();
+
+        let traffic_2 = server.client_traffic(&client_pk_2).unwrap();
+        assert_eq!(traffic_2.bytes_out, 0);
+    }
+
+    #[tokio::test]
+    async fn top_clients() {
+        let server = Server::new();
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        client_1.add_bytes_in(10);
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        client_2.add_bytes_in(30);
+        server.insert(client_2).await.unwrap();
+
+        let (client_3, _rx_3) = create_random_client("1.2.3.6:12345".parse().unwrap());
+        let client_pk_3 = client_3.pk();
+        client_3.add_bytes_in(20);
+        server.insert(client_3).await.unwrap();
+
+        let top = server.top_clients(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].pk, client_pk_2);
+        assert_eq!(top[1].pk, client_pk_3);
+        assert!(top.iter().all(|traffic| traffic.pk != client_pk_1));
+    }
+
+    #[tokio::test]
+    async fn per_client_bandwidth_limit() {
+        tokio::time::pause();
+
+        let server = Server::new();
+        server.set_bandwidth_limits(BandwidthLimits {
+            per_client: Some(1500),
+            .. BandwidthLimits::default()
+        });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, mut rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2).await.unwrap();
+
+        let oob_send = Packet::OobSend(OobSend { destination_pk: client_pk_2, data: vec![13; 1024] });
+        server.handle_packet(&client_pk_1, oob_send.clone()).await.unwrap();
+        assert!(rx_2.next().await.is_some());
+
+        // the second packet exceeds the cap and should wait
+        let mut future = server.handle_packet(&client_pk_1, oob_send).boxed();
+        assert!((&mut future).now_or_never().is_none());
+        assert!(rx_2.try_recv().is_err());
+
+        // bandwidth is restored in time
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        future.await.unwrap();
+        assert!(rx_2.next().await.is_some());
+        assert_eq!(server.client_traffic(&client_pk_1).unwrap().dropped_packets, 0);
+    }
+
+    #[tokio::test]
+    async fn per_client_bandwidth_limit_less_than_packet() {
+        tokio::time::pause();
+
+        let server = Server::new();
+        server.set_bandwidth_limits(BandwidthLimits {
+            per_client: Some(1000),
+            .. BandwidthLimits::default()
+        });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, mut rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2).await.unwrap();
+
+        // the packet can never fit into the cap so it should be dropped
+        let oob_send = Packet::OobSend(OobSend { destination_pk: client_pk_2, data: vec![13; 1024] });
+        server.handle_packet(&client_pk_1, oob_send).await.unwrap();
+
+        assert!(rx_2.try_recv().is_err());
+        assert_eq!(server.client_traffic(&client_pk_1).unwrap().dropped_packets, 1);
+    }
+
+    /// Send three `OobSend` packets of 1057 bytes when the global cap is 4000
+    /// bytes per second. Get the number of packets handled without waiting and
+    /// the number of dropped packets.
+    async fn oobs_under_load(policy: LowPriorityPolicy) -> (usize, u64) {
+        tokio::time::pause();
+
+        let server = Server::new();
+        server.set_bandwidth_limits(BandwidthLimits {
+            global: Some(4000),
+            low_priority: policy,
+            .. BandwidthLimits::default()
+        });
+
+        let (client, _rx) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk = client.pk();
+        server.insert(client).await.unwrap();
+
+        let oob_send = Packet::OobSend(OobSend { destination_pk: gen_keypair().0, data: vec![13; 1024] });
+        let mut handled = 0;
+        for _ in 0 .. 3 {
+            if server.handle_packet(&client_pk, oob_send.clone()).now_or_never().is_some() {
+                handled += 1;
+            }
+        }
+
+        (handled, server.client_traffic(&client_pk).unwrap().dropped_packets)
+    }
+
+    #[tokio::test]
+    async fn low_priority_forward() {
+        assert_eq!(oobs_under_load(LowPriorityPolicy::Forward).await, (3, 0));
+    }
+
+    #[tokio::test]
+    async fn low_priority_deprioritise() {
+        // the server is under load after the second packet so only the third
+        // one is charged twice and has to wait
+        assert_eq!(oobs_under_load(LowPriorityPolicy::Deprioritise).await, (2, 0));
+    }
+
+    #[tokio::test]
+    async fn low_priority_drop() {
+        // the server is under load after the second packet
+        assert_eq!(oobs_under_load(LowPriorityPolicy::Drop).await, (3, 1));
+    }
+
+    #[tokio::test]
+    async fn global_bandwidth_fair_share() {
+        tokio::time::pause();
+
+        let server = Server::new();
+        server.set_bandwidth_limits(BandwidthLimits {
+            global: Some(8000),
+            .. BandwidthLimits::default()
+        });
+
+        let (client_1, _rx_1) = create_random_client("1.2.3.4:12345".parse().unwrap());
+        let client_pk_1 = client_1.pk();
+        server.insert(client_1).await.unwrap();
+
+        let (client_2, _rx_2) = create_random_client("1.2.3.5:12345".parse().unwrap());
+        let client_pk_2 = client_2.pk();
+        server.insert(client_2).await.unwrap();
+
+        let oob_send_1 = Packet::OobSend(OobSend { destination_pk: client_pk_2, data: vec![13; 1024] });
+        let oob_send_2 = Packet::OobSend(OobSend { destination_pk: client_pk_1, data: vec![13; 1024] });
+
+        // client_1 can spend only its fair share of global bandwidth at once
+        for _ in 0 .. 3 {
+            server.handle_packet(&client_pk_1, oob_send_1.clone()).await.unwrap();
+        }
+        let mut future = server.handle_packet(&client_pk_1, oob_send_1).boxed();
+        assert!((&mut future).now_or_never().is_none());
+
+        // while client_2 still can send
+        server.handle_packet(&client_pk_2, oob_send_2).await.unwrap();
+        assert_eq!(server.client_traffic(&client_pk_2).unwrap().dropped_packets, 0);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        future.await.unwrap();
+        assert_eq!(server.client_traffic(&client_pk_1).unwrap().dropped_packets, 0);
+    }
 }
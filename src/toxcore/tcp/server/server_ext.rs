@@ -2,8 +2,6 @@
 */
 
 use std::io::{Error as IoError};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration};
 use std::pin::Pin;
 
@@ -18,15 +16,13 @@ use tokio::time::{Error as TimerError};
 use crate::toxcore::crypto_core::*;
 use crate::toxcore::tcp::codec::{DecodeError, EncodeError, Codec};
 use crate::toxcore::tcp::handshake::make_server_handshake;
-use crate::toxcore::tcp::server::{Client, Server};
+use crate::toxcore::tcp::server::{Client, Server, ServerLimits, RejectReason};
+use crate::toxcore::tcp::server::limits::{ConnectionsTracker, TCP_HANDSHAKE_TIMEOUT};
 use crate::toxcore::stats::*;
 
 /// Interval of time for Tcp Ping sender
 const TCP_PING_INTERVAL: Duration = Duration::from_secs(1);
 
-/// Interval of time for the TCP handshake.
-const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
-
 const SERVER_CHANNEL_SIZE: usize = 64;
 
 /// Error that can happen during server execution
@@ -119,17 +115,19 @@ pub enum ConnectionError {
 
 /// Extension trait for running TCP server on incoming `TcpStream` and ping sender
 pub trait ServerExt {
-    /// Running TCP ping sender and incoming `TcpStream`. This function uses
+    /// Running TCP ping sender and incoming `TcpStream`. Incoming connections
+    /// exceeding `limits` are closed right away. This function uses
     /// `tokio::spawn` inside so it should be executed via tokio to be able to
     /// get tokio default executor.
-    fn run(self: Self, listener: TcpListener, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>>;
+    fn run(self: Self, listener: TcpListener, dht_sk: SecretKey, stats: Stats, limits: ServerLimits) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>>;
     /// Running TCP server on incoming `TcpStream`
     fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin>;
 }
 
 impl ServerExt for Server {
-    fn run(self: Self, mut listener: TcpListener, dht_sk: SecretKey, stats: Stats, connections_limit: usize) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>> {
-        let connections_count = Arc::new(AtomicUsize::new(0));
+    fn run(self: Self, mut listener: TcpListener, dht_sk: SecretKey, stats: Stats, limits: ServerLimits) -> Pin<Box<dyn Future<Output = Result<(), ServerRunError>> + Send>> {
+        let tracker = ConnectionsTracker::new(limits);
+        let tracker_c = tracker.clone();
 
         let self_c = self.clone();
 
@@ -137,29 +135,44 @@ impl ServerExt for Server {
             listener.incoming()
                 .map_err(|error| ServerRunError::IncomingError { error })
                 .try_for_each(move |stream| {
-                    if connections_count.load(Ordering::SeqCst) < connections_limit {
-                        connections_count.fetch_add(1, Ordering::SeqCst);
-                        let connections_count_c = connections_count.clone();
-                        let self_cc = self_c.clone();
-                        let dht_sk = dht_sk.clone();
-                        let stats = stats.clone();
-
-                        tokio::spawn(
-                            async move {
-                                let res: Result<_, ConnectionError> = self_cc
-                                    .run_connection(stream, dht_sk, stats)
-                                    .await;
-
-                                if let Err(ref e) = res {
-                                    error!("Error while running tcp connection: {:?}", e)
+                    let addr = match stream.peer_addr() {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            trace!("Failed to get peer address of a new TCP connection: {}", e);
+                            return future::ok(());
+                        },
+                    };
+
+                    match tracker.acquire(addr.ip()) {
+                        Ok(slot) => {
+                            let self_cc = self_c.clone();
+                            let dht_sk = dht_sk.clone();
+                            let stats = stats.clone();
+                            let handshake_timeout = tracker.limits().handshake_timeout;
+
+                            tokio::spawn(
+                                async move {
+                                    let res: Result<_, ConnectionError> =
+                                        run_connection(self_cc, stream, dht_sk, stats, handshake_timeout)
+                                        .await;
+
+                                    if let Err(ref e) = res {
+                                        error!("Error while running tcp connection: {:?}", e)
+                                    }
+
+                                    drop(slot);
+                                    res
                                 }
-
-                                connections_count_c.fetch_sub(1, Ordering::SeqCst);
-                                res
-                            }
-                        );
-                    } else {
-                        trace!("Tcp server has reached the limit of {} connections", connections_limit);
+                            );
+                        },
+                        Err(RejectReason::HandshakeRate) => {
+                            trace!("Tcp server has reached the handshake rate limit for {}", addr.ip());
+                            stats.counters.increase_throttled_handshakes();
+                        },
+                        Err(reason) => {
+                            trace!("Tcp server rejected connection from {}: {:?}", addr, reason);
+                            stats.counters.increase_rejected_connections();
+                        },
                     }
 
                     future::ok(())
@@ -170,6 +183,7 @@ impl ServerExt for Server {
         let ping_future = async move {
             while let Some(_) = wakeups.next().await {
                 trace!("Tcp server ping sender wake up");
+                tracker_c.clear_expired();
                 self.send_pings().await
                     .map_err(|error| ServerRunError::SendPingsError { error })?;
             }
@@ -188,83 +202,90 @@ impl ServerExt for Server {
     }
 
     fn run_connection(self: Self, stream: TcpStream, dht_sk: SecretKey, stats: Stats) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin> {
-        let addr = match stream.peer_addr() {
-            Ok(addr) => addr,
-            Err(error) => return Box::new(future::err(ConnectionError::PeerAddrError {
-                error
-            })),
-        };
-
-        debug!("A new TCP client connected from {}", addr);
-
-        let process = async move {
-            let fut = tokio::time::timeout(
-                TCP_HANDSHAKE_TIMEOUT,
-                make_server_handshake(stream, dht_sk.clone())
-            );
-            let (stream, channel, client_pk) = match fut.await {
-                Err(error) => Err(
-                    ConnectionError::ServerHandshakeTimeoutError { error }
-                ),
-                Ok(Err(error)) => Err(
-                    ConnectionError::ServerHandshakeIoError { error }
-                ),
-                Ok(Ok(res)) => Ok(res)
-            }?;
-
-            debug!("Handshake for TCP client {:?} is completed", client_pk);
-
-            let secure_socket = Framed::new(stream, Codec::new(channel, stats));
-            let (mut to_client, from_client) = secure_socket.split();
-            let (to_client_tx, mut to_client_rx) = mpsc::channel(SERVER_CHANNEL_SIZE);
-
-            let server = self.clone();
-            // processor = for each Packet from client process it
-            let processor = from_client
-                .map_err(|error| ConnectionError::DecodePacketError { error })
-                .try_for_each(move |packet| {
-                    debug!("Handle {:?} => {:?}", client_pk, packet);
-                    server.handle_packet(&client_pk, packet)
-                        .map_err(|error| ConnectionError::PacketHandlingError { error } )
-                });
-
-            let writer = async {
-                while let Some(packet) = to_client_rx.next().await {
-                    trace!("Sending TCP packet {:?} to {:?}", packet, client_pk);
-                    to_client.send(packet).await
-                        .map_err(|error| ConnectionError::SendPacketError {
-                            error
-                        })?;
-                }
-
-                Ok(())
-            };
+        run_connection(self, stream, dht_sk, stats, TCP_HANDSHAKE_TIMEOUT)
+    }
+}
 
-            let client = Client::new(
-                to_client_tx,
-                &client_pk,
-                addr.ip(),
-                addr.port()
-            );
-            self.insert(client).await
-                .map_err(|error| ConnectionError::InsertClientError { error })?;
-
-            let r_processing = futures::select! {
-                res = processor.fuse() => res,
-                res = writer.fuse() => res
-            };
+/// Running TCP server on incoming `TcpStream`. The connection is closed if the
+/// client doesn't finish the handshake in `handshake_timeout`.
+fn run_connection(server: Server, stream: TcpStream, dht_sk: SecretKey, stats: Stats, handshake_timeout: Duration) -> Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Unpin> {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(error) => return Box::new(future::err(ConnectionError::PeerAddrError {
+            error
+        })),
+    };
+
+    debug!("A new TCP client connected from {}", addr);
+
+    let process = async move {
+        let fut = tokio::time::timeout(
+            handshake_timeout,
+            make_server_handshake(stream, dht_sk.clone())
+        );
+        let (stream, channel, client_pk) = match fut.await {
+            Err(error) => {
+                stats.counters.increase_handshake_timeouts();
+                Err(ConnectionError::ServerHandshakeTimeoutError { error })
+            },
+            Ok(Err(error)) => Err(
+                ConnectionError::ServerHandshakeIoError { error }
+            ),
+            Ok(Ok(res)) => Ok(res)
+        }?;
+
+        debug!("Handshake for TCP client {:?} is completed", client_pk);
+
+        let secure_socket = Framed::new(stream, Codec::new(channel, stats));
+        let (mut to_client, from_client) = secure_socket.split();
+        let (to_client_tx, mut to_client_rx) = mpsc::channel(SERVER_CHANNEL_SIZE);
+
+        let server_c = server.clone();
+        // processor = for each Packet from client process it
+        let processor = from_client
+            .map_err(|error| ConnectionError::DecodePacketError { error })
+            .try_for_each(move |packet| {
+                debug!("Handle {:?} => {:?}", client_pk, packet);
+                server_c.handle_packet(&client_pk, packet)
+                    .map_err(|error| ConnectionError::PacketHandlingError { error } )
+            });
 
-            debug!("Shutdown a client with PK {:?}", &client_pk);
+        let writer = async {
+            while let Some(packet) = to_client_rx.next().await {
+                trace!("Sending TCP packet {:?} to {:?}", packet, client_pk);
+                to_client.send(packet).await
+                    .map_err(|error| ConnectionError::SendPacketError {
+                        error
+                    })?;
+            }
 
-            self.shutdown_client(&client_pk, addr.ip(), addr.port())
-                .await
-                .map_err(|error| ConnectionError::ShutdownError { error })?;
+            Ok(())
+        };
 
-            r_processing
+        let client = Client::new(
+            to_client_tx,
+            &client_pk,
+            addr.ip(),
+            addr.port()
+        );
+        server.insert(client).await
+            .map_err(|error| ConnectionError::InsertClientError { error })?;
+
+        let r_processing = futures::select! {
+            res = processor.fuse() => res,
+            res = writer.fuse() => res
         };
 
-        Box::new(process.boxed())
-    }
+        debug!("Shutdown a client with PK {:?}", &client_pk);
+
+        server.shutdown_client(&client_pk, addr.ip(), addr.port())
+            .await
+            .map_err(|error| ConnectionError::ShutdownError { error })?;
+
+        r_processing
+    };
+
+    Box::new(process.boxed())
 }
 
 #[cfg(test)]
@@ -336,7 +357,7 @@ mod tests {
         let addr = listener.local_addr().unwrap();
 
         let stats = Stats::new();
-        let server = Server::new().run(listener, server_sk, stats.clone(), 1)
+        let server = Server::new().run(listener, server_sk, stats.clone(), ServerLimits::new(1))
             .map_err(Error::from);
 
         let client = async {
@@ -367,4 +388,86 @@ mod tests {
         let r = both.await.into_inner().0;
         assert!(r.is_ok());
     }
+
+    #[tokio::test]
+    async fn run_connection_handshake_timeout() {
+        tokio::time::pause();
+        crypto_init().unwrap();
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stats = Stats::new();
+
+        // connect but don't send handshake
+        let _socket = TcpStream::connect(&addr).await.unwrap();
+        let connection = listener.incoming().next().await.unwrap().unwrap();
+        let server = Server::new().run_connection(connection, server_sk, stats.clone());
+
+        tokio::time::advance(TCP_HANDSHAKE_TIMEOUT + Duration::from_secs(1)).await;
+
+        let error = server.await.err().unwrap();
+        match error {
+            ConnectionError::ServerHandshakeTimeoutError { .. } => {},
+            error => panic!("Unexpected error: {:?}", error),
+        }
+        assert_eq!(stats.counters.handshake_timeouts(), 1);
+    }
+
+    #[tokio::test]
+    async fn run_connections_per_ip_limit() {
+        use tokio::io::AsyncReadExt;
+
+        crypto_init().unwrap();
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stats = Stats::new();
+        let limits = ServerLimits {
+            connections_per_ip: 1,
+            ..ServerLimits::default()
+        };
+        tokio::spawn(Server::new().run(listener, server_sk, stats.clone(), limits));
+
+        let _socket_1 = TcpStream::connect(&addr).await.unwrap();
+        let mut socket_2 = TcpStream::connect(&addr).await.unwrap();
+
+        // the second connection should be closed right away
+        let mut buf = [0; 1];
+        assert_eq!(socket_2.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(stats.counters.rejected_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn run_handshake_rate_limit() {
+        use tokio::io::AsyncReadExt;
+
+        crypto_init().unwrap();
+        let (_server_pk, server_sk) = gen_keypair();
+
+        let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stats = Stats::new();
+        let limits = ServerLimits {
+            handshakes_per_ip: 1,
+            handshake_rate_window: Duration::from_secs(3600),
+            ..ServerLimits::default()
+        };
+        tokio::spawn(Server::new().run(listener, server_sk, stats.clone(), limits));
+
+        drop(TcpStream::connect(&addr).await.unwrap());
+        let mut socket = TcpStream::connect(&addr).await.unwrap();
+
+        // the second connection should be closed before the handshake
+        let mut buf = [0; 1];
+        assert_eq!(socket.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(stats.counters.throttled_handshakes(), 1);
+    }
 }